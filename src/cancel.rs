@@ -0,0 +1,312 @@
+use std::{
+    cell::RefCell,
+    io,
+    process::{Child, Command},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+#[cfg(windows)]
+use std::{ffi::c_void, sync::atomic::AtomicPtr};
+
+use anyhow::{bail, Result};
+use once_cell::sync::OnceCell;
+
+use crate::error::Error;
+
+/// Stops a single job, such as one cancelled through the server,
+/// while the rest of the batch carries on.
+///
+/// Clones share the same state, so one can be kept to cancel the job
+/// while another is given to [`Pipeline::run`](crate::pipeline::Pipeline::run).
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    state: Arc<CancelState>,
+}
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    /// The tools running for the job
+    children: Mutex<Vec<u32>>,
+    /// The job object the job's tools are placed in,
+    /// created when the first one is spawned
+    #[cfg(windows)]
+    job: AtomicPtr<c_void>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Stops the tools running for the job, along with their own children,
+    /// returning how many were signalled.
+    ///
+    /// No further stages are started, and the job fails as interrupted.
+    /// Intermediates the job was writing are left marked as incomplete,
+    /// so they are made again rather than reused.
+    pub fn cancel(&self) -> Result<usize> {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+        self.state.stop_children()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+thread_local! {
+    static CURRENT_TOKEN: RefCell<Option<CancelToken>> = const { RefCell::new(None) };
+}
+
+/// Lets tools spawned by this thread be stopped by `token`
+pub fn set_cancel_token(token: Option<CancelToken>) {
+    CURRENT_TOKEN.with(|current| *current.borrow_mut() = token);
+}
+
+pub fn current_cancel_token() -> Option<CancelToken> {
+    CURRENT_TOKEN.with(|current| current.borrow().clone())
+}
+
+/// Whether the job this thread is working on was cancelled
+pub fn is_cancelled() -> bool {
+    current_cancel_token().map_or(false, |token| token.is_cancelled())
+}
+
+/// Fails if the job this thread is working on was cancelled,
+/// for use before starting something new
+pub fn check_cancelled() -> Result<()> {
+    if is_cancelled() {
+        return Err(Error::Interrupted("Cancelled".to_string()).into());
+    }
+    Ok(())
+}
+
+/// Tools which are running, by process group, so they can be signalled together
+static RUNNING_TOOLS: OnceCell<Mutex<Vec<u32>>> = OnceCell::new();
+
+fn running_tools() -> &'static Mutex<Vec<u32>> {
+    RUNNING_TOOLS.get_or_init(Default::default)
+}
+
+/// A tool which is stopped along with the job it was spawned for, until it is dropped
+pub struct TrackedChild {
+    token: Option<CancelToken>,
+    pid: u32,
+}
+
+/// Spawns `command` as a tool which is stopped along with the job
+/// this thread is working on.
+///
+/// On Unix the tool starts a process group of its own, which everything it
+/// spawns joins too, so they can all be signalled together. It isn't in the
+/// terminal's foreground group, so it doesn't get terminal input.
+/// On Windows it is placed in a job object for the job instead.
+///
+/// A tool spawned just after its job was cancelled is stopped straight away.
+pub fn spawn_tracked(command: &mut Command) -> io::Result<(Child, TrackedChild)> {
+    start_process_group(command);
+    let child = command.spawn()?;
+    let token = current_cancel_token();
+    let pid = child.id();
+    running_tools().lock().unwrap().push(pid);
+    if let Some(ref token) = token {
+        #[cfg(windows)]
+        {
+            if let Err(err) = token.state.assign_to_job(&child) {
+                tracing::warn!("{}", err);
+            }
+        }
+        token.state.children.lock().unwrap().push(pid);
+        if token.is_cancelled() {
+            let _ = token.state.stop_children();
+        }
+    }
+    Ok((child, TrackedChild { token, pid }))
+}
+
+impl Drop for TrackedChild {
+    fn drop(&mut self) {
+        running_tools()
+            .lock()
+            .unwrap()
+            .retain(|&pid| pid != self.pid);
+        if let Some(ref token) = self.token {
+            token
+                .state
+                .children
+                .lock()
+                .unwrap()
+                .retain(|&pid| pid != self.pid);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn start_process_group(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+
+    // SAFETY: Only async-signal-safe functions are called between fork and exec
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Reading the terminal from outside its foreground group
+            // would stop the tool, such as ffmpeg checking for keypresses
+            if libc::isatty(libc::STDIN_FILENO) == 1 {
+                let null = libc::open(b"/dev/null\0".as_ptr().cast(), libc::O_RDONLY);
+                if null >= 0 {
+                    libc::dup2(null, libc::STDIN_FILENO);
+                    libc::close(null);
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn start_process_group(_command: &mut Command) {}
+
+/// Sends `signal` to every running tool along with everything it spawned,
+/// returning how many tools were signalled
+#[cfg(unix)]
+pub fn signal_tools(signal: i32) -> Result<usize> {
+    let groups = running_tools().lock().unwrap().clone();
+    let mut count = 0;
+    for pgid in groups {
+        if signal_group(pgid, signal)? {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Sends `signal` to the process group `pgid`, returning false if it has exited
+#[cfg(unix)]
+fn signal_group(pgid: u32, signal: i32) -> Result<bool> {
+    // SAFETY: `kill` has no memory safety requirements
+    let result = unsafe { libc::kill(-(pgid as libc::pid_t), signal) };
+    if result != 0 {
+        let err = io::Error::last_os_error();
+        // The tools may have exited since we looked for them
+        if err.raw_os_error() == Some(libc::ESRCH) {
+            return Ok(false);
+        }
+        bail!("Failed to signal process group {}: {}", pgid, err);
+    }
+    Ok(true)
+}
+
+impl CancelState {
+    /// Sends SIGTERM to the job's tools and everything they spawned,
+    /// returning how many tools were signalled
+    #[cfg(unix)]
+    fn stop_children(&self) -> Result<usize> {
+        let children = self.children.lock().unwrap().clone();
+        let mut count = 0;
+        for pid in children {
+            if signal_group(pid, libc::SIGTERM)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Terminates the job object holding the job's tools and everything they spawned,
+    /// returning how many tools were stopped
+    #[cfg(windows)]
+    fn stop_children(&self) -> Result<usize> {
+        use windows_sys::Win32::System::JobObjects::TerminateJobObject;
+
+        let count = self.children.lock().unwrap().len();
+        if count == 0 {
+            return Ok(0);
+        }
+        let job = self.job.load(Ordering::SeqCst);
+        if job.is_null() {
+            bail!(
+                "The running tools could not be placed in a job object, so they can't be stopped"
+            );
+        }
+        // SAFETY: The job handle stays open until this state is dropped
+        if unsafe { TerminateJobObject(job, 1) } == 0 {
+            bail!(
+                "Failed to stop running tools: {}",
+                io::Error::last_os_error()
+            );
+        }
+        Ok(count)
+    }
+
+    /// Stopping a running tool's children isn't supported here,
+    /// so fail rather than leave the tool running unnoticed.
+    /// The job is still marked as cancelled, so no further stages are started.
+    #[cfg(not(any(unix, windows)))]
+    fn stop_children(&self) -> Result<usize> {
+        if self.children.lock().unwrap().is_empty() {
+            return Ok(0);
+        }
+        bail!("Stopping running tools is not supported on this platform")
+    }
+
+    /// Places `child` in the job object for this job, creating it for the first tool
+    #[cfg(windows)]
+    fn assign_to_job(&self, child: &Child) -> Result<()> {
+        use std::{os::windows::io::AsRawHandle, ptr};
+
+        use windows_sys::Win32::{
+            Foundation::CloseHandle,
+            System::JobObjects::{AssignProcessToJobObject, CreateJobObjectW},
+        };
+
+        let mut job = self.job.load(Ordering::SeqCst);
+        if job.is_null() {
+            // SAFETY: Null attributes and name are allowed
+            let created = unsafe { CreateJobObjectW(ptr::null(), ptr::null()) };
+            if created.is_null() {
+                bail!(
+                    "Failed to create job object: {}",
+                    io::Error::last_os_error()
+                );
+            }
+            job = match self.job.compare_exchange(
+                ptr::null_mut(),
+                created,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => created,
+                // Another tool of this job created one first
+                Err(existing) => {
+                    // SAFETY: `created` is a valid handle which nothing else uses
+                    unsafe { CloseHandle(created) };
+                    existing
+                }
+            };
+        }
+        // SAFETY: Both handles are valid for the duration of the call
+        if unsafe { AssignProcessToJobObject(job, child.as_raw_handle()) } == 0 {
+            bail!(
+                "Failed to place process {} in a job object: {}",
+                child.id(),
+                io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+impl Drop for CancelState {
+    fn drop(&mut self) {
+        let job = *self.job.get_mut();
+        if !job.is_null() {
+            // SAFETY: The handle is valid and no longer used
+            unsafe { windows_sys::Win32::Foundation::CloseHandle(job) };
+        }
+    }
+}
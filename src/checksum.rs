@@ -0,0 +1,110 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+
+/// Where the checksum of `path` is written, next to it
+pub fn checksum_path(path: &Path) -> PathBuf {
+    let mut name = path
+        .file_name()
+        .expect("File should have a name")
+        .to_os_string();
+    name.push(".sha256");
+    path.with_file_name(name)
+}
+
+/// Hashes the file in chunks, so outputs don't have to fit in memory
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    let mut hex = String::with_capacity(64);
+    for byte in hasher.finalize() {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    Ok(hex)
+}
+
+/// Writes `<path>.sha256`, in the format `sha256sum` reads
+pub fn write_checksum(path: &Path) -> Result<PathBuf> {
+    let hash = sha256_file(path)?;
+    let sidecar = checksum_path(path);
+    fs::write(
+        &sidecar,
+        format!(
+            "{}  {}\n",
+            hash,
+            path.file_name()
+                .expect("File should have a name")
+                .to_string_lossy()
+        ),
+    )?;
+    Ok(sidecar)
+}
+
+/// Checks files against their checksums, returning whether every one matched.
+///
+/// Each path may be a checksum file, a file with a checksum next to it,
+/// or a directory whose checksum files are all checked.
+pub fn verify_checksums(paths: &[PathBuf]) -> Result<bool> {
+    let mut sidecars = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut found = fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().map_or(false, |ext| ext == "sha256"))
+                .collect::<Vec<_>>();
+            if found.is_empty() {
+                warn!("No checksums found in {}", path.display());
+            }
+            found.sort();
+            sidecars.extend(found);
+        } else if path.extension().map_or(false, |ext| ext == "sha256") {
+            sidecars.push(path.clone());
+        } else {
+            sidecars.push(checksum_path(path));
+        }
+    }
+
+    let mut all_ok = true;
+    for sidecar in sidecars {
+        let contents = fs::read_to_string(&sidecar)
+            .map_err(|e| anyhow!("Unable to read {}: {}", sidecar.display(), e))?;
+        let dir = sidecar.parent().unwrap_or_else(|| Path::new("."));
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let (expected, name) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow!("Invalid checksum line in {}", sidecar.display()))?;
+            // `sha256sum` marks files hashed in binary mode with `*`
+            let name = name.trim_start().trim_start_matches('*');
+            let file = dir.join(name);
+            match sha256_file(&file) {
+                Ok(actual) if actual.eq_ignore_ascii_case(expected) => {
+                    info!(success = true, "{}: OK", file.display());
+                }
+                Ok(_) => {
+                    error!("{}: checksum does not match", file.display());
+                    all_ok = false;
+                }
+                Err(e) => {
+                    error!("{}: unable to read: {}", file.display(), e);
+                    all_ok = false;
+                }
+            }
+        }
+    }
+    Ok(all_ok)
+}
@@ -1,19 +1,29 @@
 use std::{
+    fs,
     path::{Path, PathBuf},
     str::FromStr,
 };
 
+use anyhow::{anyhow, bail, Result};
+use itertools::Itertools;
 use nom::{
     branch::alt,
-    bytes::complete::tag,
+    bytes::complete::{is_not, tag, take_until},
     character::complete::{alpha1, alphanumeric1, char, digit1},
     combinator::{opt, recognize},
     multi::separated_list1,
-    sequence::{preceded, tuple},
+    sequence::{delimited, preceded, tuple},
     IResult,
 };
 
-use crate::{AudioEncoder, Profile, VideoEncoder};
+use av_data::pixel::{
+    ChromaLocation, ColorPrimaries, MatrixCoefficients, TransferCharacteristic, YUVRange,
+};
+
+use crate::{
+    parse_chroma_location, parse_color_primaries, parse_color_range, parse_matrix_coefficients,
+    parse_transfer_characteristic, AudioEncoder, Compat, Profile, VideoEncoder,
+};
 
 #[derive(Debug, Clone)]
 pub enum ParsedFilter<'a> {
@@ -22,15 +32,58 @@ pub enum ParsedFilter<'a> {
     Speed(u8),
     Profile(Profile),
     Grain(u8),
-    Compat(bool),
+    Compat(Compat),
+    Direct(bool),
     Extension(&'a str),
+    TargetQuality(f32),
+    Probes(u32),
+    ProbingRate(u32),
+    ChunkMethod(&'a str),
+    ExtraArgs(&'a str),
+    Av1anArgs(&'a str),
+    Tiles {
+        cols: u8,
+        rows: u8,
+    },
+    GrainTable(PathBuf),
+    PostGrainSynth,
+    AcBias(f32),
+    VarianceBoostStrength(u8),
+    VarianceOctile(u8),
+    Sharpness(i8),
+    QmMax(u8),
+    PsyRd(f32),
+    AqStrength(f32),
+    Qcomp(f32),
+    Bframes(u8),
+    X264Zones(String),
+    ForceKeyframes(String),
+    ScriptOutput(u8),
+    Sar((u32, u32)),
     BitDepth(u8),
-    Resolution { width: u32, height: u32 },
+    Resolution {
+        width: u32,
+        height: u32,
+    },
+    Crop {
+        left: u32,
+        right: u32,
+        top: u32,
+        bottom: u32,
+    },
+    ColorRange(YUVRange),
+    Primaries(ColorPrimaries),
+    Matrix(MatrixCoefficients),
+    Transfer(TransferCharacteristic),
+    ChromaLocation(ChromaLocation),
+    Hdr(bool),
     AudioEncoder(&'a str),
     AudioBitrate(u32),
     AudioTracks(Vec<Track>),
     AudioNormalize,
     SubtitleTracks(Vec<Track>),
+    /// A filter whose value was recognized but isn't allowed, with the reason
+    Invalid(String),
 }
 
 #[derive(Debug, Clone)]
@@ -44,11 +97,108 @@ pub struct Track {
 pub enum TrackSource {
     FromVideo(u8),
     External(PathBuf),
+    /// An audio output of the script, numbered as it is set with `set_output`
+    ScriptAudio(u8),
 }
 
-pub fn parse_filters<'a>(input: &'a str, in_file: &Path) -> Vec<ParsedFilter<'a>> {
+/// Expands filters with alternatives separated by `|`, such as `q=18|20`,
+/// into a format for every combination of them.
+///
+/// Track lists already use `|` to separate tracks, and quoted values are left alone.
+pub fn expand_alternatives(format: &str) -> Vec<String> {
+    split_filters(format)
+        .into_iter()
+        .map(|filter| match filter.split_once('=') {
+            Some((key, value)) if key != "at" && key != "st" && !value.starts_with(['"', '\'']) => {
+                value
+                    .split('|')
+                    .map(|value| format!("{}={}", key, value))
+                    .collect()
+            }
+            _ => vec![filter.to_string()],
+        })
+        .multi_cartesian_product()
+        .map(|filters| filters.join(","))
+        .collect()
+}
+
+/// Splits a format on the commas between filters, skipping any in quoted values
+fn split_filters(format: &str) -> Vec<&str> {
     let mut filters = Vec::new();
-    let mut input = input.trim_start();
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in format.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, ',') => {
+                filters.push(format[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    filters.push(format[start..].trim());
+    filters.retain(|filter| !filter.is_empty());
+    filters
+}
+
+/// The key of every filter, including aliases
+const FILTER_KEYS: &[&str] = &[
+    "enc",
+    "q",
+    "qp",
+    "crf",
+    "s",
+    "speed",
+    "p",
+    "profile",
+    "g",
+    "grain",
+    "compat",
+    "direct",
+    "hdr",
+    "ext",
+    "tq",
+    "probes",
+    "probing-rate",
+    "chunk",
+    "xargs",
+    "av1anargs",
+    "tiles",
+    "graintable",
+    "x264zones",
+    "kf",
+    "vout",
+    "sar",
+    "grainsynth",
+    "ac-bias",
+    "vb-strength",
+    "vb-octile",
+    "sharpness",
+    "qm-max",
+    "psy",
+    "aq",
+    "qcomp",
+    "bframes",
+    "bd",
+    "res",
+    "crop",
+    "range",
+    "primaries",
+    "matrix",
+    "transfer",
+    "chromaloc",
+    "aenc",
+    "ab",
+    "at",
+    "an",
+    "st",
+];
+
+pub fn parse_filters<'a>(format: &'a str, in_file: &Path) -> Result<Vec<ParsedFilter<'a>>> {
+    let mut filters = Vec::new();
+    let mut input = format.trim_start();
     while !input.is_empty() {
         let (next_input, result) = parse_video_encoder(input)
             .or_else(|_| parse_quantizer(input))
@@ -56,115 +206,605 @@ pub fn parse_filters<'a>(input: &'a str, in_file: &Path) -> Vec<ParsedFilter<'a>
             .or_else(|_| parse_profile(input))
             .or_else(|_| parse_grain(input))
             .or_else(|_| parse_compat(input))
+            .or_else(|_| parse_direct(input))
+            .or_else(|_| parse_hdr(input))
             .or_else(|_| parse_extension(input))
+            .or_else(|_| parse_target_quality(input))
+            .or_else(|_| parse_probes(input))
+            .or_else(|_| parse_probing_rate(input))
+            .or_else(|_| parse_chunk_method(input))
+            .or_else(|_| parse_extra_args(input))
+            .or_else(|_| parse_av1an_args(input))
+            .or_else(|_| parse_tiles(input))
+            .or_else(|_| parse_grain_table(input, in_file))
+            .or_else(|_| parse_x264_zones(input, in_file))
+            .or_else(|_| parse_force_keyframes(input))
+            .or_else(|_| parse_script_output(input))
+            .or_else(|_| parse_sar(input))
+            .or_else(|_| parse_grain_synth(input))
+            .or_else(|_| parse_ac_bias(input))
+            .or_else(|_| parse_variance_boost_strength(input))
+            .or_else(|_| parse_variance_octile(input))
+            .or_else(|_| parse_sharpness(input))
+            .or_else(|_| parse_qm_max(input))
+            .or_else(|_| parse_psy_rd(input))
+            .or_else(|_| parse_aq_strength(input))
+            .or_else(|_| parse_qcomp(input))
+            .or_else(|_| parse_bframes(input))
             .or_else(|_| parse_bit_depth(input))
             .or_else(|_| parse_resolution(input))
+            .or_else(|_| parse_crop(input))
+            .or_else(|_| parse_color_range_filter(input))
+            .or_else(|_| parse_primaries(input))
+            .or_else(|_| parse_matrix(input))
+            .or_else(|_| parse_transfer(input))
+            .or_else(|_| parse_chromaloc(input))
             .or_else(|_| parse_audio_encoder(input))
             .or_else(|_| parse_audio_bitrate(input))
             .or_else(|_| parse_audio_tracks(input, in_file))
             .or_else(|_| parse_audio_norm(input))
             .or_else(|_| parse_subtitle_tracks(input, in_file))
-            .expect("Unrecognized filter");
+            .map_err(|_| anyhow!(describe_unrecognized_filter(format, input)))?;
+        if let ParsedFilter::Invalid(reason) = result {
+            bail!("{} in format \"{}\"", reason, format);
+        }
         filters.push(result);
         input = next_input.trim_end().trim_start_matches(',').trim_start();
     }
-    filters
+    Ok(filters)
+}
+
+/// Explains why the start of `remainder` isn't a filter,
+/// suggesting the key which was probably meant
+fn describe_unrecognized_filter(format: &str, remainder: &str) -> String {
+    let filter = split_filters(remainder).into_iter().next().unwrap_or("");
+    let key = filter.split_once('=').map_or(filter, |(key, _)| key).trim();
+    let mut message = if FILTER_KEYS.contains(&key) {
+        format!("Invalid value for `{}=` in format \"{}\"", key, format)
+    } else {
+        format!("Unrecognized filter `{}` in format \"{}\"", key, format)
+    };
+    message.push_str(&format!("\n  unparsed: {}", remainder));
+    if key == "x264zones" {
+        message.push_str(
+            "\n  inline zones must be quoted, e.g. x264zones=\"0,100,crf=20/200,300,b=1.2\"",
+        );
+    }
+    if !FILTER_KEYS.contains(&key) {
+        let suggestion = FILTER_KEYS
+            .iter()
+            .map(|&candidate| (edit_distance(key, candidate), candidate))
+            // Every key is a letter away from a one letter key
+            .filter(|&(distance, candidate)| distance <= 2 && distance < candidate.len())
+            .min_by_key(|&(distance, _)| distance);
+        if let Some((_, suggestion)) = suggestion {
+            message.push_str(&format!("\n  did you mean `{}=`?", suggestion));
+        }
+        message.push_str(&format!("\n  valid keys: {}", FILTER_KEYS.join(", ")));
+    }
+    message
+}
+
+/// The number of single character insertions, deletions and substitutions
+/// needed to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The filter for `token` parsed as a number, or an invalid one if it's out of range
+fn numeric<'a, T: FromStr>(
+    token: &str,
+    filter: impl FnOnce(T) -> ParsedFilter<'a>,
+) -> ParsedFilter<'a> {
+    token.parse().map_or_else(
+        |_| ParsedFilter::Invalid(format!("Number out of range: {}", token)),
+        filter,
+    )
+}
+
+/// Whether a string of digits is greater than 0
+fn is_nonzero(digits: &str) -> bool {
+    digits.bytes().any(|digit| digit != b'0')
 }
 
-fn parse_video_encoder(input: &str) -> IResult<&str, ParsedFilter> {
+fn parse_video_encoder(input: &str) -> IResult<&str, ParsedFilter<'_>> {
     preceded(tag("enc="), alphanumeric1)(input).map(|(input, token)| {
         if VideoEncoder::supported_encoders().contains(&token) {
             (input, ParsedFilter::VideoEncoder(token))
         } else {
-            panic!("Unrecognize video encoder: {}", token);
+            (
+                input,
+                ParsedFilter::Invalid(format!("Unrecognized video encoder: {}", token)),
+            )
         }
     })
 }
 
-fn parse_quantizer(input: &str) -> IResult<&str, ParsedFilter> {
+fn parse_quantizer(input: &str) -> IResult<&str, ParsedFilter<'_>> {
     preceded(
         alt((tag("q="), tag("qp="), tag("crf="))),
         recognize(tuple((opt(char('-')), digit1))),
     )(input)
-    .map(|(input, token)| (input, ParsedFilter::Quantizer(token.parse().unwrap())))
+    .map(|(input, token)| (input, numeric(token, ParsedFilter::Quantizer)))
 }
 
-fn parse_speed(input: &str) -> IResult<&str, ParsedFilter> {
+fn parse_speed(input: &str) -> IResult<&str, ParsedFilter<'_>> {
     preceded(alt((tag("s="), tag("speed="))), digit1)(input)
-        .map(|(input, token)| (input, ParsedFilter::Speed(token.parse().unwrap())))
+        .map(|(input, token)| (input, numeric(token, ParsedFilter::Speed)))
 }
 
-fn parse_profile(input: &str) -> IResult<&str, ParsedFilter> {
+fn parse_profile(input: &str) -> IResult<&str, ParsedFilter<'_>> {
     preceded(alt((tag("p="), tag("profile="))), alpha1)(input).map(|(input, token)| {
         (
             input,
-            ParsedFilter::Profile(Profile::from_str(token).unwrap()),
+            Profile::from_str(token).map_or_else(
+                |e| ParsedFilter::Invalid(e.to_string()),
+                ParsedFilter::Profile,
+            ),
         )
     })
 }
 
-fn parse_grain(input: &str) -> IResult<&str, ParsedFilter> {
+fn parse_grain(input: &str) -> IResult<&str, ParsedFilter<'_>> {
     preceded(alt((tag("g="), tag("grain="))), digit1)(input)
-        .map(|(input, token)| (input, ParsedFilter::Grain(token.parse().unwrap())))
+        .map(|(input, token)| (input, numeric(token, ParsedFilter::Grain)))
 }
 
-fn parse_compat(input: &str) -> IResult<&str, ParsedFilter> {
-    preceded(tag("compat="), digit1)(input).map(|(input, token)| {
+fn parse_compat(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("compat="), alphanumeric1)(input).map(|(input, token)| {
         (
             input,
-            ParsedFilter::Compat(token.parse::<u8>().unwrap() > 0),
+            token.parse().map_or_else(
+                |e: &str| ParsedFilter::Invalid(e.to_string()),
+                ParsedFilter::Compat,
+            ),
         )
     })
 }
 
-fn parse_extension(input: &str) -> IResult<&str, ParsedFilter> {
+fn parse_direct(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("direct="), digit1)(input)
+        .map(|(input, token)| (input, ParsedFilter::Direct(is_nonzero(token))))
+}
+
+fn parse_hdr(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("hdr="), digit1)(input)
+        .map(|(input, token)| (input, ParsedFilter::Hdr(is_nonzero(token))))
+}
+
+fn parse_extension(input: &str) -> IResult<&str, ParsedFilter<'_>> {
     preceded(tag("ext="), alphanumeric1)(input).map(|(input, token)| {
         if token == "mp4" || token == "mkv" {
             (input, ParsedFilter::Extension(token))
         } else {
-            panic!("Unsupported extension: {}", token);
+            (
+                input,
+                ParsedFilter::Invalid(format!("Unsupported extension: {}", token)),
+            )
+        }
+    })
+}
+
+fn parse_target_quality(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(
+        tag("tq="),
+        recognize(tuple((digit1, opt(tuple((char('.'), digit1)))))),
+    )(input)
+    .map(|(input, token)| (input, numeric(token, ParsedFilter::TargetQuality)))
+}
+
+fn parse_probes(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("probes="), digit1)(input)
+        .map(|(input, token)| (input, numeric(token, ParsedFilter::Probes)))
+}
+
+fn parse_probing_rate(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("probing-rate="), digit1)(input)
+        .map(|(input, token)| (input, numeric(token, ParsedFilter::ProbingRate)))
+}
+
+fn parse_chunk_method(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("chunk="), alphanumeric1)(input).map(|(input, token)| {
+        if [
+            "lsmash",
+            "ffms2",
+            "bestsource",
+            "hybrid",
+            "select",
+            "segment",
+        ]
+        .contains(&token)
+        {
+            (input, ParsedFilter::ChunkMethod(token))
+        } else {
+            (
+                input,
+                ParsedFilter::Invalid(format!("Unsupported chunk method: {}", token)),
+            )
+        }
+    })
+}
+
+fn parse_extra_args(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("xargs="), quoted_string)(input)
+        .map(|(input, token)| (input, ParsedFilter::ExtraArgs(token)))
+}
+
+fn parse_av1an_args(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("av1anargs="), quoted_string)(input)
+        .map(|(input, token)| (input, ParsedFilter::Av1anArgs(token)))
+}
+
+fn quoted_string(input: &str) -> IResult<&str, &str> {
+    alt((
+        delimited(char('"'), take_until("\""), char('"')),
+        delimited(char('\''), take_until("'"), char('\'')),
+    ))(input)
+}
+
+/// A list of frames such as `kf=1,240,1000`, which may also be quoted
+fn parse_force_keyframes(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(
+        tag("kf="),
+        alt((quoted_string, recognize(separated_list1(char(','), digit1)))),
+    )(input)
+    .map(|(input, token)| {
+        let frames = token
+            .split(',')
+            .map(|frame| frame.trim().parse::<u32>().map_err(|_| frame))
+            .collect::<Result<Vec<_>, _>>();
+        let filter = match frames {
+            Ok(frames) => ParsedFilter::ForceKeyframes(frames.iter().join(",")),
+            Err(frame) => ParsedFilter::Invalid(format!("Invalid keyframe in `kf=`: {}", frame)),
+        };
+        (input, filter)
+    })
+}
+
+fn parse_script_output(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("vout="), digit1)(input).map(|(input, token)| {
+        (
+            input,
+            token.parse().map_or_else(
+                |_| ParsedFilter::Invalid(format!("Unsupported video output: {}", token)),
+                ParsedFilter::ScriptOutput,
+            ),
+        )
+    })
+}
+
+fn parse_sar(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    let (input, (n, _, d)) = preceded(tag("sar="), tuple((digit1, char(':'), digit1)))(input)?;
+    let filter = match (n.parse::<u32>(), d.parse::<u32>()) {
+        (Ok(num), Ok(den)) if num > 0 && den > 0 => ParsedFilter::Sar((num, den)),
+        _ => ParsedFilter::Invalid(format!("Invalid sample aspect ratio: {}:{}", n, d)),
+    };
+    Ok((input, filter))
+}
+
+fn parse_tiles(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    let (input, (c, _, r)) = preceded(tag("tiles="), tuple((digit1, char('x'), digit1)))(input)?;
+    let filter = match (c.parse::<u8>(), r.parse::<u8>()) {
+        (Ok(cols), Ok(rows)) if cols <= 6 && rows <= 6 => ParsedFilter::Tiles { cols, rows },
+        _ => ParsedFilter::Invalid(format!(
+            "Tiles must be between 0 and 6 (log2), got {}x{}",
+            c, r
+        )),
+    };
+    Ok((input, filter))
+}
+
+fn parse_grain_table<'a>(input: &'a str, in_file: &Path) -> IResult<&'a str, ParsedFilter<'a>> {
+    preceded(tag("graintable="), alt((quoted_string, is_not(","))))(input).map(|(input, token)| {
+        // Relative paths are relative to the input script
+        let path = in_file
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join(token.trim());
+        if !path.is_file() {
+            return (
+                input,
+                ParsedFilter::Invalid(format!("Grain table does not exist: {}", path.display())),
+            );
+        }
+        (input, ParsedFilter::GrainTable(path))
+    })
+}
+
+/// Zones are given either inline, as x264's own `start,end,options/...` syntax,
+/// or as the path to a file with one zone per line.
+///
+/// Inline zones contain commas, so they must be quoted to stay in one filter.
+fn parse_x264_zones<'a>(input: &'a str, in_file: &Path) -> IResult<&'a str, ParsedFilter<'a>> {
+    let (rest, token) = preceded(tag("x264zones="), alt((quoted_string, is_not(","))))(input)?;
+    // Relative paths are relative to the input script
+    let path = in_file
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(token.trim());
+    let zones = if path.is_file() {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                return Ok((
+                    rest,
+                    ParsedFilter::Invalid(format!(
+                        "Unable to read zones file {}: {}",
+                        path.display(),
+                        e
+                    )),
+                ))
+            }
+        };
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .join("/")
+    } else {
+        token.trim().to_string()
+    };
+    if !zones.split('/').all(is_x264_zone) {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    Ok((rest, ParsedFilter::X264Zones(zones)))
+}
+
+/// Whether `zone` is a `start,end,options` zone
+fn is_x264_zone(zone: &str) -> bool {
+    let mut parts = zone.split(',');
+    let start = parts
+        .next()
+        .and_then(|start| start.trim().parse::<u32>().ok());
+    let end = parts.next().and_then(|end| end.trim().parse::<u32>().ok());
+    match (start, end) {
+        (Some(start), Some(end)) => start <= end && parts.next().is_some(),
+        _ => false,
+    }
+}
+
+fn parse_grain_synth(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("grainsynth="), alpha1)(input).map(|(input, token)| {
+        if token == "post" {
+            (input, ParsedFilter::PostGrainSynth)
+        } else {
+            (
+                input,
+                ParsedFilter::Invalid(format!("Unsupported grain synthesis mode: {}", token)),
+            )
         }
     })
 }
 
-fn parse_bit_depth(input: &str) -> IResult<&str, ParsedFilter> {
+fn parse_ac_bias(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(
+        tag("ac-bias="),
+        recognize(tuple((digit1, opt(tuple((char('.'), digit1)))))),
+    )(input)
+    .map(|(input, token)| (input, numeric(token, ParsedFilter::AcBias)))
+}
+
+fn parse_variance_boost_strength(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("vb-strength="), digit1)(input)
+        .map(|(input, token)| (input, numeric(token, ParsedFilter::VarianceBoostStrength)))
+}
+
+fn parse_variance_octile(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("vb-octile="), digit1)(input)
+        .map(|(input, token)| (input, numeric(token, ParsedFilter::VarianceOctile)))
+}
+
+fn parse_sharpness(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(
+        tag("sharpness="),
+        recognize(tuple((opt(char('-')), digit1))),
+    )(input)
+    .map(|(input, token)| (input, numeric(token, ParsedFilter::Sharpness)))
+}
+
+fn parse_qm_max(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("qm-max="), digit1)(input)
+        .map(|(input, token)| (input, numeric(token, ParsedFilter::QmMax)))
+}
+
+fn parse_psy_rd(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(
+        tag("psy="),
+        recognize(tuple((digit1, opt(tuple((char('.'), digit1)))))),
+    )(input)
+    .map(|(input, token)| (input, numeric(token, ParsedFilter::PsyRd)))
+}
+
+fn parse_aq_strength(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(
+        tag("aq="),
+        recognize(tuple((digit1, opt(tuple((char('.'), digit1)))))),
+    )(input)
+    .map(|(input, token)| (input, numeric(token, ParsedFilter::AqStrength)))
+}
+
+fn parse_qcomp(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(
+        tag("qcomp="),
+        recognize(tuple((digit1, opt(tuple((char('.'), digit1)))))),
+    )(input)
+    .map(|(input, token)| (input, numeric(token, ParsedFilter::Qcomp)))
+}
+
+fn parse_bframes(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(tag("bframes="), digit1)(input)
+        .map(|(input, token)| (input, numeric(token, ParsedFilter::Bframes)))
+}
+
+fn parse_bit_depth(input: &str) -> IResult<&str, ParsedFilter<'_>> {
     preceded(tag("bd="), digit1)(input).map(|(input, token)| {
         if token == "8" || token == "10" {
-            (input, ParsedFilter::BitDepth(token.parse().unwrap()))
+            (input, numeric(token, ParsedFilter::BitDepth))
         } else {
-            panic!("Unsupported bit depth: {}", token);
+            (
+                input,
+                ParsedFilter::Invalid(format!("Unsupported bit depth: {}", token)),
+            )
         }
     })
 }
 
-fn parse_resolution(input: &str) -> IResult<&str, ParsedFilter> {
-    preceded(tag("res="), tuple((digit1, char('x'), digit1)))(input).map(|(input, (w, _, h))| {
-        let width = w.parse::<u32>().unwrap();
-        let height = h.parse::<u32>().unwrap();
-        if width % 2 != 0 || height % 2 != 0 {
-            panic!("Resolution must be mod 2, got {}x{}", w, h);
-        }
-        if width < 64 || height < 64 {
-            panic!("Resolution must be at least 64x64, got {}x{}", w, h);
+fn parse_resolution(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    let (input, (w, _, h)) = preceded(tag("res="), tuple((digit1, char('x'), digit1)))(input)?;
+    let filter = match (w.parse::<u32>(), h.parse::<u32>()) {
+        (Ok(width), Ok(height)) if width % 2 != 0 || height % 2 != 0 => {
+            ParsedFilter::Invalid(format!("Resolution must be mod 2, got {}x{}", w, h))
         }
+        (Ok(width), Ok(height)) if width < 64 || height < 64 => ParsedFilter::Invalid(format!(
+            "Resolution must be at least 64x64, got {}x{}",
+            w, h
+        )),
+        (Ok(width), Ok(height)) => ParsedFilter::Resolution { width, height },
+        _ => ParsedFilter::Invalid(format!("Invalid resolution: {}x{}", w, h)),
+    };
+    Ok((input, filter))
+}
 
-        (input, ParsedFilter::Resolution { width, height })
+fn parse_crop(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    preceded(
+        tag("crop="),
+        tuple((
+            digit1,
+            char(':'),
+            digit1,
+            char(':'),
+            digit1,
+            char(':'),
+            digit1,
+        )),
+    )(input)
+    .map(|(input, (l, _, r, _, t, _, b))| {
+        let sides = [l, r, t, b]
+            .iter()
+            .map(|side| side.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>();
+        let filter = match sides.as_deref() {
+            Ok(&[left, right, top, bottom])
+                if [left, right, top, bottom].iter().all(|side| side % 2 == 0) =>
+            {
+                ParsedFilter::Crop {
+                    left,
+                    right,
+                    top,
+                    bottom,
+                }
+            }
+            Ok(_) => {
+                ParsedFilter::Invalid(format!("Crop must be mod 2, got {}:{}:{}:{}", l, r, t, b))
+            }
+            Err(_) => ParsedFilter::Invalid(format!("Invalid crop: {}:{}:{}:{}", l, r, t, b)),
+        };
+        (input, filter)
     })
 }
 
-fn parse_audio_encoder(input: &str) -> IResult<&str, ParsedFilter> {
+/// A colorimetry value, either a name such as `bt2020-10` or an H.273 code point
+fn color_value<'a>(key: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    preceded(
+        tag(key),
+        recognize(separated_list1(char('-'), alphanumeric1)),
+    )
+}
+
+fn parse_color_range_filter(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    color_value("range=")(input).map(|(input, token)| {
+        (
+            input,
+            parse_color_range(token).map_or_else(
+                || ParsedFilter::Invalid(format!("Unsupported color range: {}", token)),
+                ParsedFilter::ColorRange,
+            ),
+        )
+    })
+}
+
+fn parse_primaries(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    color_value("primaries=")(input).map(|(input, token)| {
+        (
+            input,
+            parse_color_primaries(token).map_or_else(
+                || ParsedFilter::Invalid(format!("Unsupported color primaries: {}", token)),
+                ParsedFilter::Primaries,
+            ),
+        )
+    })
+}
+
+fn parse_matrix(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    color_value("matrix=")(input).map(|(input, token)| {
+        (
+            input,
+            parse_matrix_coefficients(token).map_or_else(
+                || ParsedFilter::Invalid(format!("Unsupported matrix coefficients: {}", token)),
+                ParsedFilter::Matrix,
+            ),
+        )
+    })
+}
+
+fn parse_transfer(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    color_value("transfer=")(input).map(|(input, token)| {
+        (
+            input,
+            parse_transfer_characteristic(token).map_or_else(
+                || {
+                    ParsedFilter::Invalid(format!(
+                        "Unsupported transfer characteristics: {}",
+                        token
+                    ))
+                },
+                ParsedFilter::Transfer,
+            ),
+        )
+    })
+}
+
+fn parse_chromaloc(input: &str) -> IResult<&str, ParsedFilter<'_>> {
+    color_value("chromaloc=")(input).map(|(input, token)| {
+        (
+            input,
+            parse_chroma_location(token).map_or_else(
+                || ParsedFilter::Invalid(format!("Unsupported chroma location: {}", token)),
+                ParsedFilter::ChromaLocation,
+            ),
+        )
+    })
+}
+
+fn parse_audio_encoder(input: &str) -> IResult<&str, ParsedFilter<'_>> {
     preceded(tag("aenc="), alphanumeric1)(input).map(|(input, token)| {
         if AudioEncoder::supported_encoders().contains(&token) {
             (input, ParsedFilter::AudioEncoder(token))
         } else {
-            panic!("Unrecognize audio encoder: {}", token);
+            (
+                input,
+                ParsedFilter::Invalid(format!("Unrecognized audio encoder: {}", token)),
+            )
         }
     })
 }
 
-fn parse_audio_bitrate(input: &str) -> IResult<&str, ParsedFilter> {
+fn parse_audio_bitrate(input: &str) -> IResult<&str, ParsedFilter<'_>> {
     preceded(tag("ab="), digit1)(input)
-        .map(|(input, token)| (input, ParsedFilter::AudioBitrate(token.parse().unwrap())))
+        .map(|(input, token)| (input, numeric(token, ParsedFilter::AudioBitrate)))
 }
 
 fn parse_audio_tracks<'a>(input: &'a str, in_file: &Path) -> IResult<&'a str, ParsedFilter<'a>> {
@@ -183,15 +823,18 @@ fn parse_audio_tracks<'a>(input: &'a str, in_file: &Path) -> IResult<&'a str, Pa
                     .into_iter()
                     .map(|(id, tags)| {
                         let tags = tags.unwrap_or("");
+                        let script_audio = id
+                            .strip_prefix('o')
+                            .and_then(|index| index.parse().ok())
+                            .map(TrackSource::ScriptAudio);
                         Track {
-                            source: id.parse().map_or_else(
-                                |_| {
-                                    let source = in_file.with_extension(id);
-                                    assert!(source.exists());
-                                    TrackSource::External(source)
-                                },
-                                TrackSource::FromVideo,
-                            ),
+                            source: script_audio.unwrap_or_else(|| {
+                                id.parse().map_or_else(
+                                    // Checked to exist along with the rest of the batch
+                                    |_| TrackSource::External(in_file.with_extension(id)),
+                                    TrackSource::FromVideo,
+                                )
+                            }),
                             enabled: tags.contains('d') || tags.contains('e'),
                             forced: tags.contains('f'),
                         }
@@ -202,7 +845,7 @@ fn parse_audio_tracks<'a>(input: &'a str, in_file: &Path) -> IResult<&'a str, Pa
     })
 }
 
-fn parse_audio_norm(input: &str) -> IResult<&str, ParsedFilter> {
+fn parse_audio_norm(input: &str) -> IResult<&str, ParsedFilter<'_>> {
     tag("an=1")(input).map(|(input, _)| (input, ParsedFilter::AudioNormalize))
 }
 
@@ -224,11 +867,8 @@ fn parse_subtitle_tracks<'a>(input: &'a str, in_file: &Path) -> IResult<&'a str,
                         let tags = tags.unwrap_or("");
                         Track {
                             source: id.parse().map_or_else(
-                                |_| {
-                                    let source = in_file.with_extension(id);
-                                    assert!(source.exists());
-                                    TrackSource::External(source)
-                                },
+                                // Checked to exist along with the rest of the batch
+                                |_| TrackSource::External(in_file.with_extension(id)),
                                 TrackSource::FromVideo,
                             ),
                             enabled: tags.contains('d') || tags.contains('e'),
@@ -240,3 +880,42 @@ fn parse_subtitle_tracks<'a>(input: &'a str, in_file: &Path) -> IResult<&'a str,
         )
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn x264_zones_are_quoted() {
+        let script = Path::new("/nonexistent/script.vpy");
+        let filters = parse_filters(
+            r#"enc=x264,x264zones="0,100,crf=20/200,300,b=1.2",q=18"#,
+            script,
+        )
+        .unwrap();
+        match filters.as_slice() {
+            [ParsedFilter::VideoEncoder("x264"), ParsedFilter::X264Zones(zones), ParsedFilter::Quantizer(18)] =>
+            {
+                assert_eq!(zones, "0,100,crf=20/200,300,b=1.2");
+            }
+            filters => panic!("Unexpected filters: {:?}", filters),
+        }
+        // Unquoted, the zone ends at its first comma
+        assert!(parse_x264_zones("x264zones=0,100,crf=20", script).is_err());
+        assert!(parse_x264_zones("x264zones=\"100,0,crf=20\"", script).is_err());
+    }
+
+    #[test]
+    fn invalid_filters_are_errors() {
+        let script = Path::new("/nonexistent/script.vpy");
+        for format in [
+            "q=99999999",
+            "res=63x64",
+            "crop=1:0:0:0",
+            "enc=x266",
+            "qq=18",
+        ] {
+            assert!(parse_filters(format, script).is_err(), "{}", format);
+        }
+    }
+}
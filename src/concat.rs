@@ -0,0 +1,127 @@
+use std::{
+    ffi::OsString,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use tracing::info;
+
+use crate::{
+    input::get_media_duration,
+    output::Output,
+    pipeline::{InputContext, OutputContext, ProcessOptions},
+    summary::BatchSummary,
+    tool_log::{is_dry_run, run_logged},
+    work_dir::work_path,
+};
+
+/// Joins the outputs of several scripts into `output` with mkvmerge,
+/// for movies delivered in several parts.
+///
+/// `segments` are each script along with its output, in order. Each segment gets
+/// a chapter named after its script, replacing any chapters the outputs have.
+pub fn concat_outputs(segments: &[(PathBuf, PathBuf)], output: &Path) -> Result<()> {
+    if output.extension().map_or(true, |ext| ext != "mkv") {
+        bail!("Concatenated output must be an mkv: {}", output.display());
+    }
+    if segments.is_empty() {
+        bail!("No outputs to concatenate");
+    }
+    let chapters = work_path(output).with_extension("chapters.txt");
+    if !is_dry_run() {
+        fs::write(&chapters, segment_chapters(segments)?)?;
+    }
+
+    let mut command = Command::new("mkvmerge");
+    command
+        .arg("--output")
+        .arg(output)
+        .arg("--chapters")
+        .arg(&chapters);
+    for (i, (_, segment)) in segments.iter().enumerate() {
+        command.arg("--no-chapters");
+        if i == 0 {
+            command.arg(segment);
+        } else {
+            // Appended to the previous file, rather than added as more tracks
+            let mut append = OsString::from("+");
+            append.push(segment);
+            command.arg(append);
+        }
+    }
+    let status = run_logged(&mut command)?;
+    let _ = fs::remove_file(&chapters);
+    if !status.success() {
+        bail!("Failed to concatenate outputs into {}", output.display());
+    }
+    info!(
+        "Concatenated {} outputs into {}",
+        segments.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+/// Each part to concatenate along with its output, in order.
+///
+/// A part skipped in this run, such as one already done, uses the output
+/// an earlier run wrote. Fails if any part has no finished output,
+/// rather than leaving it out of the movie.
+pub fn concat_segments(
+    parts: &[(PathBuf, Vec<Output>)],
+    summary: &BatchSummary,
+    options: &ProcessOptions,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    parts
+        .iter()
+        .map(|(input, outputs)| {
+            let output = match summary.outputs(input) {
+                Some(written) => written.into_iter().next(),
+                None => {
+                    let context = InputContext::new(input, outputs, options)?;
+                    let output = OutputContext::new(&context, &outputs[0])?
+                        .output_path
+                        .clone();
+                    output.is_file().then(|| output)
+                }
+            };
+            let output = output.ok_or_else(|| {
+                anyhow!("{} has no finished output to concatenate", input.display())
+            })?;
+            Ok((input.clone(), output))
+        })
+        .collect()
+}
+
+/// An OGM style chapter file, with a chapter at the start of each segment
+fn segment_chapters(segments: &[(PathBuf, PathBuf)]) -> Result<String> {
+    let mut chapters = String::new();
+    let mut start = Duration::ZERO;
+    for (i, (input, segment)) in segments.iter().enumerate() {
+        let millis = start.as_millis();
+        writeln!(
+            chapters,
+            "CHAPTER{:02}={:02}:{:02}:{:02}.{:03}",
+            i + 1,
+            millis / 3_600_000,
+            millis / 60_000 % 60,
+            millis / 1000 % 60,
+            millis % 1000
+        )?;
+        writeln!(
+            chapters,
+            "CHAPTER{:02}NAME={}",
+            i + 1,
+            input
+                .file_stem()
+                .expect("File should have a name")
+                .to_string_lossy()
+        )?;
+        start += get_media_duration(segment)?;
+    }
+    Ok(chapters)
+}
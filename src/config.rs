@@ -0,0 +1,128 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::{
+    events::path_value,
+    input::{get_video_dimensions, VideoDimensions},
+    output::{Av1anWorkers, Output},
+    pipeline::{InputContext, OutputContext, ProcessOptions},
+    work_dir::work_path,
+};
+
+/// Prints the settings each output of each input resolves to as JSON,
+/// including the arguments the encoder would be given, without encoding anything
+pub fn print_config(jobs: &[(PathBuf, Vec<Output>)], options: &ProcessOptions) -> Result<()> {
+    let inputs = jobs
+        .iter()
+        .map(|(input, outputs)| input_config(input, outputs, options))
+        .collect::<Result<Vec<_>>>()?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&json!({ "inputs": inputs }))?
+    );
+    Ok(())
+}
+
+fn input_config(input_vpy: &Path, outputs: &[Output], options: &ProcessOptions) -> Result<Value> {
+    let input = InputContext::new(input_vpy, outputs, options)?;
+    let dimensions = get_video_dimensions(input_vpy)?;
+    let outputs = outputs
+        .iter()
+        .map(|output| output_config(&input, output, dimensions))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(json!({
+        "input": path_value(input_vpy),
+        "source": path_value(&input.source_video),
+        "dimensions": dimensions_json(dimensions),
+        "colorimetry": format!("{:?}", input.colorimetry),
+        "lossless": {
+            "path": path_value(&work_path(input_vpy).with_extension("lossless.mkv")),
+            "codec": options.lossless_codec.to_string(),
+            "segment_minutes": options.lossless_segments,
+            "audio": options.lossless_audio.as_ref().map(|audio| format!("{:?}", audio)),
+            "skipped": input.skip_lossless,
+        },
+        "outputs": outputs,
+    }))
+}
+
+/// The settings `output` resolves to, including the arguments the encoder is given
+pub fn output_config(
+    input: &InputContext,
+    output: &Output,
+    source_dimensions: VideoDimensions,
+) -> Result<Value> {
+    let context = OutputContext::new(input, output)?;
+    let video = &output.video;
+    let av1an = &input.options.av1an;
+    let dimensions = video.filtered_dimensions(source_dimensions);
+    let workers = if video.uses_av1an() {
+        let workers = Av1anWorkers::new(video, dimensions, av1an)?;
+        json!({
+            "cores": workers.cores.get(),
+            "workers": workers.workers.get(),
+            "threads_per_worker": workers.threads_per_worker.get(),
+            "tiles": format!("{}x{}", 1 << workers.tiles.0, 1 << workers.tiles.1),
+        })
+    } else {
+        Value::Null
+    };
+    Ok(json!({
+        "output": path_value(&context.output_path),
+        "script": path_value(&context.output_vpy),
+        "video_out": path_value(&context.video_out),
+        "settings": context.settings,
+        "video": {
+            "encoder": format!("{:?}", video.encoder),
+            "script_output": video.script_output,
+            "sar": video.sar.map(|(num, den)| format!("{}:{}", num, den)),
+            "dimensions": dimensions_json(dimensions),
+            "overrides": format!("{:?}", video.overrides),
+            "colorimetry": format!("{:?}", video.colorimetry),
+            "svt_tuning": format!("{:?}", video.svt_tuning),
+            "target_quality": video.target_quality,
+            "chunk_method": video.chunk_method,
+            "av1an_args": video.av1an_args,
+            "grain_table": video.grain_table.as_deref().map(path_value),
+            "post_grain_synth": video.post_grain_synth,
+            "encoder_args": video
+                .encoder_args(
+                    dimensions,
+                    &input.output_colorimetry(output)?,
+                    &input.output_keyframes(output)?,
+                    av1an,
+                )?
+                .trim(),
+            "av1an": workers,
+        },
+        "audio": context
+            .audio_outputs
+            .iter()
+            .map(|(path, track, encoder)| json!({
+                "path": path_value(path),
+                "track": format!("{:?}", track),
+                "encoder": encoder.to_string(),
+                "kbps_per_channel": output.audio.kbps_per_channel,
+                "normalize": output.audio.normalize,
+            }))
+            .collect::<Vec<_>>(),
+        "subtitles": output
+            .sub_tracks
+            .iter()
+            .map(|track| format!("{:?}", track))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+fn dimensions_json(dimensions: VideoDimensions) -> Value {
+    json!({
+        "width": dimensions.width,
+        "height": dimensions.height,
+        "frames": dimensions.frames,
+        "fps": format!("{}/{}", dimensions.fps.0, dimensions.fps.1),
+        "pixel_format": format!("{:?}", dimensions.pixel_format),
+        "bit_depth": dimensions.bit_depth,
+    })
+}
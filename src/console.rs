@@ -0,0 +1,97 @@
+use anyhow::Result;
+
+/// Makes sure closing the console window, logging off, or shutting down
+/// also kills every process we spawned, including vspipe processes started by av1an.
+///
+/// Windows doesn't deliver these as signals, and doesn't kill grandchildren
+/// when their parent dies, so we place ourselves in a job object which
+/// takes the whole process tree down with it.
+#[cfg(windows)]
+pub fn handle_console_events() -> Result<()> {
+    use std::{mem, ptr, sync::atomic::Ordering};
+
+    use anyhow::bail;
+    use windows_sys::Win32::System::{
+        Console::SetConsoleCtrlHandler,
+        JobObjects::{
+            AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+            SetInformationJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+            JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+        },
+        Threading::GetCurrentProcess,
+    };
+
+    // SAFETY: All pointers passed are either null or point to live locals,
+    // and the job handle is intentionally kept open for the life of the process.
+    unsafe {
+        let job = CreateJobObjectW(ptr::null(), ptr::null());
+        if job.is_null() {
+            bail!(
+                "Failed to create job object: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+        info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        if SetInformationJobObject(
+            job,
+            JobObjectExtendedLimitInformation,
+            &info as *const _ as *const _,
+            mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+        ) == 0
+            || AssignProcessToJobObject(job, GetCurrentProcess()) == 0
+        {
+            bail!(
+                "Failed to set up job object: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        windows::JOB.store(job, Ordering::SeqCst);
+
+        if SetConsoleCtrlHandler(Some(windows::console_handler), 1) == 0 {
+            bail!(
+                "Failed to register console handler: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub fn handle_console_events() -> Result<()> {
+    Ok(())
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::{
+        ffi::c_void,
+        ptr,
+        sync::atomic::{AtomicPtr, Ordering},
+    };
+
+    use windows_sys::Win32::{
+        Foundation::BOOL,
+        System::{
+            Console::{CTRL_CLOSE_EVENT, CTRL_LOGOFF_EVENT, CTRL_SHUTDOWN_EVENT},
+            JobObjects::TerminateJobObject,
+        },
+    };
+
+    pub static JOB: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+    pub unsafe extern "system" fn console_handler(ctrl_type: u32) -> BOOL {
+        if matches!(
+            ctrl_type,
+            CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT
+        ) {
+            let job = JOB.load(Ordering::SeqCst);
+            if !job.is_null() {
+                TerminateJobObject(job, 1);
+            }
+        }
+        // Let the default handler terminate us as well
+        0
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    fmt::{self, Display},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::cancel::is_cancelled;
+
+/// Failures which wrapper scripts may want to react to differently,
+/// each exiting with its own code
+#[derive(Debug)]
+pub enum Error {
+    /// The tool with this name is not installed
+    ToolMissing(String),
+    /// The source or its script could not be read
+    SourceProbeFailed(String),
+    /// A tool failed while encoding or extracting a stream
+    EncodeFailed(String),
+    /// Something was written, but it is incomplete or not good enough,
+    /// such as a frame count mismatch or a quality below `--min-quality`
+    VerificationFailed(String),
+    /// The streams could not be muxed, or the output's tags written
+    MuxFailed(String),
+    /// Stopped by Ctrl+C or SIGTERM, or by cancelling the job
+    Interrupted(String),
+}
+
+impl Error {
+    /// 1 is left for other failures, and 2 for invalid arguments
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::ToolMissing(_) => 3,
+            Error::SourceProbeFailed(_) => 4,
+            Error::EncodeFailed(_) => 5,
+            Error::VerificationFailed(_) => 6,
+            Error::MuxFailed(_) => 7,
+            // The shell's code for being killed by SIGINT
+            Error::Interrupted(_) => 130,
+        }
+    }
+
+    /// The same kind of error with another message, such as one with more context
+    pub fn map_message(self, f: impl FnOnce(String) -> String) -> Self {
+        match self {
+            Error::ToolMissing(tool) => Error::ToolMissing(tool),
+            Error::SourceProbeFailed(message) => Error::SourceProbeFailed(f(message)),
+            Error::EncodeFailed(message) => Error::EncodeFailed(f(message)),
+            Error::VerificationFailed(message) => Error::VerificationFailed(f(message)),
+            Error::MuxFailed(message) => Error::MuxFailed(f(message)),
+            Error::Interrupted(message) => Error::Interrupted(f(message)),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ToolMissing(tool) => write!(f, "{} not installed or not in PATH!", tool),
+            Error::SourceProbeFailed(message)
+            | Error::EncodeFailed(message)
+            | Error::VerificationFailed(message)
+            | Error::MuxFailed(message)
+            | Error::Interrupted(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Gives `err` the kind `default`, unless it already has one.
+///
+/// Anything which failed after an interruption, or after its job was cancelled,
+/// was interrupted, since the tools were stopped along with us.
+pub fn classify(err: anyhow::Error, default: fn(String) -> Error) -> Error {
+    if is_interrupted() || is_cancelled() {
+        return Error::Interrupted(err.to_string());
+    }
+    err.downcast::<Error>()
+        .unwrap_or_else(|err| default(err.to_string()))
+}
+
+/// The code to exit with after `err`, which is 1 unless it has a kind
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    err.chain()
+        .find_map(|err| err.downcast_ref::<Error>())
+        .map_or(1, Error::exit_code)
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Stops the batch on the first Ctrl+C or SIGTERM once the running tools exit,
+/// rather than leaving them behind. A second signal exits immediately.
+#[cfg(unix)]
+pub fn handle_interrupt_signals() -> anyhow::Result<()> {
+    use signal_hook::{
+        consts::{SIGINT, SIGTERM},
+        iterator::Signals,
+    };
+    use tracing::{info, warn};
+
+    use crate::cancel::signal_tools;
+
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            if INTERRUPTED.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+            // Tools run in process groups of their own,
+            // so Ctrl+C from the terminal doesn't reach them by itself
+            let stopped = signal_tools(signal);
+            match stopped {
+                Ok(0) => std::process::exit(130),
+                Ok(_) => info!("Interrupted, stopping once the running tools exit"),
+                Err(err) => {
+                    warn!("{}", err);
+                    std::process::exit(130);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn handle_interrupt_signals() -> anyhow::Result<()> {
+    Ok(())
+}
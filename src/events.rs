@@ -0,0 +1,181 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Mutex, RwLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+
+/// Something which happened while processing, such as a stage starting or
+/// the frames encoded so far
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// Such as `stage_started`, `stage_finished`, `progress` or `warning`
+    pub kind: &'static str,
+    /// Seconds since the Unix epoch
+    pub time: f64,
+    /// A JSON object of the event's fields, such as the input and stage
+    pub fields: Value,
+}
+
+impl Event {
+    /// The event as a single JSON object, as written for `--progress-json`
+    pub fn to_json(&self) -> Value {
+        let mut line = json!({ "event": self.kind, "time": self.time });
+        if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), &self.fields) {
+            line.extend(fields.clone());
+        }
+        line
+    }
+}
+
+type Listener = Box<dyn Fn(&Event) + Send + Sync>;
+
+static LISTENERS: OnceCell<RwLock<Vec<Listener>>> = OnceCell::new();
+/// Lets events be skipped cheaply while nothing listens for them
+static LISTENING: AtomicBool = AtomicBool::new(false);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Calls `listener` with every event from now on.
+///
+/// Listeners are called on the thread the event happened on,
+/// so they should return quickly, and must not log warnings themselves.
+pub fn add_listener(listener: impl Fn(&Event) + Send + Sync + 'static) {
+    LISTENERS
+        .get_or_init(Default::default)
+        .write()
+        .expect("Lock should not be poisoned")
+        .push(Box::new(listener));
+    LISTENING.store(true, Ordering::SeqCst);
+}
+
+/// Sends every event from now on to the returned channel,
+/// such as for the server, which handles them on its own thread
+pub fn subscribe() -> Receiver<Event> {
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    add_listener(move |event| {
+        // The receiver may have been dropped, which only means nobody is listening
+        let _ = sender
+            .lock()
+            .expect("Lock should not be poisoned")
+            .send(event.clone());
+    });
+    receiver
+}
+
+/// Whether anything listens for events, such as `--progress-json`
+pub fn has_listeners() -> bool {
+    LISTENING.load(Ordering::SeqCst)
+}
+
+/// Starts writing events to stdout as newline-delimited JSON
+pub fn enable_events() {
+    ENABLED.store(true, Ordering::SeqCst);
+    add_listener(|event| {
+        // Hold the lock so lines from parallel jobs are never interleaved
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = writeln!(stdout, "{}", event.to_json());
+        let _ = stdout.flush();
+    });
+}
+
+/// Whether stdout is reserved for events
+pub fn events_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Sends an event of the given type with the given fields to every listener
+pub fn emit(kind: &'static str, fields: Value) {
+    if !has_listeners() {
+        return;
+    }
+    let event = Event {
+        kind,
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |time| time.as_secs_f64()),
+        fields,
+    };
+    let listeners = LISTENERS
+        .get()
+        .expect("Listeners exist once something listens")
+        .read()
+        .expect("Lock should not be poisoned");
+    for listener in listeners.iter() {
+        listener(&event);
+    }
+}
+
+pub fn path_value(path: &Path) -> Value {
+    Value::from(path.to_string_lossy())
+}
+
+/// Where tools we spawn should write their regular output.
+///
+/// Stdout is reserved for events while they are enabled,
+/// so the tools write to our stderr instead.
+pub fn tool_stdout() -> Stdio {
+    if !events_enabled() {
+        return Stdio::inherit();
+    }
+    stderr_for_child().unwrap_or_else(Stdio::null)
+}
+
+#[cfg(unix)]
+fn stderr_for_child() -> Option<Stdio> {
+    use std::{fs::File, os::unix::io::FromRawFd};
+
+    // SAFETY: `dup` has no memory safety requirements,
+    // and the new descriptor is owned only by the returned `File`.
+    let fd = unsafe { libc::dup(libc::STDERR_FILENO) };
+    if fd < 0 {
+        return None;
+    }
+    // SAFETY: `fd` is a valid descriptor which nothing else owns
+    Some(Stdio::from(unsafe { File::from_raw_fd(fd) }))
+}
+
+#[cfg(not(unix))]
+fn stderr_for_child() -> Option<Stdio> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicUsize, Arc};
+
+    use super::*;
+
+    #[test]
+    fn listeners_and_subscribers_receive_events() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        add_listener(move |event| {
+            if event.kind == "test_event" {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let events = subscribe();
+
+        emit("test_event", json!({ "input": "a.vpy" }));
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // Other tests may emit events of their own
+        let event = events
+            .iter()
+            .find(|event| event.kind == "test_event")
+            .expect("Event was sent");
+        let line = event.to_json();
+        assert_eq!(line["event"], "test_event");
+        assert_eq!(line["input"], "a.vpy");
+    }
+}
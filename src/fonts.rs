@@ -0,0 +1,216 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, Result};
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use tracing::{debug, warn};
+use which::which;
+
+use crate::python::{parse_path_expression, PATH_EXPRESSION, STRING_LITERAL};
+
+/// Checks that every font used by subtitles rendered in the script is installed,
+/// or in the `fontdir` assrender is given, since the renderers silently fall back
+/// to another font when one isn't.
+///
+/// Missing fonts are warned about, or fail the input if `strict` is set.
+pub fn check_subtitle_fonts(input_vpy: &Path, strict: bool) -> Result<()> {
+    let subtitles = rendered_subtitles(input_vpy)?;
+    if subtitles.is_empty() {
+        return Ok(());
+    }
+    if which("fc-match").is_err() {
+        warn!("fc-match not installed or not in PATH, unable to check subtitle fonts");
+        return Ok(());
+    }
+
+    let mut missing = BTreeSet::new();
+    for (subtitle, fontdir) in subtitles {
+        let bundled = fontdir.as_deref().map(font_families).unwrap_or_default();
+        let contents = match fs::read(&subtitle) {
+            Ok(contents) => String::from_utf8_lossy(&contents).into_owned(),
+            Err(e) => {
+                warn!(
+                    "Unable to read subtitles {} to check fonts: {}",
+                    subtitle.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        for font in ass_fonts(&contents) {
+            if !bundled.contains(&font.to_lowercase()) && !font_is_installed(&font) {
+                missing.insert(font);
+            }
+        }
+    }
+    if !missing.is_empty() {
+        let missing = missing.into_iter().collect::<Vec<_>>().join(", ");
+        if strict {
+            bail!(
+                "Fonts used by the rendered subtitles are not installed: {}",
+                missing
+            );
+        }
+        warn!(
+            "Fonts used by the rendered subtitles are not installed: {}",
+            missing
+        );
+    }
+    Ok(())
+}
+
+/// The subtitle files rendered by assrender or sub in the script,
+/// along with the `fontdir` assrender loads fonts from, if it is given one
+fn rendered_subtitles(input_vpy: &Path) -> Result<Vec<(PathBuf, Option<PathBuf>)>> {
+    static PATTERN: OnceCell<Regex> = OnceCell::new();
+    static FONTDIR: OnceCell<Regex> = OnceCell::new();
+    let pattern = PATTERN.get_or_init(|| {
+        Regex::new(&format!(
+            r#"(?:assrender\.TextSub|sub\.TextFile)\((?:[^)"']|{})*?(?:file\s*=\s*)?({})"#,
+            STRING_LITERAL, PATH_EXPRESSION
+        ))
+        .expect("Valid regex")
+    });
+    let fontdir = FONTDIR.get_or_init(|| {
+        Regex::new(&format!(
+            r#"^assrender\.TextSub\((?:[^)"']|{})*?fontdir\s*=\s*({})"#,
+            STRING_LITERAL, PATH_EXPRESSION
+        ))
+        .expect("Valid regex")
+    });
+    let script = fs::read_to_string(input_vpy)?;
+    let dir = input_vpy.parent().unwrap_or_else(|| Path::new("."));
+    Ok(pattern
+        .captures_iter(&script)
+        .filter_map(|captures| {
+            let path = parse_path_expression(&captures[1])?;
+            let call = &script[captures.get(0).expect("Match exists").start()..];
+            let fonts = fontdir
+                .captures(call)
+                .and_then(|captures| parse_path_expression(&captures[1]))
+                .map(|fonts| dir.join(fonts));
+            Some((path, fonts))
+        })
+        .filter(|(path, _)| {
+            path.extension().map_or(false, |ext| {
+                ext.eq_ignore_ascii_case("ass") || ext.eq_ignore_ascii_case("ssa")
+            })
+        })
+        .map(|(path, fonts)| (dir.join(path), fonts))
+        .collect())
+}
+
+/// The lowercased family names of every font in `dir`
+fn font_families(dir: &Path) -> BTreeSet<String> {
+    let output = match Command::new("fc-scan")
+        .arg("--format=%{family}\n")
+        .arg(dir)
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            debug!("Unable to run fc-scan: {}", e);
+            return BTreeSet::new();
+        }
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .flat_map(|families| families.split(','))
+        .map(|family| family.trim().to_lowercase())
+        .filter(|family| !family.is_empty())
+        .collect()
+}
+
+/// Every font named by the styles, or by `\fn` overrides in the events
+fn ass_fonts(contents: &str) -> BTreeSet<String> {
+    static OVERRIDE: OnceCell<Regex> = OnceCell::new();
+    let overrides = OVERRIDE.get_or_init(|| Regex::new(r"\\fn([^\\}]+)").expect("Valid regex"));
+
+    let mut fonts = BTreeSet::new();
+    let mut section = "";
+    // Styles list their fields in the order given by the section's format line
+    let mut fontname_index = 1;
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('[') {
+            section = line;
+            continue;
+        }
+        let is_styles = section.eq_ignore_ascii_case("[V4+ Styles]")
+            || section.eq_ignore_ascii_case("[V4 Styles]");
+        if is_styles {
+            if let Some(format) = line.strip_prefix("Format:") {
+                if let Some(index) = format
+                    .split(',')
+                    .position(|field| field.trim().eq_ignore_ascii_case("Fontname"))
+                {
+                    fontname_index = index;
+                }
+            } else if let Some(style) = line.strip_prefix("Style:") {
+                if let Some(font) = style.split(',').nth(fontname_index) {
+                    fonts.insert(clean_font_name(font));
+                }
+            }
+        } else if section.eq_ignore_ascii_case("[Events]") && line.starts_with("Dialogue:") {
+            for captures in overrides.captures_iter(line) {
+                fonts.insert(clean_font_name(&captures[1]));
+            }
+        }
+    }
+    fonts.remove("");
+    fonts
+}
+
+/// Vertical fonts are named with a leading `@`, but installed without it
+fn clean_font_name(font: &str) -> String {
+    font.trim().trim_start_matches('@').to_string()
+}
+
+/// Asks fontconfig for the font, which gives the closest match rather than failing,
+/// so the match has to be checked against the name asked for
+fn font_is_installed(font: &str) -> bool {
+    let output = match Command::new("fc-match")
+        .arg("--format=%{family}")
+        .arg(escape_pattern(font))
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            debug!("Unable to run fc-match: {}", e);
+            return true;
+        }
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .split(',')
+        .any(|family| family.trim().eq_ignore_ascii_case(font))
+}
+
+/// Escapes the characters fontconfig reads as pattern syntax in a family name
+fn escape_pattern(font: &str) -> String {
+    let mut escaped = String::with_capacity(font.len());
+    for c in font.chars() {
+        if matches!(c, '\\' | '-' | ':' | ',') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn font_patterns_are_escaped() {
+        assert_eq!(escape_pattern("Arial"), "Arial");
+        assert_eq!(
+            escape_pattern(r"A-OTF: Gothic, Pro\N"),
+            r"A\-OTF\: Gothic\, Pro\\N"
+        );
+    }
+}
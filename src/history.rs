@@ -0,0 +1,153 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use size::Size;
+
+use crate::{notify::format_duration, output::VideoEncoder, tools::TOOLS};
+
+/// Where every completed encode is recorded, one JSON object per line.
+///
+/// Can be moved with the `MP4BATCH_HISTORY` environment variable.
+pub fn history_path() -> Result<PathBuf> {
+    if let Some(path) = env::var_os("MP4BATCH_HISTORY") {
+        return Ok(PathBuf::from(path));
+    }
+    let data_dir = dirs::data_dir().ok_or_else(|| anyhow!("Unable to find a data directory"))?;
+    Ok(data_dir.join("mp4batch").join("history.jsonl"))
+}
+
+pub fn record_encode(entry: &Value) -> Result<()> {
+    let path = history_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", entry)?;
+    Ok(())
+}
+
+/// Reads every recorded encode, oldest first, skipping any lines which are damaged
+pub fn read_history() -> Result<Vec<Value>> {
+    let path = history_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let reader = BufReader::new(fs::File::open(path)?);
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Prints the most recent `limit` encodes whose paths or settings contain `filter`
+pub fn print_history(filter: Option<&str>, limit: Option<usize>, as_json: bool) -> Result<()> {
+    let filter = filter.map(str::to_lowercase);
+    let mut entries = read_history()?
+        .into_iter()
+        .filter(|entry| {
+            filter.as_ref().map_or(true, |filter| {
+                ["input", "source", "output", "settings"].iter().any(|key| {
+                    entry
+                        .get(*key)
+                        .and_then(Value::as_str)
+                        .map_or(false, |value| value.to_lowercase().contains(filter))
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+    if let Some(limit) = limit {
+        let skip = entries.len().saturating_sub(limit);
+        entries.drain(..skip);
+    }
+
+    if as_json {
+        println!("{}", Value::Array(entries));
+        return Ok(());
+    }
+    println!(
+        "{:<16}  {:>4}  {:>7}  {:>10}  {:>9}  OUTPUT",
+        "DATE", "CRF", "FPS", "SIZE", "TIME"
+    );
+    for entry in &entries {
+        let number = |key: &str| entry.get(key).and_then(Value::as_f64);
+        println!(
+            "{:<16}  {:>4}  {:>7}  {:>10}  {:>9}  {}",
+            number("time").map_or_else(String::new, |time| format_date(time as u64)),
+            entry
+                .get("crf")
+                .and_then(Value::as_i64)
+                .map_or_else(|| "-".to_string(), |crf| crf.to_string()),
+            number("fps").map_or_else(|| "-".to_string(), |fps| format!("{:.2}", fps)),
+            number("output_size").map_or_else(
+                || "-".to_string(),
+                |size| Size::from_bytes(size as u64).format().to_string()
+            ),
+            number("duration_secs").map_or_else(
+                || "-".to_string(),
+                |secs| format_duration(Duration::from_secs_f64(secs))
+            ),
+            entry
+                .get("output")
+                .and_then(Value::as_str)
+                .unwrap_or_default(),
+        );
+    }
+    Ok(())
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Formats a unix timestamp as `YYYY-MM-DD HH:MM` in UTC
+fn format_date(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+    // Converts days since the epoch to a civil date, from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60
+    )
+}
+
+/// Asks the encoder binary for its version
+pub fn encoder_version(encoder: VideoEncoder) -> Option<String> {
+    let binary = match encoder {
+        VideoEncoder::Copy => return None,
+        VideoEncoder::Aom { .. } => "aomenc",
+        VideoEncoder::Rav1e { .. } => "rav1e",
+        VideoEncoder::SvtAv1 { .. } => "SvtAv1EncApp",
+        VideoEncoder::X264 { .. } => "x264",
+        VideoEncoder::X265 { .. } => "x265",
+    };
+    TOOLS
+        .iter()
+        .find(|tool| tool.binary == binary)?
+        .version_line()
+}
@@ -0,0 +1,69 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+
+/// Results of probing files, such as evaluating a script, which are slow to get
+/// and needed by several stages.
+///
+/// A result is kept until the file is modified, so a script which is written again
+/// with different contents is probed again.
+pub struct ProbeCache<T> {
+    entries: Mutex<HashMap<PathBuf, (FileVersion, T)>>,
+}
+
+/// The modification time and size of a file
+type FileVersion = (SystemTime, u64);
+
+impl<T> Default for ProbeCache<T> {
+    fn default() -> Self {
+        ProbeCache {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> ProbeCache<T> {
+    /// The result of `probe` for `path`, reusing the last one if the file hasn't changed.
+    ///
+    /// Failures are not kept, so they are retried next time.
+    pub fn get_or_try_insert_with(
+        &self,
+        path: &Path,
+        probe: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let version = match file_version(path) {
+            Some(version) => version,
+            None => return probe(),
+        };
+        if let Some((cached_version, value)) = self.lock().get(path) {
+            if *cached_version == version {
+                return Ok(value.clone());
+            }
+        }
+        // Not locked while probing, so other files can be probed at the same time
+        let value = probe()?;
+        self.lock()
+            .insert(path.to_path_buf(), (version, value.clone()));
+        Ok(value)
+    }
+
+    pub fn get_or_insert_with(&self, path: &Path, probe: impl FnOnce() -> T) -> T {
+        self.get_or_try_insert_with(path, || Ok(probe()))
+            .expect("Probe should not fail")
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<PathBuf, (FileVersion, T)>> {
+        self.entries.lock().expect("Lock should not be poisoned")
+    }
+}
+
+fn file_version(path: &Path) -> Option<FileVersion> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
@@ -0,0 +1,179 @@
+use std::{path::Path, process::Command, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+
+use super::{MediaInfo, PixelFormat, VideoInfo};
+
+/// Runs ffprobe on `input` with `args`, such as the entries to show
+fn ffprobe_json(input: &Path, args: &[&str]) -> Result<Value> {
+    let output = Command::new("ffprobe")
+        .arg("-v")
+        .arg("error")
+        .args(args)
+        .arg("-of")
+        .arg("json")
+        .arg(input)
+        .output()
+        .map_err(|e| anyhow!("Failed to run ffprobe on {}: {}", input.display(), e))?;
+    if !output.status.success() {
+        bail!(
+            "ffprobe failed on {}: {}",
+            input.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// The first video stream of `input`
+fn video_stream(input: &Path, entries: &str) -> Result<Value> {
+    let probe = ffprobe_json(input, &["-select_streams", "v:0", "-show_entries", entries])?;
+    probe["streams"]
+        .as_array()
+        .and_then(|streams| streams.first())
+        .cloned()
+        .ok_or_else(|| anyhow!("ffprobe found no video in {}", input.display()))
+}
+
+pub fn ffprobe_video_info(input: &Path) -> Result<MediaInfo> {
+    let probe = ffprobe_json(
+        input,
+        &[
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream=width,height,avg_frame_rate,r_frame_rate,pix_fmt:stream_tags=NUMBER_OF_BYTES:\
+             format=size",
+        ],
+    )?;
+    let video = probe["streams"]
+        .as_array()
+        .and_then(|streams| streams.first())
+        .map(|stream| VideoInfo {
+            width: stream["width"].as_u64().map(|width| width as u32),
+            height: stream["height"].as_u64().map(|height| height as u32),
+            frame_rate: frame_rate(stream),
+            bit_depth: stream["pix_fmt"]
+                .as_str()
+                .and_then(parse_pix_fmt)
+                .map(|(_, bit_depth)| bit_depth),
+            stream_size: number(&stream["tags"]["NUMBER_OF_BYTES"]),
+        });
+    Ok(MediaInfo {
+        file_size: number(&probe["format"]["size"]),
+        video,
+    })
+}
+
+/// Counts the packets of the video, since containers don't always store a frame count
+pub fn ffprobe_frame_count(input: &Path) -> Result<u32> {
+    let probe = ffprobe_json(
+        input,
+        &[
+            "-select_streams",
+            "v:0",
+            "-count_packets",
+            "-show_entries",
+            "stream=nb_read_packets",
+        ],
+    )?;
+    number(&probe["streams"][0]["nb_read_packets"])
+        .ok_or_else(|| anyhow!("ffprobe found no frames in {}", input.display()))
+}
+
+pub fn ffprobe_duration(input: &Path) -> Result<Duration> {
+    let probe = ffprobe_json(input, &["-show_entries", "format=duration"])?;
+    let seconds: f64 = number(&probe["format"]["duration"])
+        .ok_or_else(|| anyhow!("ffprobe found no duration for {}", input.display()))?;
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+pub fn ffprobe_frame_rate(input: &Path) -> Result<f64> {
+    frame_rate(&video_stream(input, "stream=avg_frame_rate,r_frame_rate")?)
+        .ok_or_else(|| anyhow!("ffprobe found no frame rate for {}", input.display()))
+}
+
+pub fn ffprobe_pixel_format(input: &Path) -> Result<(PixelFormat, u8)> {
+    let stream = video_stream(input, "stream=pix_fmt")?;
+    let pix_fmt = stream["pix_fmt"].as_str().unwrap_or("");
+    parse_pix_fmt(pix_fmt).ok_or_else(|| anyhow!("Unrecognized pixel format: {}", pix_fmt))
+}
+
+/// The delay of the audio track numbered `track` from the start of the video
+pub fn ffprobe_audio_delay_ms(input: &Path, track: usize) -> Result<i32> {
+    let probe = ffprobe_json(input, &["-show_entries", "stream=codec_type,start_time"])?;
+    let streams = probe["streams"].as_array().cloned().unwrap_or_default();
+    let start_time = |codec_type: &str, index: usize| {
+        streams
+            .iter()
+            .filter(|stream| stream["codec_type"] == codec_type)
+            .nth(index)
+            .map(|stream| number::<f64>(&stream["start_time"]).unwrap_or(0.0))
+    };
+    let audio = start_time("audio", track)
+        .ok_or_else(|| anyhow!("Expected {} tracks, did not find enough", track + 1))?;
+    let video = start_time("video", 0).unwrap_or(0.0);
+    Ok(((audio - video) * 1000.0).round() as i32)
+}
+
+/// The average frame rate, or the base frame rate if the container has no average
+fn frame_rate(stream: &Value) -> Option<f64> {
+    ["avg_frame_rate", "r_frame_rate"]
+        .iter()
+        .filter_map(|key| stream[*key].as_str())
+        .find_map(|rate| {
+            let (num, den) = rate.split_once('/')?;
+            let (num, den) = (num.parse::<f64>().ok()?, den.parse::<f64>().ok()?);
+            // Unknown rates are written as 0/0
+            (num > 0.0 && den > 0.0).then(|| num / den)
+        })
+}
+
+/// Reads a pixel format such as `yuv420p10le`
+fn parse_pix_fmt(pix_fmt: &str) -> Option<(PixelFormat, u8)> {
+    let rest = pix_fmt
+        .strip_prefix("yuvj")
+        .or_else(|| pix_fmt.strip_prefix("yuv"))?;
+    let pixel_format = PixelFormat::from_chroma_subsampling(&format!(
+        "{}:{}:{}",
+        rest.get(0..1)?,
+        rest.get(1..2)?,
+        rest.get(2..3)?
+    ))?;
+    let bit_depth = rest[3..]
+        .trim_start_matches('p')
+        .trim_end_matches("le")
+        .trim_end_matches("be");
+    let bit_depth = if bit_depth.is_empty() {
+        8
+    } else {
+        bit_depth.parse().ok()?
+    };
+    Some((pixel_format, bit_depth))
+}
+
+/// ffprobe writes most numbers as strings
+fn number<T: std::str::FromStr>(value: &Value) -> Option<T> {
+    match value {
+        Value::String(value) => value.parse().ok(),
+        Value::Number(value) => value.to_string().parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixel_formats() {
+        assert_eq!(parse_pix_fmt("yuv420p"), Some((PixelFormat::Yuv420, 8)));
+        assert_eq!(
+            parse_pix_fmt("yuv422p10le"),
+            Some((PixelFormat::Yuv422, 10))
+        );
+        assert_eq!(parse_pix_fmt("yuvj444p"), Some((PixelFormat::Yuv444, 8)));
+        assert_eq!(parse_pix_fmt("rgb24"), None);
+    }
+}
@@ -0,0 +1,258 @@
+use std::{path::Path, process::Command, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use tracing::debug;
+
+use super::PixelFormat;
+
+/// What mediainfo or ffprobe reports about a file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaInfo {
+    /// In bytes
+    pub file_size: Option<u64>,
+    /// The first video track, if there is one
+    pub video: Option<VideoInfo>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct VideoInfo {
+    #[serde(default, deserialize_with = "number")]
+    pub width: Option<u32>,
+    #[serde(default, deserialize_with = "number")]
+    pub height: Option<u32>,
+    #[serde(default, deserialize_with = "number")]
+    pub frame_rate: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    pub bit_depth: Option<u8>,
+    /// In bytes
+    #[serde(default, deserialize_with = "number")]
+    pub stream_size: Option<u64>,
+}
+
+/// Reads a file with mediainfo.
+///
+/// Its JSON output is used where possible, since the text output
+/// is formatted differently across versions and locales.
+pub fn get_video_mediainfo(input: &Path) -> Result<MediaInfo> {
+    match mediainfo_json(input) {
+        Ok(info) => Ok(info),
+        Err(e) => {
+            debug!(
+                "Unable to read mediainfo's JSON for {}, reading its text instead: {}",
+                input.display(),
+                e
+            );
+            let command = Command::new("mediainfo").arg(input).output()?;
+            Ok(parse_mediainfo_text(&String::from_utf8_lossy(
+                &command.stdout,
+            )))
+        }
+    }
+}
+
+/// Runs mediainfo with an output template, such as `Video;%FrameCount%`
+fn mediainfo_template(input: &Path, template: &str) -> Result<String> {
+    let command = Command::new("mediainfo")
+        .arg(format!("--Output={}", template))
+        .arg(input)
+        .output()?;
+    Ok(String::from_utf8_lossy(&command.stdout).trim().to_string())
+}
+
+pub fn mediainfo_frame_count(input: &Path) -> Result<u32> {
+    Ok(mediainfo_template(input, "Video;%FrameCount%")?.parse()?)
+}
+
+pub fn mediainfo_duration(input: &Path) -> Result<Duration> {
+    let millis: f64 = mediainfo_template(input, "General;%Duration%")?.parse()?;
+    Ok(Duration::from_secs_f64(millis / 1000.0))
+}
+
+pub fn mediainfo_frame_rate(input: &Path) -> Result<f64> {
+    Ok(mediainfo_template(input, "Video;%FrameRate%")?.parse()?)
+}
+
+pub fn mediainfo_pixel_format(input: &Path) -> Result<(PixelFormat, u8)> {
+    let output = mediainfo_template(input, "Video;%ChromaSubsampling%,%BitDepth%")?;
+    let (subsampling, bit_depth) = output
+        .split_once(',')
+        .ok_or_else(|| anyhow!("Unexpected mediainfo output: {}", output))?;
+    let pixel_format = PixelFormat::from_chroma_subsampling(subsampling)
+        .ok_or_else(|| anyhow!("Unrecognized chroma subsampling: {}", subsampling))?;
+    Ok((pixel_format, bit_depth.parse()?))
+}
+
+/// mediainfo can give unparseable and wrong results for some formats like PCM
+pub fn mediainfo_audio_delay_ms(input: &Path, track: usize) -> Result<i32> {
+    let output = mediainfo_template(input, "Audio;%Delay%,")?;
+    Ok(output
+        .split(',')
+        .filter(|p| !p.trim().is_empty())
+        .nth(track)
+        .ok_or_else(|| anyhow!("Expected {} tracks, did not find enough", track + 1))?
+        .parse::<i32>()?)
+}
+
+fn mediainfo_json(input: &Path) -> Result<MediaInfo> {
+    let output = Command::new("mediainfo")
+        .arg("--Output=JSON")
+        .arg(input)
+        .output()?;
+    if !output.status.success() {
+        bail!("mediainfo failed on {}", input.display());
+    }
+    parse_mediainfo_json(&output.stdout)
+}
+
+#[derive(Deserialize)]
+struct Report {
+    media: Option<Media>,
+}
+
+#[derive(Deserialize)]
+struct Media {
+    #[serde(default)]
+    track: Vec<Track>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "@type")]
+enum Track {
+    General {
+        #[serde(rename = "FileSize", default, deserialize_with = "number")]
+        file_size: Option<u64>,
+    },
+    Video(VideoInfo),
+    #[serde(other)]
+    Other,
+}
+
+fn parse_mediainfo_json(json: &[u8]) -> Result<MediaInfo> {
+    let report: Report = serde_json::from_slice(json)?;
+    let tracks = report
+        .media
+        .ok_or_else(|| anyhow!("mediainfo found no media"))?
+        .track;
+    let mut info = MediaInfo::default();
+    for track in tracks {
+        match track {
+            Track::General { file_size } => info.file_size = file_size,
+            Track::Video(video) if info.video.is_none() => info.video = Some(video),
+            _ => (),
+        }
+    }
+    Ok(info)
+}
+
+/// mediainfo writes every value as a string
+fn number<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+{
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(value) => value.parse().ok(),
+        Value::Number(value) => value.to_string().parse().ok(),
+        _ => None,
+    })
+}
+
+/// Reads mediainfo's human readable output, such as `Width : 1 920 pixels`
+fn parse_mediainfo_text(output: &str) -> MediaInfo {
+    let fields = |section: &'static str| {
+        output
+            .lines()
+            .skip_while(move |line| line.trim() != section)
+            .skip(1)
+            .take_while(|line| !line.trim().is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim(), value.trim()))
+    };
+    let first_word = |value: &str| value.split_whitespace().next().unwrap_or("").to_string();
+
+    let file_size = fields("General")
+        .find(|&(key, _)| key == "File size")
+        .and_then(|(_, value)| parse_size(value));
+    let mut video = None;
+    for (key, value) in fields("Video") {
+        let video = video.get_or_insert_with(VideoInfo::default);
+        match key {
+            // Thousands are separated by spaces
+            "Width" | "Height" => {
+                let digits = value
+                    .chars()
+                    .take_while(|c| c.is_ascii_digit() || *c == ' ')
+                    .filter(char::is_ascii_digit)
+                    .collect::<String>();
+                if key == "Width" {
+                    video.width = digits.parse().ok();
+                } else {
+                    video.height = digits.parse().ok();
+                }
+            }
+            "Frame rate" => video.frame_rate = first_word(value).parse().ok(),
+            "Bit depth" => video.bit_depth = first_word(value).parse().ok(),
+            "Stream size" => video.stream_size = parse_size(value),
+            _ => (),
+        }
+    }
+    MediaInfo { file_size, video }
+}
+
+/// Reads a size such as `1.23 GiB (95%)` as bytes
+fn parse_size(value: &str) -> Option<u64> {
+    let mut words = value.split_whitespace();
+    let number: f64 = words.next()?.parse().ok()?;
+    let unit = match words.next()? {
+        "Byte" | "Bytes" => 1u64,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        _ => return None,
+    };
+    Some((number * unit as f64).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mediainfo_json_and_text_agree() {
+        let json = br#"{"media": {"@ref": "a.mkv", "track": [
+            {"@type": "General", "FileSize": "1073741824", "Format": "Matroska"},
+            {"@type": "Video", "Width": "1920", "Height": "1080", "FrameRate": "23.976",
+             "BitDepth": "10", "StreamSize": "1048576", "extra": {"x": "y"}},
+            {"@type": "Audio", "Channels": "2"}
+        ]}}"#;
+        let text = "General\n\
+            Complete name                            : a.mkv\n\
+            File size                                : 1.00 GiB\n\
+            \n\
+            Video\n\
+            Width                                    : 1 920 pixels\n\
+            Height                                   : 1 080 pixels\n\
+            Frame rate                               : 23.976 (24000/1001) FPS\n\
+            Bit depth                                : 10 bits\n\
+            Stream size                              : 1.00 MiB (0%)\n\
+            \n\
+            Audio\n\
+            Channel(s)                               : 2 channels\n";
+        let expected = MediaInfo {
+            file_size: Some(1 << 30),
+            video: Some(VideoInfo {
+                width: Some(1920),
+                height: Some(1080),
+                frame_rate: Some(23.976),
+                bit_depth: Some(10),
+                stream_size: Some(1 << 20),
+            }),
+        };
+        assert_eq!(parse_mediainfo_json(json).unwrap(), expected);
+        assert_eq!(parse_mediainfo_text(text), expected);
+    }
+}
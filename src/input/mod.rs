@@ -3,9 +3,11 @@ use std::{
     fs,
     path::{Path, PathBuf},
     process::Command,
+    sync::Mutex,
+    time::Duration,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use av_data::pixel::{
     ChromaLocation, ColorPrimaries, FromPrimitive, MatrixCoefficients, TransferCharacteristic,
     YUVRange,
@@ -13,7 +15,25 @@ use av_data::pixel::{
 use itertools::Itertools;
 use once_cell::sync::OnceCell;
 use regex::Regex;
-use vapoursynth::vsscript::{Environment, EvalFlags};
+use serde_json::Value;
+use tracing::{debug, warn};
+use vapoursynth::{
+    api::API,
+    format::ColorFamily,
+    map::OwnedMap,
+    video_info::Property,
+    vsscript::{Environment, EvalFlags},
+};
+
+pub use self::{cache::*, ffprobe::*, mediainfo::*, probe::*, timecodes::*, wobbly::*};
+use crate::python::{parse_path_expression, split_arguments, PATH_EXPRESSION, STRING_LITERAL};
+
+mod cache;
+mod ffprobe;
+mod mediainfo;
+mod probe;
+mod timecodes;
+mod wobbly;
 
 #[derive(Debug, Clone, Copy)]
 pub struct VideoDimensions {
@@ -40,183 +60,201 @@ impl Default for PixelFormat {
 }
 
 impl PixelFormat {
-    fn from_vapoursynth_format(format: &str) -> Self {
-        if format.starts_with("YUV420") {
-            return PixelFormat::Yuv420;
+    fn from_chroma_subsampling(subsampling: &str) -> Option<Self> {
+        match subsampling {
+            "4:2:0" => Some(PixelFormat::Yuv420),
+            "4:2:2" => Some(PixelFormat::Yuv422),
+            "4:4:4" => Some(PixelFormat::Yuv444),
+            _ => None,
         }
-        if format.starts_with("YUV422") {
-            return PixelFormat::Yuv422;
-        }
-        if format.starts_with("YUV444") {
-            return PixelFormat::Yuv444;
-        }
-        unimplemented!()
     }
 }
 
 pub fn get_video_dimensions(input: &Path) -> Result<VideoDimensions> {
+    static DIMENSIONS: OnceCell<ProbeCache<VideoDimensions>> = OnceCell::new();
     let filename = input
         .file_name()
         .expect("File should have a name")
         .to_string_lossy();
-    if filename.ends_with(".vpy") {
-        get_video_dimensions_vps(input)
-    } else {
-        get_video_dimensions_ffprobe(input)
-    }
+    DIMENSIONS
+        .get_or_init(Default::default)
+        .get_or_try_insert_with(input, || {
+            if filename.ends_with(".vpy") {
+                get_video_dimensions_vps(input)
+            } else {
+                get_video_dimensions_ffprobe(input)
+            }
+        })
 }
 
 fn get_video_dimensions_ffprobe(input: &Path) -> Result<VideoDimensions> {
-    let mediainfo = get_video_mediainfo(input)?;
-
-    let width = mediainfo
-        .get("Width")
-        .expect("Width should be specified in ffprobe output")
-        .replace(' ', "")
-        .parse()?;
-    let height = mediainfo
-        .get("Height")
-        .expect("Height should be specified in ffprobe output")
-        .replace(' ', "")
-        .parse()?;
-    let fps = (
-        mediainfo
-            .get("Frame rate")
-            .expect("Frame rate should be specified in ffprobe output")
-            .parse::<f32>()?
-            .round() as u32,
-        1,
-    );
-    let bit_depth = mediainfo
-        .get("Bit depth")
-        .expect("Bit depth should be specified in ffprobe output")
-        .parse()?;
+    let missing = |field| anyhow!("Found no {} in {}", field, input.display());
+    let video = get_video_info(input)?
+        .video
+        .ok_or_else(|| missing("video"))?;
 
     Ok(VideoDimensions {
-        width,
-        height,
-        fps,
+        width: video.width.ok_or_else(|| missing("width"))?,
+        height: video.height.ok_or_else(|| missing("height"))?,
+        fps: (
+            video
+                .frame_rate
+                .ok_or_else(|| missing("frame rate"))?
+                .round() as u32,
+            1,
+        ),
         frames: 0,
         pixel_format: PixelFormat::Yuv420,
-        bit_depth,
+        bit_depth: video.bit_depth.ok_or_else(|| missing("bit depth"))?,
     })
 }
 
 pub fn get_video_frame_count(input: &Path) -> Result<u32> {
-    let command = Command::new("mediainfo")
-        .arg("--Output=Video;%FrameCount%")
-        .arg(input)
-        .output()?;
-    let output = String::from_utf8_lossy(&command.stdout);
-    Ok(output.trim().parse()?)
+    probe(input, mediainfo_frame_count, ffprobe_frame_count)
+}
+
+/// Returns the overall duration of a media file
+pub fn get_media_duration(input: &Path) -> Result<Duration> {
+    probe(input, mediainfo_duration, ffprobe_duration)
+}
+
+/// Returns the frame rate of an encoded video
+pub fn get_video_frame_rate(input: &Path) -> Result<f64> {
+    probe(input, mediainfo_frame_rate, ffprobe_frame_rate)
+}
+
+/// Returns the chroma subsampling and bit depth of an encoded video
+pub fn get_video_pixel_format(input: &Path) -> Result<(PixelFormat, u8)> {
+    probe(input, mediainfo_pixel_format, ffprobe_pixel_format)
+}
+
+/// Returns the size of a media file and the properties of its first video track
+pub fn get_video_info(input: &Path) -> Result<MediaInfo> {
+    probe(input, get_video_mediainfo, ffprobe_video_info)
+}
+
+/// Encodes and probes `script`'s video output numbered `index`, rather than output 0,
+/// for scripts which also set outputs for previews or comparisons
+pub fn set_script_output(script: &Path, index: u8) {
+    script_outputs()
+        .lock()
+        .expect("Lock should not be poisoned")
+        .insert(script.to_path_buf(), index);
+}
+
+/// The video output of `script` which is encoded
+pub fn script_output(script: &Path) -> i32 {
+    script_outputs()
+        .lock()
+        .expect("Lock should not be poisoned")
+        .get(script)
+        .map_or(0, |&index| i32::from(index))
+}
+
+fn script_outputs() -> &'static Mutex<HashMap<PathBuf, u8>> {
+    static OUTPUTS: OnceCell<Mutex<HashMap<PathBuf, u8>>> = OnceCell::new();
+    OUTPUTS.get_or_init(Default::default)
 }
 
 fn get_video_dimensions_vps(input: &Path) -> Result<VideoDimensions> {
-    let command = Command::new("vspipe")
-        .arg("-i")
-        .arg(input)
-        .arg("-")
-        .output()
-        .map_err(|e| anyhow!("Failed to execute vspipe -i to get video dimensions: {}", e))?;
-    // Width: 1280
-    // Height: 720
-    // Frames: 17982
-    // FPS: 24000/1001 (23.976 fps)
-    // Format Name: YUV420P8
-    // Color Family: YUV
-    // Alpha: No
-    // Sample Type: Integer
-    // Bits: 8
-    // SubSampling W: 1
-    // SubSampling H: 1
-    let output = String::from_utf8_lossy(&command.stdout);
-
-    let lines = output.lines().collect::<Vec<_>>();
-    let width = lines
-        .iter()
-        .find(|l| l.starts_with("Width: "))
-        .unwrap()
-        .replace("Width: ", "")
-        .trim()
-        .parse()?;
-    let height = lines
-        .iter()
-        .find(|l| l.starts_with("Height: "))
-        .unwrap()
-        .replace("Height: ", "")
-        .trim()
-        .parse()?;
-    let fps: Vec<_> = lines
-        .iter()
-        .find(|l| l.starts_with("FPS: "))
-        .unwrap()
-        .replace("FPS: ", "")
-        .split_whitespace()
-        .next()
-        .expect("FPS should have a value")
-        .split('/')
-        .map(|num| num.parse())
-        .collect();
-    let bit_depth = lines
-        .iter()
-        .find(|l| l.starts_with("Bits: "))
-        .unwrap()
-        .replace("Bits: ", "")
-        .trim()
-        .parse()
-        .expect("Bits should be a number");
-    Ok(VideoDimensions {
-        width,
-        height,
-        frames: lines
-            .iter()
-            .find(|l| l.starts_with("Frames: "))
-            .unwrap()
-            .replace("Frames: ", "")
-            .trim()
-            .parse()?,
-        fps: (fps[0].clone()?, fps[1].clone()?),
-        pixel_format: PixelFormat::from_vapoursynth_format(
-            &lines
-                .iter()
-                .find(|l| l.starts_with("Format Name: "))
-                .unwrap()
-                .replace("Format Name: ", ""),
+    let env = open_script(input)?;
+    let (node, _) = env.get_output(script_output(input))?;
+    let info = node.info();
+    let variable = |property| anyhow!("{} has a variable {}", input.display(), property);
+    let format = match info.format {
+        Property::Constant(format) => format,
+        Property::Variable => return Err(variable("format")),
+    };
+    let resolution = match info.resolution {
+        Property::Constant(resolution) => resolution,
+        Property::Variable => return Err(variable("resolution")),
+    };
+    let fps = match info.framerate {
+        Property::Constant(framerate) => (framerate.numerator as u32, framerate.denominator as u32),
+        // Variable frame rate scripts are encoded at their average rate,
+        // then muxed with their timecodes
+        Property::Variable => script_timecodes(input)?
+            .as_deref()
+            .and_then(average_frame_rate)
+            .ok_or_else(|| variable("frame rate"))?,
+    };
+    let pixel_format = match (
+        format.color_family(),
+        format.sub_sampling_w(),
+        format.sub_sampling_h(),
+    ) {
+        (ColorFamily::YUV, 1, 1) => PixelFormat::Yuv420,
+        (ColorFamily::YUV, 1, 0) => PixelFormat::Yuv422,
+        (ColorFamily::YUV, 0, 0) => PixelFormat::Yuv444,
+        _ => bail!(
+            "{} outputs {}, which is not a supported YUV format",
+            input.display(),
+            format.name()
         ),
-        bit_depth,
+    };
+    Ok(VideoDimensions {
+        width: resolution.width as u32,
+        height: resolution.height as u32,
+        frames: info.num_frames as u32,
+        fps,
+        pixel_format,
+        bit_depth: format.bits_per_sample(),
     })
 }
 
-pub fn get_video_mediainfo(input: &Path) -> Result<HashMap<String, String>> {
-    let command = Command::new("mediainfo").arg(input).output()?;
-    let output = String::from_utf8_lossy(&command.stdout);
-
-    let mut data = output
-        .lines()
-        .skip_while(|line| line.trim() != "Video")
-        .skip(1)
-        .take_while(|line| !line.is_empty())
-        .map(|line| {
-            let (key, value) = line
-                .split_once(':')
-                .expect("String should be a 'key: value' pair");
-            (key.trim().to_string(), value.trim().to_string())
+/// The indexes of the script's outputs which are audio nodes, in order
+pub fn script_audio_outputs(input: &Path) -> Result<Vec<u8>> {
+    static AUDIO_OUTPUTS: OnceCell<ProbeCache<Vec<u8>>> = OnceCell::new();
+    AUDIO_OUTPUTS
+        .get_or_init(Default::default)
+        .get_or_try_insert_with(input, || {
+            let mut env = open_script(input)?;
+            // Audio nodes aren't available through the API this is built against,
+            // and the indexes are joined since an empty list can't be read back
+            env.eval_script(&format!(
+                "import vapoursynth as _vs\n\
+                 try:\n    \
+                     {0} = ','.join(str(i) for i, node in sorted(_vs.get_outputs().items()) \
+                     if isinstance(node, _vs.AudioNode))\n\
+                 except Exception:\n    \
+                     {0} = ''\n",
+                AUDIO_VARIABLE
+            ))
+            .map_err(script_error)?;
+            let api = API::get().ok_or_else(|| anyhow!("Unable to load the VapourSynth API"))?;
+            let mut variables = OwnedMap::new(api);
+            env.get_variable(AUDIO_VARIABLE, &mut variables)?;
+            let outputs = String::from_utf8_lossy(variables.get_data(AUDIO_VARIABLE)?).to_string();
+            outputs
+                .split(',')
+                .filter(|index| !index.is_empty())
+                .map(|index| {
+                    index.parse().map_err(|_| {
+                        anyhow!(
+                            "Audio output {} of {} is out of range",
+                            index,
+                            input.display()
+                        )
+                    })
+                })
+                .collect()
         })
-        .collect::<HashMap<String, String>>();
-    data.insert(
-        "File size".to_owned(),
-        output
-            .lines()
-            .find(|l| l.starts_with("File size"))
-            .unwrap_or("File size: 0.00 MiB")
-            .split_once(':')
-            .expect("String should be a 'key: value' pair")
-            .1
-            .trim()
-            .to_string(),
-    );
-
-    Ok(data)
+}
+
+const AUDIO_VARIABLE: &str = "_mp4batch_audio_outputs";
+
+/// Evaluates a script, so its outputs can be read or rendered
+pub fn open_script(input: &Path) -> Result<Environment> {
+    Environment::from_file(input, EvalFlags::SetWorkingDir).map_err(script_error)
+}
+
+fn script_error(e: vapoursynth::vsscript::Error) -> anyhow::Error {
+    match e {
+        vapoursynth::vsscript::Error::VSScript(e) => {
+            anyhow!("An error occurred in VSScript: {}", e)
+        }
+        _ => anyhow!("{}", e),
+    }
 }
 
 pub fn find_source_file(input: &Path) -> PathBuf {
@@ -229,36 +267,245 @@ pub fn find_source_file(input: &Path) -> PathBuf {
         return input.to_path_buf();
     }
 
+    // Evaluating the script is slow, and this is needed by several stages
+    static RESOLVED: OnceCell<ProbeCache<PathBuf>> = OnceCell::new();
+    RESOLVED
+        .get_or_init(Default::default)
+        .get_or_insert_with(input, || resolve_source_file(input))
+}
+
+fn resolve_source_file(input: &Path) -> PathBuf {
+    if let Some(source) = source_directive(input, "source") {
+        return source;
+    }
+    let source = match source_from_script_env(input) {
+        Ok(Some(source)) => source,
+        Ok(None) => source_from_script_text(input),
+        Err(e) => {
+            debug!(
+                "Unable to evaluate {} to find its source, parsing it instead: {}",
+                input.display(),
+                e
+            );
+            source_from_script_text(input)
+        }
+    };
+    // Handle relative or absolute paths
+    let mut output = input
+        .parent()
+        .expect("File should have a parent dir")
+        .to_path_buf();
+    output.push(source);
+    resolve_index_file(output)
+}
+
+/// The sources each kind of stream is taken from, for scripts which splice several files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMap {
+    pub audio: PathBuf,
+    pub subtitles: PathBuf,
+    /// Chapters are only muxed when a script names a file to take them from
+    pub chapters: Option<PathBuf>,
+}
+
+/// Which source each kind of stream comes from.
+///
+/// A script chooses them with comments such as `# mp4batch: audio=part2.mkv`,
+/// with paths relative to the script. Anything not chosen comes from the source
+/// found by `find_source_file`, which `# mp4batch: source=...` also chooses.
+pub fn find_source_map(input: &Path) -> SourceMap {
+    let source = || find_source_file(input);
+    SourceMap {
+        audio: source_directive(input, "audio").unwrap_or_else(source),
+        subtitles: source_directive(input, "subtitles").unwrap_or_else(source),
+        chapters: source_directive(input, "chapters"),
+    }
+}
+
+/// The source a `# mp4batch: key=path` comment in the script names
+fn source_directive(input: &Path, key: &str) -> Option<PathBuf> {
+    if input.extension().map_or(true, |ext| ext != "vpy") {
+        return None;
+    }
+    let script = fs::read_to_string(input).ok()?;
+    let path = parse_source_directives(&script).remove(key)?;
+    Some(resolve_index_file(
+        input
+            .parent()
+            .expect("File should have a parent dir")
+            .join(path),
+    ))
+}
+
+fn parse_source_directives(script: &str) -> HashMap<String, PathBuf> {
+    static DIRECTIVE: OnceCell<Regex> = OnceCell::new();
+    let directive = DIRECTIVE.get_or_init(|| {
+        Regex::new(
+            r"(?m)^[ \t]*#[ \t]*mp4batch:[ \t]*(source|audio|subtitles|chapters|wobbly|timecodes)[ \t]*=(.+)$",
+        )
+        .expect("Valid regex")
+    });
+    directive
+        .captures_iter(script)
+        .map(|cap| {
+            let value = cap[2].trim();
+            // Paths may be quoted, for ones with surrounding spaces
+            let path = parse_path_expression(value).unwrap_or_else(|| PathBuf::from(value));
+            (cap[1].to_string(), path)
+        })
+        .collect()
+}
+
+/// The variable or frame prop a script can set to name its source,
+/// for scripts whose source paths can't be found by parsing them
+const SOURCE_VARIABLE: &str = "mp4batch_source";
+
+/// Evaluates the script, and reads its source from the `mp4batch_source` variable,
+/// or from the frame prop of the same name on the output's first frame
+fn source_from_script_env(input: &Path) -> Result<Option<PathBuf>> {
+    let env = open_script(input)?;
+    let api = API::get().ok_or_else(|| anyhow!("Unable to load the VapourSynth API"))?;
+    let mut variables = OwnedMap::new(api);
+    if env.get_variable(SOURCE_VARIABLE, &mut variables).is_ok() {
+        if let Ok(source) = variables.get_data(SOURCE_VARIABLE) {
+            return Ok(Some(PathBuf::from(
+                String::from_utf8_lossy(source).into_owned(),
+            )));
+        }
+    }
+    let (node, _) = env.get_output(script_output(input))?;
+    let frame = node.get_frame(0)?;
+    Ok(frame
+        .props()
+        .get_data(SOURCE_VARIABLE)
+        .ok()
+        .map(|source| PathBuf::from(String::from_utf8_lossy(source).into_owned())))
+}
+
+fn source_from_script_text(input: &Path) -> PathBuf {
     let script = fs::read_to_string(input).expect("Failed to read source script");
     let sources = parse_sources(&script);
     // If there's a source that matches this script's name then use that,
     // otherwise assume the first source is correct.
     // This is mostly for OC merging.
-    let source = sources
+    sources
         .iter()
         .find(|source| source.file_stem() == input.file_stem())
-        .unwrap_or_else(|| &sources[0]);
-    // Handle relative or absolute paths
-    let mut output = input
-        .parent()
-        .expect("File should have a parent dir")
-        .to_path_buf();
-    output.push(source);
-    output
+        .unwrap_or_else(|| &sources[0])
+        .clone()
+}
+
+/// Returns every source file referenced by the script
+pub fn find_all_source_files(input: &Path) -> Vec<PathBuf> {
+    let script = fs::read_to_string(input).expect("Failed to read source script");
+    let parent = input.parent().expect("File should have a parent dir");
+    parse_sources(&script)
+        .into_iter()
+        .map(|source| resolve_index_file(parent.join(source)))
+        .collect()
 }
 
 fn parse_sources(script: &str) -> Vec<PathBuf> {
-    // If you have a quotation mark in your filename then go to hell
+    static SOURCE_CALL: OnceCell<Regex> = OnceCell::new();
+    static GENERIC: OnceCell<Regex> = OnceCell::new();
+    let source_call = SOURCE_CALL.get_or_init(|| {
+        // Arguments may contain strings, and calls one level deep
+        let arg = format!(r#"(?:[^()"']|{}|\((?:[^()"']|{})*\))"#, STRING_LITERAL, STRING_LITERAL);
+        Regex::new(&format!(
+            r"(?:lsmas\.LWLibavSource|lsmas\.LSMASHVideoSource|bs\.VideoSource|ffms2\.Source|dgdecodenv\.DGSource)\(({}*)\)",
+            arg
+        ))
+        .expect("Valid regex")
+    });
+    let generic = GENERIC.get_or_init(|| {
+        Regex::new(&format!(r"\bsource\s*=\s*({})", PATH_EXPRESSION)).expect("Valid regex")
+    });
+    let variables = path_variables(script);
+
+    let mut sources = Vec::new();
+    for call in source_call.captures_iter(script) {
+        let args = split_arguments(call.get(1).expect("Regex has a capture group").as_str());
+        // The path is the first positional argument, or passed as `source=`
+        let arg = args
+            .iter()
+            .find_map(|arg| {
+                arg.strip_prefix("source")
+                    .map(str::trim_start)
+                    .and_then(|arg| arg.strip_prefix('='))
+            })
+            .or_else(|| args.first().copied().filter(|arg| !arg.contains('=')))
+            .map(str::trim);
+        let path = arg.and_then(|arg| {
+            if arg.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                variables.get(arg).cloned()
+            } else {
+                parse_path_expression(arg)
+            }
+        });
+        if let Some(path) = path {
+            sources.push((call.get(0).expect("Regex has a match").start(), path));
+        }
+    }
+    // Other source filters which take `source=`, such as audio sources
+    for cap in generic.captures_iter(script) {
+        if let Some(path) = parse_path_expression(&cap[1]) {
+            sources.push((cap.get(0).expect("Regex has a match").start(), path));
+        }
+    }
+    sources
+        .into_iter()
+        .sorted_by_key(|(pos, _)| *pos)
+        .map(|(_, path)| path)
+        .unique()
+        .collect()
+}
+
+/// Top level variables assigned a path, such as `src = r"video.mkv"`
+fn path_variables(script: &str) -> HashMap<String, PathBuf> {
     static PATTERN: OnceCell<Regex> = OnceCell::new();
-    let pattern = PATTERN
-        .get_or_init(|| Regex::new("source=['\"](.+\\.\\w{2,4})['\"]").expect("Valid regex"));
+    let pattern = PATTERN.get_or_init(|| {
+        Regex::new(&format!(
+            r"(?m)^([A-Za-z_]\w*)[ \t]*=[ \t]*({})[ \t]*(?:#.*)?$",
+            PATH_EXPRESSION
+        ))
+        .expect("Valid regex")
+    });
     pattern
         .captures_iter(script)
-        .map(|cap| PathBuf::from(&cap[1]))
-        .unique()
+        .filter_map(|cap| Some((cap[1].to_string(), parse_path_expression(&cap[2])?)))
         .collect()
 }
 
+/// DGDecNV scripts load a `.dgi` index, which lists the video it indexes
+/// on the line after its header
+fn resolve_index_file(source: PathBuf) -> PathBuf {
+    if source.extension().map_or(true, |ext| ext != "dgi") {
+        return source;
+    }
+    let index = match fs::read_to_string(&source) {
+        Ok(index) => index,
+        Err(_) => return source,
+    };
+    index
+        .lines()
+        .skip(1)
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(|line| {
+            // Each file is followed by its size
+            let path = line
+                .rsplit_once(' ')
+                .filter(|(_, size)| size.chars().all(|c| c.is_ascii_digit()))
+                .map_or(line, |(path, _)| path);
+            source
+                .parent()
+                .expect("File should have a parent dir")
+                .join(path)
+        })
+        .filter(|video| video.is_file())
+        .unwrap_or(source)
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Colorimetry {
     pub range: YUVRange,
@@ -266,23 +513,222 @@ pub struct Colorimetry {
     pub matrix: MatrixCoefficients,
     pub transfer: TransferCharacteristic,
     pub chroma_location: ChromaLocation,
+    /// Enables or disables HDR encoding features regardless of the transfer
+    pub force_hdr: Option<bool>,
 }
 
 impl Colorimetry {
     pub fn is_hdr(&self) -> bool {
-        self.transfer == TransferCharacteristic::HybridLogGamma
-            || self.transfer == TransferCharacteristic::PerceptualQuantizer
+        self.force_hdr.unwrap_or_else(|| {
+            self.transfer == TransferCharacteristic::HybridLogGamma
+                || self.transfer == TransferCharacteristic::PerceptualQuantizer
+        })
+    }
+
+    /// This colorimetry with any values set in `overrides` replaced
+    pub fn with_overrides(self, overrides: &ColorimetryOverrides) -> Colorimetry {
+        Colorimetry {
+            range: overrides.range.unwrap_or(self.range),
+            primaries: overrides.primaries.unwrap_or(self.primaries),
+            matrix: overrides.matrix.unwrap_or(self.matrix),
+            transfer: overrides.transfer.unwrap_or(self.transfer),
+            chroma_location: overrides.chroma_location.unwrap_or(self.chroma_location),
+            force_hdr: overrides.hdr.or(self.force_hdr),
+        }
+    }
+
+    /// Fills in unspecified primaries, matrix and transfer with the usual ones
+    /// for a video of this size, warning about each one assumed.
+    ///
+    /// `input` is only used for the warning.
+    pub fn with_fallbacks(self, input: &Path, width: u32, height: u32) -> Colorimetry {
+        let (primaries, matrix, transfer) = fallback_colorimetry(width, height, self.is_hdr());
+        let mut colorimetry = self;
+        let mut assumed = Vec::new();
+        if self.primaries == ColorPrimaries::Unspecified {
+            colorimetry.primaries = primaries;
+            assumed.push(format!("primaries={:?}", primaries));
+        }
+        if self.matrix == MatrixCoefficients::Unspecified {
+            colorimetry.matrix = matrix;
+            assumed.push(format!("matrix={:?}", matrix));
+        }
+        if self.transfer == TransferCharacteristic::Unspecified {
+            colorimetry.transfer = transfer;
+            assumed.push(format!("transfer={:?}", transfer));
+        }
+        if !assumed.is_empty() {
+            warn!(
+                "{} does not specify its colorimetry, ASSUMING {} from its {}x{} resolution. \
+                 If this is wrong, set the frame props in the script \
+                 or `primaries=`, `matrix=` and `transfer=` in the format.",
+                input.display(),
+                assumed.join(", "),
+                width,
+                height
+            );
+        }
+        colorimetry
+    }
+}
+
+/// The colorimetry a video of this size most likely uses: BT.2020 for UHD or HDR,
+/// BT.709 for HD, and BT.601 for SD, as PAL when it is 576 lines and NTSC otherwise.
+/// HDR is assumed to be PQ, as HLG is rare outside of broadcasts.
+fn fallback_colorimetry(
+    width: u32,
+    height: u32,
+    hdr: bool,
+) -> (ColorPrimaries, MatrixCoefficients, TransferCharacteristic) {
+    if hdr || width > 1920 || height > 1080 {
+        (
+            ColorPrimaries::BT2020,
+            MatrixCoefficients::BT2020NonConstantLuminance,
+            if hdr {
+                TransferCharacteristic::PerceptualQuantizer
+            } else {
+                TransferCharacteristic::BT2020Ten
+            },
+        )
+    } else if width > 1024 || height > 576 {
+        (
+            ColorPrimaries::BT709,
+            MatrixCoefficients::BT709,
+            TransferCharacteristic::BT1886,
+        )
+    } else if height == 576 {
+        (
+            ColorPrimaries::BT470BG,
+            MatrixCoefficients::BT470BG,
+            TransferCharacteristic::BT470BG,
+        )
+    } else {
+        (
+            ColorPrimaries::ST170M,
+            MatrixCoefficients::ST170M,
+            TransferCharacteristic::ST170M,
+        )
+    }
+}
+
+/// Colorimetry to encode with in place of the script's frame props,
+/// for sources which tag them wrongly or not at all
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ColorimetryOverrides {
+    pub range: Option<YUVRange>,
+    pub primaries: Option<ColorPrimaries>,
+    pub matrix: Option<MatrixCoefficients>,
+    pub transfer: Option<TransferCharacteristic>,
+    pub chroma_location: Option<ChromaLocation>,
+    pub hdr: Option<bool>,
+}
+
+impl ColorimetryOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == ColorimetryOverrides::default()
+    }
+}
+
+// The names below are the ones x264 and ffmpeg use. Numbers are read as
+// the code points from H.273, the same as the `_Matrix`-style frame props.
+
+pub fn parse_color_range(value: &str) -> Option<YUVRange> {
+    match value {
+        "limited" | "tv" | "1" => Some(YUVRange::Limited),
+        "full" | "pc" | "0" => Some(YUVRange::Full),
+        _ => None,
+    }
+}
+
+pub fn parse_color_primaries(value: &str) -> Option<ColorPrimaries> {
+    let primaries = match value {
+        "bt709" => ColorPrimaries::BT709,
+        "bt470m" => ColorPrimaries::BT470M,
+        "bt470bg" => ColorPrimaries::BT470BG,
+        "smpte170m" => ColorPrimaries::ST170M,
+        "smpte240m" => ColorPrimaries::ST240M,
+        "film" => ColorPrimaries::Film,
+        "bt2020" => ColorPrimaries::BT2020,
+        "smpte428" => ColorPrimaries::ST428,
+        "smpte431" => ColorPrimaries::P3DCI,
+        "smpte432" => ColorPrimaries::P3Display,
+        _ => ColorPrimaries::from_i64(value.parse().ok()?)?,
+    };
+    (primaries != ColorPrimaries::Unspecified).then(|| primaries)
+}
+
+pub fn parse_matrix_coefficients(value: &str) -> Option<MatrixCoefficients> {
+    let matrix = match value {
+        "gbr" | "rgb" => MatrixCoefficients::Identity,
+        "bt709" => MatrixCoefficients::BT709,
+        "fcc" => MatrixCoefficients::BT470M,
+        "bt470bg" => MatrixCoefficients::BT470BG,
+        "smpte170m" => MatrixCoefficients::ST170M,
+        "smpte240m" => MatrixCoefficients::ST240M,
+        "ycgco" => MatrixCoefficients::YCgCo,
+        "bt2020nc" => MatrixCoefficients::BT2020NonConstantLuminance,
+        "bt2020c" => MatrixCoefficients::BT2020ConstantLuminance,
+        "smpte2085" => MatrixCoefficients::ST2085,
+        "ictcp" => MatrixCoefficients::ICtCp,
+        _ => MatrixCoefficients::from_i64(value.parse().ok()?)?,
+    };
+    (matrix != MatrixCoefficients::Unspecified).then(|| matrix)
+}
+
+pub fn parse_transfer_characteristic(value: &str) -> Option<TransferCharacteristic> {
+    let transfer = match value {
+        "bt709" | "bt1886" => TransferCharacteristic::BT1886,
+        "bt470m" => TransferCharacteristic::BT470M,
+        "bt470bg" => TransferCharacteristic::BT470BG,
+        "smpte170m" => TransferCharacteristic::ST170M,
+        "smpte240m" => TransferCharacteristic::ST240M,
+        "linear" => TransferCharacteristic::Linear,
+        "srgb" | "iec61966-2-1" => TransferCharacteristic::SRGB,
+        "bt2020-10" => TransferCharacteristic::BT2020Ten,
+        "bt2020-12" => TransferCharacteristic::BT2020Twelve,
+        "pq" | "smpte2084" => TransferCharacteristic::PerceptualQuantizer,
+        "hlg" | "arib-std-b67" => TransferCharacteristic::HybridLogGamma,
+        _ => TransferCharacteristic::from_i64(value.parse().ok()?)?,
+    };
+    (transfer != TransferCharacteristic::Unspecified).then(|| transfer)
+}
+
+pub fn parse_chroma_location(value: &str) -> Option<ChromaLocation> {
+    let location = match value {
+        "left" => ChromaLocation::Left,
+        "center" => ChromaLocation::Center,
+        "topleft" => ChromaLocation::TopLeft,
+        "top" => ChromaLocation::Top,
+        "bottomleft" => ChromaLocation::BottomLeft,
+        "bottom" => ChromaLocation::Bottom,
+        _ => chroma_location(value.parse().ok()?),
+    };
+    (location != ChromaLocation::Unspecified).then(|| location)
+}
+
+/// The chroma location for a `_ChromaLocation` frame prop
+fn chroma_location(value: i64) -> ChromaLocation {
+    match value {
+        0 => ChromaLocation::Left,
+        1 => ChromaLocation::Center,
+        2 => ChromaLocation::TopLeft,
+        3 => ChromaLocation::Top,
+        4 => ChromaLocation::BottomLeft,
+        5 => ChromaLocation::Bottom,
+        _ => ChromaLocation::Unspecified,
     }
 }
 
 pub fn get_video_colorimetry(input: &Path) -> Result<Colorimetry> {
-    let env = Environment::from_file(input, EvalFlags::SetWorkingDir).map_err(|e| match e {
-        vapoursynth::vsscript::Error::VSScript(e) => {
-            anyhow!("An error occurred in VSScript: {}", e)
-        }
-        _ => anyhow!("{}", e),
-    })?;
-    let (node, _) = env.get_output(0)?;
+    static COLORIMETRY: OnceCell<ProbeCache<Colorimetry>> = OnceCell::new();
+    COLORIMETRY
+        .get_or_init(Default::default)
+        .get_or_try_insert_with(input, || read_colorimetry(input))
+}
+
+fn read_colorimetry(input: &Path) -> Result<Colorimetry> {
+    let env = open_script(input)?;
+    let (node, _) = env.get_output(script_output(input))?;
     let frame = node.get_frame(0)?;
     let props = frame.props();
     Ok(Colorimetry {
@@ -305,28 +751,185 @@ pub fn get_video_colorimetry(input: &Path) -> Result<Colorimetry> {
             .map_or(TransferCharacteristic::Unspecified, |val| {
                 TransferCharacteristic::from_i64(val).unwrap_or(TransferCharacteristic::Unspecified)
             }),
-        chroma_location: match props.get_int("_ChromaLocation") {
-            Ok(0) => ChromaLocation::Left,
-            Ok(1) => ChromaLocation::Center,
-            Ok(2) => ChromaLocation::TopLeft,
-            Ok(3) => ChromaLocation::Top,
-            Ok(4) => ChromaLocation::BottomLeft,
-            Ok(5) => ChromaLocation::Bottom,
-            _ => ChromaLocation::Unspecified,
-        },
+        chroma_location: props
+            .get_int("_ChromaLocation")
+            .map_or(ChromaLocation::Unspecified, chroma_location),
+        force_hdr: None,
     })
 }
 
+/// Returns how far the audio track numbered `track` is delayed from the video
 pub fn get_audio_delay_ms(input: &Path, track: usize) -> Result<i32> {
-    let command = Command::new("mediainfo")
-        .arg("--Output=Audio;%Delay%,")
+    probe(
+        input,
+        |input| mediainfo_audio_delay_ms(input, track),
+        |input| ffprobe_audio_delay_ms(input, track),
+    )
+}
+
+/// A subtitle track of a video, as reported by ffprobe
+#[derive(Debug, Clone)]
+pub struct SubtitleTrack {
+    /// The index of the track among all of the video's tracks,
+    /// which is what mkvextract refers to it by
+    pub stream_index: u32,
+    /// ffprobe's name for the track's codec, such as `ass` or `hdmv_pgs_subtitle`
+    pub codec: String,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub forced: bool,
+}
+
+/// Lists the subtitle tracks of a video, in the order `0:s:N` refers to them
+pub fn get_subtitle_tracks(input: &Path) -> Result<Vec<SubtitleTrack>> {
+    let output = Command::new("ffprobe")
+        .arg("-v")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("s")
+        .arg("-show_entries")
+        .arg("stream=index,codec_name:stream_tags=language,title:stream_disposition=forced")
+        .arg("-of")
+        .arg("json")
         .arg(input)
-        .output()?;
-    let output = String::from_utf8_lossy(&command.stdout);
-    Ok(output
-        .split(',')
-        .filter(|p| !p.trim().is_empty())
-        .nth(track)
-        .unwrap_or_else(|| panic!("Expected {} tracks, did not find enough", track + 1))
-        .parse::<i32>()?)
+        .output()
+        .map_err(|e| anyhow!("Failed to run ffprobe on {}: {}", input.display(), e))?;
+    if !output.status.success() {
+        bail!(
+            "ffprobe failed on {}: {}",
+            input.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let probe: Value = serde_json::from_slice(&output.stdout)?;
+    let streams = probe["streams"].as_array().cloned().unwrap_or_default();
+    Ok(streams
+        .iter()
+        .map(|stream| {
+            let tag = |name: &str| {
+                stream["tags"][name]
+                    .as_str()
+                    .filter(|value| !value.is_empty())
+                    .map(ToString::to_string)
+            };
+            SubtitleTrack {
+                stream_index: stream["index"].as_u64().unwrap_or_default() as u32,
+                codec: stream["codec_name"]
+                    .as_str()
+                    .unwrap_or("unknown")
+                    .to_string(),
+                language: tag("language").filter(|language| language != "und"),
+                title: tag("title"),
+                forced: stream["disposition"]["forced"].as_i64() == Some(1),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::python::python_path;
+
+    #[test]
+    fn sources_with_hostile_names() {
+        for name in [
+            "it's here.mkv",
+            r#"the "best" episode.mkv"#,
+            "[Group] Show (2020) - 01 [1080p].mkv",
+            "commas, and (parens).mkv",
+        ] {
+            let script = format!(
+                "import vapoursynth as vs\nclip = vs.core.lsmas.LWLibavSource(source={}, cache=0)\n",
+                python_path(Path::new(name))
+            );
+            assert_eq!(
+                parse_sources(&script),
+                vec![PathBuf::from(name)],
+                "{}",
+                script
+            );
+        }
+    }
+
+    #[test]
+    fn sources_from_other_source_filters() {
+        let script = r#"
+src = r"C:\videos\[BD] Show (01).m2ts"  # the disc rip
+index = 'Show 02.dgi'
+a = core.bs.VideoSource(src)
+b = core.ffms2.Source(cache=False, source="Show 03.mkv")
+c = core.dgdecodenv.DGSource(index)
+d = core.bs.VideoSource(source=os.path.join(root, "ignored.mkv"))
+"#;
+        assert_eq!(
+            parse_sources(script),
+            vec![
+                PathBuf::from(r"C:\videos\[BD] Show (01).m2ts"),
+                PathBuf::from("Show 03.mkv"),
+                PathBuf::from("Show 02.dgi"),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sources_with_non_utf8_names() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let path = Path::new(OsStr::from_bytes(b"/media/caf\xe9.mkv"));
+        let script = format!(
+            "mp4batch_source = {}\nclip = core.lsmas.LWLibavSource(source=mp4batch_source)\n",
+            python_path(path)
+        );
+        assert_eq!(parse_sources(&script), vec![path.to_path_buf()]);
+    }
+
+    #[test]
+    fn colorimetry_names_and_code_points() {
+        assert_eq!(parse_color_primaries("bt709"), Some(ColorPrimaries::BT709));
+        assert_eq!(parse_color_primaries("9"), Some(ColorPrimaries::BT2020));
+        assert_eq!(parse_color_primaries("2"), None);
+        assert_eq!(
+            parse_matrix_coefficients("6"),
+            parse_matrix_coefficients("smpte170m")
+        );
+        assert_eq!(
+            parse_transfer_characteristic("pq"),
+            Some(TransferCharacteristic::PerceptualQuantizer)
+        );
+        assert_eq!(parse_color_range("pc"), Some(YUVRange::Full));
+        assert_eq!(parse_chroma_location("2"), Some(ChromaLocation::TopLeft));
+        assert_eq!(parse_chroma_location("bt709"), None);
+    }
+
+    #[test]
+    fn colorimetry_fallbacks_by_resolution() {
+        let primaries = |width, height, hdr| fallback_colorimetry(width, height, hdr).0;
+        assert_eq!(primaries(720, 480, false), ColorPrimaries::ST170M);
+        assert_eq!(primaries(720, 576, false), ColorPrimaries::BT470BG);
+        assert_eq!(primaries(1280, 720, false), ColorPrimaries::BT709);
+        assert_eq!(primaries(1920, 800, false), ColorPrimaries::BT709);
+        assert_eq!(primaries(1920, 1080, true), ColorPrimaries::BT2020);
+        assert_eq!(primaries(3840, 2160, false), ColorPrimaries::BT2020);
+    }
+
+    #[test]
+    fn source_directives() {
+        let script = r#"
+# mp4batch: source=part1.mkv
+#mp4batch: audio = "Part 2.mkv"
+  # mp4batch: chapters=r'C:\discs\chapters.mkv'
+# mp4batch: video=ignored.mkv
+a = core.lsmas.LWLibavSource("part1.mkv")
+"#;
+        let directives = parse_source_directives(script);
+        assert_eq!(directives.len(), 3);
+        assert_eq!(directives["source"], PathBuf::from("part1.mkv"));
+        assert_eq!(directives["audio"], PathBuf::from("Part 2.mkv"));
+        assert_eq!(
+            directives["chapters"],
+            PathBuf::from(r"C:\discs\chapters.mkv")
+        );
+    }
 }
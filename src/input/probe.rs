@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use once_cell::sync::OnceCell;
+use tracing::debug;
+use which::which;
+
+/// The tool media files are probed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProbeBackend {
+    /// mediainfo if it is installed, with ffprobe for anything mediainfo can't read
+    Auto,
+    Mediainfo,
+    Ffprobe,
+}
+
+static BACKEND: OnceCell<ProbeBackend> = OnceCell::new();
+
+/// Probes media files with `backend` instead of choosing automatically
+pub fn set_probe_backend(backend: ProbeBackend) {
+    BACKEND
+        .set(backend)
+        .expect("Probe backend should only be set once");
+}
+
+/// The backend media files are probed with, resolving `Auto` to
+/// ffprobe when mediainfo isn't installed
+pub fn probe_backend() -> ProbeBackend {
+    static MEDIAINFO_INSTALLED: OnceCell<bool> = OnceCell::new();
+    match BACKEND.get().copied().unwrap_or(ProbeBackend::Auto) {
+        ProbeBackend::Auto if !*MEDIAINFO_INSTALLED.get_or_init(|| which("mediainfo").is_ok()) => {
+            ProbeBackend::Ffprobe
+        }
+        backend => backend,
+    }
+}
+
+/// Probes `input` with the chosen backend.
+///
+/// When choosing automatically, mediainfo is tried first, and ffprobe is used
+/// if mediainfo's answer can't be parsed, as with raw streams or PCM delays.
+pub fn probe<T>(
+    input: &Path,
+    mediainfo: impl FnOnce(&Path) -> Result<T>,
+    ffprobe: impl FnOnce(&Path) -> Result<T>,
+) -> Result<T> {
+    match probe_backend() {
+        ProbeBackend::Mediainfo => mediainfo(input),
+        ProbeBackend::Ffprobe => ffprobe(input),
+        ProbeBackend::Auto => mediainfo(input).or_else(|e| {
+            debug!(
+                "mediainfo was unable to probe {}, using ffprobe instead: {}",
+                input.display(),
+                e
+            );
+            ffprobe(input)
+        }),
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    fmt::Write as _,
+    fs, iter,
+    path::{Path, PathBuf},
+    sync::mpsc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use once_cell::sync::OnceCell;
+use tracing::info;
+use vapoursynth::video_info::Property;
+
+use super::{
+    find_all_source_files, open_script, script_output, source_directive, wobbly_project, ProbeCache,
+};
+use crate::{tool_log::is_dry_run, work_dir::work_path};
+
+/// Where the timecodes the script is muxed with are saved, as a v2 timecodes file
+pub fn timecodes_path(script: &Path) -> PathBuf {
+    work_path(script).with_extension("timecodes.v2.txt")
+}
+
+/// A timecodes file named by a `# mp4batch: timecodes=path` comment in the script,
+/// or the `.timecodes.txt` or `.tc.txt` beside the script with the same name
+pub fn find_timecodes_file(input: &Path) -> Option<PathBuf> {
+    source_directive(input, "timecodes").or_else(|| {
+        ["timecodes.txt", "tc.txt"]
+            .iter()
+            .map(|ext| input.with_extension(ext))
+            .find(|path| path.is_file())
+    })
+}
+
+/// The start of every frame in milliseconds of a video with `frames` frames,
+/// from a v1 or v2 timecodes file
+pub fn read_timecodes_file(path: &Path, frames: u32) -> Result<Vec<f64>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+    parse_timecodes(&contents, frames)
+        .map_err(|e| anyhow!("Invalid timecodes {}: {}", path.display(), e))
+}
+
+/// The start of every frame in milliseconds, for a script with a timecodes file
+/// or a variable frame rate.
+///
+/// A timecodes file beside the script is used first, then its Wobbly project,
+/// and otherwise the `_DurationNum` and `_DurationDen` of its frames. Every frame
+/// has to be rendered to read its duration, so they are reused until the script
+/// or its sources change. Scripts with a constant frame rate have none.
+pub fn script_timecodes(script: &Path) -> Result<Option<Vec<f64>>> {
+    static TIMECODES: OnceCell<ProbeCache<Option<Vec<f64>>>> = OnceCell::new();
+    TIMECODES
+        .get_or_init(Default::default)
+        .get_or_try_insert_with(script, || {
+            let env = open_script(script)?;
+            let (node, _) = env.get_output(script_output(script))?;
+            let info = node.info();
+            let path = timecodes_path(script);
+            if let Some(file) = find_timecodes_file(script) {
+                let timecodes = read_timecodes_file(&file, info.num_frames as u32)?;
+                if !is_dry_run() {
+                    fs::write(&path, format_timecodes_v2(&timecodes))?;
+                }
+                return Ok(Some(timecodes));
+            }
+            if let Property::Constant(_) = info.framerate {
+                return Ok(None);
+            }
+
+            // A Wobbly project already says how long each frame is
+            if let Some(project) = wobbly_project(script)? {
+                if project.timecodes.len() != info.num_frames {
+                    bail!(
+                        "{} outputs {} frames, but its Wobbly project has {}",
+                        script.display(),
+                        info.num_frames,
+                        project.timecodes.len()
+                    );
+                }
+                if !is_dry_run() {
+                    fs::write(&path, format_timecodes_v2(&project.timecodes))?;
+                }
+                return Ok(Some(project.timecodes));
+            }
+            if !is_timecodes_stale(script, &path) {
+                if let Ok(timecodes) = fs::read_to_string(&path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|contents| parse_timecodes_v2(&contents))
+                {
+                    if timecodes.len() == info.num_frames {
+                        return Ok(Some(timecodes));
+                    }
+                }
+            }
+
+            info!(
+                "{} has a variable frame rate, reading its frame durations",
+                script.display()
+            );
+            let frames = info.num_frames as u32;
+            // Frames are rendered in parallel, as many at a time as the core has threads
+            let parallel = env.get_core()?.info().num_threads.max(1) as u32;
+            let (sender, receiver) = mpsc::channel::<(u32, Result<(i64, i64), String>)>();
+            let mut durations = vec![None; frames as usize];
+            let mut requested = 0;
+            let mut outstanding = 0;
+            let mut result = Ok(());
+            for n in 0..frames {
+                while requested < frames && requested < n + parallel {
+                    let sender = sender.clone();
+                    node.get_frame_async(requested as usize, move |frame, n, _| {
+                        let duration = frame.map_err(|e| e.to_string()).and_then(|frame| {
+                            let props = frame.props();
+                            props
+                                .get_int("_DurationNum")
+                                .and_then(|num| Ok((num, props.get_int("_DurationDen")?)))
+                                .map_err(|_| "it has no duration".to_string())
+                        });
+                        let _ = sender.send((n as u32, duration));
+                    });
+                    requested += 1;
+                    outstanding += 1;
+                }
+                while durations[n as usize].is_none() {
+                    let (i, duration) = receiver.recv()?;
+                    outstanding -= 1;
+                    durations[i as usize] = Some(duration);
+                }
+                if let Some(Err(ref e)) = durations[n as usize] {
+                    result = Err(anyhow!(
+                        "Failed to read the duration of frame {} of {}: {}",
+                        n,
+                        script.display(),
+                        e
+                    ));
+                    break;
+                }
+            }
+            // Frames still being rendered belong to the script, so they must finish before it is freed
+            for _ in 0..outstanding {
+                let _ = receiver.recv();
+            }
+            result?;
+
+            let mut timecodes = Vec::with_capacity(frames as usize);
+            let mut start = 0.0;
+            for (n, duration) in durations.into_iter().enumerate() {
+                timecodes.push(start);
+                let (num, den) = duration.and_then(Result::ok).expect("Every frame was read");
+                if num <= 0 || den <= 0 {
+                    bail!(
+                        "Frame {} of {} has an invalid duration of {}/{}",
+                        n,
+                        script.display(),
+                        num,
+                        den
+                    );
+                }
+                start += num as f64 * 1000.0 / den as f64;
+            }
+            if !is_dry_run() {
+                fs::write(&path, format_timecodes_v2(&timecodes))?;
+            }
+            Ok(Some(timecodes))
+        })
+}
+
+/// The average frame rate of `timecodes`, which variable frame rate videos are encoded at
+pub fn average_frame_rate(timecodes: &[f64]) -> Option<(u32, u32)> {
+    let span = timecodes.last()? - timecodes.first()?;
+    if span <= 0.0 {
+        return None;
+    }
+    let fps = (timecodes.len() - 1) as f64 * 1000.0 / span;
+    Some(((fps * 1000.0).round() as u32, 1000))
+}
+
+/// Checks whether the script or any of its sources
+/// have been modified since the timecodes were saved
+fn is_timecodes_stale(script: &Path, timecodes: &Path) -> bool {
+    let saved = match timecodes.metadata().and_then(|meta| meta.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return true,
+    };
+    iter::once(script.to_path_buf())
+        .chain(find_all_source_files(script))
+        .filter_map(|path| path.metadata().and_then(|meta| meta.modified()).ok())
+        .any(|modified| modified > saved)
+}
+
+fn format_timecodes_v2(timecodes: &[f64]) -> String {
+    let mut contents = String::from("# timecode format v2\n");
+    for timecode in timecodes {
+        let _ = writeln!(contents, "{:.6}", timecode);
+    }
+    contents
+}
+
+/// Reads the timestamps of `frames` frames from a v1 or v2 timecodes file,
+/// which must increase from each frame to the next
+fn parse_timecodes(contents: &str, frames: u32) -> Result<Vec<f64>> {
+    let mut timecodes = match contents.lines().next().map(str::trim) {
+        Some("# timecode format v1") => parse_timecodes_v1(contents, frames)?,
+        Some("# timecode format v2") => parse_timecodes_v2(contents)?,
+        _ => bail!("Unrecognized timecode format"),
+    };
+    // The last timestamp may be where the final frame ends
+    if timecodes.len() == frames as usize + 1 {
+        timecodes.pop();
+    }
+    if timecodes.len() != frames as usize {
+        bail!("Found {} timestamps for {} frames", timecodes.len(), frames);
+    }
+    if let Some(n) = timecodes.windows(2).position(|pair| pair[1] <= pair[0]) {
+        bail!("Frame {} does not start after frame {}", n + 1, n);
+    }
+    Ok(timecodes)
+}
+
+/// Converts a v1 timecodes file, which gives the frame rate of ranges of frames,
+/// into the start of each of `frames` frames
+fn parse_timecodes_v1(contents: &str, frames: u32) -> Result<Vec<f64>> {
+    let mut lines = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    let rate = |value: &str| match value.trim().parse::<f64>() {
+        Ok(rate) if rate > 0.0 => Ok(rate),
+        _ => Err(anyhow!("Invalid frame rate in timecodes: {}", value)),
+    };
+    let assumed = lines
+        .next()
+        .and_then(|line| {
+            line.get(..7)
+                .filter(|prefix| prefix.eq_ignore_ascii_case("assume "))
+                .map(|_| &line[7..])
+        })
+        .ok_or_else(|| anyhow!("Missing the assumed frame rate"))?;
+    let mut rates = vec![rate(assumed)?; frames as usize];
+    for line in lines {
+        let range = match line.split(',').collect::<Vec<_>>().as_slice() {
+            &[start, end, fps] => start
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .zip(end.trim().parse::<usize>().ok())
+                .map(|(start, end)| (start, end, fps)),
+            _ => None,
+        };
+        let (start, end, fps) =
+            range.ok_or_else(|| anyhow!("Invalid range in timecodes: {}", line))?;
+        if start > end || end >= rates.len() {
+            bail!(
+                "Range {}-{} is outside of the {} frames",
+                start,
+                end,
+                frames
+            );
+        }
+        rates[start..=end].fill(rate(fps)?);
+    }
+
+    let mut start = 0.0;
+    Ok(rates
+        .into_iter()
+        .map(|rate| {
+            let timecode = start;
+            start += 1000.0 / rate;
+            timecode
+        })
+        .collect())
+}
+
+/// Reads the frame timestamps from a v2 timecodes file
+pub fn parse_timecodes_v2(contents: &str) -> Result<Vec<f64>> {
+    let mut lines = contents.lines().map(str::trim);
+    if lines.next() != Some("# timecode format v2") {
+        bail!("Not a v2 timecodes file");
+    }
+    lines
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse()
+                .map_err(|_| anyhow!("Invalid timestamp in timecodes: {}", line))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timecodes_v2_round_trip() {
+        let timecodes = vec![0.0, 41.708333, 83.416667, 116.783333];
+        let parsed = parse_timecodes_v2(&format_timecodes_v2(&timecodes)).unwrap();
+        assert_eq!(parsed, timecodes);
+        assert!(parse_timecodes_v2("0\n41.7\n").is_err());
+    }
+
+    #[test]
+    fn timecodes_v1_conversion() {
+        let timecodes =
+            parse_timecodes("# timecode format v1\nAssume 25\n# a comment\n2,3,50\n", 5).unwrap();
+        assert_eq!(timecodes, vec![0.0, 40.0, 80.0, 100.0, 120.0]);
+        assert!(parse_timecodes("# timecode format v1\nAssume 25\n2,5,50\n", 5).is_err());
+        assert!(parse_timecodes("# timecode format v1\n2,3,50\n", 5).is_err());
+    }
+
+    #[test]
+    fn timecodes_validation() {
+        let v2 = "# timecode format v2\n0\n40\n80\n";
+        assert_eq!(parse_timecodes(v2, 3).unwrap().len(), 3);
+        // The end of the last frame may be included
+        assert_eq!(parse_timecodes(v2, 2).unwrap().len(), 2);
+        assert!(parse_timecodes(v2, 4).is_err());
+        assert!(parse_timecodes("# timecode format v2\n0\n40\n40\n", 3).is_err());
+        assert!(parse_timecodes("0\n40\n", 2).is_err());
+    }
+
+    #[test]
+    fn average_frame_rates() {
+        let timecodes = (0..25).map(|n| n as f64 * 40.0).collect::<Vec<_>>();
+        assert_eq!(average_frame_rate(&timecodes), Some((25000, 1000)));
+        assert_eq!(average_frame_rate(&[0.0]), None);
+    }
+}
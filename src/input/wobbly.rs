@@ -0,0 +1,170 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::OnceCell;
+use serde_json::Value;
+
+use super::{source_directive, ProbeCache};
+
+/// Frames are decimated from cycles of this many
+const CYCLE: u32 = 5;
+
+/// What a script's Wobbly project says about the frames of its output
+#[derive(Debug, Clone, PartialEq)]
+pub struct WobblyProject {
+    /// The start of every output frame in milliseconds, after decimation
+    pub timecodes: Vec<f64>,
+    /// The output frames which start a section
+    pub keyframes: Vec<u32>,
+}
+
+/// The Wobbly project named by a `# mp4batch: wobbly=path` comment in the script,
+/// or the one beside the script with the same name.
+///
+/// Any other JSON file beside the script is ignored, rather than read as a project.
+pub fn find_wobbly_project(input: &Path) -> Option<PathBuf> {
+    source_directive(input, "wobbly").or_else(|| {
+        let project = input.with_extension("json");
+        is_wobbly_project(&project).then(|| project)
+    })
+}
+
+fn is_wobbly_project(path: &Path) -> bool {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str::<Value>(&contents).ok())
+        .map_or(false, |project| project.get("wobbly version").is_some())
+}
+
+/// Reads the script's Wobbly project, if it has one
+pub fn wobbly_project(input: &Path) -> Result<Option<WobblyProject>> {
+    static PROJECTS: OnceCell<ProbeCache<WobblyProject>> = OnceCell::new();
+    let path = match find_wobbly_project(input) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    PROJECTS
+        .get_or_init(Default::default)
+        .get_or_try_insert_with(&path, || {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+            parse_wobbly_project(&contents)
+                .map_err(|e| anyhow!("Invalid Wobbly project {}: {}", path.display(), e))
+        })
+        .map(Some)
+}
+
+fn parse_wobbly_project(contents: &str) -> Result<WobblyProject> {
+    let project: Value = serde_json::from_str(contents)?;
+    if project.get("wobbly version").is_none() {
+        return Err(anyhow!("Not a Wobbly project"));
+    }
+    let frame = |value: &Value| {
+        value
+            .as_u64()
+            .map(|frame| frame as u32)
+            .ok_or_else(|| anyhow!("Expected a frame number, found {}", value))
+    };
+
+    let rate = project["input frame rate"]
+        .as_array()
+        .filter(|rate| rate.len() == 2)
+        .ok_or_else(|| anyhow!("Missing the input frame rate"))?;
+    let (num, den) = (frame(&rate[0])?, frame(&rate[1])?);
+    if num == 0 || den == 0 {
+        return Err(anyhow!("Invalid input frame rate {}/{}", num, den));
+    }
+    let frames = project["trim"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Missing the trims"))?
+        .iter()
+        .map(|trim| {
+            let (first, last) = match trim.as_array().map(Vec::as_slice) {
+                Some([first, last]) => (frame(first)?, frame(last)?),
+                _ => return Err(anyhow!("Expected a trim, found {}", trim)),
+            };
+            Ok(last.saturating_sub(first) + 1)
+        })
+        .sum::<Result<u32>>()?;
+
+    // Frames may be listed on their own, or grouped by cycle
+    let mut decimated = Vec::new();
+    for value in project["decimated frames"].as_array().into_iter().flatten() {
+        match value.as_array() {
+            Some(cycle) => {
+                for value in cycle {
+                    decimated.push(frame(value)?);
+                }
+            }
+            None => decimated.push(frame(value)?),
+        }
+    }
+    decimated.sort_unstable();
+    decimated.dedup();
+    let decimated_before = |frame: u32| decimated.partition_point(|&n| n < frame) as u32;
+
+    let input_duration = den as f64 * 1000.0 / num as f64;
+    let mut timecodes = Vec::new();
+    let mut start = 0.0;
+    for cycle_start in (0..frames).step_by(CYCLE as usize) {
+        let cycle_end = (cycle_start + CYCLE).min(frames);
+        let kept = (cycle_end - cycle_start)
+            - (decimated_before(cycle_end) - decimated_before(cycle_start));
+        if kept == 0 {
+            continue;
+        }
+        // The frames left in a cycle are stretched to fill it
+        let duration = (cycle_end - cycle_start) as f64 * input_duration / kept as f64;
+        for _ in 0..kept {
+            timecodes.push(start);
+            start += duration;
+        }
+    }
+
+    let mut keyframes = Vec::new();
+    for section in project["sections"].as_array().into_iter().flatten() {
+        let start = frame(&section["start"])?;
+        let keyframe = start - decimated_before(start);
+        if keyframe > 0 && start < frames {
+            keyframes.push(keyframe);
+        }
+    }
+    keyframes.sort_unstable();
+    keyframes.dedup();
+
+    Ok(WobblyProject {
+        timecodes,
+        keyframes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wobbly_projects() {
+        let project = parse_wobbly_project(
+            r#"{
+                "wobbly version": 6,
+                "input frame rate": [30000, 1001],
+                "trim": [[0, 9], [20, 24]],
+                "decimated frames": [3, 5, 6, 7, 8, 9],
+                "sections": [{"start": 0}, {"start": 2}, {"start": 10}]
+            }"#,
+        )
+        .unwrap();
+        let frame = 1001.0 / 30.0;
+        let expected = [0.0, 1.25, 2.5, 3.75, 5.0, 6.0, 7.0, 8.0, 9.0];
+        assert_eq!(project.timecodes.len(), expected.len());
+        for (timecode, expected) in project.timecodes.iter().zip(expected.iter()) {
+            assert!((timecode - expected * frame).abs() < 1e-6);
+        }
+        assert_eq!(project.keyframes, vec![2, 4]);
+
+        assert!(parse_wobbly_project(r#"{"input frame rate": [24, 1]}"#).is_err());
+    }
+}
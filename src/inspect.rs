@@ -0,0 +1,201 @@
+use std::{fmt::Write as _, path::Path, process::Command, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+use tracing::debug;
+
+use crate::{input::find_source_file, notify::format_duration};
+
+/// The kinds of track listed, with mkvmerge's and mediainfo's names for them
+const TRACK_TYPES: &[(&str, &str, &str)] = &[
+    ("video", "video", "Video"),
+    ("audio", "audio", "Audio"),
+    ("subtitle", "subtitles", "Text"),
+];
+
+/// Prints a table of the tracks of a video, or of the source of a script.
+///
+/// Tracks are numbered within their type, the same way `at=` and `st=` number them.
+pub fn print_tracks(input: &Path) -> Result<()> {
+    let source = if input.extension().map_or(false, |ext| ext == "vpy") {
+        find_source_file(input)
+    } else {
+        input.to_path_buf()
+    };
+    let mediainfo = mediainfo_tracks(&source)?;
+    // mkvmerge has more reliable flags, but doesn't read every container
+    let mkvmerge = mkvmerge_tracks(&source).unwrap_or_else(|e| {
+        debug!(
+            "Unable to identify {} with mkvmerge: {}",
+            source.display(),
+            e
+        );
+        Vec::new()
+    });
+    print!("{}", track_table(&source, &mediainfo, &mkvmerge));
+    Ok(())
+}
+
+fn mediainfo_tracks(source: &Path) -> Result<Vec<Value>> {
+    let output = Command::new("mediainfo")
+        .arg("--Output=JSON")
+        .arg(source)
+        .output()?;
+    if !output.status.success() {
+        bail!("mediainfo failed on {}", source.display());
+    }
+    let info: Value = serde_json::from_slice(&output.stdout)?;
+    info["media"]["track"]
+        .as_array()
+        .cloned()
+        .ok_or_else(|| anyhow!("mediainfo found no tracks in {}", source.display()))
+}
+
+fn mkvmerge_tracks(source: &Path) -> Result<Vec<Value>> {
+    let output = Command::new("mkvmerge").arg("-J").arg(source).output()?;
+    let info: Value = serde_json::from_slice(&output.stdout)?;
+    if info["container"]["recognized"].as_bool() != Some(true) {
+        bail!("Unrecognized container");
+    }
+    Ok(info["tracks"].as_array().cloned().unwrap_or_default())
+}
+
+fn track_table(source: &Path, mediainfo: &[Value], mkvmerge: &[Value]) -> String {
+    let general = mediainfo
+        .iter()
+        .find(|track| track["@type"] == "General")
+        .cloned()
+        .unwrap_or(Value::Null);
+    let mut table = format!(
+        "{} ({}{})\n",
+        source.display(),
+        string(&general, "Format").unwrap_or("unknown format"),
+        seconds(&general, "Duration").map_or_else(String::new, |duration| format!(
+            ", {}",
+            format_duration(Duration::from_secs_f64(duration))
+        ))
+    );
+    let _ = writeln!(
+        table,
+        "{:<8}  {:>2}  {:<10}  {:<5}  {:<44}  {:>8}  {:<14}  NAME",
+        "TYPE", "#", "CODEC", "LANG", "DETAILS", "DELAY", "FLAGS"
+    );
+    for &(name, mkvmerge_type, mediainfo_type) in TRACK_TYPES {
+        let info = mediainfo
+            .iter()
+            .filter(|track| track["@type"] == mediainfo_type)
+            .collect::<Vec<_>>();
+        let mkv = mkvmerge
+            .iter()
+            .filter(|track| track["type"] == mkvmerge_type)
+            .collect::<Vec<_>>();
+        for i in 0..info.len().max(mkv.len()) {
+            let info = info.get(i).copied().unwrap_or(&Value::Null);
+            let mkv = mkv.get(i).copied().unwrap_or(&Value::Null);
+            let properties = &mkv["properties"];
+            let codec = string(info, "Format")
+                .or_else(|| mkv["codec"].as_str())
+                .unwrap_or("unknown");
+            let language = properties["language"]
+                .as_str()
+                .or_else(|| string(info, "Language"))
+                .filter(|language| *language != "und")
+                .unwrap_or("");
+            let details = match name {
+                "video" => video_details(info),
+                "audio" => audio_details(info, properties),
+                _ => String::new(),
+            };
+            // Video is the reference the others are delayed from
+            let delay = if name == "video" {
+                None
+            } else {
+                seconds(info, "Video_Delay")
+            };
+            let _ = writeln!(
+                table,
+                "{:<8}  {:>2}  {:<10}  {:<5}  {:<44}  {:>8}  {:<14}  {}",
+                name,
+                i,
+                codec,
+                language,
+                details,
+                delay.map_or_else(String::new, |delay| format!(
+                    "{}ms",
+                    (delay * 1000.0).round()
+                )),
+                flags(info, properties),
+                properties["track_name"]
+                    .as_str()
+                    .or_else(|| string(info, "Title"))
+                    .unwrap_or("")
+            );
+        }
+    }
+    table
+}
+
+/// Resolution, bit depth, frame rate and colorimetry, including any HDR metadata
+fn video_details(info: &Value) -> String {
+    let mut details = format!(
+        "{}x{}",
+        string(info, "Width").unwrap_or("?"),
+        string(info, "Height").unwrap_or("?")
+    );
+    if let Some(bit_depth) = string(info, "BitDepth") {
+        let _ = write!(details, " {}-bit", bit_depth);
+    }
+    if let Some(fps) = string(info, "FrameRate") {
+        let _ = write!(details, " {}fps", fps);
+    }
+    if let Some(transfer) = string(info, "transfer_characteristics") {
+        let _ = write!(details, " {}", transfer);
+    }
+    if let Some(hdr) = string(info, "HDR_Format") {
+        let _ = write!(details, ", {}", hdr);
+    }
+    if let Some(max_cll) = string(info, "MaxCLL") {
+        let _ = write!(details, ", MaxCLL {}", max_cll);
+    }
+    details
+}
+
+fn audio_details(info: &Value, properties: &Value) -> String {
+    let channels = string(info, "Channels")
+        .map(ToString::to_string)
+        .or_else(|| properties["audio_channels"].as_u64().map(|c| c.to_string()));
+    let mut details = channels.map_or_else(String::new, |channels| format!("{}ch", channels));
+    if let Some(rate) = string(info, "SamplingRate") {
+        let _ = write!(details, " {}Hz", rate);
+    }
+    if let Some(bit_depth) = string(info, "BitDepth") {
+        let _ = write!(details, " {}-bit", bit_depth);
+    }
+    details.trim().to_string()
+}
+
+fn flags(info: &Value, properties: &Value) -> String {
+    let flag = |mkvmerge: &str, mediainfo: &str| {
+        properties[mkvmerge]
+            .as_bool()
+            .unwrap_or_else(|| string(info, mediainfo) == Some("Yes"))
+    };
+    let mut flags = Vec::new();
+    if flag("default_track", "Default") {
+        flags.push("default");
+    }
+    if flag("forced_track", "Forced") {
+        flags.push("forced");
+    }
+    flags.join(",")
+}
+
+/// mediainfo writes every value as a string
+fn string<'a>(track: &'a Value, key: &str) -> Option<&'a str> {
+    track[key].as_str().filter(|value| !value.is_empty())
+}
+
+/// mediainfo writes durations in seconds
+fn seconds(track: &Value, key: &str) -> Option<f64> {
+    string(track, key).and_then(|value| value.parse().ok())
+}
@@ -0,0 +1,774 @@
+//! The processing behind the `mp4batch` binary.
+//!
+//! Inputs are processed by a [`pipeline::Pipeline`] of stages, which can be
+//! replaced or extended with custom [`pipeline::Stage`]s, starting from
+//! [`pipeline::default_stages`]. Stage and progress events can be handled
+//! as they happen with [`events::add_listener`], or on another thread
+//! with [`events::subscribe`]. A job started with [`pipeline::Pipeline::run`]
+//! can be stopped, along with every tool it spawned, through its
+//! [`cancel::CancelToken`].
+
+use std::{
+    env,
+    fmt::Write as FmtWrite,
+    fs::{read_to_string, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use path_clean::PathClean;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cli::{expand_alternatives, parse_filters, ParsedFilter},
+    input::*,
+    output::*,
+    python::python_path,
+    work_dir::work_path,
+};
+
+pub mod cancel;
+pub mod checksum;
+pub mod cli;
+pub mod concat;
+pub mod config;
+pub mod console;
+pub mod error;
+pub mod events;
+pub mod fonts;
+pub mod history;
+pub mod input;
+pub mod inspect;
+pub mod lock;
+pub mod logging;
+pub mod metrics;
+pub mod name_filter;
+pub mod naming;
+pub mod notify;
+pub mod order;
+pub mod output;
+pub mod pause;
+pub mod pipeline;
+pub mod progress;
+pub mod python;
+pub mod queue;
+pub mod server;
+pub mod settings;
+pub mod state;
+pub mod subs;
+pub mod summary;
+pub mod tool_log;
+pub mod tools;
+pub mod upload;
+pub mod validate;
+pub mod watch;
+pub mod work_dir;
+
+/// The outputs described by `formats` for `input`, exiting with the reason
+/// if any format is invalid
+pub fn parse_outputs(formats: Option<&str>, input: &Path) -> Vec<Output> {
+    try_parse_outputs(formats, input).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// The outputs described by `formats` for `input`, or why a format is invalid
+pub fn try_parse_outputs(formats: Option<&str>, input: &Path) -> Result<Vec<Output>> {
+    let formats = match formats {
+        Some(formats) => formats,
+        None => return Ok(vec![Output::default()]),
+    };
+    let formats = formats.trim();
+    if formats.is_empty() {
+        return Ok(vec![Output::default()]);
+    }
+    let outputs = formats
+        .split(';')
+        .flat_map(expand_alternatives)
+        .map(|format| {
+            let mut output = Output::default();
+            let filters = parse_filters(&format, input)?;
+            if let Some(encoder) = filters.iter().find_map(|filter| {
+                if let ParsedFilter::VideoEncoder(encoder) = filter {
+                    Some(encoder)
+                } else {
+                    None
+                }
+            }) {
+                match encoder.to_lowercase().as_str() {
+                    "x264" => {
+                        // This is the default, do nothing
+                    }
+                    "x265" => {
+                        output.video.encoder = VideoEncoder::X265 {
+                            crf: 18,
+                            profile: Profile::Film,
+                            compat: Compat::None,
+                        }
+                    }
+                    "aom" => {
+                        output.video.encoder = VideoEncoder::Aom {
+                            crf: 16,
+                            speed: 4,
+                            profile: Profile::Film,
+                            grain: 0,
+                            compat: Compat::None,
+                        }
+                    }
+                    "rav1e" => {
+                        output.video.encoder = VideoEncoder::Rav1e {
+                            crf: 40,
+                            speed: 5,
+                            profile: Profile::Film,
+                            grain: 0,
+                        }
+                    }
+                    "svt" => {
+                        output.video.encoder = VideoEncoder::SvtAv1 {
+                            crf: 16,
+                            speed: 4,
+                            profile: Profile::Film,
+                            grain: 0,
+                            direct: false,
+                        }
+                    }
+                    "copy" => {
+                        output.video.encoder = VideoEncoder::Copy;
+                    }
+                    enc => bail!("Unrecognized encoder: {}", enc),
+                }
+            }
+            for filter in &filters {
+                apply_filter(filter, &mut output)?;
+            }
+            if let VideoEncoder::X264 { compat, .. } | VideoEncoder::X265 { compat, .. } =
+                output.video.encoder
+            {
+                compat.check_output(&output);
+            }
+            Ok(output)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // The lossless is shared by every output, so they must all read the same output
+    let script_output = outputs[0].video.script_output;
+    if outputs
+        .iter()
+        .any(|output| output.video.script_output != script_output)
+    {
+        bail!("Every output of a script must use the same `vout=`");
+    }
+    if script_output != 0 {
+        set_script_output(input, script_output);
+    }
+    Ok(outputs)
+}
+
+/// Whether this is a script to process, rather than one we generated for an output
+pub fn is_input_script(path: &Path) -> bool {
+    if path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_string())
+        != Some("vpy".to_string())
+    {
+        return false;
+    }
+    let filestem = path
+        .file_stem()
+        .expect("File should have a name")
+        .to_string_lossy();
+    !(filestem.contains(".aom-q")
+        || filestem.contains(".rav1e-q")
+        || filestem.contains(".svt-q")
+        || filestem.contains(".x264-q")
+        || filestem.contains(".x265-q")
+        || filestem.ends_with(".copy"))
+}
+
+pub fn absolute_path(path: impl AsRef<Path>) -> io::Result<PathBuf> {
+    let path = path.as_ref();
+
+    let absolute_path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir()?.join(path)
+    }
+    .clean();
+
+    Ok(absolute_path)
+}
+
+fn apply_filter(filter: &ParsedFilter, output: &mut Output) -> Result<()> {
+    match filter {
+        ParsedFilter::VideoEncoder(_) => (),
+        ParsedFilter::Quantizer(arg) => {
+            let arg = *arg;
+            let range = match output.video.encoder {
+                VideoEncoder::X264 { ref mut crf, .. } => {
+                    *crf = arg;
+                    (-12, 51)
+                }
+                VideoEncoder::X265 { ref mut crf, .. } => {
+                    *crf = arg;
+                    (0, 51)
+                }
+                VideoEncoder::Aom { ref mut crf, .. }
+                | VideoEncoder::SvtAv1 { ref mut crf, .. } => {
+                    *crf = arg;
+                    (0, 63)
+                }
+                VideoEncoder::Rav1e { ref mut crf, .. } => {
+                    *crf = arg;
+                    (0, 255)
+                }
+                VideoEncoder::Copy => {
+                    return Ok(());
+                }
+            };
+            if arg < range.0 || arg > range.1 {
+                bail!(
+                    "'q' must be between {} and {}, received {}",
+                    range.0,
+                    range.1,
+                    arg
+                );
+            }
+        }
+        ParsedFilter::Speed(arg) => match output.video.encoder {
+            VideoEncoder::Aom { ref mut speed, .. }
+            | VideoEncoder::Rav1e { ref mut speed, .. }
+            | VideoEncoder::SvtAv1 { ref mut speed, .. } => {
+                let arg = *arg;
+                if arg > 10 {
+                    bail!("'s' must be between 0 and 10, received {}", arg);
+                }
+                *speed = arg;
+            }
+            _ => (),
+        },
+        ParsedFilter::Profile(arg) => match output.video.encoder {
+            VideoEncoder::X264 {
+                ref mut profile, ..
+            }
+            | VideoEncoder::X265 {
+                ref mut profile, ..
+            }
+            | VideoEncoder::Aom {
+                ref mut profile, ..
+            }
+            | VideoEncoder::Rav1e {
+                ref mut profile, ..
+            }
+            | VideoEncoder::SvtAv1 {
+                ref mut profile, ..
+            } => {
+                *profile = *arg;
+            }
+            VideoEncoder::Copy => (),
+        },
+        ParsedFilter::Grain(arg) => match output.video.encoder {
+            VideoEncoder::Aom { ref mut grain, .. }
+            | VideoEncoder::Rav1e { ref mut grain, .. }
+            | VideoEncoder::SvtAv1 { ref mut grain, .. } => {
+                let arg = *arg;
+                if arg > 64 {
+                    bail!("'grain' must be between 0 and 64, received {}", arg);
+                }
+                *grain = arg;
+            }
+            _ => (),
+        },
+        ParsedFilter::Compat(arg) => {
+            if *arg == Compat::Bluray && !matches!(output.video.encoder, VideoEncoder::X264 { .. })
+            {
+                bail!("'compat=bluray' is only supported by x264");
+            }
+            match output.video.encoder {
+                VideoEncoder::X264 { ref mut compat, .. }
+                | VideoEncoder::X265 { ref mut compat, .. }
+                | VideoEncoder::Aom { ref mut compat, .. } => {
+                    *compat = *arg;
+                }
+                _ => (),
+            }
+        }
+        ParsedFilter::Direct(arg) => {
+            if let VideoEncoder::SvtAv1 { ref mut direct, .. } = output.video.encoder {
+                *direct = *arg;
+            }
+        }
+        ParsedFilter::Extension(arg) => {
+            output.video.output_ext = (*arg).to_string();
+        }
+        ParsedFilter::TargetQuality(arg) => {
+            let arg = *arg;
+            if !(0.0..=100.0).contains(&arg) {
+                bail!("'tq' must be between 0 and 100, received {}", arg);
+            }
+            output.video.target_quality = Some(arg);
+        }
+        ParsedFilter::Probes(arg) => {
+            let arg = *arg;
+            if arg == 0 {
+                bail!("'probes' must be greater than 0, got {}", arg);
+            }
+            output.video.probes = Some(arg);
+        }
+        ParsedFilter::ProbingRate(arg) => {
+            let arg = *arg;
+            if arg == 0 || arg > 4 {
+                bail!("'probing-rate' must be between 1 and 4, received {}", arg);
+            }
+            output.video.probing_rate = Some(arg);
+        }
+        ParsedFilter::ExtraArgs(arg) => {
+            output.video.extra_args = Some((*arg).to_string());
+        }
+        ParsedFilter::Av1anArgs(arg) => {
+            output.video.av1an_args = Some((*arg).to_string());
+        }
+        ParsedFilter::Tiles { cols, rows } => {
+            output.video.tiles = Some((*cols, *rows));
+        }
+        ParsedFilter::GrainTable(path) => match output.video.encoder {
+            VideoEncoder::Aom { .. } | VideoEncoder::SvtAv1 { .. } => {
+                output.video.grain_table = Some(path.clone());
+            }
+            _ => bail!("'graintable' is only supported by aom and svt"),
+        },
+        ParsedFilter::X264Zones(zones) => match output.video.encoder {
+            VideoEncoder::X264 { .. } => {
+                output.video.x264_zones = Some(zones.clone());
+            }
+            _ => bail!("'x264zones' is only supported by x264"),
+        },
+        ParsedFilter::ForceKeyframes(frames) => {
+            output.video.force_keyframes = Some(frames.clone());
+        }
+        ParsedFilter::ScriptOutput(index) => {
+            output.video.script_output = *index;
+        }
+        ParsedFilter::Sar(sar) => match output.video.encoder {
+            VideoEncoder::Copy => bail!("'sar' is not supported when copying the video"),
+            _ => {
+                output.video.sar = Some(*sar);
+            }
+        },
+        ParsedFilter::AcBias(arg) => {
+            let arg = *arg;
+            if arg > 8.0 {
+                bail!("'ac-bias' must be between 0 and 8, received {}", arg);
+            }
+            svt_tuning(output, "ac-bias")?.ac_bias = Some(arg);
+        }
+        ParsedFilter::VarianceBoostStrength(arg) => {
+            let arg = *arg;
+            if !(1..=4).contains(&arg) {
+                bail!("'vb-strength' must be between 1 and 4, received {}", arg);
+            }
+            svt_tuning(output, "vb-strength")?.variance_boost_strength = Some(arg);
+        }
+        ParsedFilter::VarianceOctile(arg) => {
+            let arg = *arg;
+            if !(1..=8).contains(&arg) {
+                bail!("'vb-octile' must be between 1 and 8, received {}", arg);
+            }
+            svt_tuning(output, "vb-octile")?.variance_octile = Some(arg);
+        }
+        ParsedFilter::Sharpness(arg) => {
+            let arg = *arg;
+            if !(-7..=7).contains(&arg) {
+                bail!("'sharpness' must be between -7 and 7, received {}", arg);
+            }
+            svt_tuning(output, "sharpness")?.sharpness = Some(arg);
+        }
+        ParsedFilter::QmMax(arg) => {
+            let arg = *arg;
+            if arg > 15 {
+                bail!("'qm-max' must be between 0 and 15, received {}", arg);
+            }
+            svt_tuning(output, "qm-max")?.qm_max = Some(arg);
+        }
+        ParsedFilter::PsyRd(arg) => {
+            let arg = *arg;
+            if arg > 5.0 {
+                bail!("'psy' must be between 0 and 5, received {}", arg);
+            }
+            profile_overrides(output, "psy")?.psy_rd = Some(arg);
+        }
+        ParsedFilter::AqStrength(arg) => {
+            let arg = *arg;
+            if arg > 3.0 {
+                bail!("'aq' must be between 0 and 3, received {}", arg);
+            }
+            profile_overrides(output, "aq")?.aq_strength = Some(arg);
+        }
+        ParsedFilter::Qcomp(arg) => {
+            let arg = *arg;
+            if arg > 1.0 {
+                bail!("'qcomp' must be between 0 and 1, received {}", arg);
+            }
+            profile_overrides(output, "qcomp")?.qcomp = Some(arg);
+        }
+        ParsedFilter::Bframes(arg) => {
+            let arg = *arg;
+            if arg > 16 {
+                bail!("'bframes' must be between 0 and 16, received {}", arg);
+            }
+            profile_overrides(output, "bframes")?.bframes = Some(arg);
+        }
+        ParsedFilter::PostGrainSynth => match output.video.encoder {
+            VideoEncoder::Aom { .. } | VideoEncoder::Rav1e { .. } | VideoEncoder::SvtAv1 { .. } => {
+                output.video.post_grain_synth = true;
+            }
+            _ => bail!("'grainsynth' is only supported by aom, rav1e and svt"),
+        },
+        ParsedFilter::ChunkMethod(arg) => {
+            output.video.chunk_method = Some((*arg).to_string());
+        }
+        ParsedFilter::BitDepth(arg) => {
+            output.video.bit_depth = Some(*arg);
+        }
+        ParsedFilter::Resolution { width, height } => {
+            output.video.resolution = Some((*width, *height));
+        }
+        ParsedFilter::Crop {
+            left,
+            right,
+            top,
+            bottom,
+        } => {
+            output.video.crop = Some((*left, *right, *top, *bottom));
+        }
+        ParsedFilter::ColorRange(arg) => {
+            output.video.colorimetry.range = Some(*arg);
+        }
+        ParsedFilter::Primaries(arg) => {
+            output.video.colorimetry.primaries = Some(*arg);
+        }
+        ParsedFilter::Matrix(arg) => {
+            output.video.colorimetry.matrix = Some(*arg);
+        }
+        ParsedFilter::Transfer(arg) => {
+            output.video.colorimetry.transfer = Some(*arg);
+        }
+        ParsedFilter::ChromaLocation(arg) => {
+            output.video.colorimetry.chroma_location = Some(*arg);
+        }
+        ParsedFilter::Hdr(arg) => {
+            output.video.colorimetry.hdr = Some(*arg);
+        }
+        ParsedFilter::AudioEncoder(arg) => {
+            output.audio.encoder = match arg.to_lowercase().as_str() {
+                "copy" => AudioEncoder::Copy,
+                "flac" => AudioEncoder::Flac,
+                "aac" => AudioEncoder::Aac,
+                "opus" => AudioEncoder::Opus,
+                arg => bail!("Invalid value provided for 'aenc': {}", arg),
+            }
+        }
+        ParsedFilter::AudioBitrate(arg) => {
+            let arg = *arg;
+            if arg == 0 {
+                bail!("'ab' must be greater than 0, got {}", arg);
+            }
+            output.audio.kbps_per_channel = arg;
+        }
+        ParsedFilter::AudioTracks(args) => {
+            output.audio_tracks.clone_from(args);
+        }
+        ParsedFilter::AudioNormalize => {
+            output.audio.normalize = true;
+        }
+        ParsedFilter::SubtitleTracks(args) => {
+            output.sub_tracks.clone_from(args);
+        }
+        ParsedFilter::Invalid(reason) => bail!("{}", reason),
+    }
+    Ok(())
+}
+
+/// The SVT-AV1 tuning of the output, which must be using SVT-AV1 to set `filter`
+fn svt_tuning<'a>(output: &'a mut Output, filter: &str) -> Result<&'a mut SvtTuning> {
+    if !matches!(output.video.encoder, VideoEncoder::SvtAv1 { .. }) {
+        bail!("'{}' is only supported by svt", filter);
+    }
+    Ok(&mut output.video.svt_tuning)
+}
+
+/// The profile overrides of the output, which must be using x264 or x265 to set `filter`
+fn profile_overrides<'a>(output: &'a mut Output, filter: &str) -> Result<&'a mut ProfileOverrides> {
+    if !matches!(
+        output.video.encoder,
+        VideoEncoder::X264 { .. } | VideoEncoder::X265 { .. }
+    ) {
+        bail!("'{}' is only supported by x264 and x265", filter);
+    }
+    Ok(&mut output.video.overrides)
+}
+
+pub fn build_video_suffix(output: &Output) -> Result<String> {
+    let mut codec_str = match output.video.encoder {
+        VideoEncoder::Aom {
+            crf,
+            speed,
+            profile,
+            grain,
+            compat,
+        } => format!(
+            "aom-q{}-s{}-{}-g{}{}",
+            crf,
+            speed,
+            profile,
+            grain,
+            compat.suffix()
+        ),
+        VideoEncoder::Rav1e {
+            crf,
+            speed,
+            profile,
+            grain,
+        } => format!("rav1e-q{}-s{}-{}-g{}", crf, speed, profile, grain),
+        VideoEncoder::SvtAv1 {
+            crf,
+            speed,
+            profile,
+            grain,
+            direct,
+        } => format!(
+            "svt-q{}-s{}-{}-g{}{}",
+            crf,
+            speed,
+            profile,
+            grain,
+            if direct { "-direct" } else { "" }
+        ),
+        VideoEncoder::X264 {
+            crf,
+            profile,
+            compat,
+        } => format!("x264-q{}-{}{}", crf, profile, compat.suffix()),
+        VideoEncoder::X265 {
+            crf,
+            profile,
+            compat,
+        } => format!("x265-q{}-{}{}", crf, profile, compat.suffix()),
+        VideoEncoder::Copy => "copy".to_string(),
+    };
+    if let Some((left, right, top, bottom)) = output.video.crop {
+        write!(codec_str, "-crop{}-{}-{}-{}", left, right, top, bottom)?;
+    }
+    if let Some(res) = output.video.resolution {
+        write!(codec_str, "-{}x{}", res.0, res.1)?;
+    }
+    if let Some(bd) = output.video.bit_depth {
+        write!(codec_str, "-{}b", bd)?;
+    }
+    if let Some(target_quality) = output.video.target_quality {
+        write!(codec_str, "-tq{}", target_quality)?;
+    }
+    if let Some((cols, rows)) = output.video.tiles {
+        write!(codec_str, "-t{}x{}", cols, rows)?;
+    }
+    if let Some((num, den)) = output.video.sar {
+        write!(codec_str, "-sar{}x{}", num, den)?;
+    }
+    if output.video.post_grain_synth {
+        codec_str.push_str("-gspost");
+    }
+    let tuning = output.video.svt_tuning;
+    if let Some(ac_bias) = tuning.ac_bias {
+        write!(codec_str, "-ab{}", ac_bias)?;
+    }
+    if let Some(strength) = tuning.variance_boost_strength {
+        write!(codec_str, "-vbs{}", strength)?;
+    }
+    if let Some(octile) = tuning.variance_octile {
+        write!(codec_str, "-vbo{}", octile)?;
+    }
+    if let Some(sharpness) = tuning.sharpness {
+        write!(codec_str, "-sh{}", sharpness)?;
+    }
+    if let Some(qm_max) = tuning.qm_max {
+        write!(codec_str, "-qm{}", qm_max)?;
+    }
+    let overrides = output.video.overrides;
+    if let Some(psy_rd) = overrides.psy_rd {
+        write!(codec_str, "-psy{}", psy_rd)?;
+    }
+    if let Some(aq_strength) = overrides.aq_strength {
+        write!(codec_str, "-aq{}", aq_strength)?;
+    }
+    if let Some(qcomp) = overrides.qcomp {
+        write!(codec_str, "-qc{}", qcomp)?;
+    }
+    if let Some(bframes) = overrides.bframes {
+        write!(codec_str, "-bf{}", bframes)?;
+    }
+    if let Some(ref grain_table) = output.video.grain_table {
+        write!(
+            codec_str,
+            "-gt{:08x}",
+            short_hash(&grain_table.to_string_lossy())
+        )?;
+    }
+    if let Some(ref zones) = output.video.x264_zones {
+        write!(codec_str, "-z{:08x}", short_hash(zones))?;
+    }
+    if output.video.script_output != 0 {
+        write!(codec_str, "-vo{}", output.video.script_output)?;
+    }
+    if let Some(ref frames) = output.video.force_keyframes {
+        write!(codec_str, "-kf{:08x}", short_hash(frames))?;
+    }
+    if !output.video.colorimetry.is_empty() {
+        write!(
+            codec_str,
+            "-cm{:08x}",
+            short_hash(&format!("{:?}", output.video.colorimetry))
+        )?;
+    }
+    // Raw arguments don't make for a sensible filename,
+    // but different arguments need to produce different outputs.
+    if let Some(ref extra_args) = output.video.extra_args {
+        write!(codec_str, "-x{:08x}", short_hash(extra_args))?;
+    }
+    if let Some(ref av1an_args) = output.video.av1an_args {
+        write!(codec_str, "-a{:08x}", short_hash(av1an_args))?;
+    }
+    Ok(codec_str)
+}
+
+/// A short hash of `input` for filenames, which stays the same between
+/// versions of Rust so that existing outputs and intermediates are still found
+pub fn short_hash(input: &str) -> u32 {
+    let digest = Sha256::digest(input.as_bytes());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+pub fn build_vpy_script(filename: &Path, input: &Path, output: &Output, skip_lossless: bool) {
+    let mut script = BufWriter::new(File::create(filename).expect("Unable to write script file"));
+    if skip_lossless {
+        copy_and_modify_vpy_script(input, output, &mut script);
+    } else {
+        build_new_vpy_script(input, output, &mut script);
+    }
+}
+
+/// Writes a copy of `script` which only outputs the given ranges of frames, joined together
+pub fn build_sample_script(script: &Path, filename: &Path, ranges: &[(u32, u32)]) {
+    let contents = read_to_string(script).expect("Unable to read output script");
+    let line = contents
+        .lines()
+        .find(|line| line.contains(".set_output()") || line.contains(".set_output(0)"))
+        .expect("Output script does not have an output clip");
+    let pos = contents.find(line).expect("Line is in the script");
+    let (clip, _) = line
+        .split_once(".set_output(")
+        .expect("Line has an output clip");
+    let indent = &clip[..clip.len() - clip.trim_start().len()];
+    let sample = ranges
+        .iter()
+        .map(|(first, last)| {
+            format!(
+                "({}).std.Trim(first={}, last={})",
+                clip.trim_start(),
+                first,
+                last
+            )
+        })
+        .join(" + ");
+    let mut probe = BufWriter::new(File::create(filename).expect("Unable to write script file"));
+    write!(probe, "{}", &contents[..pos]).unwrap();
+    writeln!(probe, "{}({}).set_output()", indent, sample).unwrap();
+    write!(probe, "{}", &contents[pos + line.len()..]).unwrap();
+    probe.flush().expect("Unable to flush script data");
+}
+
+fn build_new_vpy_script(input: &Path, output: &Output, script: &mut BufWriter<File>) {
+    writeln!(script, "import vapoursynth as vs").unwrap();
+    writeln!(script, "core = vs.core").unwrap();
+    writeln!(script, "core.max_cache_size=1024").unwrap();
+    writeln!(
+        script,
+        "clip = core.lsmas.LWLibavSource(source={})",
+        python_path(
+            &absolute_path(work_path(input).with_extension("lossless.mkv"))
+                .expect("Should be able to get absolute filepath")
+        )
+    )
+    .unwrap();
+
+    write_filters(output, script, None);
+
+    writeln!(script, "clip.set_output()").unwrap();
+    script.flush().expect("Unable to flush script data");
+}
+
+fn copy_and_modify_vpy_script(input: &Path, output: &Output, script: &mut BufWriter<File>) {
+    let contents = read_to_string(input).expect("Unable to read input script");
+    let index = output.video.script_output;
+    let set_output = format!(".set_output({})", index);
+    let mut output_pos = None;
+    let mut output_var = None;
+    for line in contents.lines() {
+        if let Some(pos) = line
+            .find(&set_output)
+            .or_else(|| line.find(".set_output()").filter(|_| index == 0))
+        {
+            assert!(pos > 0);
+            output_pos = Some(
+                contents
+                    .find(line)
+                    .expect("Input script does not have an output clip"),
+            );
+            output_var = Some(&line[0..pos]);
+            break;
+        }
+    }
+    match (output_pos, output_var) {
+        (Some(pos), Some(var)) => {
+            write!(script, "{}", &contents[..pos]).unwrap();
+            write_filters(output, script, Some(var));
+            writeln!(script).unwrap();
+            write!(script, "{}", &contents[pos..]).unwrap();
+            if index != 0 {
+                // The encoders read output 0
+                writeln!(script).unwrap();
+                writeln!(script, "{}.set_output(0)", var.trim()).unwrap();
+            }
+            script.flush().expect("Unable to flush contents of script");
+        }
+        _ => {
+            panic!(
+                "Invalid input vapoursynth script, no `set_output({})` found",
+                index
+            );
+        }
+    }
+}
+
+fn write_filters(output: &Output, script: &mut BufWriter<File>, clip: Option<&str>) {
+    let clip = clip.unwrap_or("clip");
+
+    if let Some((left, right, top, bottom)) = output.video.crop {
+        writeln!(
+            script,
+            "{clip} = {clip}.std.Crop(left={left}, right={right}, top={top}, bottom={bottom})"
+        )
+        .unwrap();
+    }
+    // We downscale resolution first because it's more likely that
+    // we would be going from 10 bit to 8 bit, rather than the other way.
+    // So this gives the best quality.
+    if let Some((w, h)) = output.video.resolution {
+        writeln!(
+            script,
+            "{clip} = {clip}.resize.Spline36({w}, {h}, dither_type='error_diffusion')"
+        )
+        .unwrap();
+    }
+    if let Some(bd) = output.video.bit_depth {
+        writeln!(script, "import vsutil").unwrap();
+        writeln!(script, "{clip} = vsutil.depth({clip}, {bd})").unwrap();
+    }
+}
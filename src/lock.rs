@@ -0,0 +1,145 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use tracing::info;
+
+const LOCK_FILENAME: &str = ".mp4batch.lock";
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a lock without a readable PID is assumed to be held,
+/// such as one left half-written by a crash
+const LOCK_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Prevents two runs from working in the same directory at once,
+/// since they would race on the same intermediate files.
+///
+/// The lock is released when this is dropped.
+pub struct DirectoryLock {
+    path: PathBuf,
+}
+
+impl DirectoryLock {
+    /// Takes the lock for `dir`, replacing it if the process that held it has exited.
+    ///
+    /// If another run holds the lock, either waits for it to finish or fails,
+    /// depending on `wait`.
+    pub fn acquire(dir: &Path, wait: bool) -> Result<Self> {
+        let path = dir.join(LOCK_FILENAME);
+        let mut warned = false;
+        loop {
+            if try_create_lock(&path)? {
+                return Ok(DirectoryLock { path });
+            }
+
+            let owner = fs::read_to_string(&path)
+                .ok()
+                .and_then(|contents| contents.trim().parse::<u32>().ok());
+            match owner {
+                Some(pid) if is_process_running(pid) => {
+                    if !wait {
+                        bail!(
+                            "Another run (PID {}) is already processing this directory. Remove \
+                             {} if this is not the case.",
+                            pid,
+                            path.display()
+                        );
+                    }
+                    if !warned {
+                        info!(
+                            "Another run (PID {}) is processing this directory, waiting for it \
+                             to finish",
+                            pid
+                        );
+                        warned = true;
+                    }
+                    thread::sleep(LOCK_POLL_INTERVAL);
+                }
+                Some(_) => {
+                    // The previous run died without cleaning up after itself
+                    let _ = fs::remove_file(&path);
+                }
+                None => {
+                    let age = fs::metadata(&path)
+                        .and_then(|meta| meta.modified())
+                        .ok()
+                        .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+                    match age {
+                        Some(age) if age < LOCK_GRACE_PERIOD => {
+                            if !wait {
+                                bail!(
+                                    "Another run may be processing this directory. Remove {} if \
+                                     this is not the case.",
+                                    path.display()
+                                );
+                            }
+                            thread::sleep(LOCK_GRACE_PERIOD - age);
+                        }
+                        // Gone since we looked for it, so try again straight away
+                        None if !path.exists() => (),
+                        _ => {
+                            let _ = fs::remove_file(&path);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Creates the lock at `path` holding our PID, returning false if it already exists.
+///
+/// The PID is written to a file of our own first, then linked into place,
+/// so the lock is never seen without it.
+fn try_create_lock(path: &Path) -> Result<bool> {
+    let temp = path.with_extension(format!("lock.{}", process::id()));
+    fs::write(&temp, process::id().to_string())?;
+    let result = fs::hard_link(&temp, path);
+    let _ = fs::remove_file(&temp);
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl Drop for DirectoryLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn is_process_running(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) => pid,
+        Err(_) => return false,
+    };
+    // SAFETY: Signal 0 only checks whether the process exists
+    let result = unsafe { libc::kill(pid, 0) };
+    // EPERM means the process exists but belongs to someone else
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn is_process_running(pid: u32) -> bool {
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, STILL_ACTIVE},
+        System::Threading::{GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION},
+    };
+
+    // SAFETY: The handle is checked before use and closed afterwards
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            return false;
+        }
+        let mut exit_code = 0;
+        let result = GetExitCodeProcess(handle, &mut exit_code);
+        CloseHandle(handle);
+        result != 0 && exit_code == STILL_ACTIVE as u32
+    }
+}
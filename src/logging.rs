@@ -0,0 +1,233 @@
+use std::{
+    cell::RefCell,
+    env,
+    fmt::{self, Debug, Write as _},
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use ansi_term::Colour::{Blue, Green, Purple, Red, White, Yellow};
+use anyhow::Result;
+use clap::ValueEnum;
+use console::Term;
+use serde_json::json;
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    filter::LevelFilter,
+    fmt::{
+        format::Writer,
+        time::{FormatTime, SystemTime},
+        FmtContext, FormatEvent, FormatFields,
+    },
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    Layer,
+};
+
+use crate::{
+    events::{emit, has_listeners},
+    progress::suspend_progress,
+};
+
+/// When to color the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color when writing to a terminal, unless `NO_COLOR` is set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                env::var_os("NO_COLOR").map_or(true, |value| value.is_empty())
+                    && Term::stderr().is_term()
+            }
+        }
+    }
+}
+
+/// Sets up logging to stderr, at a level raised by each `verbose`
+/// and lowered by each `quiet`, and optionally to `log_file`.
+///
+/// The log file always gets at least debug messages,
+/// so that it has the whole story when something goes wrong.
+/// It is never colored.
+pub fn init_logging(
+    verbose: u8,
+    quiet: u8,
+    color: ColorChoice,
+    log_file: Option<&Path>,
+) -> Result<()> {
+    let color = color.enabled();
+    // Progress bars are colored separately
+    console::set_colors_enabled_stderr(color);
+    let level = match verbose as i16 - quiet as i16 {
+        i16::MIN..=-2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        2..=i16::MAX => LevelFilter::TRACE,
+    };
+    let terminal = tracing_subscriber::fmt::layer()
+        .event_format(Prefixed { timestamps: false })
+        .with_writer(|| ProgressWriter)
+        .with_ansi(color)
+        .with_filter(level);
+    let file = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .event_format(Prefixed { timestamps: true })
+                    .with_writer(Arc::new(file))
+                    .with_ansi(false)
+                    .with_filter(std::cmp::max(level, LevelFilter::DEBUG)),
+            )
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(terminal)
+        .with(file)
+        .with(WarningEvents)
+        .try_init()?;
+    Ok(())
+}
+
+/// Formats messages as `[Level] message`, the way mp4batch always has
+struct Prefixed {
+    timestamps: bool,
+}
+
+impl<S, N> FormatEvent<S, N> for Prefixed
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let (prefix, colour) = match *event.metadata().level() {
+            Level::ERROR => ("[Error]", Red),
+            Level::WARN => ("[Warning]", Yellow),
+            Level::INFO if fields.success => ("[Success]", Green),
+            Level::INFO => ("[Info]", Blue),
+            Level::DEBUG => ("[Debug]", Purple),
+            Level::TRACE => ("[Trace]", White),
+        };
+        if self.timestamps {
+            SystemTime.format_time(&mut writer)?;
+            write!(writer, " ")?;
+        }
+        if writer.has_ansi_escapes() {
+            writeln!(
+                writer,
+                "{} {}",
+                colour.bold().paint(prefix),
+                colour.paint(fields.message)
+            )
+        } else {
+            writeln!(writer, "{} {}", prefix, fields.message)
+        }
+    }
+}
+
+/// The message of an event, with any other fields appended to it.
+///
+/// `success = true` marks an info message as reporting something finished.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    success: bool,
+}
+
+impl Visit for Fields {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == "success" {
+            self.success = value;
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.message, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Warnings logged while processing an input, by its thread and any it starts
+pub type Warnings = Arc<Mutex<Vec<String>>>;
+
+thread_local! {
+    static WARNINGS: RefCell<Warnings> = RefCell::default();
+}
+
+/// Returns the warnings logged by this thread since the last call,
+/// so they can be included in the summary for an input
+pub fn take_warnings() -> Vec<String> {
+    WARNINGS.with(|warnings| std::mem::take(&mut *warnings.borrow().lock().unwrap()))
+}
+
+/// Shares where warnings are kept with another thread,
+/// so warnings from stages run in the background are summarized with the input
+pub fn set_current_warnings(warnings: Warnings) {
+    WARNINGS.with(|current| *current.borrow_mut() = warnings);
+}
+
+pub fn current_warnings() -> Warnings {
+    WARNINGS.with(|current| Arc::clone(&current.borrow()))
+}
+
+/// Keeps warnings for the batch summary, and reports them as events to any listeners
+struct WarningEvents;
+
+impl<S: Subscriber> Layer<S> for WarningEvents {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::WARN {
+            return;
+        }
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        if has_listeners() {
+            emit("warning", json!({ "message": fields.message }));
+        }
+        WARNINGS.with(|warnings| warnings.borrow().lock().unwrap().push(fields.message));
+    }
+}
+
+/// Writes to stderr without tearing any progress bars being drawn
+struct ProgressWriter;
+
+impl Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        suspend_progress(|| io::stderr().write(buf))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        suspend_progress(|| io::stderr().write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
@@ -1,43 +1,96 @@
 use std::{
+    collections::VecDeque,
     env,
-    fmt::Write as FmtWrite,
-    fs,
-    fs::{read_to_string, File},
-    io::{self, BufWriter, Write},
+    fs::{self, read_to_string},
+    io, panic,
     path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
-use ansi_term::Colour::{Blue, Green, Red};
-use anyhow::{anyhow, bail, Result};
-use clap::Parser;
-use dotenvy_macro::dotenv;
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
 use itertools::Itertools;
 use lexical_sort::natural_lexical_cmp;
-use path_clean::PathClean;
-use size::Size;
+use tracing::{debug, error, info, warn};
 use walkdir::WalkDir;
 use which::which;
 
-use crate::cli::{parse_filters, ParsedFilter, Track, TrackSource};
-
-use self::{input::*, output::*};
-
-mod cli;
-mod input;
-mod output;
+use mp4batch::{
+    absolute_path,
+    checksum::verify_checksums,
+    concat::{concat_outputs, concat_segments},
+    config::print_config,
+    console::handle_console_events,
+    error::{exit_code, handle_interrupt_signals, is_interrupted, Error},
+    events::enable_events,
+    history::{print_history, unix_time},
+    input::*,
+    inspect::print_tracks,
+    is_input_script,
+    lock::DirectoryLock,
+    logging::{init_logging, take_warnings, ColorChoice},
+    metrics::{print_comparison, Metric},
+    name_filter::{NameFilter, NamePattern},
+    notify::{FileReport, Notifier},
+    order::{order_inputs, InputOrder},
+    output::*,
+    parse_outputs,
+    pause::handle_pause_signals,
+    pipeline::{estimate_stages, matrix_stages, Pipeline, ProcessOptions},
+    python::python_path,
+    queue::JobQueue,
+    server::start_server,
+    settings::load_settings,
+    state::{BatchState, DoneList, InputStatus},
+    subs::extract_all_subtitles,
+    summary::{BatchSummary, ReportFormat},
+    tool_log::set_dry_run,
+    tools::{check_tool_versions, print_doctor},
+    upload::UploadDestination,
+    validate::{validate_batch, validate_job},
+    watch::watch_directory,
+    work_dir::{create_work_dir, set_work_dir, work_path},
+};
 
 #[derive(Parser, Debug)]
+#[clap(subcommand_negates_reqs = true)]
 struct InputArgs {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     /// Sets the input directory or file
-    pub input: String,
+    #[clap(required = true)]
+    pub input: Option<String>,
 
     /// Override the default output directory
     #[clap(short, long, value_name = "DIR")]
     pub output: Option<String>,
 
+    /// Create the lossless, encoded streams and other intermediates in this
+    /// directory, such as a fast scratch drive, instead of next to each
+    /// script.
+    ///
+    /// Generated scripts and tool logs stay next to the input scripts.
+    #[clap(long, value_name = "DIR")]
+    pub work_dir: Option<PathBuf>,
+
+    /// The tool to read frame counts, durations and other properties of
+    /// media files with.
+    ///
+    /// By default, mediainfo is used if it is installed, and ffprobe reads
+    /// anything mediainfo can't, such as raw streams or the delay of PCM audio.
+    #[clap(long, value_enum, default_value = "auto", value_name = "TOOL")]
+    pub probe: ProbeBackend,
+
     /// Takes a list of desired formats to output.
     /// Each filter is comma separated, each output is semicolon separated.
     ///
+    /// Filters may list alternatives separated by pipes, such as
+    /// `enc=x265|svt,q=18|20`, which creates an output for every combination.
+    ///
     ///
     /// Video encoder options:
     ///
@@ -49,13 +102,71 @@ struct InputArgs {
     ///   anime, animedetailed, animegrain, fast]
     /// - grain=#: Grain synth level [aom only] [0-50, 0 = disabled]
     /// - compat=0/1: Enable extra playback compatibility/DXVA options
-    /// - hdr=0/1: Enable HDR encoding features
+    /// - compat=bluray: Use Blu-ray compliant settings, restricted to the
+    ///   resolutions and frame rates allowed on discs [x264 only]
+    /// - compat=dxva/chromecast/webtv: Restrict the level and VBV to what a
+    ///   class of devices can play, warning about any other settings they
+    ///   can't play. dxva is the same as compat=1 [x264/x265 only]
+    /// - direct=0/1: Encode directly with SvtAv1EncApp instead of through
+    ///   av1an, useful for short content [svt only]
+    /// - hdr=0/1: Enable or disable HDR encoding features and copying HDR
+    ///   metadata from the source [default: from the transfer characteristics]
     /// - ext=mkv/mp4: Output file format [default: mkv]
+    /// - tq=#: Use av1an's target quality mode, aiming for this VMAF score.
+    ///   `q` is ignored when this is set. [av1an encodes only]
+    /// - probes=#: Number of target quality probes to run [default: av1an's]
+    /// - probing-rate=#: Framerate divisor to use for target quality probes
+    ///   [default: av1an's]
+    /// - xargs="str": Extra arguments appended to the encoder's arguments,
+    ///   overriding any built-in ones. May not contain semicolons.
+    /// - av1anargs="str": Extra arguments appended to the av1an command line.
+    ///   May not contain semicolons. [av1an encodes only]
+    /// - tiles=#x#: Log2 tile columns and rows, overriding the resolution based
+    ///   default [aom/rav1e/svt only]
+    /// - graintable=path: Film grain table to apply instead of synthesizing
+    ///   noise with `grain`, relative to the input script [aom/svt only]
+    /// - grainsynth=post: After encoding, replace the grain synthesis with
+    ///   grain matched to the source using grav1synth [aom/rav1e/svt only]
+    /// - ac-bias=#: AC bias, from psy forks of SVT-AV1 [svt only]
+    /// - vb-strength=#: Variance boost strength [svt only] [1-4]
+    /// - vb-octile=#: Variance boost octile [svt only] [1-8]
+    /// - sharpness=#: Deblocking sharpness [svt only] [-7-7]
+    /// - qm-max=#: Maximum quantization matrix level [svt only] [0-15,
+    ///   default: 8]
+    /// - x264zones=str: Zones to encode with different settings, either in
+    ///   x264's `start,end,options/...` syntax, quoted since it contains
+    ///   commas, or as the path to a file with one zone per line,
+    ///   e.g. x264zones="0,1000,crf=24/30000,32000,b=1.2" [x264 only]
+    /// - vout=#: Which of the script's video outputs to encode [default: 0].
+    ///   Every output of a script must use the same one.
+    /// - kf=#,#,...: Frames to force keyframes at, replacing
+    ///   `--force-keyframes` for this output
+    /// - sar=#:#: Sample aspect ratio, for video with non-square pixels. Flagged
+    ///   by x264/x265, and as the display size in the container for AV1
+    /// - psy=#: Psy-RD strength, replacing the profile's [x264/x265 only]
+    /// - aq=#: AQ strength, replacing the profile's [x264/x265 only]
+    /// - qcomp=#: Quantizer curve compression, replacing the profile's
+    ///   [x264/x265 only] [0-1]
+    /// - bframes=#: Maximum consecutive B-frames, replacing the profile's
+    ///   [x264/x265 only] [0-16]
+    /// - chunk=str: av1an chunking method [default: av1an's] [options: lsmash,
+    ///   ffms2, bestsource, hybrid, select, segment]
     ///
     /// Video filters (any unset will leave the input unchanged):
     ///
     /// - bd=#: Output bit depth
     /// - res=#x#: Output resolution
+    /// - crop=#:#:#:#: Pixels to crop from the left, right, top and bottom,
+    ///   before resizing. Must be mod 2.
+    ///
+    /// Colorimetry (any unset will use the script's frame props). Values are
+    /// x264's names, e.g. bt709, smpte170m, bt2020nc, or H.273 numbers:
+    ///
+    /// - range=str: limited or full
+    /// - primaries=str: Color primaries
+    /// - matrix=str: Matrix coefficients
+    /// - transfer=str: Transfer characteristics, e.g. bt709, smpte2084
+    /// - chromaloc=#: Chroma sample location [0-5]
     ///
     /// Audio encoder options:
     ///
@@ -64,7 +175,8 @@ struct InputArgs {
     /// - ab=#: Audio bitrate per channel in Kb/sec [default: 96 for aac, 64 for
     ///   opus]
     /// - at=#-[e][f]: Audio tracks, pipe separated [default: 0, e=enabled,
-    ///   f=forced]
+    ///   f=forced]. o# is the script's audio output #, and every audio output
+    ///   of a script is used by default
     /// - an=1: Enable audio normalization. Be SURE you want this. [default: 0]
     ///
     /// Subtitle options:
@@ -74,6 +186,52 @@ struct InputArgs {
     #[clap(short, long, value_name = "FILTERS", verbatim_doc_comment)]
     pub formats: Option<String>,
 
+    /// Also accept mkv, mp4 and m2ts videos as inputs, wrapping each in a
+    /// generated script which loads it with LWLibavSource.
+    ///
+    /// The script is written next to the video, unless a hand-written one is
+    /// already there. Use `crop` and `res` in the formats to filter them.
+    #[clap(long)]
+    pub raw_inputs: bool,
+
+    /// Only process inputs whose filenames match this glob, such as
+    /// `*E0[1-6]*`. May be given more than once.
+    #[clap(long, value_name = "GLOB")]
+    pub include: Vec<NamePattern>,
+
+    /// Skip inputs whose filenames match this glob, such as `*NCOP*`,
+    /// or this regex if prefixed with `re:`. May be given more than once.
+    #[clap(long, value_name = "PATTERN")]
+    pub exclude: Vec<NamePattern>,
+
+    /// How many directories deep to look for inputs, where 1 is only the
+    /// input directory itself [default: unlimited]
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_depth: Option<u32>,
+
+    /// Follow symlinks to files and directories when looking for inputs
+    #[clap(long)]
+    pub follow_symlinks: bool,
+
+    /// The order to process a directory's inputs in
+    #[clap(long, value_enum, default_value = "name")]
+    pub order: InputOrder,
+
+    /// Process the inputs named in this file first, one filename or path per
+    /// line, in the order listed. Any others follow in `--order`.
+    #[clap(long, value_name = "FILE")]
+    pub order_file: Option<PathBuf>,
+
+    /// Skip the first N inputs which still need processing
+    #[clap(long, value_name = "N", default_value = "0")]
+    pub skip: usize,
+
+    /// Stop after queueing N inputs.
+    ///
+    /// Combine with `--resume` on the next run to continue with the rest.
+    #[clap(long, value_name = "N")]
+    pub limit: Option<usize>,
+
     /// Don't delete the lossless intermediate encode
     #[clap(long)]
     pub keep_lossless: bool,
@@ -82,6 +240,159 @@ struct InputArgs {
     #[clap(long)]
     pub lossless_only: bool,
 
+    /// Instead of encoding, encode a few short probes of each output
+    /// and estimate its final size and encode time.
+    ///
+    /// Keeps the lossless, so the real encode can reuse it.
+    #[clap(long, conflicts_with = "lossless_only")]
+    pub estimate: bool,
+
+    /// Instead of encoding, encode a sample of each output and compare their
+    /// size, speed and quality.
+    ///
+    /// Meant for comparing settings, using alternatives in the formats.
+    /// Quality is scored with VMAF unless `--quality-check` says otherwise.
+    /// Keeps the lossless and the samples.
+    #[clap(long, conflicts_with_all = &["lossless_only", "estimate"])]
+    pub matrix: bool,
+
+    /// Print the command line of every tool, the contents of each generated
+    /// script, and each output path, without running or writing anything
+    /// else.
+    ///
+    /// Sources are still probed, and scripts for `--raw-inputs` and each
+    /// output are still written, since the commands read them.
+    #[clap(long, conflicts_with_all = &["estimate", "matrix", "serve"])]
+    pub dry_run: bool,
+
+    /// Print the settings each output resolves to as JSON, including the
+    /// encoder arguments, av1an worker counts and output paths, then exit
+    /// without encoding anything.
+    #[clap(long, conflicts_with_all = &["estimate", "matrix", "serve", "dry_run"])]
+    pub print_config: bool,
+
+    /// Join the outputs of every input, in order, into this mkv, with a
+    /// chapter at the start of each. For movies delivered as several parts.
+    /// Needs a single output format. Parts skipped in this run use the
+    /// output an earlier run wrote, and any part without one fails the join.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["estimate", "matrix", "serve", "lossless_only"]
+    )]
+    pub concat: Option<PathBuf>,
+
+    /// Codec to use for the lossless intermediate.
+    ///
+    /// Falls back to x264 if the chosen codec is unavailable.
+    #[clap(long, value_enum, default_value = "x264")]
+    pub lossless_codec: LosslessCodec,
+
+    /// Compare checksums of a sample of frames between the script and the
+    /// lossless after creating it.
+    ///
+    /// Catches corrupted frames which still produce the right frame count.
+    #[clap(long)]
+    pub verify_lossless: bool,
+
+    /// Create the lossless in segments of this many minutes, which are
+    /// joined afterwards.
+    ///
+    /// Retries after a failure only need to redo the failed segment.
+    #[clap(long, value_name = "MINUTES", value_parser = clap::value_parser!(u32).range(1..))]
+    pub lossless_segments: Option<u32>,
+
+    /// Audio tracks of the source to include in the lossless, as a comma
+    /// separated list of track numbers, or `all`.
+    ///
+    /// Meant for `--lossless-only`. The audio is taken from the source as it
+    /// is, so it won't line up with scripts which trim or splice frames.
+    #[clap(long, value_name = "TRACKS")]
+    pub lossless_audio: Option<LosslessAudioTracks>,
+
+    /// Transcode the audio included in the lossless to FLAC, instead of
+    /// copying it
+    #[clap(long, requires = "lossless_audio")]
+    pub lossless_audio_flac: bool,
+
+    /// Continue a directory batch from where a previous run stopped,
+    /// skipping inputs which it completed.
+    #[clap(long)]
+    pub resume: bool,
+
+    /// Process inputs again even if they are in the directory's
+    /// `.mp4batch-done` list, which records each input finished with the
+    /// same script and formats
+    #[clap(long)]
+    pub force: bool,
+
+    /// If another run is already processing the same directory,
+    /// wait for it to finish instead of exiting.
+    #[clap(long)]
+    pub wait_for_lock: bool,
+
+    /// Number of inputs to process at the same time.
+    ///
+    /// Each av1an encode gets an equal share of the cores and memory.
+    #[clap(short, long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    pub jobs: u32,
+
+    /// URL to POST a JSON report to whenever a file finishes or fails,
+    /// such as a Discord webhook
+    #[clap(long, value_name = "URL", env = "MP4BATCH_NOTIFY_URL")]
+    pub notify_url: Option<String>,
+
+    /// Show a desktop notification when a file fails or the batch finishes
+    #[clap(long)]
+    pub notify_desktop: bool,
+
+    /// Serve the queue status at this address, with endpoints to add and
+    /// cancel jobs.
+    ///
+    /// A bare port listens only on this machine. Any other address must
+    /// be given a --serve-token. Only scripts inside the input directory
+    /// can be added. Keeps running and waiting for jobs after the batch is done.
+    #[clap(long, value_name = "ADDR")]
+    pub serve: Option<String>,
+
+    /// Require requests to the server to send `Authorization: Bearer <TOKEN>`
+    #[clap(long, value_name = "TOKEN", env = "MP4BATCH_SERVE_TOKEN")]
+    pub serve_token: Option<String>,
+
+    /// Write newline-delimited JSON events to stdout as the batch progresses
+    ///
+    /// Events are stage starts and finishes, frames encoded,
+    /// warnings, and outputs written.
+    #[clap(long)]
+    pub progress_json: bool,
+
+    /// Show more detail, pass twice for even more
+    #[clap(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Only show warnings and errors, pass twice for only errors
+    #[clap(short, long, action = clap::ArgAction::Count, conflicts_with = "verbose")]
+    pub quiet: u8,
+
+    /// Also write log messages to this file, including debug messages
+    #[clap(long, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// When to color the output
+    #[clap(long, value_enum, default_value = "auto", value_name = "WHEN")]
+    pub color: ColorChoice,
+
+    /// Also write the summary printed after the batch as a report
+    /// in the output directory
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    pub report: Option<ReportFormat>,
+
+    /// Write a run-report.json to the output directory, with the settings,
+    /// timings, sizes, scores, and checks of every output, and the version
+    /// of every tool, for automation which checks the results
+    #[clap(long)]
+    pub run_report: bool,
+
     /// Do not create a lossless before running av1an.
     ///
     /// Useful for encodes with very little or no filtering.
@@ -89,6 +400,10 @@ struct InputArgs {
     pub skip_lossless: bool,
 
     /// Comma-separated list of forced keyframes.
+    ///
+    /// Without this, a script with a Wobbly project forces keyframes at the start of
+    /// each of its sections. The project is the .json beside the script with the
+    /// same name, or the one a `# mp4batch: wobbly=path` comment names.
     #[clap(long)]
     pub force_keyframes: Option<String>,
 
@@ -103,734 +418,893 @@ struct InputArgs {
     /// Instead of retrying failed encodes, exit immediately
     #[clap(long)]
     pub no_retry: bool,
+
+    /// Mux without the date, and with a segment UID derived from the
+    /// output's name, so muxing the same streams again doesn't leak when
+    /// it was done
+    #[clap(long)]
+    pub reproducible: bool,
+
+    /// Set the title of mkv outputs from this template.
+    ///
+    /// {show} is replaced with the name of the input's directory,
+    /// {season} and {episode} with the numbers in its filename, such as
+    /// `S01E05` or ` - 05`, {name} with its filename, and {resolution} with
+    /// the output's height, e.g. "{show} - {episode} [{resolution}]".
+    /// {season} and {episode} are padded to two digits, or as many as given
+    /// like {episode:3}, and {absolute} is the episode without padding.
+    #[clap(long, value_name = "TEMPLATE")]
+    pub title: Option<String>,
+
+    /// Name outputs from this template instead of the input's filename and
+    /// the encode settings, without the extension.
+    ///
+    /// Takes the same placeholders as `--title`, and {settings} for the encode
+    /// settings, which is needed to tell outputs apart when there are several.
+    /// e.g. "{show} - S{season}E{episode} [{resolution}]"
+    #[clap(long = "name", value_name = "TEMPLATE")]
+    pub name_template: Option<String>,
+
+    /// Fail an input whose rendered subtitles use fonts which are neither
+    /// installed nor in assrender's `fontdir`, instead of warning about them
+    #[clap(long)]
+    pub strict_fonts: bool,
+
+    /// Write the SHA-256 of each output next to it, as `<output>.sha256`,
+    /// which the `verify` subcommand checks
+    #[clap(long)]
+    pub checksum: bool,
+
+    /// Also place each finished output (and its checksum) in these directories,
+    /// as hard links where possible and copies otherwise.
+    ///
+    /// A destination which fails is warned about without failing the output.
+    /// Can be set as a comma-separated list with `MP4BATCH_COPY_TO`,
+    /// and defaults to the `destinations` of the settings file,
+    /// `mp4batch/config.json` in the user's config directory.
+    #[clap(
+        long,
+        value_name = "DIR",
+        env = "MP4BATCH_COPY_TO",
+        value_delimiter = ','
+    )]
+    pub copy_to: Vec<PathBuf>,
+
+    /// After each output is finished, copy it (and its checksum) to a remote
+    /// destination: rsync://host/module/path, [user@]host:path over SSH,
+    /// sftp://[user@]host[:port]/path, or s3://bucket/prefix
+    #[clap(long, value_name = "DEST")]
+    pub upload: Option<UploadDestination>,
+
+    /// Delete each output locally once it has been uploaded
+    #[clap(long, requires = "upload")]
+    pub delete_after_upload: bool,
+
+    /// Score each output against the lossless with this metric after
+    /// encoding.
+    ///
+    /// Scores against the source if there is no lossless.
+    /// SSIMULACRA2 tends to track perceived quality better than VMAF for
+    /// grainy sources.
+    #[clap(long, value_enum, value_name = "METRIC")]
+    pub quality_check: Option<Metric>,
+
+    /// Fail any output whose mean score is below this.
+    /// Checks with VMAF unless `--quality-check` says otherwise.
+    #[clap(long, value_name = "SCORE")]
+    pub min_quality: Option<f64>,
+
+    /// Don't delete av1an's temp directory after encoding
+    #[clap(long)]
+    pub keep_temp: bool,
+
+    /// Restart interrupted av1an encodes from the beginning,
+    /// instead of resuming from their last finished chunk
+    #[clap(long)]
+    pub no_resume_chunks: bool,
+
+    /// Export av1an's scene changes to a `.scenes.json` next to each script,
+    /// or import them from it, so they match between encodes of the script
+    #[clap(long, value_enum, value_name = "MODE")]
+    pub scenes: Option<ScenesMode>,
+
+    /// Comma-separated list of NUMA nodes to spread av1an workers across.
+    ///
+    /// av1an and its workers may run on any CPU of these nodes, with memory
+    /// allocated on the node each thread runs on. Workers aren't pinned to
+    /// a single node, and av1an's own thread pinning is turned off.
+    /// Each job needs at least one core per node.
+    ///
+    /// Requires numactl. Linux only.
+    #[clap(long, value_name = "NODES", value_delimiter = ',')]
+    pub numa_nodes: Option<Vec<u32>>,
+
+    /// Limit av1an workers to fit within this much memory, in GiB.
+    ///
+    /// Defaults to three quarters of the available system memory,
+    /// if it can be detected.
+    #[clap(long, value_name = "GIB")]
+    pub max_memory: Option<u64>,
+
+    /// Run all encoding tools at this nice level [0-19].
+    ///
+    /// On Windows, levels of 15 and above use the idle priority class, and
+    /// anything else above 0 uses below normal.
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u8).range(0..=19))]
+    pub nice: Option<u8>,
+
+    /// Run all encoding tools at a reduced priority. Same as `--nice 10`.
+    #[clap(long, conflicts_with = "nice")]
+    pub low_priority: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Watch a directory, and process each new script which appears in it
+    /// using the options given before the subcommand
+    Watch {
+        /// The directory to watch
+        dir: String,
+
+        /// Seconds a file must stop changing for before it is processed
+        #[clap(long, value_name = "SECS", default_value = "30")]
+        settle: u64,
+    },
+    /// List previous encodes, most recent last
+    History {
+        /// Only show encodes whose paths or settings contain this text
+        filter: Option<String>,
+
+        /// Only show this many of the most recent encodes
+        #[clap(long, value_name = "N")]
+        limit: Option<usize>,
+
+        /// Print the entries as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Compare two finished encodes, scene by scene, using `a` as the reference
+    Compare {
+        a: PathBuf,
+        b: PathBuf,
+
+        /// Comma-separated list of metrics to compute [default: psnr,ssim,xpsnr]
+        #[clap(long, value_enum, value_delimiter = ',')]
+        metrics: Option<Vec<Metric>>,
+
+        /// Print the scores as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Report the external tools, VapourSynth plugins and their versions,
+    /// for including in bug reports
+    Doctor,
+    /// Check outputs against the checksums written by `--checksum`
+    Verify {
+        /// Outputs, checksum files, or directories of them
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Extract every subtitle track of a video, or of the source of a script,
+    /// as ASS, SRT, WebVTT, PGS or VobSub depending on the track
+    Subs {
+        input: PathBuf,
+
+        /// Directory to write the subtitles to, instead of next to the video
+        #[clap(long, value_name = "DIR")]
+        output_dir: Option<PathBuf>,
+    },
+    /// Print a table of the tracks of a video, or of the source of a script,
+    /// numbered the same way as in `at=` and `st=`
+    Inspect { input: PathBuf },
 }
 
 fn main() {
     env::set_var("RUST_BACKTRACE", "1");
 
-    check_for_required_apps().unwrap();
-
     let args = InputArgs::parse();
+    if let Some(Command::Doctor) = args.command {
+        print_doctor();
+        return;
+    }
 
-    let input = Path::new(&args.input);
+    init_logging(
+        args.verbose,
+        args.quiet,
+        args.color,
+        args.log_file.as_deref(),
+    )
+    .unwrap();
+    set_probe_backend(args.probe);
+    check_for_required_apps().unwrap_or_else(exit_with_error);
+    let settings = load_settings().unwrap_or_else(exit_with_error);
+    if args.progress_json {
+        enable_events();
+    }
+    set_dry_run(args.dry_run);
+    if let Some(ref work_dir) = args.work_dir {
+        set_work_dir(work_dir).unwrap();
+    }
+    if let Some(Command::History {
+        ref filter,
+        limit,
+        json,
+    }) = args.command
+    {
+        print_history(filter.as_deref(), limit, json).unwrap();
+        return;
+    }
+    if let Some(Command::Compare {
+        ref a,
+        ref b,
+        ref metrics,
+        json,
+    }) = args.command
+    {
+        let metrics = metrics.as_deref().unwrap_or(Metric::DEFAULT);
+        print_comparison(a, b, metrics, json).unwrap();
+        return;
+    }
+    if let Some(Command::Verify { ref paths }) = args.command {
+        if !verify_checksums(paths).unwrap() {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(Command::Subs {
+        ref input,
+        ref output_dir,
+    }) = args.command
+    {
+        extract_all_subtitles(input, output_dir.as_deref()).unwrap_or_else(exit_with_error);
+        return;
+    }
+    if let Some(Command::Inspect { ref input }) = args.command {
+        print_tracks(input).unwrap_or_else(exit_with_error);
+        return;
+    }
+    check_tool_versions().unwrap();
+    if let Some(nice) = args
+        .nice
+        .or(if args.low_priority { Some(10) } else { None })
+    {
+        // Every tool we spawn inherits our priority
+        set_process_priority(nice).unwrap();
+    }
+    // Lets a heavy encode be paused with SIGUSR1 and resumed with SIGUSR2
+    handle_pause_signals().unwrap();
+    handle_interrupt_signals().unwrap();
+    handle_console_events().unwrap();
+    if args.numa_nodes.is_some() {
+        require_tool("numactl");
+    }
+
+    let lossless_codec = if args.lossless_codec.is_available() {
+        args.lossless_codec
+    } else {
+        warn!(
+            "Lossless codec {} is not available, falling back to x264",
+            args.lossless_codec
+        );
+        LosslessCodec::X264
+    };
+
+    if let Some(ref upload) = args.upload {
+        upload.check_tool().unwrap_or_else(exit_with_error);
+    }
+
+    let options = ProcessOptions {
+        output_dir: args.output.clone(),
+        keep_lossless: args.keep_lossless || args.estimate || args.matrix,
+        lossless_only: args.lossless_only,
+        skip_lossless: args.skip_lossless,
+        lossless_codec,
+        verify_lossless: args.verify_lossless,
+        lossless_segments: args.lossless_segments,
+        lossless_audio: args.lossless_audio.clone().map(|tracks| LosslessAudio {
+            tracks,
+            flac: args.lossless_audio_flac,
+        }),
+        force_keyframes: args.force_keyframes.clone(),
+        verify_frame_count: !args.no_verify,
+        ignore_delay: args.no_delay,
+        no_retry: args.no_retry,
+        reproducible: args.reproducible,
+        title: args.title.clone(),
+        name_template: args.name_template.clone(),
+        checksum: args.checksum,
+        strict_fonts: args.strict_fonts,
+        copy_to: if args.copy_to.is_empty() {
+            settings.destinations.clone()
+        } else {
+            args.copy_to.clone()
+        },
+        upload: args.upload.clone(),
+        delete_after_upload: args.delete_after_upload,
+        quality_check: args
+            .quality_check
+            .or_else(|| args.min_quality.map(|_| Metric::Vmaf)),
+        min_quality: args.min_quality,
+        av1an: Av1anOptions {
+            keep_temp: args.keep_temp,
+            resume_chunks: !args.no_resume_chunks,
+            numa_nodes: args.numa_nodes.clone(),
+            max_memory_mb: args.max_memory.map(|gib| gib * 1024),
+            concurrent_jobs: args.jobs as usize,
+            scenes: args.scenes,
+        },
+    };
+    let pipeline = Arc::new(if args.estimate {
+        Pipeline {
+            stages: estimate_stages(),
+        }
+    } else if args.matrix {
+        Pipeline {
+            stages: matrix_stages(),
+        }
+    } else {
+        Pipeline::default()
+    });
+    let options = Arc::new(options);
+    let notifier = Arc::new(Notifier {
+        webhook_url: args.notify_url.clone().filter(|_| !args.dry_run),
+        desktop: args.notify_desktop && !args.dry_run,
+    });
+
+    let name_filter = NameFilter {
+        include: args.include.clone(),
+        exclude: args.exclude.clone(),
+    };
+
+    if let Some(Command::Watch { ref dir, settle }) = args.command {
+        let dir = Path::new(dir);
+        assert!(dir.is_dir(), "Watch path is not a directory");
+        let _lock = directory_lock(dir, &args);
+        info!("Watching for new scripts in {}", dir.to_string_lossy());
+        let raw_inputs = args.raw_inputs;
+        let names = name_filter.clone();
+        let done_list = done_list(dir, &args);
+        let filter_done_list = done_list.clone();
+        let filter = move |path: &Path| {
+            names.matches(path) && is_input(path, raw_inputs, filter_done_list.as_deref())
+        };
+        watch_directory(dir, Duration::from_secs(settle), filter, |input| {
+            let input = if is_raw_video(&input, done_list.as_deref()) {
+                // Videos with a hand-written script are processed through that script
+                let script = input.with_extension("vpy");
+                if script.exists() && !is_raw_script(&script) {
+                    return;
+                }
+                wrap_raw_video(&input).unwrap()
+            } else {
+                input
+            };
+            if let Some(ref done_list) = done_list {
+                if !args.force && done_list.contains(&input) {
+                    info!("Skipping already done input {}", input.display());
+                    return;
+                }
+            }
+            let outputs = parse_outputs(args.formats.as_deref(), &input);
+            if let Err(err) = validate_job(&input, &outputs) {
+                error!("Skipping {}: {}", input.display(), err);
+                return;
+            }
+            let queue = Arc::new(JobQueue::new(VecDeque::from(vec![(input, outputs)]), false));
+            run_batch(
+                args.jobs,
+                &pipeline,
+                &options,
+                &notifier,
+                &queue,
+                None,
+                done_list.clone().filter(|_| !args.dry_run),
+            );
+        })
+        .unwrap();
+        return;
+    }
+
+    let input = Path::new(args.input.as_deref().expect("Input is required"));
     assert!(input.exists(), "Input path does not exist");
 
-    let inputs = if input.is_file() {
-        vec![input.to_path_buf()]
+    let lock_dir = if input.is_dir() {
+        input
+    } else {
+        input.parent().unwrap_or_else(|| Path::new("."))
+    };
+    let _lock = directory_lock(lock_dir, &args);
+    let done_list = done_list(lock_dir, &args);
+
+    let inputs: Vec<PathBuf> = if input.is_file() {
+        if args.raw_inputs && is_raw_video(input, done_list.as_deref()) {
+            vec![wrap_raw_video(input).unwrap()]
+        } else {
+            vec![input.to_path_buf()]
+        }
     } else if input.is_dir() {
-        WalkDir::new(input)
+        let mut walker = WalkDir::new(input).follow_links(args.follow_symlinks);
+        if let Some(depth) = args.max_depth {
+            walker = walker.max_depth(depth as usize);
+        }
+        let inputs = walker
             .into_iter()
             .filter_map(|e| e.ok())
-            .filter(|e| {
-                e.path()
-                    .extension()
-                    .map(|ext| ext.to_string_lossy().to_string())
-                    == Some("vpy".to_string())
-            })
-            .filter(|e| {
-                let filestem = e
-                    .path()
-                    .file_stem()
-                    .expect("File should have a name")
-                    .to_string_lossy();
-                !(filestem.contains(".aom-q")
-                    || filestem.contains(".rav1e-q")
-                    || filestem.contains(".svt-q")
-                    || filestem.contains(".x264-q")
-                    || filestem.contains(".x265-q")
-                    || filestem.ends_with(".copy"))
+            .filter(|e| is_input(e.path(), args.raw_inputs, done_list.as_deref()))
+            .filter(|e| name_filter.matches(e.path()))
+            .map(|e| {
+                if is_raw_video(e.path(), done_list.as_deref()) {
+                    wrap_raw_video(e.path()).unwrap()
+                } else {
+                    e.path().to_path_buf()
+                }
             })
-            .map(|e| e.path().to_path_buf())
+            // A video and the script wrapping it are the same input
+            .unique()
             .sorted_unstable_by(|a, b| {
                 natural_lexical_cmp(&a.to_string_lossy(), &b.to_string_lossy())
             })
-            .collect()
+            .collect();
+        order_inputs(inputs, args.order, args.order_file.as_deref()).unwrap()
     } else {
         panic!("Input is neither a file nor a directory");
     };
 
-    for input in inputs {
-        let outputs = args.formats.as_ref().map_or_else(
-            || vec![Output::default()],
-            |formats| {
-                let formats = formats.trim();
-                if formats.is_empty() {
-                    return vec![Output::default()];
-                }
-                formats
-                    .split(';')
-                    .map(|format| {
-                        let mut output = Output::default();
-                        let filters = parse_filters(format, &input);
-                        if let Some(encoder) = filters.iter().find_map(|filter| {
-                            if let ParsedFilter::VideoEncoder(encoder) = filter {
-                                Some(encoder)
-                            } else {
-                                None
-                            }
-                        }) {
-                            match encoder.to_lowercase().as_str() {
-                                "x264" => {
-                                    which("x264")
-                                        .map_err(|_| anyhow!("x264 not installed or not in PATH!"))
-                                        .unwrap();
-                                    // This is the default, do nothing
-                                }
-                                "x265" => {
-                                    which("x265")
-                                        .map_err(|_| anyhow!("x265 not installed or not in PATH!"))
-                                        .unwrap();
-                                    output.video.encoder = VideoEncoder::X265 {
-                                        crf: 18,
-                                        profile: Profile::Film,
-                                        compat: false,
-                                    }
-                                }
-                                "aom" => {
-                                    which("aomenc")
-                                        .map_err(|_| {
-                                            anyhow!("aomenc not installed or not in PATH!")
-                                        })
-                                        .unwrap();
-                                    output.video.encoder = VideoEncoder::Aom {
-                                        crf: 16,
-                                        speed: 4,
-                                        profile: Profile::Film,
-                                        grain: 0,
-                                        compat: false,
-                                    }
-                                }
-                                "rav1e" => {
-                                    which("rav1e")
-                                        .map_err(|_| anyhow!("rav1e not installed or not in PATH!"))
-                                        .unwrap();
-                                    output.video.encoder = VideoEncoder::Rav1e {
-                                        crf: 40,
-                                        speed: 5,
-                                        profile: Profile::Film,
-                                        grain: 0,
-                                    }
-                                }
-                                "svt" => {
-                                    which("SvtAv1EncApp")
-                                        .map_err(|_| {
-                                            anyhow!("SvtAv1EncApp not installed or not in PATH!")
-                                        })
-                                        .unwrap();
-                                    output.video.encoder = VideoEncoder::SvtAv1 {
-                                        crf: 16,
-                                        speed: 4,
-                                        profile: Profile::Film,
-                                        grain: 0,
-                                    }
-                                }
-                                "copy" => {
-                                    output.video.encoder = VideoEncoder::Copy;
-                                }
-                                enc => panic!("Unrecognized encoder: {}", enc),
-                            }
-                        }
-                        for filter in &filters {
-                            apply_filter(filter, &mut output);
-                        }
-                        output
-                    })
-                    .collect()
-            },
-        );
+    // Only directory batches are worth tracking,
+    // and estimates, samples and dry runs don't complete anything
+    let batch_state = if input.is_dir()
+        && !args.estimate
+        && !args.matrix
+        && !args.dry_run
+        && !args.print_config
+    {
+        let resumed = if args.resume {
+            let resumed = BatchState::resume(input, &inputs).unwrap();
+            if resumed.is_none() {
+                warn!("No previous batch state found, starting from the beginning");
+            }
+            resumed
+        } else {
+            None
+        };
+        Some(match resumed {
+            Some(state) => state,
+            None => BatchState::new(input, &inputs).unwrap(),
+        })
+    } else {
+        None
+    };
 
-        let result = process_file(
-            &input,
-            &outputs,
-            args.output.as_deref(),
-            args.keep_lossless,
-            args.lossless_only,
-            args.skip_lossless,
-            &args.force_keyframes,
-            !args.no_verify,
-            args.no_delay,
-            args.no_retry,
-        );
-        if let Err(err) = result {
-            eprintln!(
-                "{} Failed processing file {}: {}",
-                Red.bold().paint("[Error]"),
-                Red.paint(
+    // Every part is concatenated, including those done or skipped in this run
+    let concat_parts = args
+        .concat
+        .as_ref()
+        .map(|_| {
+            inputs
+                .iter()
+                .map(|input| {
+                    let outputs = parse_outputs(args.formats.as_deref(), input);
+                    assert!(
+                        outputs.len() == 1,
+                        "--concat needs exactly one output format"
+                    );
+                    (input.clone(), outputs)
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let mut queue = VecDeque::new();
+    let mut to_skip = args.skip;
+    let mut left_over = 0;
+    for input in inputs {
+        if let Some(ref state) = batch_state {
+            if state.status(&input) == InputStatus::Completed {
+                info!(
+                    "Skipping already completed input {}",
                     input
                         .file_name()
                         .expect("File should have a name")
                         .to_string_lossy()
-                ),
-                Red.paint(err.to_string())
-            );
+                );
+                continue;
+            }
         }
-        eprintln!();
-    }
-}
-
-fn check_for_required_apps() -> Result<()> {
-    which("mediainfo").map_err(|_| anyhow!("mediainfo not installed or not in PATH!"))?;
-    which("mkvmerge").map_err(|_| anyhow!("mkvmerge not installed or not in PATH!"))?;
-    which("vspipe").map_err(|_| anyhow!("vspipe not installed or not in PATH!"))?;
-    which("ffmpeg").map_err(|_| anyhow!("ffmpeg not installed or not in PATH!"))?;
-
-    Ok(())
-}
-
-#[allow(clippy::too_many_arguments)]
-#[allow(clippy::fn_params_excessive_bools)]
-fn process_file(
-    input_vpy: &Path,
-    outputs: &[Output],
-    output_dir: Option<&str>,
-    keep_lossless: bool,
-    lossless_only: bool,
-    mut skip_lossless: bool,
-    force_keyframes: &Option<String>,
-    verify_frame_count: bool,
-    ignore_delay: bool,
-    no_retry: bool,
-) -> Result<()> {
-    let source_video = find_source_file(input_vpy);
-    let mediainfo = get_video_mediainfo(&source_video)?;
-    let colorimetry = get_video_colorimetry(input_vpy)?;
-    eprintln!(
-        "{} {} {}{}{}{}",
-        Blue.bold().paint("[Info]"),
-        Blue.bold().paint(
-            source_video
-                .file_name()
-                .expect("File should have a name")
-                .to_string_lossy()
-        ),
-        Blue.paint("("),
-        Blue.bold().paint(
-            Size::from_bytes(
-                source_video
-                    .metadata()
-                    .expect("Unable to get source file metadata")
-                    .len()
-            )
-            .format()
-            .to_string()
-        ),
-        mediainfo
-            .get("Stream size")
-            .map_or_else(String::new, |stream_size| format!(
-                "{}{}",
-                Blue.paint(" - Video stream: "),
-                Blue.bold().paint(stream_size)
-            )),
-        Blue.paint(")")
-    );
-    if outputs
-        .iter()
-        .all(|output| matches!(output.video.encoder, VideoEncoder::Copy))
-    {
-        skip_lossless = true;
-    }
-    if !skip_lossless {
-        eprintln!(
-            "{} {} {} {}",
-            Blue.bold().paint("[Info]"),
-            Blue.paint("Encoding"),
-            Blue.paint(
-                input_vpy
-                    .file_name()
-                    .expect("File should have a name")
-                    .to_string_lossy()
-            ),
-            Blue.paint("lossless")
-        );
-        let mut retry_count = 0;
-        loop {
-            // I hate this lazy workaround,
-            // but this is due to a heisenbug in Vapoursynth
-            // due to some sort of race condition,
-            // which causes crashes often enough to be annoying.
-            //
-            // Essentially, we retry the encode until it works.
-            let dimensions = get_video_dimensions(input_vpy)?;
-            let result = create_lossless(input_vpy, dimensions, verify_frame_count);
-            match result {
-                Ok(_) => {
-                    break;
-                }
-                Err(e) => {
-                    if no_retry || retry_count >= 3 {
-                        bail!(
-                            "{} {}: {}",
-                            Red.bold().paint("[Error]"),
-                            Red.paint("While encoding lossless"),
-                            e
-                        );
-                    } else {
-                        retry_count += 1;
-                        eprintln!(
-                            "{} {}: {}",
-                            Red.bold().paint("[Error]"),
-                            Red.paint("While encoding lossless"),
-                            e
-                        );
-                    }
-                }
+        if let Some(ref done_list) = done_list {
+            if !args.force && done_list.contains(&input) {
+                info!(
+                    "Skipping already done input {}",
+                    input
+                        .file_name()
+                        .expect("File should have a name")
+                        .to_string_lossy()
+                );
+                continue;
             }
         }
-        eprintln!();
-    }
+        if to_skip > 0 {
+            to_skip -= 1;
+            debug!("Skipping input {} due to --skip", input.display());
+            continue;
+        }
+        if args.limit.map_or(false, |limit| queue.len() >= limit) {
+            left_over += 1;
+            continue;
+        }
 
-    if lossless_only {
-        if skip_lossless {
-            eprintln!(
-                "Received both --lossless-only and --skip-lossless. Doing nothing. This is \
-                 probably a mistake."
+        let outputs = parse_outputs(args.formats.as_deref(), &input);
+        if let Some(ref template) = args.name_template {
+            assert!(
+                outputs.len() == 1 || template.contains("{settings}"),
+                "--name must include {{settings}} when there is more than one output"
             );
         }
-        return Ok(());
-    }
-
-    for output in outputs {
-        let video_suffix = build_video_suffix(output)?;
-        let output_vpy = input_vpy.with_extension(format!("{}.vpy", video_suffix));
-        eprintln!(
-            "{} {} {}",
-            Blue.bold().paint("[Info]"),
-            Blue.paint("Encoding"),
-            Blue.paint(
-                output_vpy
-                    .file_name()
-                    .expect("File should have a name")
-                    .to_string_lossy()
-            )
+        queue.push_back((input, outputs));
+    }
+    if left_over > 0 {
+        info!(
+            "Processing {} inputs, leaving {} for a later run",
+            queue.len(),
+            left_over
         );
+    }
 
-        let video_out = output_vpy.with_extension("mkv");
-        match output.video.encoder {
-            VideoEncoder::Copy => {
-                extract_video(&source_video, &video_out)?;
-            }
-            VideoEncoder::X264 {
-                crf,
-                profile,
-                compat,
-            } => {
-                build_vpy_script(&output_vpy, input_vpy, output, skip_lossless);
-                let dimensions = get_video_dimensions(&output_vpy)?;
-                convert_video_x264(
-                    &output_vpy,
-                    &video_out,
-                    crf,
-                    profile,
-                    compat,
-                    dimensions,
-                    force_keyframes,
-                    &colorimetry,
-                )?;
-            }
-            encoder => {
-                build_vpy_script(&output_vpy, input_vpy, output, skip_lossless);
-                let dimensions = get_video_dimensions(&output_vpy)?;
-                convert_video_av1an(
-                    &output_vpy,
-                    &video_out,
-                    encoder,
-                    dimensions,
-                    force_keyframes,
-                    &colorimetry,
-                )?;
-            }
-        };
+    if args.print_config {
+        print_config(queue.make_contiguous(), &options).unwrap_or_else(exit_with_error);
+        return;
+    }
+    validate_batch(queue.make_contiguous(), &options).unwrap_or_else(exit_with_error);
 
-        let mut audio_tracks = if output.audio_tracks.is_empty() {
-            vec![Track {
-                source: TrackSource::FromVideo(0),
-                enabled: true,
-                forced: false,
-            }]
+    // When serving, keep waiting for jobs to be added once the batch is done
+    let queue = Arc::new(JobQueue::new(queue, args.serve.is_some()));
+    if let Some(ref addr) = args.serve {
+        start_server(
+            addr,
+            args.serve_token.clone(),
+            lock_dir,
+            Arc::clone(&queue),
+            args.formats.clone(),
+        )
+        .unwrap_or_else(exit_with_error);
+    }
+    let started = unix_time();
+    let summary = run_batch(
+        args.jobs,
+        &pipeline,
+        &options,
+        &notifier,
+        &queue,
+        batch_state,
+        // Inputs already done are skipped, but a dry run doesn't finish any
+        done_list.filter(|_| !args.dry_run),
+    );
+    let mut concat_failed = false;
+    if let Some(ref concat) = args.concat {
+        if summary.failed() > 0 {
+            error!(
+                "Not concatenating into {}, since inputs failed",
+                concat.display()
+            );
+            concat_failed = true;
         } else {
-            output.audio_tracks.clone()
-        };
-        let has_vpy_audio = fs::read_to_string(input_vpy)?.contains(".set_output(1)");
-        if has_vpy_audio {
-            let audio_path = input_vpy.with_extension("flac");
-            save_vpy_audio(input_vpy, &audio_path)?;
-            audio_tracks = vec![Track {
-                source: TrackSource::External(audio_path),
-                enabled: true,
-                forced: false,
-            }];
+            let result = concat_segments(&concat_parts, &summary, &options)
+                .and_then(|segments| concat_outputs(&segments, concat));
+            if let Err(err) = result {
+                error!("{}", err);
+                concat_failed = true;
+            }
         }
-        let mut audio_outputs = Vec::new();
-        let mut audio_suffixes = Vec::new();
-        for (i, audio_track) in audio_tracks.iter().enumerate() {
-            let audio_suffix = format!(
-                "{}-{}kbpc-at{}",
-                output.audio.encoder, output.audio.kbps_per_channel, i
-            );
-            let audio_out = input_vpy.with_extension(format!("{}.mka", audio_suffix));
-            convert_audio(
-                input_vpy,
-                &audio_out,
-                output.audio.encoder,
-                audio_track,
-                output.audio.kbps_per_channel,
-                output.audio.normalize,
-            )?;
-            audio_outputs.push((audio_out, audio_track.clone(), output.audio.encoder));
-            audio_suffixes.push(audio_suffix);
+    }
+    notifier.batch_finished(summary.completed(), summary.failed());
+    if !summary.is_empty() {
+        if args.quiet == 0 {
+            eprint!("\n{}", summary.to_table());
         }
-        let audio_suffix = audio_suffixes.join("-");
-        let mut output_path = PathBuf::from(output_dir.unwrap_or(dotenv!("OUTPUT_PATH")));
-        output_path.push(
-            input_vpy
-                .with_extension(format!(
-                    "{}-{}.{}",
-                    video_suffix, audio_suffix, output.video.output_ext
-                ))
-                .file_name()
-                .expect("File should have a name"),
-        );
-
-        let mut subtitle_outputs = Vec::new();
-        if !output.sub_tracks.is_empty() {
-            for (i, subtitle) in output.sub_tracks.iter().enumerate() {
-                let mut subtitle_out;
-                match &subtitle.source {
-                    TrackSource::External(path) => {
-                        let ext = path
-                            .extension()
-                            .expect("Output file should have an extension")
-                            .to_string_lossy();
-                        subtitle_out = input_vpy.with_extension(format!("{}.{}", i, ext));
-                        fs::copy(path, &subtitle_out)?;
-                    }
-                    TrackSource::FromVideo(j) => {
-                        subtitle_out = input_vpy.with_extension(format!("{}.ass", i));
-                        if extract_subtitles(&source_video, *j, &subtitle_out).is_err() {
-                            subtitle_out = input_vpy.with_extension(format!("{}.srt", i));
-                            extract_subtitles(&source_video, *j, &subtitle_out)?;
-                        }
-                    }
-                }
-                subtitle_outputs.push((subtitle_out, subtitle.enabled, subtitle.forced));
+        if let Some(format) = args.report.filter(|_| !args.dry_run) {
+            match summary.write_report(options.output_path(), format) {
+                Ok(path) => info!("Wrote report to {}", path.to_string_lossy()),
+                Err(err) => warn!("Failed to write report: {}", err),
             }
         }
-
-        mux_video(
-            &source_video,
-            &video_out,
-            &audio_outputs,
-            &subtitle_outputs,
-            output
-                .sub_tracks
-                .iter()
-                .any(|track| matches!(track.source, TrackSource::FromVideo(_))),
-            ignore_delay,
-            &output_path,
-        )?;
-
-        if colorimetry.is_hdr() {
-            copy_hdr_data(&source_video, &output_path)?;
+    }
+    let code = match summary.exit_code() {
+        0 if concat_failed => 1,
+        code => code,
+    };
+    if args.run_report && !args.dry_run {
+        match summary.write_run_report(options.output_path(), started, code) {
+            Ok(path) => info!("Wrote run report to {}", path.to_string_lossy()),
+            Err(err) => warn!("Failed to write run report: {}", err),
         }
-
-        eprintln!(
-            "{} {} {}",
-            Green.bold().paint("[Success]"),
-            Green.paint("Finished encoding"),
-            Green.paint(
-                output_vpy
-                    .file_name()
-                    .expect("File should have a name")
-                    .to_string_lossy()
-            )
-        );
-        eprintln!();
     }
-
-    if !keep_lossless {
-        let _ = fs::remove_file(input_vpy.with_extension("lossless.mkv"));
+    if code != 0 {
+        // Exiting skips destructors, and the lock must not outlive us
+        drop(_lock);
+        process::exit(code);
     }
-
-    Ok(())
 }
 
-fn absolute_path(path: impl AsRef<Path>) -> io::Result<PathBuf> {
-    let path = path.as_ref();
-
-    let absolute_path = if path.is_absolute() {
-        path.to_path_buf()
-    } else {
-        env::current_dir()?.join(path)
+/// Stops other runs from processing `dir` at the same time, unless this is a dry run
+fn directory_lock(dir: &Path, args: &InputArgs) -> Option<DirectoryLock> {
+    if args.dry_run || args.print_config {
+        return None;
     }
-    .clean();
+    Some(DirectoryLock::acquire(dir, args.wait_for_lock).unwrap())
+}
 
-    Ok(absolute_path)
+/// The list of inputs done in `dir`, unless this run won't finish any
+fn done_list(dir: &Path, args: &InputArgs) -> Option<Arc<DoneList>> {
+    if args.estimate || args.matrix || args.lossless_only {
+        return None;
+    }
+    Some(Arc::new(
+        DoneList::load(dir, args.formats.as_deref()).unwrap(),
+    ))
 }
 
-fn escape_python_string(input: &str) -> String {
-    input.replace('\\', r"\\").replace('\'', r"\'")
+/// Processes every input in the queue, `jobs` at a time,
+/// returning a summary of how each one went
+fn run_batch(
+    jobs: u32,
+    pipeline: &Arc<Pipeline>,
+    options: &Arc<ProcessOptions>,
+    notifier: &Arc<Notifier>,
+    queue: &Arc<JobQueue>,
+    batch_state: Option<BatchState>,
+    done_list: Option<Arc<DoneList>>,
+) -> BatchSummary {
+    let summary = Arc::new(BatchSummary::default());
+    if jobs <= 1 {
+        run_worker(
+            queue,
+            pipeline,
+            options,
+            notifier,
+            &Mutex::new(batch_state),
+            done_list.as_deref(),
+            &summary,
+        );
+    } else {
+        let batch_state = Arc::new(Mutex::new(batch_state));
+        let workers = (0..jobs)
+            .map(|_| {
+                let queue = Arc::clone(queue);
+                let pipeline = Arc::clone(pipeline);
+                let options = Arc::clone(options);
+                let notifier = Arc::clone(notifier);
+                let batch_state = Arc::clone(&batch_state);
+                let done_list = done_list.clone();
+                let summary = Arc::clone(&summary);
+                thread::spawn(move || {
+                    run_worker(
+                        &queue,
+                        &pipeline,
+                        &options,
+                        &notifier,
+                        &batch_state,
+                        done_list.as_deref(),
+                        &summary,
+                    );
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().unwrap_or_else(|e| panic::resume_unwind(e));
+        }
+    }
+    Arc::try_unwrap(summary).unwrap_or_else(|_| panic!("Workers should have finished"))
 }
 
-fn apply_filter(filter: &ParsedFilter, output: &mut Output) {
-    match filter {
-        ParsedFilter::VideoEncoder(_) => (),
-        ParsedFilter::Quantizer(arg) => {
-            let arg = *arg;
-            let range = match output.video.encoder {
-                VideoEncoder::X264 { ref mut crf, .. } => {
-                    *crf = arg;
-                    (-12, 51)
-                }
-                VideoEncoder::X265 { ref mut crf, .. } => {
-                    *crf = arg;
-                    (0, 51)
-                }
-                VideoEncoder::Aom { ref mut crf, .. }
-                | VideoEncoder::SvtAv1 { ref mut crf, .. } => {
-                    *crf = arg;
-                    (0, 63)
-                }
-                VideoEncoder::Rav1e { ref mut crf, .. } => {
-                    *crf = arg;
-                    (0, 255)
-                }
-                VideoEncoder::Copy => {
-                    return;
-                }
+/// Processes inputs from the queue until it is empty
+fn run_worker(
+    queue: &JobQueue,
+    pipeline: &Pipeline,
+    options: &ProcessOptions,
+    notifier: &Notifier,
+    batch_state: &Mutex<Option<BatchState>>,
+    done_list: Option<&DoneList>,
+    summary: &BatchSummary,
+) {
+    // Inputs left after an interruption are neither started nor counted as failed
+    while let Some(((input, outputs), cancel)) = queue.next().filter(|_| !is_interrupted()) {
+        let started = Instant::now();
+        // Warnings from a previous input have already been summarized
+        take_warnings();
+        let result = pipeline.run(&input, &outputs, options, &cancel, &|stage| {
+            queue.set_stage(&input, stage)
+        });
+        queue.finish(&input, result.as_ref().err().map(|e| e.to_string()));
+        let output_paths = result
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .map(|output| output.path.clone())
+            .collect::<Vec<_>>();
+        notifier.file_finished(&FileReport {
+            input: &input,
+            outputs: &output_paths,
+            duration: started.elapsed(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        if let Some(ref mut state) = *batch_state.lock().unwrap() {
+            let status = if result.is_ok() {
+                InputStatus::Completed
+            } else {
+                InputStatus::Failed
             };
-            if arg < range.0 || arg > range.1 {
-                panic!(
-                    "'q' must be between {} and {}, received {}",
-                    range.0, range.1, arg
-                );
-            }
-        }
-        ParsedFilter::Speed(arg) => match output.video.encoder {
-            VideoEncoder::Aom { ref mut speed, .. }
-            | VideoEncoder::Rav1e { ref mut speed, .. }
-            | VideoEncoder::SvtAv1 { ref mut speed, .. } => {
-                let arg = *arg;
-                if arg > 10 {
-                    panic!("'s' must be between 0 and 10, received {}", arg);
-                }
-                *speed = arg;
-            }
-            _ => (),
-        },
-        ParsedFilter::Profile(arg) => match output.video.encoder {
-            VideoEncoder::X264 {
-                ref mut profile, ..
+            if let Err(err) = state.set_status(&input, status) {
+                warn!("Failed to save batch state: {}", err);
             }
-            | VideoEncoder::X265 {
-                ref mut profile, ..
-            }
-            | VideoEncoder::Aom {
-                ref mut profile, ..
-            }
-            | VideoEncoder::Rav1e {
-                ref mut profile, ..
-            }
-            | VideoEncoder::SvtAv1 {
-                ref mut profile, ..
-            } => {
-                *profile = *arg;
-            }
-            VideoEncoder::Copy => (),
-        },
-        ParsedFilter::Grain(arg) => match output.video.encoder {
-            VideoEncoder::Aom { ref mut grain, .. }
-            | VideoEncoder::Rav1e { ref mut grain, .. }
-            | VideoEncoder::SvtAv1 { ref mut grain, .. } => {
-                let arg = *arg;
-                if arg > 64 {
-                    panic!("'grain' must be between 0 and 64, received {}", arg);
-                }
-                *grain = arg;
-            }
-            _ => (),
-        },
-        ParsedFilter::Compat(arg) => match output.video.encoder {
-            VideoEncoder::X264 { ref mut compat, .. }
-            | VideoEncoder::X265 { ref mut compat, .. }
-            | VideoEncoder::Aom { ref mut compat, .. } => {
-                *compat = *arg;
-            }
-            _ => (),
-        },
-        ParsedFilter::Extension(arg) => {
-            output.video.output_ext = (*arg).to_string();
         }
-        ParsedFilter::BitDepth(arg) => {
-            output.video.bit_depth = Some(*arg);
-        }
-        ParsedFilter::Resolution { width, height } => {
-            output.video.resolution = Some((*width, *height));
-        }
-        ParsedFilter::AudioEncoder(arg) => {
-            output.audio.encoder = match arg.to_lowercase().as_str() {
-                "copy" => AudioEncoder::Copy,
-                "flac" => AudioEncoder::Flac,
-                "aac" => AudioEncoder::Aac,
-                "opus" => AudioEncoder::Opus,
-                arg => panic!("Invalid value provided for 'aenc': {}", arg),
+        if let (Some(done_list), Ok(_)) = (done_list, &result) {
+            if let Err(err) = done_list.add(&input, &output_paths) {
+                warn!("Failed to add input to the done list: {}", err);
             }
         }
-        ParsedFilter::AudioBitrate(arg) => {
-            let arg = *arg;
-            if arg == 0 {
-                panic!("'ab' must be greater than 0, got {}", arg);
-            }
-            output.audio.kbps_per_channel = arg;
-        }
-        ParsedFilter::AudioTracks(args) => {
-            output.audio_tracks.clone_from(args);
-        }
-        ParsedFilter::AudioNormalize => {
-            output.audio.normalize = true;
-        }
-        ParsedFilter::SubtitleTracks(args) => {
-            output.sub_tracks.clone_from(args);
+        summary.add(
+            &input,
+            result.as_deref().unwrap_or_default(),
+            started.elapsed(),
+            result.as_ref().err(),
+            take_warnings(),
+        );
+        if let Err(err) = result {
+            error!(
+                "Failed processing file {}: {}",
+                input
+                    .file_name()
+                    .expect("File should have a name")
+                    .to_string_lossy(),
+                err
+            );
         }
     }
 }
 
-fn build_video_suffix(output: &Output) -> Result<String> {
-    let mut codec_str = match output.video.encoder {
-        VideoEncoder::Aom {
-            crf,
-            speed,
-            profile,
-            grain,
-            compat,
-        } => format!(
-            "aom-q{}-s{}-{}-g{}{}",
-            crf,
-            speed,
-            profile,
-            grain,
-            if compat { "-compat" } else { "" }
-        ),
-        VideoEncoder::Rav1e {
-            crf,
-            speed,
-            profile,
-            grain,
-        } => format!("rav1e-q{}-s{}-{}-g{}", crf, speed, profile, grain),
-        VideoEncoder::SvtAv1 {
-            crf,
-            speed,
-            profile,
-            grain,
-        } => format!("svt-q{}-s{}-{}-g{}", crf, speed, profile, grain),
-        VideoEncoder::X264 {
-            crf,
-            profile,
-            compat,
-        } => format!(
-            "x264-q{}-{}{}",
-            crf,
-            profile,
-            if compat { "-compat" } else { "" }
-        ),
-        VideoEncoder::X265 {
-            crf,
-            profile,
-            compat,
-        } => format!(
-            "x265-q{}-{}{}",
-            crf,
-            profile,
-            if compat { "-compat" } else { "" }
-        ),
-        VideoEncoder::Copy => "copy".to_string(),
-    };
-    if let Some(res) = output.video.resolution {
-        write!(codec_str, "-{}x{}", res.0, res.1)?;
-    }
-    if let Some(bd) = output.video.bit_depth {
-        write!(codec_str, "-{}b", bd)?;
+/// Whether `path` is a script or, with `--raw-inputs`, a video to process.
+///
+/// Scripts we wrapped videos in are processed through their video instead.
+fn is_input(path: &Path, raw_inputs: bool, done_list: Option<&DoneList>) -> bool {
+    if raw_inputs {
+        is_raw_video(path, done_list) || (is_input_script(path) && !is_raw_script(path))
+    } else {
+        is_input_script(path)
     }
-    Ok(codec_str)
 }
 
-fn build_vpy_script(filename: &Path, input: &Path, output: &Output, skip_lossless: bool) {
-    let mut script = BufWriter::new(File::create(filename).expect("Unable to write script file"));
-    if skip_lossless {
-        copy_and_modify_vpy_script(input, output, &mut script);
-    } else {
-        build_new_vpy_script(input, output, &mut script);
+/// Whether `path` is a video to wrap in a script, rather than an output
+/// recorded in `done_list` or an intermediate named after a script
+fn is_raw_video(path: &Path, done_list: Option<&DoneList>) -> bool {
+    if done_list.map_or(false, |done_list| done_list.is_output(path)) {
+        return false;
+    }
+    let is_video = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .map_or(false, |ext| RAW_VIDEO_EXTENSIONS.contains(&ext.as_str()));
+    if !is_video {
+        return false;
     }
+    let filestem = path
+        .file_stem()
+        .expect("File should have a name")
+        .to_string_lossy();
+    !(filestem.ends_with(".lossless")
+        || filestem.contains(".lossless.seg")
+        || filestem.ends_with(".lossless.audio")
+        || filestem.ends_with(".grain")
+        || filestem.ends_with(".sample")
+        || filestem.contains(".probe")
+        || filestem.ends_with(".mux-ready")
+        || filestem.contains(".aom-q")
+        || filestem.contains(".rav1e-q")
+        || filestem.contains(".svt-q")
+        || filestem.contains(".x264-q")
+        || filestem.contains(".x265-q")
+        || filestem.ends_with(".copy"))
 }
 
-fn build_new_vpy_script(input: &Path, output: &Output, script: &mut BufWriter<File>) {
-    writeln!(script, "import vapoursynth as vs").unwrap();
-    writeln!(script, "core = vs.core").unwrap();
-    writeln!(script, "core.max_cache_size=1024").unwrap();
-    writeln!(
-        script,
-        "clip = core.lsmas.LWLibavSource(source=\"{}\")",
-        escape_python_string(
-            &absolute_path(input.with_extension("lossless.mkv"))
-                .expect("Should be able to get absolute filepath")
-                .to_string_lossy()
-        )
-    )
-    .unwrap();
+const RAW_VIDEO_EXTENSIONS: &[&str] = &["mkv", "mp4", "m2ts"];
 
-    write_filters(output, script, None);
+/// Marks scripts written by `wrap_raw_video`, which are safe to overwrite
+const RAW_SCRIPT_HEADER: &str = "# Generated by mp4batch for --raw-inputs";
 
-    writeln!(script, "clip.set_output()").unwrap();
-    script.flush().expect("Unable to flush script data");
+/// Whether this script was generated by `wrap_raw_video`
+fn is_raw_script(script: &Path) -> bool {
+    read_to_string(script).map_or(false, |contents| contents.starts_with(RAW_SCRIPT_HEADER))
 }
 
-fn copy_and_modify_vpy_script(input: &Path, output: &Output, script: &mut BufWriter<File>) {
-    let contents = read_to_string(input).expect("Unable to read input script");
-    let mut output_pos = None;
-    let mut output_var = None;
-    for line in contents.lines() {
-        if let Some(pos) = line
-            .find(".set_output()")
-            .or_else(|| line.find(".set_output(0)"))
-        {
-            assert!(pos > 0);
-            output_pos = Some(
-                contents
-                    .find(line)
-                    .expect("Input script does not have an output clip"),
-            );
-            output_var = Some(&line[0..pos]);
-            break;
-        }
+/// Writes a script which loads `video` with LWLibavSource, next to it or in
+/// the work directory, and returns its path. A hand-written script next to
+/// the video is used instead.
+fn wrap_raw_video(video: &Path) -> Result<PathBuf> {
+    let existing = video.with_extension("vpy");
+    if existing.exists() && !is_raw_script(&existing) {
+        debug!(
+            "Using the existing script {} for {}",
+            existing.display(),
+            video.display()
+        );
+        return Ok(existing);
     }
-    match (output_pos, output_var) {
-        (Some(pos), Some(var)) => {
-            write!(script, "{}", &contents[..pos]).unwrap();
-            write_filters(output, script, Some(var));
-            writeln!(script).unwrap();
-            write!(script, "{}", &contents[pos..]).unwrap();
-            script.flush().expect("Unable to flush contents of script");
+    create_work_dir(video)?;
+    let script = work_path(video).with_extension("vpy");
+    let source = absolute_path(video)?;
+    fs::write(
+        &script,
+        format!(
+            "{}\nimport vapoursynth as vs\ncore = vs.core\nmp4batch_source = {}\nclip = \
+             core.lsmas.LWLibavSource(source=mp4batch_source)\nclip.set_output()\n",
+            RAW_SCRIPT_HEADER,
+            python_path(&source)
+        ),
+    )?;
+    Ok(script)
+}
+
+/// Logs `err` and exits with its code, for failures which stop the whole run
+fn exit_with_error<T>(err: anyhow::Error) -> T {
+    error!("{}", err);
+    process::exit(exit_code(&err));
+}
+
+/// Exits if `tool` isn't installed, such as one needed by the chosen encoder
+fn require_tool(tool: &str) {
+    if which(tool).is_err() {
+        exit_with_error(Error::ToolMissing(tool.to_string()).into())
+    }
+}
+
+fn check_for_required_apps() -> Result<()> {
+    match probe_backend() {
+        // Automatically probing means mediainfo was found
+        ProbeBackend::Auto => (),
+        ProbeBackend::Mediainfo => {
+            which("mediainfo").map_err(|_| Error::ToolMissing("mediainfo".to_string()))?;
         }
-        _ => {
-            panic!("Invalid input vapoursynth script, no `set_output()` found");
+        ProbeBackend::Ffprobe => {
+            which("ffprobe").map_err(|_| Error::ToolMissing("ffprobe".to_string()))?;
         }
     }
-}
+    which("mkvmerge").map_err(|_| Error::ToolMissing("mkvmerge".to_string()))?;
+    which("vspipe").map_err(|_| Error::ToolMissing("vspipe".to_string()))?;
+    which("ffmpeg").map_err(|_| Error::ToolMissing("ffmpeg".to_string()))?;
 
-fn write_filters(output: &Output, script: &mut BufWriter<File>, clip: Option<&str>) {
-    let clip = clip.unwrap_or("clip");
+    Ok(())
+}
 
-    // We downscale resolution first because it's more likely that
-    // we would be going from 10 bit to 8 bit, rather than the other way.
-    // So this gives the best quality.
-    if let Some((w, h)) = output.video.resolution {
-        writeln!(
-            script,
-            "{clip} = {clip}.resize.Spline36({w}, {h}, dither_type='error_diffusion')"
-        )
-        .unwrap();
+#[cfg(unix)]
+fn set_process_priority(nice: u8) -> Result<()> {
+    // SAFETY: `setpriority` has no memory safety requirements
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice.into()) };
+    if result != 0 {
+        bail!(
+            "Failed to set process priority: {}",
+            io::Error::last_os_error()
+        );
     }
-    if let Some(bd) = output.video.bit_depth {
-        writeln!(script, "import vsutil").unwrap();
-        writeln!(script, "{clip} = vsutil.depth({clip}, {bd})").unwrap();
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_process_priority(nice: u8) -> Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, SetPriorityClass, BELOW_NORMAL_PRIORITY_CLASS, IDLE_PRIORITY_CLASS,
+        NORMAL_PRIORITY_CLASS,
+    };
+
+    let class = match nice {
+        0 => NORMAL_PRIORITY_CLASS,
+        1..=14 => BELOW_NORMAL_PRIORITY_CLASS,
+        _ => IDLE_PRIORITY_CLASS,
+    };
+    // SAFETY: `GetCurrentProcess` returns a pseudo-handle which is always valid
+    let result = unsafe { SetPriorityClass(GetCurrentProcess(), class) };
+    if result == 0 {
+        bail!(
+            "Failed to set process priority: {}",
+            io::Error::last_os_error()
+        );
     }
+    Ok(())
 }
@@ -0,0 +1,539 @@
+use std::{
+    env,
+    fmt::{self, Display},
+    fs,
+    path::Path,
+    process::{self, Command, Stdio},
+    thread::available_parallelism,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
+use itertools::Itertools;
+use serde_json::{json, Map, Value};
+use tracing::{info, warn};
+
+use crate::{
+    absolute_path,
+    input::{get_video_frame_count, get_video_frame_rate},
+    progress::{parse_ffmpeg_progress, run_with_progress, ProgressSource},
+    python::python_path,
+    tool_log::is_dry_run,
+};
+
+/// PSNR of identical frames is infinite, which would swamp any average
+const MAX_PSNR: f64 = 100.0;
+/// How different a frame must be from the last to start a new scene, from 0 to 1
+const SCENE_THRESHOLD: f64 = 0.3;
+
+/// A full-reference quality metric which ffmpeg can compute for every frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Metric {
+    Psnr,
+    Ssim,
+    /// Requires ffmpeg 7.1 or newer
+    Xpsnr,
+    /// Requires ffmpeg built with libvmaf
+    Vmaf,
+    /// Requires the vship or vszip Vapoursynth plugin
+    Ssimulacra2,
+}
+
+impl Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Metric::Psnr => "psnr",
+                Metric::Ssim => "ssim",
+                Metric::Xpsnr => "xpsnr",
+                Metric::Vmaf => "vmaf",
+                Metric::Ssimulacra2 => "ssimulacra2",
+            }
+        )
+    }
+}
+
+impl Metric {
+    /// The metrics computed when none are chosen, leaving out the slow ones
+    pub const DEFAULT: &'static [Metric] = &[Metric::Psnr, Metric::Ssim, Metric::Xpsnr];
+
+    /// The ffmpeg filter which computes this metric, writing every frame's score to `stats_file`
+    fn filter(self, stats_file: &str) -> String {
+        match self {
+            Metric::Vmaf => format!(
+                "libvmaf=shortest=1:log_fmt=json:log_path={}:n_threads={}",
+                stats_file,
+                available_parallelism().map_or(1, |threads| threads.get())
+            ),
+            _ => format!("{}=shortest=1:stats_file={}", self, stats_file),
+        }
+    }
+
+    /// Parses the score of every frame from the stats file
+    fn parse_stats(self, stats: &str) -> Result<Vec<f64>> {
+        if self == Metric::Ssimulacra2 {
+            return Ok(stats
+                .lines()
+                .map(|line| line.trim().parse())
+                .collect::<Result<_, _>>()?);
+        }
+        if self == Metric::Vmaf {
+            let log: Value = serde_json::from_str(stats)?;
+            return log
+                .get("frames")
+                .and_then(Value::as_array)
+                .ok_or_else(|| anyhow!("VMAF log has no frames"))?
+                .iter()
+                .map(|frame| {
+                    frame
+                        .pointer("/metrics/vmaf")
+                        .and_then(Value::as_f64)
+                        .ok_or_else(|| anyhow!("VMAF log is missing a score"))
+                })
+                .collect();
+        }
+        let key = match self {
+            Metric::Psnr => "psnr_avg:",
+            Metric::Ssim => "All:",
+            // XPSNR is only reported per plane, and luma is what matters most
+            Metric::Xpsnr => "XPSNR y:",
+            Metric::Vmaf | Metric::Ssimulacra2 => unreachable!(),
+        };
+        Ok(stats
+            .lines()
+            .filter_map(|line| {
+                let (_, rest) = line.split_once(key)?;
+                let score: f64 = rest.split_whitespace().next()?.parse().ok()?;
+                Some(match self {
+                    Metric::Psnr | Metric::Xpsnr => score.min(MAX_PSNR),
+                    _ => score,
+                })
+            })
+            .collect())
+    }
+}
+
+/// Aggregate scores over a set of frames
+#[derive(Debug, Clone, Copy)]
+pub struct ScoreSummary {
+    pub mean: f64,
+    pub min: f64,
+    /// The mean of the worst 1% of frames
+    pub low_1pct: f64,
+}
+
+impl ScoreSummary {
+    pub fn from_scores(scores: &[f64]) -> Option<Self> {
+        if scores.is_empty() {
+            return None;
+        }
+        let mut sorted = scores.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).expect("Scores are not NaN"));
+        let low_count = std::cmp::max(sorted.len() / 100, 1);
+        Some(ScoreSummary {
+            mean: mean(&sorted),
+            min: sorted[0],
+            low_1pct: mean(&sorted[..low_count]),
+        })
+    }
+
+    pub fn to_json(self) -> Value {
+        json!({ "mean": self.mean, "min": self.min, "low_1pct": self.low_1pct })
+    }
+}
+
+/// Formats scores as an object keyed by the metric
+pub fn scores_json(scores: &[(Metric, ScoreSummary)]) -> Value {
+    Value::Object(
+        scores
+            .iter()
+            .map(|(metric, summary)| (metric.to_string(), summary.to_json()))
+            .collect(),
+    )
+}
+
+fn mean(scores: &[f64]) -> f64 {
+    scores.iter().sum::<f64>() / scores.len() as f64
+}
+
+/// Computes `metric` for every frame of `distorted` against `reference`.
+///
+/// Both videos are aligned to their first frame,
+/// and `distorted` is scaled to the size of `reference` if they differ.
+/// Scoring stops at the end of the shorter video.
+///
+/// If `reference_ranges` are given, only those ranges of frames in the reference
+/// are used, joined together, for scoring a sample made from the same ranges.
+///
+/// A dry run only prints the command, and returns no scores.
+pub fn frame_scores(
+    reference: &Path,
+    reference_ranges: Option<&[(u32, u32)]>,
+    distorted: &Path,
+    metric: Metric,
+) -> Result<Vec<f64>> {
+    let stats_dir = env::temp_dir();
+    let stats_file = format!(
+        "mp4batch-{}-{}.{}.log",
+        process::id(),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos()),
+        metric
+    );
+    let mut command = match metric {
+        Metric::Ssimulacra2 => {
+            let script = stats_dir.join(&stats_file).with_extension("py");
+            write_ssimulacra2_script(
+                &script,
+                reference,
+                reference_ranges,
+                distorted,
+                &stats_dir.join(&stats_file),
+            )?;
+            let mut command = Command::new(if cfg!(windows) { "python" } else { "python3" });
+            command.arg(script);
+            command
+        }
+        _ => ffmpeg_metric_command(
+            reference,
+            reference_ranges,
+            distorted,
+            metric,
+            &stats_dir,
+            &stats_file,
+        )?,
+    };
+    let status = run_with_progress(
+        command.stdin(Stdio::null()),
+        format!(
+            "{} {}",
+            metric.to_string().to_uppercase(),
+            distorted.file_name().unwrap_or_default().to_string_lossy()
+        ),
+        get_video_frame_count(distorted).unwrap_or(0),
+        ProgressSource::Stderr(parse_ffmpeg_progress),
+    )
+    .map_err(|e| anyhow!("Failed to execute {:?}: {}", command.get_program(), e))?;
+    let stats = fs::read_to_string(stats_dir.join(&stats_file));
+    let _ = fs::remove_file(stats_dir.join(&stats_file));
+    let _ = fs::remove_file(stats_dir.join(&stats_file).with_extension("py"));
+    if !status.success() {
+        bail!(
+            "Failed to compute {}: Exited with code {:x}",
+            metric,
+            status.code().unwrap_or(-1)
+        );
+    }
+    if is_dry_run() {
+        return Ok(Vec::new());
+    }
+    let scores = metric.parse_stats(&stats?)?;
+    if scores.is_empty() {
+        bail!("No {} scores were reported", metric);
+    }
+    Ok(scores)
+}
+
+fn ffmpeg_metric_command(
+    reference: &Path,
+    reference_ranges: Option<&[(u32, u32)]>,
+    distorted: &Path,
+    metric: Metric,
+    stats_dir: &Path,
+    stats_file: &str,
+) -> Result<Command> {
+    let select = reference_ranges.map_or_else(String::new, |ranges| {
+        format!(
+            "select='{}',",
+            ranges
+                .iter()
+                .map(|(first, last)| format!("between(n,{},{})", first, last))
+                .join("+")
+        )
+    });
+    let filter = format!(
+        "[0:v]{}setpts=PTS-STARTPTS[ref];[1:v]setpts=PTS-STARTPTS[dist];[dist][ref]scale2ref=\
+         flags=bicubic[scaled][ref2];[scaled][ref2]{}",
+        select,
+        metric.filter(stats_file)
+    );
+    let mut command = Command::new("ffmpeg");
+    // ffmpeg runs in the temp directory, so the stats file can be named
+    // without escaping a full path for the filter graph
+    command
+        .current_dir(stats_dir)
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("level+error")
+        .arg("-stats")
+        .arg("-i")
+        .arg(absolute_path(reference)?)
+        .arg("-i")
+        .arg(absolute_path(distorted)?)
+        .arg("-filter_complex")
+        .arg(filter)
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+    Ok(command)
+}
+
+/// Writes a Python script which scores every frame with SSIMULACRA2 through Vapoursynth,
+/// on the GPU with vship if it is installed, or otherwise on the CPU with vszip.
+///
+/// Progress is printed the same way as ffmpeg, so it can be parsed the same way.
+fn write_ssimulacra2_script(
+    script: &Path,
+    reference: &Path,
+    reference_ranges: Option<&[(u32, u32)]>,
+    distorted: &Path,
+    stats_file: &Path,
+) -> Result<()> {
+    let select = reference_ranges.map_or_else(String::new, |ranges| {
+        format!(
+            "ref = {}\n",
+            ranges
+                .iter()
+                .map(|(first, last)| format!("ref[{}:{}]", first, last + 1))
+                .join(" + ")
+        )
+    });
+    let path_string = |path: &Path| -> Result<String> { Ok(python_path(&absolute_path(path)?)) };
+    let contents = format!(
+        r#"import sys
+import vapoursynth as vs
+core = vs.core
+ref = core.lsmas.LWLibavSource(source={reference})
+{select}dist = core.lsmas.LWLibavSource(source={distorted})
+if dist.width != ref.width or dist.height != ref.height:
+    dist = dist.resize.Bicubic(width=ref.width, height=ref.height)
+frames = min(ref.num_frames, dist.num_frames)
+ref = ref[:frames]
+dist = dist[:frames]
+if hasattr(core, 'vship'):
+    scores = core.vship.SSIMULACRA2(ref, dist)
+else:
+    ref = ref.resize.Bicubic(format=vs.RGBS, matrix_in_s='709')
+    dist = dist.resize.Bicubic(format=vs.RGBS, matrix_in_s='709')
+    scores = core.vszip.Metrics(ref, dist, mode=0)
+with open({stats_file}, 'w') as stats:
+    for n, frame in enumerate(scores.frames()):
+        stats.write('%f\n' % frame.props['_SSIMULACRA2'])
+        print('frame=%d' % (n + 1), file=sys.stderr, flush=True)
+"#,
+        reference = path_string(reference)?,
+        select = select,
+        distorted = path_string(distorted)?,
+        stats_file = path_string(stats_file)?,
+    );
+    fs::write(script, contents)?;
+    Ok(())
+}
+
+/// Returns the first frame of every scene in the video.
+///
+/// Scenes shorter than a second are merged into the previous one,
+/// so that flashes and fades don't produce a wall of tiny scenes.
+pub fn detect_scenes(input: &Path) -> Result<Vec<usize>> {
+    let fps = get_video_frame_rate(input)?;
+    let output = Command::new("ffmpeg")
+        .arg("-hide_banner")
+        .arg("-nostats")
+        .arg("-i")
+        .arg(absolute_path(input)?)
+        .arg("-vf")
+        .arg(format!(
+            "setpts=PTS-STARTPTS,select='gt(scene,{})',metadata=print:file=-",
+            SCENE_THRESHOLD
+        ))
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .output()
+        .map_err(|e| anyhow!("Failed to execute ffmpeg: {}", e))?;
+    if !output.status.success() {
+        bail!("Failed to detect scenes in {}", input.display());
+    }
+    let min_length = std::cmp::max(fps.round() as usize, 1);
+    let mut scenes = vec![0];
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let time = line
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("pts_time:"))
+            .and_then(|time| time.parse::<f64>().ok());
+        if let Some(time) = time {
+            let frame = (time * fps).round() as usize;
+            if frame >= scenes.last().expect("Scenes are not empty") + min_length {
+                scenes.push(frame);
+            }
+        }
+    }
+    Ok(scenes)
+}
+
+/// Compares two finished encodes, printing the scores for each scene and overall.
+///
+/// `a` is used as the reference.
+pub fn print_comparison(a: &Path, b: &Path, metrics: &[Metric], as_json: bool) -> Result<()> {
+    let mut results = Vec::new();
+    for &metric in metrics {
+        info!("Computing {}", metric);
+        match frame_scores(a, None, b, metric) {
+            Ok(scores) => results.push((metric, scores)),
+            Err(e) => warn!("{}", e),
+        }
+    }
+    if results.is_empty() {
+        bail!("None of the metrics could be computed");
+    }
+    let frames = results
+        .iter()
+        .map(|(_, scores)| scores.len())
+        .min()
+        .expect("Results are not empty");
+    // Nothing is scored in a dry run
+    if frames == 0 {
+        if as_json {
+            println!(
+                "{:#}",
+                json!({
+                    "reference": a.to_string_lossy(),
+                    "distorted": b.to_string_lossy(),
+                    "frames": 0,
+                    "overall": {},
+                    "scenes": [],
+                })
+            );
+        } else {
+            println!("No scores");
+        }
+        return Ok(());
+    }
+    let mut starts = detect_scenes(a)?;
+    starts.retain(|&start| start < frames);
+    let scenes = starts
+        .iter()
+        .zip(starts.iter().skip(1).chain(Some(&frames)))
+        .map(|(&start, &end)| {
+            let summaries = results
+                .iter()
+                .map(|(_, scores)| {
+                    ScoreSummary::from_scores(&scores[start..end]).expect("Scenes are not empty")
+                })
+                .collect::<Vec<_>>();
+            (start, end, summaries)
+        })
+        .collect::<Vec<_>>();
+    let overall = results
+        .iter()
+        .map(|(_, scores)| {
+            ScoreSummary::from_scores(&scores[..frames]).expect("Scores are not empty")
+        })
+        .collect::<Vec<_>>();
+
+    if as_json {
+        let summaries_json = |summaries: &[ScoreSummary]| {
+            results
+                .iter()
+                .zip(summaries)
+                .map(|((metric, _), summary)| (metric.to_string(), summary.to_json()))
+                .collect::<Map<_, _>>()
+        };
+        let scenes = scenes
+            .iter()
+            .map(|(start, end, summaries)| {
+                let mut scene = summaries_json(summaries);
+                scene.insert("start".to_string(), json!(start));
+                scene.insert("end".to_string(), json!(end - 1));
+                Value::Object(scene)
+            })
+            .collect::<Vec<_>>();
+        println!(
+            "{:#}",
+            json!({
+                "reference": a.to_string_lossy(),
+                "distorted": b.to_string_lossy(),
+                "frames": frames,
+                "overall": summaries_json(&overall),
+                "scenes": scenes,
+            })
+        );
+        return Ok(());
+    }
+
+    let header = results
+        .iter()
+        .map(|(metric, _)| format!("{:>9}", metric.to_string().to_uppercase()))
+        .collect::<String>();
+    println!("{:<6}  {:<15}{}", "SCENE", "FRAMES", header);
+    for (i, (start, end, summaries)) in scenes.iter().enumerate() {
+        println!(
+            "{:<6}  {:<15}{}",
+            i + 1,
+            format!("{}-{}", start, end - 1),
+            format_scores(summaries.iter().map(|summary| summary.mean))
+        );
+    }
+    println!();
+    println!(
+        "{:<23}{}",
+        "MEAN",
+        format_scores(overall.iter().map(|summary| summary.mean))
+    );
+    println!(
+        "{:<23}{}",
+        "1% LOW",
+        format_scores(overall.iter().map(|summary| summary.low_1pct))
+    );
+    println!(
+        "{:<23}{}",
+        "MIN",
+        format_scores(overall.iter().map(|summary| summary.min))
+    );
+    Ok(())
+}
+
+fn format_scores(scores: impl Iterator<Item = f64>) -> String {
+    scores.map(|score| format!("{:>9.4}", score)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn ssimulacra2_script_compiles() {
+        let dir = env::temp_dir().join(format!("mp4batch-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("ssimulacra2.py");
+        write_ssimulacra2_script(
+            &script,
+            Path::new("/media/it's \"here\".mkv"),
+            Some(&[(0, 9), (20, 29)]),
+            Path::new("/media/out.mkv"),
+            &dir.join("ssimulacra2.log"),
+        )
+        .unwrap();
+        let python = if cfg!(windows) { "python" } else { "python3" };
+        let result = Command::new(python)
+            .arg("-m")
+            .arg("py_compile")
+            .arg(&script)
+            .output();
+        let _ = fs::remove_dir_all(&dir);
+        match result {
+            Ok(output) => assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(e) => eprintln!("Skipping, since {} could not be run: {}", python, e),
+        }
+    }
+}
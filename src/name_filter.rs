@@ -0,0 +1,101 @@
+use std::{
+    fmt::{self, Display},
+    path::Path,
+    str::FromStr,
+};
+
+use regex::Regex;
+
+/// A pattern matched against input filenames, either a glob such as `*E0[1-6]*`,
+/// or a regex when prefixed with `re:`
+#[derive(Debug, Clone)]
+pub struct NamePattern {
+    source: String,
+    regex: Regex,
+}
+
+impl FromStr for NamePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let regex = match s.strip_prefix("re:") {
+            Some(pattern) => Regex::new(pattern),
+            None => Regex::new(&glob_to_regex(s)?),
+        }
+        .map_err(|e| format!("Invalid pattern '{}': {}", s, e))?;
+        Ok(NamePattern {
+            source: s.to_string(),
+            regex,
+        })
+    }
+}
+
+impl Display for NamePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl NamePattern {
+    fn matches(&self, name: &str) -> bool {
+        self.regex.is_match(name)
+    }
+}
+
+/// Translates a glob into an anchored regex. Supports `*`, `?`, and `[...]` classes,
+/// which may be negated with `!`.
+fn glob_to_regex(glob: &str) -> Result<String, String> {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        closed = true;
+                        break;
+                    }
+                    if matches!(c, '\\' | '[' | '^' | '&' | '~') {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                if !closed {
+                    return Err(format!("Unclosed '[' in pattern '{}'", glob));
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Ok(regex)
+}
+
+/// Which inputs of a directory batch to process, by filename
+#[derive(Debug, Clone, Default)]
+pub struct NameFilter {
+    pub include: Vec<NamePattern>,
+    pub exclude: Vec<NamePattern>,
+}
+
+impl NameFilter {
+    /// Whether the file matches any include pattern, if there are any,
+    /// and no exclude pattern
+    pub fn matches(&self, path: &Path) -> bool {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return false,
+        };
+        (self.include.is_empty() || self.include.iter().any(|pattern| pattern.matches(&name)))
+            && !self.exclude.iter().any(|pattern| pattern.matches(&name))
+    }
+}
@@ -0,0 +1,137 @@
+use std::path::Path;
+
+use anyhow::Result;
+use once_cell::sync::OnceCell;
+use regex::{Captures, Regex};
+
+use crate::{absolute_path, input::get_video_dimensions, output::Output};
+
+/// The season and episode found in a filename
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Episode {
+    /// Absent for absolute numbering, like `Show - 105`
+    pub season: Option<u32>,
+    pub episode: u32,
+}
+
+/// Finds the episode in a filename like `Show S01E05`, `Show 1x05`, `Show - 05` or `Show Ep05`
+pub fn detect_episode(name: &str) -> Option<Episode> {
+    static SEASON: OnceCell<Regex> = OnceCell::new();
+    static ABSOLUTE: OnceCell<Regex> = OnceCell::new();
+    let season = SEASON.get_or_init(|| {
+        Regex::new(r"(?i)\bs(\d{1,2})[ ._-]?e(\d{1,4})(?:v\d+)?\b|\b(\d{1,2})x(\d{2,3})\b")
+            .expect("Valid regex")
+    });
+    if let Some(captures) = season.captures(name) {
+        let season = captures.get(1).or_else(|| captures.get(3))?;
+        let episode = captures.get(2).or_else(|| captures.get(4))?;
+        return Some(Episode {
+            season: season.as_str().parse().ok(),
+            episode: episode.as_str().parse().ok()?,
+        });
+    }
+    // Release groups add a `v2` to fixed episodes
+    let absolute = ABSOLUTE.get_or_init(|| {
+        Regex::new(r"(?i)(?:\bep?\.?\s*|\s-\s|#)(\d{1,4})(?:v\d+)?\b").expect("Valid regex")
+    });
+    absolute.captures(name).and_then(|captures| {
+        Some(Episode {
+            season: None,
+            episode: captures[1].parse().ok()?,
+        })
+    })
+}
+
+/// Values for the placeholders of the `--title` and `--name` templates
+pub struct NameTokens {
+    /// The name of the directory the input is in
+    pub show: String,
+    /// The input's filename, without its extension
+    pub name: String,
+    pub episode: Option<Episode>,
+    /// The height of the output
+    pub resolution: Option<u32>,
+    /// The encode settings which are part of the default output filename
+    pub settings: String,
+}
+
+impl NameTokens {
+    pub fn new(input_vpy: &Path, settings: String) -> Result<Self> {
+        let name = input_vpy
+            .file_stem()
+            .expect("File should have a name")
+            .to_string_lossy()
+            .into_owned();
+        let show = absolute_path(input_vpy)?
+            .parent()
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(NameTokens {
+            show,
+            episode: detect_episode(&name),
+            name,
+            resolution: None,
+            settings,
+        })
+    }
+
+    /// Fills in the placeholders of `template`.
+    ///
+    /// `{season}` and `{episode}` are zero padded to two digits, or to the width
+    /// given after a colon, such as `{episode:3}`. `{absolute}` is the episode
+    /// without padding. Placeholders with no value are left empty.
+    pub fn fill(&self, template: &str) -> String {
+        static PATTERN: OnceCell<Regex> = OnceCell::new();
+        let pattern =
+            PATTERN.get_or_init(|| Regex::new(r"\{([a-z]+)(?::(\d))?\}").expect("Valid regex"));
+        let filled = pattern.replace_all(template, |captures: &Captures| {
+            let width = captures
+                .get(2)
+                .map_or(2, |width| width.as_str().parse().expect("Width is a digit"));
+            let padded = |value: Option<u32>| {
+                value.map_or_else(String::new, |value| {
+                    format!("{:0width$}", value, width = width)
+                })
+            };
+            match &captures[1] {
+                "show" => self.show.clone(),
+                "name" => self.name.clone(),
+                "season" => padded(self.episode.and_then(|episode| episode.season)),
+                "episode" => padded(self.episode.map(|episode| episode.episode)),
+                "absolute" => self
+                    .episode
+                    .map_or_else(String::new, |episode| episode.episode.to_string()),
+                "resolution" => self
+                    .resolution
+                    .map_or_else(String::new, |height| format!("{}p", height)),
+                "settings" => self.settings.clone(),
+                _ => captures[0].to_string(),
+            }
+        });
+        filled.trim().to_string()
+    }
+}
+
+/// The height of the output, without having to build its script
+pub fn output_height(input_vpy: &Path, output: &Output) -> Result<u32> {
+    if let Some((_, height)) = output.video.resolution {
+        return Ok(height);
+    }
+    let height = get_video_dimensions(input_vpy)?.height;
+    Ok(match output.video.crop {
+        Some((_, _, top, bottom)) => height - top - bottom,
+        None => height,
+    })
+}
+
+/// Replaces characters which aren't allowed in filenames on some platforms
+pub fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
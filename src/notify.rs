@@ -0,0 +1,121 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use notify_rust::Notification;
+use serde_json::json;
+use size::Size;
+use tracing::warn;
+
+/// The outcome of processing a single input
+pub struct FileReport<'a> {
+    pub input: &'a Path,
+    pub outputs: &'a [PathBuf],
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+impl FileReport<'_> {
+    fn summary(&self) -> String {
+        let filename = self
+            .input
+            .file_name()
+            .expect("File should have a name")
+            .to_string_lossy();
+        let duration = format_duration(self.duration);
+        match self.error {
+            Some(ref error) => format!("Failed {} after {}: {}", filename, duration, error),
+            None => {
+                let sizes = self
+                    .outputs
+                    .iter()
+                    .filter_map(|output| output.metadata().ok())
+                    .map(|meta| Size::from_bytes(meta.len()).format().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("Finished {} in {} ({})", filename, duration, sizes)
+            }
+        }
+    }
+}
+
+/// Where to report on the progress of a batch
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    pub webhook_url: Option<String>,
+    pub desktop: bool,
+}
+
+impl Notifier {
+    pub fn file_finished(&self, report: &FileReport) {
+        if let Some(ref url) = self.webhook_url {
+            warn_on_error(send_webhook(url, report));
+        }
+        // Successes are only worth a desktop notification once the whole batch is done
+        if self.desktop && report.error.is_some() {
+            warn_on_error(show_desktop_notification(
+                "Encode failed",
+                &report.summary(),
+            ));
+        }
+    }
+
+    pub fn batch_finished(&self, completed: usize, failed: usize) {
+        if self.desktop {
+            warn_on_error(show_desktop_notification(
+                "Batch finished",
+                &format!("{} completed, {} failed", completed, failed),
+            ));
+        }
+    }
+}
+
+fn warn_on_error(result: Result<()>) {
+    if let Err(err) = result {
+        warn!("{}", err);
+    }
+}
+
+/// POSTs a JSON report to a webhook.
+///
+/// The payload includes a `content` field with a readable summary,
+/// so it can be pointed directly at a Discord webhook.
+fn send_webhook(url: &str, report: &FileReport) -> Result<()> {
+    let payload = json!({
+        "content": report.summary(),
+        "file": report.input.to_string_lossy(),
+        "status": if report.error.is_some() { "failed" } else { "completed" },
+        "error": report.error,
+        "duration_secs": report.duration.as_secs(),
+        "outputs": report
+            .outputs
+            .iter()
+            .map(|output| json!({
+                "path": output.to_string_lossy(),
+                "size": output.metadata().map(|meta| meta.len()).ok(),
+            }))
+            .collect::<Vec<_>>(),
+    });
+    ureq::post(url)
+        .set("Content-Type", "application/json")
+        .send_string(&payload.to_string())
+        .map_err(|e| anyhow!("Failed to send notification: {}", e))?;
+    Ok(())
+}
+
+fn show_desktop_notification(summary: &str, body: &str) -> Result<()> {
+    Notification::new()
+        .appname("mp4batch")
+        .summary(summary)
+        .body(body)
+        .show()
+        .map_err(|e| anyhow!("Failed to show desktop notification: {}", e))?;
+    Ok(())
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
@@ -0,0 +1,78 @@
+use std::{
+    cmp::Reverse,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use tracing::warn;
+
+use crate::input::find_source_file;
+
+/// The order a directory batch processes its inputs in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InputOrder {
+    /// Natural sort by path, so episode 2 comes before episode 10
+    Name,
+    /// Most recently modified scripts first
+    Newest,
+    /// Smallest source videos first, which are usually the shortest
+    Smallest,
+}
+
+/// Reorders inputs which are already in name order.
+///
+/// Inputs named in the list file come first, in the order it lists them,
+/// followed by the rest in the chosen order.
+pub fn order_inputs(
+    inputs: Vec<PathBuf>,
+    order: InputOrder,
+    list_file: Option<&Path>,
+) -> Result<Vec<PathBuf>> {
+    let mut inputs = inputs;
+    match order {
+        InputOrder::Name => (),
+        InputOrder::Newest => inputs.sort_by_cached_key(|input| {
+            Reverse(
+                fs::metadata(input)
+                    .and_then(|meta| meta.modified())
+                    .unwrap_or(SystemTime::UNIX_EPOCH),
+            )
+        }),
+        InputOrder::Smallest => inputs.sort_by_cached_key(|input| {
+            fs::metadata(find_source_file(input)).map_or(u64::MAX, |meta| meta.len())
+        }),
+    }
+
+    let list_file = match list_file {
+        Some(list_file) => list_file,
+        None => return Ok(inputs),
+    };
+    let list = fs::read_to_string(list_file)
+        .map_err(|e| anyhow!("Unable to read {}: {}", list_file.display(), e))?;
+    let mut listed = Vec::new();
+    for entry in list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        // Entries may be filenames or paths, and name a raw video instead of its script
+        let entry = Path::new(entry);
+        let script = entry.with_extension("vpy");
+        match inputs
+            .iter()
+            .position(|input| input.ends_with(entry) || input.ends_with(&script))
+        {
+            Some(pos) => listed.push(inputs.remove(pos)),
+            None => warn!(
+                "{} is listed in {} but is not an input",
+                entry.display(),
+                list_file.display()
+            ),
+        }
+    }
+    listed.extend(inputs);
+    Ok(listed)
+}
@@ -1,15 +1,17 @@
 use std::{
     fmt::Display,
-    path::Path,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 
-use ansi_term::Colour::Green;
 use anyhow::Result;
+use tracing::info;
 
 use crate::{
     cli::{Track, TrackSource},
-    find_source_file,
+    find_source_map,
+    tool_log::{is_dry_run, print_dry_run_command, run_logged, spawn_logged},
+    work_dir::work_path,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -58,7 +60,7 @@ impl AudioEncoder {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 struct FirstPassData {
     pub integrated: f32,
     pub true_peak: f32,
@@ -77,27 +79,29 @@ pub fn convert_audio(
 ) -> Result<()> {
     if output.exists() {
         // TODO: Verify the audio output is complete
-        eprintln!("Audio output already exists, reusing");
+        info!("Audio output already exists, reusing");
         return Ok(());
     }
 
     let mut fp_data = None;
     if normalize {
-        eprintln!("Normalizing audio");
-        let result = Command::new("ffmpeg")
+        info!("Normalizing audio");
+        let mut analysis = Command::new("ffmpeg");
+        analysis
             .arg("-hide_banner")
             .arg("-y")
             .arg("-i")
             .arg(match audio_track.source {
-                TrackSource::FromVideo(_) => find_source_file(input),
+                TrackSource::FromVideo(_) => find_source_map(input).audio,
                 TrackSource::External(ref path) => path.clone(),
+                TrackSource::ScriptAudio(index) => script_audio_path(input, index),
             })
             .arg("-map")
             .arg(format!(
                 "0:a:{}",
                 match audio_track.source {
                     TrackSource::FromVideo(id) => id,
-                    TrackSource::External(_) => 0,
+                    TrackSource::External(_) | TrackSource::ScriptAudio(_) => 0,
                 }
             ))
             .arg("-map_chapters")
@@ -106,61 +110,66 @@ pub fn convert_audio(
             .arg("loudnorm=I=-16:dual_mono=true:TP=-1.5:LRA=11:print_format=summary")
             .arg("-f")
             .arg("null")
-            .arg("-")
-            .output()?;
-
-        let stderr = String::from_utf8_lossy(&result.stderr);
-        let norm_data = stderr
-            .lines()
-            .skip_while(|line| !line.starts_with("[Parsed_loudnorm_"))
-            .skip(1)
-            .collect::<Vec<_>>();
-        fp_data = Some(FirstPassData {
-            integrated: norm_data
-                .iter()
-                .find(|line| line.starts_with("Input Integrated:"))
-                .unwrap()
-                .split_whitespace()
-                .nth(2)
-                .unwrap()
-                .parse()
-                .unwrap(),
-            true_peak: norm_data
-                .iter()
-                .find(|line| line.starts_with("Input True Peak:"))
-                .unwrap()
-                .split_whitespace()
-                .nth(3)
-                .unwrap()
-                .parse()
-                .unwrap(),
-            lra: norm_data
-                .iter()
-                .find(|line| line.starts_with("Input LRA:"))
-                .unwrap()
-                .split_whitespace()
-                .nth(2)
-                .unwrap()
-                .parse()
-                .unwrap(),
-            threshold: norm_data
-                .iter()
-                .find(|line| line.starts_with("Input Threshold:"))
-                .unwrap()
-                .split_whitespace()
-                .nth(2)
-                .unwrap()
-                .parse()
-                .unwrap(),
-            offset: norm_data
-                .iter()
-                .find(|line| line.starts_with("Target Offset:"))
-                .unwrap()
-                .split_whitespace()
-                .nth(2)
-                .unwrap()
-                .parse()
-                .unwrap(),
+            .arg("-");
+        fp_data = Some(if is_dry_run() {
+            // The measurements are only known once the analysis has run
+            print_dry_run_command(&analysis);
+            FirstPassData::default()
+        } else {
+            let result = analysis.output()?;
+            let stderr = String::from_utf8_lossy(&result.stderr);
+            let norm_data = stderr
+                .lines()
+                .skip_while(|line| !line.starts_with("[Parsed_loudnorm_"))
+                .skip(1)
+                .collect::<Vec<_>>();
+            FirstPassData {
+                integrated: norm_data
+                    .iter()
+                    .find(|line| line.starts_with("Input Integrated:"))
+                    .unwrap()
+                    .split_whitespace()
+                    .nth(2)
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+                true_peak: norm_data
+                    .iter()
+                    .find(|line| line.starts_with("Input True Peak:"))
+                    .unwrap()
+                    .split_whitespace()
+                    .nth(3)
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+                lra: norm_data
+                    .iter()
+                    .find(|line| line.starts_with("Input LRA:"))
+                    .unwrap()
+                    .split_whitespace()
+                    .nth(2)
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+                threshold: norm_data
+                    .iter()
+                    .find(|line| line.starts_with("Input Threshold:"))
+                    .unwrap()
+                    .split_whitespace()
+                    .nth(2)
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+                offset: norm_data
+                    .iter()
+                    .find(|line| line.starts_with("Target Offset:"))
+                    .unwrap()
+                    .split_whitespace()
+                    .nth(2)
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+            }
         });
     }
 
@@ -173,15 +182,16 @@ pub fn convert_audio(
         .arg("-y")
         .arg("-i")
         .arg(match audio_track.source {
-            TrackSource::FromVideo(_) => find_source_file(input),
+            TrackSource::FromVideo(_) => find_source_map(input).audio,
             TrackSource::External(ref path) => path.clone(),
+            TrackSource::ScriptAudio(index) => script_audio_path(input, index),
         })
         .arg("-map")
         .arg(format!(
             "0:a:{}",
             match audio_track.source {
                 TrackSource::FromVideo(id) => id,
-                TrackSource::External(_) => 0,
+                TrackSource::External(_) | TrackSource::ScriptAudio(_) => 0,
             }
         ))
         .arg("-map_chapters")
@@ -223,8 +233,9 @@ pub fn convert_audio(
             }
             let channels = get_channel_count(
                 &match audio_track.source {
-                    TrackSource::FromVideo(_) => find_source_file(input),
+                    TrackSource::FromVideo(_) => find_source_map(input).audio,
                     TrackSource::External(ref path) => path.clone(),
+                    TrackSource::ScriptAudio(index) => script_audio_path(input, index),
                 },
                 audio_track,
             )?;
@@ -244,9 +255,8 @@ pub fn convert_audio(
     };
     command.arg(output);
 
-    let status = command
-        .status()
-        .map_err(|e| anyhow::anyhow!("Failed to execute ffmpeg: {}", e))?;
+    let status =
+        run_logged(&mut command).map_err(|e| anyhow::anyhow!("Failed to execute ffmpeg: {}", e))?;
     if status.success() {
         Ok(())
     } else {
@@ -254,28 +264,36 @@ pub fn convert_audio(
     }
 }
 
-pub fn save_vpy_audio(input: &Path, output: &Path) -> Result<()> {
+/// Where the script's audio output `index` is rendered to
+pub fn script_audio_path(input: &Path, index: u8) -> PathBuf {
+    work_path(input).with_extension(format!("o{}.flac", index))
+}
+
+/// Renders the script's audio output `index` to flac
+pub fn save_vpy_audio(input: &Path, index: u8) -> Result<()> {
+    let output = script_audio_path(input, index);
     let filename = input
         .file_name()
         .expect("File should have a name")
         .to_string_lossy();
     let mut pipe = if filename.ends_with(".vpy") {
-        Command::new("vspipe")
-            .arg("-o")
-            .arg("1")
-            .arg("-c")
-            .arg("wav")
-            .arg(input)
-            .arg("-")
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(|e| anyhow::anyhow!("Failed to start vspipe to extract wav audio: {}", e))?
+        spawn_logged(
+            Command::new("vspipe")
+                .arg("-o")
+                .arg(index.to_string())
+                .arg("-c")
+                .arg("wav")
+                .arg(input)
+                .arg("-")
+                .stdout(Stdio::piped()),
+        )
+        .map_err(|e| anyhow::anyhow!("Failed to start vspipe to extract wav audio: {}", e))?
     } else {
         panic!("Unrecognized input type");
     };
 
     let mut command = Command::new("ffmpeg");
-    let status = command
+    command
         .arg("-hide_banner")
         .arg("-loglevel")
         .arg("level+error")
@@ -288,10 +306,9 @@ pub fn save_vpy_audio(input: &Path, output: &Path) -> Result<()> {
         .arg("-compression_level")
         .arg("9")
         .arg(output)
-        .stdin(pipe.stdout.take().expect("stdout should be writeable"))
-        .stderr(Stdio::inherit())
-        .status()
-        .map_err(|e| anyhow::anyhow!("Failed to execute ffmpeg: {}", e))?;
+        .stdin(pipe.stdout());
+    let status =
+        run_logged(&mut command).map_err(|e| anyhow::anyhow!("Failed to execute ffmpeg: {}", e))?;
     pipe.wait()?;
     if !status.success() {
         anyhow::bail!(
@@ -300,11 +317,7 @@ pub fn save_vpy_audio(input: &Path, output: &Path) -> Result<()> {
         );
     }
 
-    eprintln!(
-        "{} {}",
-        Green.bold().paint("[Success]"),
-        Green.paint("Finished extracting Vapoursynth audio"),
-    );
+    info!(success = true, "Finished extracting Vapoursynth audio");
 
     Ok(())
 }
@@ -318,7 +331,7 @@ fn get_channel_count(path: &Path, audio_track: &Track) -> Result<u32> {
             "a:{}",
             match audio_track.source {
                 TrackSource::FromVideo(id) => id,
-                TrackSource::External(_) => 0,
+                TrackSource::External(_) | TrackSource::ScriptAudio(_) => 0,
             }
         ))
         .arg("-show_entries")
@@ -1,15 +1,22 @@
 use std::{
     borrow::Cow,
+    ffi::OsString,
+    fmt::{self, Display, Write as _},
     path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 
-use ansi_term::Colour::Yellow;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use which::which;
 
 use crate::{
     cli::{Track, TrackSource},
-    find_source_file, get_audio_delay_ms,
+    error::Error,
+    get_audio_delay_ms, get_subtitle_tracks,
+    input::SourceMap,
+    tool_log::run_logged,
 };
 
 pub use self::{audio::*, video::*};
@@ -25,13 +32,34 @@ pub struct Output {
     pub sub_tracks: Vec<Track>,
 }
 
+/// The delay of an audio track, which is assumed to be 0 if it can't be read
+fn audio_delay_or_zero(input: &Path, track: usize) -> i32 {
+    get_audio_delay_ms(input, track).unwrap_or_else(|e| {
+        warn!(
+            "Unable to read the delay of audio track {} of {}, assuming 0: {}",
+            track,
+            input.display(),
+            e
+        );
+        0
+    })
+}
+
+/// Muxes the encoded streams into `output`, with chapters from `sources` if it has any.
+///
+/// If `reproducible` is set, the muxing date and writing application are left out
+/// and the UIDs are fixed, so that muxing the same streams again gives an identical file.
+#[allow(clippy::too_many_arguments)]
 pub fn mux_video(
-    input: &Path,
+    sources: &SourceMap,
     video: &Path,
+    timecodes: Option<&Path>,
+    display_dimensions: Option<(u32, u32)>,
     audios: &[(PathBuf, Track, AudioEncoder)],
     subtitles: &[(PathBuf, bool, bool)],
     copy_fonts: bool,
     ignore_delay: bool,
+    reproducible: bool,
     output: &Path,
 ) -> Result<()> {
     let mut extension = output
@@ -40,49 +68,54 @@ pub fn mux_video(
         .to_string_lossy();
 
     if extension != "mkv" && !subtitles.is_empty() {
-        eprintln!(
-            "{} {}",
-            Yellow.bold().paint("[Warning]"),
-            Yellow.paint("Subtitles present, forcing mkv"),
-        );
+        warn!("Subtitles present, forcing mkv");
+        extension = Cow::Borrowed("mkv");
+    }
+    if extension != "mkv" && timecodes.is_some() {
+        warn!("Variable frame rate video, forcing mkv");
         extension = Cow::Borrowed("mkv");
     }
     if extension == "mkv" {
         let mut track_order = vec!["0:0".to_string()];
         let mut inputs_read = 1;
         let mut command = Command::new("mkvmerge");
+        command.arg("--output").arg(output);
+        if reproducible {
+            command
+                .arg("--no-date")
+                .arg("--segment-uid")
+                .arg(fixed_segment_uid(output));
+        }
         command
-            .arg("--output")
-            .arg(output)
             .arg("--no-audio")
             .arg("--no-subtitles")
             .arg("--no-attachments")
             .arg("--no-chapters")
+            .arg("--no-track-tags")
             .arg("--language")
-            .arg("0:en")
-            .arg("(")
-            .arg(video)
-            .arg(")");
+            .arg("0:en");
+        if let Some(timecodes) = timecodes {
+            let mut arg = OsString::from("0:");
+            arg.push(timecodes);
+            command.arg("--timestamps").arg(arg);
+        }
+        if let Some((width, height)) = display_dimensions {
+            command
+                .arg("--display-dimensions")
+                .arg(format!("0:{}x{}", width, height));
+        }
+        command.arg("(").arg(video).arg(")");
         if !audios.is_empty() {
             for audio in audios {
-                let audio_delay = if ignore_delay || audio.2 == AudioEncoder::Copy {
+                let audio_delay = match audio.1.source {
                     // If we're copying, mkvtoolnix copies the sync automatically.
-                    0
-                } else {
+                    _ if ignore_delay || audio.2 == AudioEncoder::Copy => 0,
+                    // The script renders its audio in sync with its video.
+                    TrackSource::ScriptAudio(_) => 0,
                     // If we're reencoding the audio, then we need to manually apply the sync.
-                    // Note that mediainfo can give unparseable and wrong results for some formats
-                    // like PCM, so we just assume 0 for those.
-                    get_audio_delay_ms(
-                        &match audio.1.source {
-                            TrackSource::FromVideo(_) => find_source_file(input),
-                            TrackSource::External(ref path) => path.clone(),
-                        },
-                        match audio.1.source {
-                            TrackSource::FromVideo(id) => id as usize,
-                            TrackSource::External(_) => 0,
-                        },
-                    )
-                    .unwrap_or(0)
+                    // If neither mediainfo nor ffprobe can tell, we just assume 0.
+                    TrackSource::FromVideo(id) => audio_delay_or_zero(&sources.audio, id as usize),
+                    TrackSource::External(ref path) => audio_delay_or_zero(path, 0),
                 };
 
                 command
@@ -91,7 +124,8 @@ pub fn mux_video(
                     .arg("--no-video")
                     .arg("--no-subtitles")
                     .arg("--no-attachments")
-                    .arg("--no-chapters");
+                    .arg("--no-chapters")
+                    .arg("--no-track-tags");
                 if audio_delay != 0 {
                     command.arg("--sync").arg(format!("{}:{}", 0, audio_delay));
                 }
@@ -115,6 +149,7 @@ pub fn mux_video(
                     .arg("--no-video")
                     .arg("--no-audio")
                     .arg("--no-attachments")
+                    .arg("--no-track-tags")
                     .arg("--language")
                     .arg("0:en")
                     .arg("--sub-charset")
@@ -130,17 +165,28 @@ pub fn mux_video(
                 inputs_read += 1;
             }
         }
+        if let Some(ref chapters) = sources.chapters {
+            command
+                .arg("--no-video")
+                .arg("--no-audio")
+                .arg("--no-subtitles")
+                .arg("--no-attachments")
+                .arg("--no-track-tags")
+                .arg("--no-global-tags")
+                .arg("(")
+                .arg(chapters)
+                .arg(")");
+        }
         if copy_fonts {
-            eprintln!("WARNING: copy fonts not currently implemented for mkv");
+            warn!("Copying fonts is not currently implemented for mkv");
         }
         command.arg("--track-order").arg(track_order.join(","));
 
-        let status = command.status()?;
-        if status.success() {
-            Ok(())
-        } else {
+        let status = run_logged(&mut command)?;
+        if !status.success() {
             anyhow::bail!("Failed to mux video");
         }
+        write_track_statistics(output)
     } else {
         let mut command = Command::new("ffmpeg");
         command
@@ -157,13 +203,19 @@ pub fn mux_video(
             command.arg("-i").arg(&subtitle.0);
         }
         if copy_fonts {
-            command.arg("-i").arg(input);
+            command.arg("-i").arg(&sources.subtitles);
+        }
+        if let Some(ref chapters) = sources.chapters {
+            command.arg("-i").arg(chapters);
         }
         command
             .arg("-vcodec")
             .arg("copy")
             .arg("-acodec")
             .arg("copy");
+        if let Some((width, height)) = display_dimensions {
+            command.arg("-aspect").arg(format!("{}:{}", width, height));
+        }
         if !subtitles.is_empty() {
             command.arg("-c:s").arg("copy");
         }
@@ -192,7 +244,8 @@ pub fn mux_video(
                 .arg("-map")
                 .arg(format!("{}:t?", 1 + audios.len() + subtitles.len()));
         } else {
-            let fonts_dir = input
+            let fonts_dir = sources
+                .subtitles
                 .parent()
                 .expect("File should have parent dir")
                 .join("fonts");
@@ -216,13 +269,9 @@ pub fn mux_video(
                         "woff" => "font/woff",
                         "woff2" => "font/woff2",
                         _ => {
-                            eprintln!(
-                                "{} {}",
-                                Yellow.bold().paint("[Warning]"),
-                                Yellow.paint(format!(
-                                    "Attachment with unrecognized extension skipped: {}",
-                                    font.path().to_string_lossy()
-                                )),
+                            warn!(
+                                "Attachment with unrecognized extension skipped: {}",
+                                font.path().to_string_lossy()
                             );
                             continue;
                         }
@@ -236,11 +285,25 @@ pub fn mux_video(
                 }
             }
         }
+        if sources.chapters.is_some() {
+            let chapters_input = 1 + audios.len() + subtitles.len() + usize::from(copy_fonts);
+            command.arg("-map_chapters").arg(chapters_input.to_string());
+        }
         if extension == "mp4" {
             command.arg("-movflags").arg("+faststart");
         }
+        if reproducible {
+            // Leaves out the encoder tags and creation time
+            command
+                .arg("-fflags")
+                .arg("+bitexact")
+                .arg("-flags:v")
+                .arg("+bitexact")
+                .arg("-flags:a")
+                .arg("+bitexact");
+        }
 
-        let status = command.arg(output).status()?;
+        let status = run_logged(command.arg(output))?;
         if status.success() {
             Ok(())
         } else {
@@ -249,7 +312,118 @@ pub fn mux_video(
     }
 }
 
-pub fn extract_subtitles(input: &Path, track: u8, output: &Path) -> Result<()> {
+/// Sets the segment title of a muxed mkv
+pub fn set_title(output: &Path, title: &str) -> Result<()> {
+    let status = run_logged(
+        Command::new("mkvpropedit")
+            .arg(output)
+            .arg("--edit")
+            .arg("info")
+            .arg("--set")
+            .arg(format!("title={}", title)),
+    )?;
+    if !status.success() {
+        anyhow::bail!("Failed to set the title");
+    }
+    Ok(())
+}
+
+/// Rewrites the statistics tags (bitrate, duration, frame and byte counts) of every track
+/// from the muxed file itself, since the tags of the intermediates are left out.
+///
+/// mkvpropedit is optional, so without it the output keeps mkvmerge's tags.
+fn write_track_statistics(output: &Path) -> Result<()> {
+    if which("mkvpropedit").is_err() {
+        warn!("mkvpropedit not installed or not in PATH, unable to write track statistics");
+        return Ok(());
+    }
+    let status = run_logged(
+        Command::new("mkvpropedit")
+            .arg(output)
+            .arg("--add-track-statistics-tags"),
+    )?;
+    if !status.success() {
+        anyhow::bail!("Failed to write track statistics tags");
+    }
+    Ok(())
+}
+
+/// A segment UID derived from the output's filename,
+/// so that it stays the same when the output is muxed again
+fn fixed_segment_uid(output: &Path) -> String {
+    let name = output.file_name().expect("File should have a name");
+    let digest = Sha256::digest(name.to_string_lossy().as_bytes());
+    let mut uid = String::with_capacity(32);
+    for byte in &digest[..16] {
+        let _ = write!(uid, "{:02x}", byte);
+    }
+    uid
+}
+
+/// A format subtitle tracks are extracted in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubtitleFormat {
+    Ass,
+    Srt,
+    WebVtt,
+    /// Blu-ray bitmap subtitles
+    Pgs,
+    /// DVD bitmap subtitles, written as an `.idx` and `.sub` pair
+    VobSub,
+}
+
+impl SubtitleFormat {
+    /// The format to extract a track with ffprobe's codec name in.
+    ///
+    /// MP4 text tracks can't be copied out as they are, so they are converted to SRT.
+    pub fn from_codec(codec: &str) -> Option<Self> {
+        Some(match codec {
+            "ass" | "ssa" => SubtitleFormat::Ass,
+            "subrip" | "srt" | "mov_text" | "text" => SubtitleFormat::Srt,
+            "webvtt" => SubtitleFormat::WebVtt,
+            "hdmv_pgs_subtitle" => SubtitleFormat::Pgs,
+            "dvd_subtitle" => SubtitleFormat::VobSub,
+            _ => return None,
+        })
+    }
+
+    /// The extension of the extracted file, which for VobSub is the file to mux
+    pub const fn extension(self) -> &'static str {
+        match self {
+            SubtitleFormat::Ass => "ass",
+            SubtitleFormat::Srt => "srt",
+            SubtitleFormat::WebVtt => "vtt",
+            SubtitleFormat::Pgs => "sup",
+            SubtitleFormat::VobSub => "idx",
+        }
+    }
+}
+
+impl Display for SubtitleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SubtitleFormat::Ass => "ASS",
+            SubtitleFormat::Srt => "SRT",
+            SubtitleFormat::WebVtt => "WebVTT",
+            SubtitleFormat::Pgs => "PGS",
+            SubtitleFormat::VobSub => "VobSub",
+        })
+    }
+}
+
+/// Extracts the `track`th subtitle track of `input` into `output` in `format`.
+///
+/// Text tracks are converted if they aren't already in `format`,
+/// but bitmap tracks have to already be in it.
+pub fn extract_subtitles(
+    input: &Path,
+    track: u8,
+    format: SubtitleFormat,
+    output: &Path,
+) -> Result<()> {
+    if format == SubtitleFormat::VobSub {
+        return extract_vobsub(input, track, output);
+    }
     let mut command = Command::new("ffmpeg");
     command
         .stderr(Stdio::null())
@@ -260,15 +434,43 @@ pub fn extract_subtitles(input: &Path, track: u8, output: &Path) -> Result<()> {
         .arg("-y")
         .arg("-i")
         .arg(input)
-        .arg("-c:s")
-        .arg("copy")
         .arg("-map")
         .arg(format!("0:s:{}", track))
-        .arg(output);
-    let status = command.arg(output).status()?;
+        .arg("-c:s")
+        .arg(match format {
+            SubtitleFormat::Srt => "srt",
+            SubtitleFormat::WebVtt => "webvtt",
+            _ => "copy",
+        });
+    let status = run_logged(command.arg(output))?;
     if status.success() {
         Ok(())
     } else {
         anyhow::bail!("Failed to extract subtitles");
     }
 }
+
+/// ffmpeg can't write VobSub files, so they are extracted with mkvextract,
+/// which only reads Matroska
+fn extract_vobsub(input: &Path, track: u8, output: &Path) -> Result<()> {
+    which("mkvextract").map_err(|_| Error::ToolMissing("mkvextract".to_string()))?;
+    let tracks = get_subtitle_tracks(input)?;
+    let stream_index = tracks
+        .get(track as usize)
+        .ok_or_else(|| anyhow!("{} has no subtitle track {}", input.display(), track))?
+        .stream_index;
+    let mut target = OsString::from(format!("{}:", stream_index));
+    target.push(output);
+    let status = run_logged(
+        Command::new("mkvextract")
+            .arg(input)
+            .arg("tracks")
+            .arg(target),
+    )?;
+    // mkvextract exits with 1 for warnings
+    if matches!(status.code(), Some(0) | Some(1)) {
+        Ok(())
+    } else {
+        anyhow::bail!("Failed to extract VobSub subtitles, which requires a Matroska source");
+    }
+}
@@ -14,13 +14,11 @@ pub fn build_aom_args_string(
     profile: Profile,
     colorimetry: &Colorimetry,
     threads: NonZeroUsize,
+    tiles: (u8, u8),
 ) -> String {
     // Note: aom doesn't have a parameter to control full vs limited range
     let bd = dimensions.bit_depth;
-    let tile_cols = i32::from(dimensions.width >= 2000);
-    let tile_rows = i32::from(
-        dimensions.height >= 2000 || (dimensions.height >= 1550 && dimensions.width >= 3600),
-    );
+    let (tile_cols, tile_rows) = tiles;
     let arnr_str = if profile == Profile::Anime || profile == Profile::AnimeDetailed {
         1
     } else {
@@ -1,39 +1,182 @@
 use std::{
+    ffi::OsString,
     fmt::Display,
+    fs, iter,
     num::NonZeroUsize,
-    path::Path,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
     str::FromStr,
     thread::available_parallelism,
 };
 
-use ansi_term::Colour::{Green, Yellow};
 use anyhow::Result;
+use clap::ValueEnum;
+use itertools::Itertools;
+use tracing::{info, warn};
 
 use crate::{
     absolute_path,
-    input::{get_video_frame_count, Colorimetry, PixelFormat, VideoDimensions},
+    cancel::spawn_tracked,
+    error::Error,
+    input::{
+        find_all_source_files, find_source_file, get_video_frame_count, get_video_pixel_format,
+        script_output, Colorimetry, ColorimetryOverrides, PixelFormat, VideoDimensions,
+    },
     output::video::{
         aom::build_aom_args_string, rav1e::build_rav1e_args_string,
         svt_av1::build_svtav1_args_string, x264::build_x264_args_string,
-        x265::build_x265_args_string,
+        x265::build_x265_args_string, y4m::ScriptPipe,
     },
+    output::{AudioEncoder, Output},
+    progress::{parse_ffmpeg_progress, run_with_progress, run_with_progress_from, ProgressSource},
+    short_hash,
+    tool_log::{is_dry_run, run_logged},
+    work_dir::work_path,
 };
 
-pub use self::x264::convert_video_x264;
+pub use self::{
+    svt_av1::{convert_video_svtav1, svtav1_direct_args, SvtTuning},
+    x264::{convert_video_x264, x264_args},
+};
 
 mod aom;
 mod rav1e;
 mod svt_av1;
 mod x264;
 mod x265;
+mod y4m;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct VideoOutput {
     pub encoder: VideoEncoder,
     pub output_ext: String,
     pub bit_depth: Option<u8>,
     pub resolution: Option<(u32, u32)>,
+    /// Pixels to crop from the left, right, top and bottom, before resizing
+    pub crop: Option<(u32, u32, u32, u32)>,
+    /// Target VMAF score for av1an's target quality mode
+    pub target_quality: Option<f32>,
+    pub probes: Option<u32>,
+    pub probing_rate: Option<u32>,
+    pub chunk_method: Option<String>,
+    /// Raw arguments appended to the generated encoder arguments
+    pub extra_args: Option<String>,
+    /// Raw arguments appended to the av1an command line
+    pub av1an_args: Option<String>,
+    /// Overrides the log2 tile columns and rows chosen from the resolution
+    pub tiles: Option<(u8, u8)>,
+    /// A film grain table to apply instead of synthesizing photon noise
+    pub grain_table: Option<PathBuf>,
+    /// Match the grain of the source with grav1synth after encoding
+    pub post_grain_synth: bool,
+    pub svt_tuning: SvtTuning,
+    pub overrides: ProfileOverrides,
+    /// Zones passed to x264's `--zones`
+    pub x264_zones: Option<String>,
+    /// Comma separated frames to force keyframes at, replacing `--force-keyframes`
+    pub force_keyframes: Option<String>,
+    /// Replaces the colorimetry read from the script's frame props
+    pub colorimetry: ColorimetryOverrides,
+    /// Which of the script's video outputs to encode, for scripts with several
+    pub script_output: u8,
+    /// The sample aspect ratio to flag, for video with non-square pixels
+    pub sar: Option<(u32, u32)>,
+}
+
+impl VideoOutput {
+    /// The dimensions this output's filters give a script with the `input` dimensions
+    pub fn filtered_dimensions(&self, input: VideoDimensions) -> VideoDimensions {
+        let mut dimensions = input;
+        if let Some((left, right, top, bottom)) = self.crop {
+            dimensions.width -= left + right;
+            dimensions.height -= top + bottom;
+        }
+        if let Some((width, height)) = self.resolution {
+            dimensions.width = width;
+            dimensions.height = height;
+        }
+        if let Some(bit_depth) = self.bit_depth {
+            dimensions.bit_depth = bit_depth;
+        }
+        dimensions
+    }
+
+    /// The display dimensions of a video with `dimensions`, for encoders
+    /// which can't flag the sample aspect ratio in the video itself
+    pub fn display_dimensions(&self, dimensions: VideoDimensions) -> Option<(u32, u32)> {
+        let (num, den) = self.sar?;
+        match self.encoder {
+            VideoEncoder::Aom { .. } | VideoEncoder::Rav1e { .. } | VideoEncoder::SvtAv1 { .. } => {
+                let width = (dimensions.width as u64 * num as u64 + den as u64 / 2) / den as u64;
+                Some((width as u32, dimensions.height))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the video is encoded through av1an, rather than by the encoder itself
+    pub fn uses_av1an(&self) -> bool {
+        !matches!(
+            self.encoder,
+            VideoEncoder::Copy
+                | VideoEncoder::X264 { .. }
+                | VideoEncoder::SvtAv1 { direct: true, .. }
+        )
+    }
+
+    /// The arguments the encoder is given, built the same way as for the encode
+    pub fn encoder_args(
+        &self,
+        dimensions: VideoDimensions,
+        colorimetry: &Colorimetry,
+        force_keyframes: &Option<String>,
+        options: &Av1anOptions,
+    ) -> Result<String> {
+        match self.encoder {
+            VideoEncoder::Copy => Ok(String::new()),
+            VideoEncoder::X264 {
+                crf,
+                profile,
+                compat,
+            } => x264_args(
+                crf,
+                profile,
+                compat,
+                dimensions,
+                force_keyframes,
+                colorimetry,
+                &self.overrides,
+                self.sar,
+                self.x264_zones.as_deref(),
+                self.extra_args.as_deref(),
+            ),
+            VideoEncoder::SvtAv1 {
+                crf,
+                speed,
+                profile,
+                grain,
+                direct: true,
+            } => Ok(svtav1_direct_args(
+                crf,
+                speed,
+                profile,
+                grain,
+                dimensions,
+                colorimetry,
+                self.extra_args.as_deref(),
+                self.tiles,
+                self.grain_table.is_some(),
+                &self.svt_tuning,
+            )),
+            _ => av1an_encoder_args(
+                self,
+                dimensions,
+                colorimetry,
+                &Av1anWorkers::new(self, dimensions, options)?,
+                force_keyframes,
+            ),
+        }
+    }
 }
 
 impl Default for VideoOutput {
@@ -42,15 +185,232 @@ impl Default for VideoOutput {
             encoder: VideoEncoder::X264 {
                 crf: 18,
                 profile: Profile::Film,
-                compat: false,
+                compat: Compat::None,
             },
             output_ext: "mkv".to_string(),
             bit_depth: None,
             resolution: None,
+            crop: None,
+            target_quality: None,
+            probes: None,
+            probing_rate: None,
+            chunk_method: None,
+            extra_args: None,
+            av1an_args: None,
+            tiles: None,
+            grain_table: None,
+            post_grain_synth: false,
+            svt_tuning: SvtTuning::default(),
+            overrides: ProfileOverrides::default(),
+            x264_zones: None,
+            force_keyframes: None,
+            colorimetry: ColorimetryOverrides::default(),
+            script_output: 0,
+            sar: None,
         }
     }
 }
 
+/// Settings which apply to every av1an encode in a run
+#[derive(Debug, Clone, Default)]
+pub struct Av1anOptions {
+    pub keep_temp: bool,
+    /// Whether interrupted encodes resume from their last finished chunk, which is the default
+    pub resume_chunks: bool,
+    /// NUMA nodes to spread the av1an workers across
+    pub numa_nodes: Option<Vec<u32>>,
+    /// Memory budget for all workers combined, in MiB
+    pub max_memory_mb: Option<u64>,
+    /// Number of files being encoded at once, which split the cores and memory between them
+    pub concurrent_jobs: usize,
+    /// Whether scene changes are exported to or imported from the file next to the script
+    pub scenes: Option<ScenesMode>,
+}
+
+/// How the scene changes detected by av1an are kept next to the script,
+/// so they stay the same between encodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ScenesMode {
+    /// Copy the detected scene changes to the file after encoding
+    Export,
+    /// Encode with the scene changes in the file instead of detecting them
+    Import,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LosslessCodec {
+    /// libx264 at QP 0
+    X264,
+    /// NVENC H.264 lossless, requires an Nvidia GPU
+    NvencH264,
+    /// NVENC HEVC lossless, requires an Nvidia GPU
+    NvencHevc,
+    /// FFV1, smaller than x264 for noisy sources but slower to decode
+    Ffv1,
+    /// UT Video, very fast to decode but large
+    Utvideo,
+}
+
+/// Which of the source's audio tracks to include in the lossless
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LosslessAudioTracks {
+    All,
+    /// Numbered among the audio tracks, as in `at=`
+    Tracks(Vec<u8>),
+}
+
+impl FromStr for LosslessAudioTracks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("all") {
+            return Ok(LosslessAudioTracks::All);
+        }
+        s.split(',')
+            .map(|track| {
+                track.trim().parse().map_err(|_| {
+                    format!(
+                        "Invalid audio track '{}', expected track numbers or 'all'",
+                        track
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(LosslessAudioTracks::Tracks)
+    }
+}
+
+/// Audio copied into the lossless, so it can be used on its own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LosslessAudio {
+    pub tracks: LosslessAudioTracks,
+    /// Transcode the tracks to FLAC instead of copying them
+    pub flac: bool,
+}
+
+impl LosslessAudio {
+    /// ffmpeg arguments mapping the tracks from the ffmpeg input numbered `input`
+    fn ffmpeg_args(&self, input: usize) -> Vec<String> {
+        let mut args = Vec::new();
+        match self.tracks {
+            LosslessAudioTracks::All => {
+                args.push("-map".to_string());
+                args.push(format!("{}:a?", input));
+            }
+            LosslessAudioTracks::Tracks(ref tracks) => {
+                for track in tracks {
+                    args.push("-map".to_string());
+                    args.push(format!("{}:a:{}", input, track));
+                }
+            }
+        }
+        args.push("-c:a".to_string());
+        args.push(if self.flac { "flac" } else { "copy" }.to_string());
+        args
+    }
+}
+
+impl Default for LosslessCodec {
+    fn default() -> Self {
+        LosslessCodec::X264
+    }
+}
+
+impl Display for LosslessCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "{}",
+            match self {
+                LosslessCodec::X264 => "x264",
+                LosslessCodec::NvencH264 => "nvenc-h264",
+                LosslessCodec::NvencHevc => "nvenc-hevc",
+                LosslessCodec::Ffv1 => "ffv1",
+                LosslessCodec::Utvideo => "utvideo",
+            }
+        )
+    }
+}
+
+impl LosslessCodec {
+    const fn ffmpeg_args(self) -> &'static [&'static str] {
+        match self {
+            LosslessCodec::X264 => &["-vcodec", "libx264", "-preset", "ultrafast", "-qp", "0"],
+            LosslessCodec::NvencH264 => &["-vcodec", "h264_nvenc", "-tune", "lossless"],
+            LosslessCodec::NvencHevc => &["-vcodec", "hevc_nvenc", "-tune", "lossless"],
+            // Multithreaded, intra-only, with per-slice CRCs to detect corruption
+            LosslessCodec::Ffv1 => &[
+                "-vcodec",
+                "ffv1",
+                "-level",
+                "3",
+                "-slices",
+                "16",
+                "-slicecrc",
+                "1",
+                "-g",
+                "1",
+            ],
+            LosslessCodec::Utvideo => &["-vcodec", "utvideo", "-pred", "median"],
+        }
+    }
+
+    /// The pixel format to encode a lossless of this input with,
+    /// or `None` if this codec can't encode it losslessly
+    fn pix_fmt(self, dimensions: VideoDimensions) -> Option<String> {
+        let supported = match self {
+            LosslessCodec::X264 => dimensions.bit_depth <= 10,
+            LosslessCodec::NvencH264 => {
+                dimensions.bit_depth == 8 && dimensions.pixel_format != PixelFormat::Yuv422
+            }
+            LosslessCodec::NvencHevc => match dimensions.pixel_format {
+                PixelFormat::Yuv420 => dimensions.bit_depth <= 10,
+                PixelFormat::Yuv422 => false,
+                PixelFormat::Yuv444 => dimensions.bit_depth == 8,
+            },
+            LosslessCodec::Ffv1 => true,
+            LosslessCodec::Utvideo => {
+                dimensions.bit_depth == 8
+                    || (dimensions.bit_depth == 10
+                        && dimensions.pixel_format != PixelFormat::Yuv420)
+            }
+        };
+        if !supported {
+            return None;
+        }
+        if self == LosslessCodec::NvencHevc && dimensions.bit_depth == 10 {
+            // NVENC only accepts 10-bit input in the semi-planar format
+            return Some("p010le".to_string());
+        }
+        Some(ffmpeg_pix_fmt(dimensions))
+    }
+
+    /// Checks whether this codec can actually be used on this machine
+    pub fn is_available(self) -> bool {
+        if !matches!(self, LosslessCodec::NvencH264 | LosslessCodec::NvencHevc) {
+            return true;
+        }
+        // ffmpeg may be built with NVENC support on a machine with no usable GPU,
+        // so the only reliable check is to try encoding something.
+        Command::new("ffmpeg")
+            .arg("-hide_banner")
+            .arg("-loglevel")
+            .arg("quiet")
+            .arg("-f")
+            .arg("lavfi")
+            .arg("-i")
+            .arg("nullsrc=s=256x256:d=0.1")
+            .args(self.ffmpeg_args())
+            .arg("-f")
+            .arg("null")
+            .arg("-")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map_or(false, |status| status.success())
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Profile {
     Film,
@@ -111,6 +471,248 @@ impl Profile {
     }
 }
 
+/// Playback targets which restrict the encoder's settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compat {
+    None,
+    /// Hardware decoding through DXVA, and other older hardware decoders
+    Dxva,
+    /// Blu-ray compliant streams, for authoring discs [x264 only]
+    Bluray,
+    /// Chromecast and Google TV streaming devices
+    Chromecast,
+    /// The web browsers and media players built into smart TVs
+    WebTv,
+}
+
+impl Default for Compat {
+    fn default() -> Self {
+        Compat::None
+    }
+}
+
+impl FromStr for Compat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_ref() {
+            "0" => Compat::None,
+            "1" | "dxva" => Compat::Dxva,
+            "bluray" => Compat::Bluray,
+            "chromecast" => Compat::Chromecast,
+            "webtv" => Compat::WebTv,
+            _ => {
+                return Err("Unrecognized compat target");
+            }
+        })
+    }
+}
+
+impl Compat {
+    /// The part of the output filename identifying the target
+    pub const fn suffix(self) -> &'static str {
+        match self {
+            Compat::None => "",
+            Compat::Dxva => "-compat",
+            Compat::Bluray => "-bluray",
+            Compat::Chromecast => "-chromecast",
+            Compat::WebTv => "-webtv",
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Compat::None => "none",
+            Compat::Dxva => "dxva",
+            Compat::Bluray => "bluray",
+            Compat::Chromecast => "chromecast",
+            Compat::WebTv => "webtv",
+        }
+    }
+
+    /// What the devices of this target are able to play
+    pub const fn limits(self) -> Option<CompatLimits> {
+        Some(match self {
+            Compat::None => return None,
+            Compat::Dxva => CompatLimits {
+                h264: ("4.1", 50000, 78125),
+                hevc: ("5.1", None),
+                max_bit_depth: 8,
+                max_height: 1080,
+                audio: None,
+            },
+            Compat::Bluray => CompatLimits {
+                h264: ("4.1", 40000, 30000),
+                hevc: ("5.1", None),
+                max_bit_depth: 8,
+                max_height: 1080,
+                // Discs need AC-3, DTS or LPCM, which have to be authored separately
+                audio: Some(&[]),
+            },
+            Compat::Chromecast => CompatLimits {
+                h264: ("4.1", 40000, 50000),
+                hevc: ("5.1", Some((40000, 40000))),
+                max_bit_depth: 10,
+                max_height: 2160,
+                audio: Some(&[AudioEncoder::Aac, AudioEncoder::Flac, AudioEncoder::Opus]),
+            },
+            Compat::WebTv => CompatLimits {
+                h264: ("4.0", 20000, 25000),
+                hevc: ("4.1", Some((20000, 20000))),
+                max_bit_depth: 8,
+                max_height: 1080,
+                audio: Some(&[AudioEncoder::Aac]),
+            },
+        })
+    }
+
+    /// Warns about any settings of the output which the target can't play
+    pub fn check_output(self, output: &Output) {
+        let limits = match self.limits() {
+            Some(limits) => limits,
+            None => return,
+        };
+        let target = self.name();
+        if let Some(bit_depth) = output.video.bit_depth {
+            limits.check_bit_depth(target, bit_depth);
+        }
+        if let Some((_, height)) = output.video.resolution {
+            limits.check_height(target, height);
+        }
+        if let Some(audio) = limits.audio {
+            let encoder = output.audio.encoder;
+            if encoder != AudioEncoder::Copy && !audio.contains(&encoder) {
+                warn!("{} audio is not supported by {}", encoder, target);
+            }
+        }
+    }
+}
+
+/// The constraints of a compatibility target
+#[derive(Debug, Clone, Copy)]
+pub struct CompatLimits {
+    /// Lowest H.264 level to signal, and the VBV max rate and buffer size to use at it
+    pub h264: (&'static str, u32, u32),
+    /// Lowest HEVC level to signal, and the VBV max rate and buffer size to use at it
+    /// if restricted beyond the level
+    pub hevc: (&'static str, Option<(u32, u32)>),
+    pub max_bit_depth: u8,
+    pub max_height: u32,
+    /// Audio codecs which can be played, if restricted
+    pub audio: Option<&'static [AudioEncoder]>,
+}
+
+impl CompatLimits {
+    pub fn check_bit_depth(&self, target: &str, bit_depth: u8) {
+        if bit_depth > self.max_bit_depth {
+            warn!(
+                "{}-bit video is not supported by {}, which allows up to {}-bit",
+                bit_depth, target, self.max_bit_depth
+            );
+        }
+    }
+
+    pub fn check_height(&self, target: &str, height: u32) {
+        if height > self.max_height {
+            warn!(
+                "{}p video is not supported by {}, which allows up to {}p",
+                height, target, self.max_height
+            );
+        }
+    }
+
+    /// Checks the dimensions of the video as it will be encoded
+    pub fn check_dimensions(&self, target: &str, dimensions: VideoDimensions) {
+        self.check_bit_depth(target, dimensions.bit_depth);
+        self.check_height(target, dimensions.height);
+    }
+
+    /// The H.264 level and VBV for the video, which is the target's own
+    /// unless the resolution and frame rate need a higher level
+    pub fn h264_level(&self, dimensions: VideoDimensions) -> (&'static str, u32, u32) {
+        let macroblocks = ((dimensions.width + 15) / 16) * ((dimensions.height + 15) / 16);
+        let rate = macroblocks as f64 * dimensions.fps.0 as f64 / dimensions.fps.1 as f64;
+        let (level, _, _, maxrate, bufsize) =
+            pick_level(H264_LEVELS, self.h264.0, macroblocks, rate);
+        if level == self.h264.0 {
+            self.h264
+        } else {
+            (level, maxrate, bufsize)
+        }
+    }
+
+    /// The HEVC level and VBV for the video, which is the target's own
+    /// unless the resolution and frame rate need a higher level
+    pub fn hevc_level(&self, dimensions: VideoDimensions) -> (&'static str, Option<(u32, u32)>) {
+        let samples = dimensions.width * dimensions.height;
+        let rate = samples as f64 * dimensions.fps.0 as f64 / dimensions.fps.1 as f64;
+        let (level, _, _, maxrate, bufsize) = pick_level(HEVC_LEVELS, self.hevc.0, samples, rate);
+        if level == self.hevc.0 {
+            self.hevc
+        } else {
+            (level, self.hevc.1.map(|_| (maxrate, bufsize)))
+        }
+    }
+}
+
+/// H.264 levels, with the maximum macroblocks per frame and per second,
+/// and the VBV max rate and buffer size of the High profile
+const H264_LEVELS: &[(&str, u32, u64, u32, u32)] = &[
+    ("4.0", 8192, 245_760, 25_000, 31_250),
+    ("4.1", 8192, 245_760, 62_500, 78_125),
+    ("4.2", 8704, 522_240, 62_500, 78_125),
+    ("5.0", 22_080, 589_824, 168_750, 168_750),
+    ("5.1", 36_864, 983_040, 300_000, 300_000),
+    ("5.2", 36_864, 2_073_600, 300_000, 300_000),
+];
+
+/// HEVC levels, with the maximum luma samples per frame and per second,
+/// and the VBV max rate and buffer size of the Main tier
+const HEVC_LEVELS: &[(&str, u32, u64, u32, u32)] = &[
+    ("4.0", 2_228_224, 66_846_720, 12_000, 12_000),
+    ("4.1", 2_228_224, 133_693_440, 20_000, 20_000),
+    ("5.0", 8_912_896, 267_386_880, 25_000, 25_000),
+    ("5.1", 8_912_896, 534_773_760, 40_000, 40_000),
+    ("5.2", 8_912_896, 1_069_547_520, 60_000, 60_000),
+];
+
+/// Picks the lowest level from `minimum` upwards which fits the frame size and rate,
+/// or the highest level if none do
+fn pick_level(
+    levels: &'static [(&'static str, u32, u64, u32, u32)],
+    minimum: &str,
+    frame_size: u32,
+    rate: f64,
+) -> (&'static str, u32, u64, u32, u32) {
+    let start = levels
+        .iter()
+        .position(|level| level.0 == minimum)
+        .expect("Level should be in the table");
+    let level = levels[start..]
+        .iter()
+        .find(|&&(_, max_size, max_rate, ..)| frame_size <= max_size && rate <= max_rate as f64);
+    match level {
+        Some(&level) => level,
+        None => {
+            let level = *levels.last().expect("Level table is not empty");
+            warn!(
+                "Video is too large or fast for level {}, the highest level supported",
+                level.0
+            );
+            level
+        }
+    }
+}
+
+/// Settings which replace the values derived from the profile for x264 and x265
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ProfileOverrides {
+    pub psy_rd: Option<f32>,
+    pub aq_strength: Option<f32>,
+    pub qcomp: Option<f32>,
+    pub bframes: Option<u8>,
+}
+
 pub fn extract_video(input: &Path, output: &Path) -> Result<()> {
     let mut command = Command::new("ffmpeg");
     command
@@ -127,9 +729,8 @@ pub fn extract_video(input: &Path, output: &Path) -> Result<()> {
         .arg("0:v:0")
         .arg(output);
 
-    let status = command
-        .status()
-        .map_err(|e| anyhow::anyhow!("Failed to execute ffmpeg: {}", e))?;
+    let status =
+        run_logged(&mut command).map_err(|e| anyhow::anyhow!("Failed to execute ffmpeg: {}", e))?;
     if status.success() {
         Ok(())
     } else {
@@ -137,81 +738,470 @@ pub fn extract_video(input: &Path, output: &Path) -> Result<()> {
     }
 }
 
-pub fn create_lossless(
+fn ffmpeg_pix_fmt(dimensions: VideoDimensions) -> String {
+    match (dimensions.bit_depth, dimensions.pixel_format) {
+        (8, PixelFormat::Yuv420) => "yuv420p".to_string(),
+        (8, PixelFormat::Yuv422) => "yuv422p".to_string(),
+        (8, PixelFormat::Yuv444) => "yuv444p".to_string(),
+        (bd, PixelFormat::Yuv420) => format!("yuv420p{}le", bd),
+        (bd, PixelFormat::Yuv422) => format!("yuv422p{}le", bd),
+        (bd, PixelFormat::Yuv444) => format!("yuv444p{}le", bd),
+    }
+}
+
+/// Checks that the lossless has the same bit depth and subsampling as the
+/// script it was made from. If probing it fails, assume it does.
+fn lossless_format_matches(lossless: &Path, dimensions: VideoDimensions) -> bool {
+    get_video_pixel_format(lossless).map_or(true, |(pixel_format, bit_depth)| {
+        pixel_format == dimensions.pixel_format && bit_depth == dimensions.bit_depth
+    })
+}
+
+/// Checks whether the script or any of its sources
+/// have been modified since the lossless was created
+pub fn is_lossless_stale(input: &Path, lossless: &Path) -> bool {
+    let lossless_modified = match lossless.metadata().and_then(|meta| meta.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return false,
+    };
+    iter::once(input.to_path_buf())
+        .chain(find_all_source_files(input))
+        .filter_map(|path| path.metadata().and_then(|meta| meta.modified()).ok())
+        .any(|modified| modified > lossless_modified)
+}
+
+/// Runs an ffmpeg `command` writing framemd5 to stdout, returning the hashes
+fn framemd5(command: &mut Command) -> Result<Vec<String>> {
+    let (child, _tracked) = spawn_tracked(command.stdout(Stdio::piped()).stderr(Stdio::piped()))
+        .map_err(|e| anyhow::anyhow!("Failed to execute ffmpeg: {}", e))?;
+    let result = child.wait_with_output()?;
+    if !result.status.success() {
+        anyhow::bail!(
+            "ffmpeg failed while hashing frames ({}): {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+    Ok(parse_framemd5(&result.stdout))
+}
+
+/// Hashes of the decoded frames from ffmpeg's framemd5 output
+fn parse_framemd5(output: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(output)
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+        .filter_map(|line| line.rsplit(',').next())
+        .map(|hash| hash.trim().to_string())
+        .collect()
+}
+
+/// Compares checksums of a sample of frames from the script against the same
+/// frames in the lossless, to catch corruption from Vapoursynth race conditions
+fn verify_lossless_checksums(
     input: &Path,
+    lossless: &Path,
     dimensions: VideoDimensions,
+) -> Result<()> {
+    // Compare in the script's pixel format, since some lossless codecs
+    // decode to a different but equivalent format
+    let pix_fmt = ffmpeg_pix_fmt(dimensions);
+    let ranges = checksum_sample_ranges(dimensions.frames);
+
+    let mut script_hashes = Vec::new();
+    for &(start, end) in &ranges {
+        let (mut pipe, _tracked) = spawn_tracked(
+            Command::new("vspipe")
+                .arg("-c")
+                .arg("y4m")
+                .arg("-o")
+                .arg(script_output(input).to_string())
+                .arg("-s")
+                .arg(start.to_string())
+                .arg("-e")
+                .arg(end.to_string())
+                .arg(input)
+                .arg("-")
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null()),
+        )
+        .map_err(|e| anyhow::anyhow!("Failed to execute vspipe for verification: {}", e))?;
+        let hashes = framemd5(
+            Command::new("ffmpeg")
+                .arg("-hide_banner")
+                .arg("-loglevel")
+                .arg("error")
+                .arg("-i")
+                .arg("-")
+                .arg("-pix_fmt")
+                .arg(&pix_fmt)
+                .arg("-f")
+                .arg("framemd5")
+                .arg("-")
+                .stdin(pipe.stdout.take().expect("stdout should be writeable")),
+        );
+        let status = pipe.wait()?;
+        let hashes = hashes?;
+        if !status.success() {
+            anyhow::bail!(
+                "vspipe failed while verifying frames {}-{}: {}",
+                start,
+                end,
+                status
+            );
+        }
+        script_hashes.extend(hashes);
+    }
+
+    let select = ranges
+        .iter()
+        .map(|(start, end)| format!("between(n\\,{}\\,{})", start, end))
+        .join("+");
+    let lossless_hashes = framemd5(
+        Command::new("ffmpeg")
+            .arg("-hide_banner")
+            .arg("-loglevel")
+            .arg("error")
+            .arg("-i")
+            .arg(lossless)
+            .arg("-map")
+            .arg("0:v:0")
+            .arg("-vf")
+            .arg(format!("select={}", select))
+            .arg("-vsync")
+            .arg("passthrough")
+            .arg("-pix_fmt")
+            .arg(&pix_fmt)
+            .arg("-f")
+            .arg("framemd5")
+            .arg("-"),
+    )?;
+
+    if script_hashes.is_empty() || script_hashes != lossless_hashes {
+        anyhow::bail!(Error::VerificationFailed(
+            "Lossless frames do not match the script output".to_string()
+        ));
+    }
+    Ok(())
+}
+
+/// Inclusive ranges of frames spread across a video with `frames` frames,
+/// sorted and without overlaps, so each frame is hashed once and in order
+fn checksum_sample_ranges(frames: u32) -> Vec<(u32, u32)> {
+    const SAMPLES: u32 = 10;
+    const SAMPLE_LEN: u32 = 5;
+
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for i in 0..SAMPLES {
+        let start = (frames as u64 * i as u64 / SAMPLES as u64) as u32;
+        let end = std::cmp::min(start + SAMPLE_LEN, frames);
+        if start >= end {
+            continue;
+        }
+        match ranges.last_mut() {
+            // Short videos have samples which run into the next one
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end - 1),
+            _ => ranges.push((start, end - 1)),
+        }
+    }
+    ranges
+}
+
+/// Splits the script into inclusive frame ranges of roughly `minutes` each
+fn lossless_segments(dimensions: VideoDimensions, minutes: u32) -> Vec<(u32, u32)> {
+    let fps = dimensions.fps.0 as f64 / dimensions.fps.1 as f64;
+    let length = std::cmp::max((fps * 60.0 * minutes as f64).round() as u32, 1);
+    (0..dimensions.frames)
+        .step_by(length as usize)
+        .map(|start| (start, std::cmp::min(start + length, dimensions.frames) - 1))
+        .collect()
+}
+
+fn lossless_segment_filename(input: &Path, index: usize) -> PathBuf {
+    work_path(input).with_extension(format!("lossless.seg{:03}.mkv", index))
+}
+
+/// Encodes the script, or only the given inclusive frame range of it,
+/// to a lossless file, along with any audio from the source video
+fn encode_lossless_range(
+    input: &Path,
+    output: &Path,
+    codec: LosslessCodec,
+    pix_fmt: &str,
+    range: Option<(u32, u32)>,
+    frames: u32,
+    audio: Option<(&Path, &LosslessAudio)>,
+) -> Result<()> {
+    let pipe = ScriptPipe::new(input, range);
+    let mut command = Command::new("ffmpeg");
+    command
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("level+error")
+        .arg("-stats")
+        .arg("-y")
+        .arg("-i")
+        .arg("-");
+    if let Some((source, _)) = audio {
+        command.arg("-i").arg(source);
+    }
+    command
+        .args(codec.ffmpeg_args())
+        .arg("-pix_fmt")
+        .arg(pix_fmt);
+    if let Some((_, audio)) = audio {
+        command.arg("-map").arg("0:v:0").args(audio.ffmpeg_args(1));
+    }
+    command.arg(output);
+    let status = run_with_progress_from(
+        &mut command,
+        format!(
+            "Lossless {}",
+            output.file_name().unwrap_or_default().to_string_lossy()
+        ),
+        frames,
+        ProgressSource::Stderr(parse_ffmpeg_progress),
+        Some(pipe.writer()),
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to execute ffmpeg: {}", e))?;
+    pipe.finish()?;
+    if !status.success() {
+        anyhow::bail!(
+            "Failed to execute ffmpeg: Exited with code {:x}",
+            status.code().unwrap_or(-1)
+        );
+    }
+    Ok(())
+}
+
+/// Adds audio from the source to a lossless which was encoded without it
+fn add_lossless_audio(lossless: &Path, source: &Path, audio: &LosslessAudio) -> Result<()> {
+    let with_audio = lossless.with_extension("audio.mkv");
+    let status = run_logged(
+        Command::new("ffmpeg")
+            .arg("-hide_banner")
+            .arg("-loglevel")
+            .arg("level+error")
+            .arg("-stats")
+            .arg("-y")
+            .arg("-i")
+            .arg(lossless)
+            .arg("-i")
+            .arg(source)
+            .arg("-map")
+            .arg("0:v:0")
+            .args(audio.ffmpeg_args(1))
+            .arg("-c:v")
+            .arg("copy")
+            .arg(&with_audio),
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to execute ffmpeg: {}", e))?;
+    if !status.success() {
+        let _ = fs::remove_file(&with_audio);
+        anyhow::bail!("Failed to add audio to the lossless");
+    }
+    if is_dry_run() {
+        return Ok(());
+    }
+    fs::rename(&with_audio, lossless)?;
+    Ok(())
+}
+
+/// Creates the lossless as separately encoded segments which are then joined,
+/// so that a failure only requires redoing the segment that failed.
+///
+/// Completed segments are kept until the join succeeds, and reused by retries.
+fn create_segmented_lossless(
+    input: &Path,
+    lossless_filename: &Path,
+    dimensions: VideoDimensions,
+    codec: LosslessCodec,
+    pix_fmt: &str,
+    minutes: u32,
     verify_frame_count: bool,
 ) -> Result<()> {
-    let lossless_filename = input.with_extension("lossless.mkv");
-    if lossless_filename.exists() {
+    let segments = lossless_segments(dimensions, minutes);
+    let segment_files = (0..segments.len())
+        .map(|i| lossless_segment_filename(input, i))
+        .collect::<Vec<_>>();
+    for (i, (&(start, end), segment)) in segments.iter().zip(segment_files.iter()).enumerate() {
+        let expected_frames = end - start + 1;
+        if segment.exists()
+            && !is_lossless_stale(input, segment)
+            && (!verify_frame_count || get_video_frame_count(segment).ok() == Some(expected_frames))
+        {
+            info!(
+                "Lossless segment {}/{} already exists",
+                i + 1,
+                segments.len()
+            );
+            continue;
+        }
+
+        info!(
+            "Encoding lossless segment {}/{} (frames {}-{})",
+            i + 1,
+            segments.len(),
+            start,
+            end
+        );
+        if let Err(e) = encode_lossless_range(
+            input,
+            segment,
+            codec,
+            pix_fmt,
+            Some((start, end)),
+            expected_frames,
+            None,
+        ) {
+            let _ = fs::remove_file(segment);
+            return Err(e);
+        }
+        if verify_frame_count
+            && !is_dry_run()
+            && get_video_frame_count(segment).ok() != Some(expected_frames)
+        {
+            let _ = fs::remove_file(segment);
+            anyhow::bail!(Error::VerificationFailed(format!(
+                "Incomplete lossless segment {}",
+                i + 1
+            )));
+        }
+    }
+
+    let mut command = Command::new("mkvmerge");
+    command
+        .arg("--quiet")
+        .arg("--output")
+        .arg(lossless_filename);
+    for (i, segment) in segment_files.iter().enumerate() {
+        if i == 0 {
+            command.arg(segment);
+        } else {
+            let mut appended = OsString::from("+");
+            appended.push(segment);
+            command.arg(appended);
+        }
+    }
+    let status = run_logged(&mut command)
+        .map_err(|e| anyhow::anyhow!("Failed to execute mkvmerge: {}", e))?;
+    // mkvmerge exits with 1 for warnings
+    if !matches!(status.code(), Some(0) | Some(1)) {
+        let _ = fs::remove_file(lossless_filename);
+        anyhow::bail!("Failed to join lossless segments");
+    }
+    if is_dry_run() {
+        return Ok(());
+    }
+    for segment in &segment_files {
+        let _ = fs::remove_file(segment);
+    }
+    Ok(())
+}
+
+/// Makes the lossless intermediate for `input`, or reuses an existing one,
+/// returning the checks it passed
+pub fn create_lossless(
+    input: &Path,
+    dimensions: VideoDimensions,
+    verify_frame_count: bool,
+    codec: LosslessCodec,
+    verify_checksums: bool,
+    segment_length: Option<u32>,
+    audio: Option<&LosslessAudio>,
+) -> Result<Vec<&'static str>> {
+    let lossless_filename = work_path(input).with_extension("lossless.mkv");
+    if lossless_filename.exists() && is_lossless_stale(input, &lossless_filename) {
+        warn!("Script has changed since the lossless was created, recreating it");
+    } else if lossless_filename.exists() {
         if let Ok(lossless_frames) = get_video_frame_count(&lossless_filename) {
             // We use a fuzzy frame count check because *some cursed sources*
             // report a different frame count from the number of actual decodeable frames.
             let diff = (lossless_frames as i64 - dimensions.frames as i64).unsigned_abs() as u32;
             let allowance = dimensions.frames / 200;
-            if !verify_frame_count || diff <= allowance {
-                eprintln!(
-                    "{} {}",
-                    Green.bold().paint("[Success]"),
-                    Green.paint("Lossless already exists"),
-                );
-                return Ok(());
+            if (!verify_frame_count || diff <= allowance)
+                && lossless_format_matches(&lossless_filename, dimensions)
+            {
+                info!(success = true, "Lossless already exists");
+                return Ok(if verify_frame_count {
+                    vec!["lossless_frame_count"]
+                } else {
+                    Vec::new()
+                });
             }
         }
     }
 
+    let (codec, pix_fmt) = match codec.pix_fmt(dimensions) {
+        Some(pix_fmt) => (codec, pix_fmt),
+        None => {
+            // x264 can't do more than 10 bits, but FFV1 handles anything
+            let fallback = if dimensions.bit_depth <= 10 {
+                LosslessCodec::X264
+            } else {
+                LosslessCodec::Ffv1
+            };
+            warn!(
+                "Lossless codec {} does not support {}, using {} instead",
+                codec,
+                ffmpeg_pix_fmt(dimensions),
+                fallback
+            );
+            (
+                fallback,
+                fallback
+                    .pix_fmt(dimensions)
+                    .expect("Fallback codec supports this format"),
+            )
+        }
+    };
+
     // Print the info once
-    Command::new("vspipe")
-        .arg("-i")
-        .arg(input)
-        .arg("-")
-        .status()
+    run_logged(Command::new("vspipe").arg("-i").arg(input).arg("-"))
         .map_err(|e| anyhow::anyhow!("Failed to execute vspipe -i prior to lossless: {}", e))?;
 
     let filename = input
         .file_name()
         .expect("File should have a name")
         .to_string_lossy();
-    let mut pipe = if filename.ends_with(".vpy") {
-        Command::new("vspipe")
-            .arg("-c")
-            .arg("y4m")
-            .arg(input)
-            .arg("-")
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(|e| anyhow::anyhow!("Failed to execute vspipe for lossless encoding: {}", e))?
-    } else {
+    if !filename.ends_with(".vpy") {
         panic!("Unrecognized input type");
-    };
-    let mut command = Command::new("ffmpeg");
-    let status = command
-        .arg("-hide_banner")
-        .arg("-loglevel")
-        .arg("level+error")
-        .arg("-stats")
-        .arg("-y")
-        .arg("-i")
-        .arg("-")
-        .arg("-vcodec")
-        .arg("libx264")
-        .arg("-preset")
-        .arg("ultrafast")
-        .arg("-qp")
-        .arg("0")
-        .arg(&lossless_filename)
-        .stdin(pipe.stdout.take().expect("stdout should be writeable"))
-        .stderr(Stdio::inherit())
-        .status()
-        .map_err(|e| anyhow::anyhow!("Failed to execute ffmpeg: {}", e))?;
-    pipe.wait()?;
-    if !status.success() {
-        anyhow::bail!(
-            "Failed to execute ffmpeg: Exited with code {:x}",
-            status.code().unwrap_or(-1)
-        );
+    }
+    let source = find_source_file(input);
+    match segment_length {
+        Some(minutes) if lossless_segments(dimensions, minutes).len() > 1 => {
+            create_segmented_lossless(
+                input,
+                &lossless_filename,
+                dimensions,
+                codec,
+                &pix_fmt,
+                minutes,
+                verify_frame_count,
+            )?;
+            // Segments would each need the matching slice of audio,
+            // so it is added once they are joined
+            if let Some(audio) = audio {
+                add_lossless_audio(&lossless_filename, &source, audio)?;
+            }
+        }
+        _ => {
+            encode_lossless_range(
+                input,
+                &lossless_filename,
+                codec,
+                &pix_fmt,
+                None,
+                dimensions.frames,
+                audio.map(|audio| (source.as_path(), audio)),
+            )?;
+        }
+    }
+    if is_dry_run() {
+        // There is no lossless to check
+        return Ok(Vec::new());
     }
 
+    let mut verified = Vec::new();
     if let Ok(lossless_frames) = get_video_frame_count(&lossless_filename) {
         if verify_frame_count {
             // We use a fuzzy frame count check because *some cursed sources*
@@ -219,155 +1209,300 @@ pub fn create_lossless(
             let diff = (lossless_frames as i64 - dimensions.frames as i64).unsigned_abs() as u32;
             let allowance = dimensions.frames / 200;
             if diff > allowance {
-                anyhow::bail!("Incomplete lossless encode");
+                anyhow::bail!(Error::VerificationFailed(
+                    "Incomplete lossless encode".to_string()
+                ));
             }
+            verified.push("lossless_frame_count");
+        }
+    }
+    if !lossless_format_matches(&lossless_filename, dimensions) {
+        anyhow::bail!(Error::VerificationFailed(format!(
+            "Lossless pixel format does not match script output of {}",
+            ffmpeg_pix_fmt(dimensions)
+        )));
+    }
+    if verify_checksums {
+        info!("Verifying lossless frame checksums");
+        if let Err(e) = verify_lossless_checksums(input, &lossless_filename, dimensions) {
+            // Otherwise the next attempt would happily reuse the corrupt lossless
+            let _ = fs::remove_file(&lossless_filename);
+            return Err(e);
         }
+        verified.push("lossless_checksums");
     }
 
-    eprintln!(
-        "{} {}",
-        Green.bold().paint("[Success]"),
-        Green.paint("Finished encoding lossless"),
-    );
+    info!(success = true, "Finished encoding lossless");
 
-    Ok(())
+    Ok(verified)
+}
+
+/// How an av1an encode divides the machine between its workers
+#[derive(Debug, Clone, Copy)]
+pub struct Av1anWorkers {
+    /// The cores for this encode, after dividing them between concurrent jobs
+    pub cores: NonZeroUsize,
+    pub workers: NonZeroUsize,
+    pub threads_per_worker: NonZeroUsize,
+    /// The log2 tile columns and rows
+    pub tiles: (u8, u8),
 }
 
+impl Av1anWorkers {
+    pub fn new(
+        video: &VideoOutput,
+        dimensions: VideoDimensions,
+        options: &Av1anOptions,
+    ) -> Result<Self> {
+        let encoder = video.encoder;
+        // We may not actually split tiles at this point,
+        // but we want to make sure we don't run out of memory
+        let tile_config = tile_config(dimensions, video.tiles);
+        let tiles = NonZeroUsize::new(1 << (tile_config.0 + tile_config.1)).expect("not 0");
+        let cores = match options.numa_nodes {
+            Some(ref nodes) => numa_node_cores(nodes)?,
+            None => available_parallelism().expect("Unable to get machine parallelism count"),
+        };
+        let jobs = std::cmp::max(options.concurrent_jobs, 1);
+        let cores = NonZeroUsize::new(std::cmp::max(cores.get() / jobs, 1)).expect("not 0");
+        let mut workers = NonZeroUsize::new(match encoder {
+            VideoEncoder::Aom { .. } | VideoEncoder::Rav1e { .. } | VideoEncoder::SvtAv1 { .. } => {
+                std::cmp::max(cores.get() / tiles.get(), 1)
+            }
+            _ => (std::cmp::max(cores.get() / tiles.get(), 1) / 4).max(1),
+        })
+        .unwrap();
+        let memory_limit = options
+            .max_memory_mb
+            .or_else(default_memory_limit_mb)
+            .map(|limit| limit / jobs as u64);
+        let max_workers = memory_limit.map(|limit| {
+            let per_worker = encoder.estimated_worker_memory_mb(dimensions);
+            NonZeroUsize::new(std::cmp::max(limit / per_worker, 1) as usize).expect("not 0")
+        });
+        if let Some(ref nodes) = options.numa_nodes {
+            if cores.get() < nodes.len() {
+                anyhow::bail!(
+                    "Each of {} jobs gets {} cores, which can't be spread across {} NUMA nodes. \
+                     Use fewer jobs or fewer nodes.",
+                    jobs,
+                    cores,
+                    nodes.len()
+                );
+            }
+            // Give every node the same number of workers,
+            // so that the load is balanced between them.
+            let per_node = std::cmp::max(workers.get() / nodes.len(), 1);
+            workers = NonZeroUsize::new(per_node * nodes.len()).unwrap();
+        }
+        // Clamp after rounding, since rounding up to one worker per node
+        // can otherwise exceed the memory limit.
+        if let (Some(memory_limit), Some(max_workers)) = (memory_limit, max_workers) {
+            if workers > max_workers {
+                warn!(
+                    "Reducing workers from {} to {} to fit in {} MiB of memory",
+                    workers, max_workers, memory_limit
+                );
+                workers = match options.numa_nodes {
+                    // Keep the workers evenly spread if the limit allows it
+                    Some(ref nodes) if max_workers.get() >= nodes.len() => {
+                        NonZeroUsize::new(max_workers.get() / nodes.len() * nodes.len()).unwrap()
+                    }
+                    _ => max_workers,
+                };
+            }
+        }
+        workers = std::cmp::min(workers, cores);
+
+        let threads_per_worker = NonZeroUsize::new(std::cmp::min(
+            64,
+            (cores.get() as f32 / workers.get() as f32 * 1.5).ceil() as usize + 2,
+        ))
+        .unwrap();
+        Ok(Av1anWorkers {
+            cores,
+            workers,
+            threads_per_worker,
+            tiles: tile_config,
+        })
+    }
+}
+
+/// The `-v` arguments given to av1an, including any extra arguments of the output
+pub fn av1an_encoder_args(
+    video: &VideoOutput,
+    dimensions: VideoDimensions,
+    colorimetry: &Colorimetry,
+    workers: &Av1anWorkers,
+    force_keyframes: &Option<String>,
+) -> Result<String> {
+    let encoder = video.encoder;
+    let mut encoder_args = encoder.get_args_string(
+        dimensions,
+        colorimetry,
+        workers.threads_per_worker,
+        workers.cores,
+        workers.workers,
+        force_keyframes,
+        workers.tiles,
+        &video.svt_tuning,
+        &video.overrides,
+    )?;
+    if let Some(ref grain_table) = video.grain_table {
+        encoder_args.push_str(&grain_table_args(encoder, grain_table)?);
+    }
+    if let (VideoEncoder::X265 { .. }, Some((num, den))) = (encoder, video.sar) {
+        encoder_args.push_str(&format!("--sar {}:{} ", num, den));
+    }
+    if let Some(ref extra_args) = video.extra_args {
+        encoder_args.push_str(extra_args);
+        encoder_args.push(' ');
+    }
+    Ok(encoder_args)
+}
+
+/// Encodes `vpy_input` with av1an.
+///
+/// `shared_scenes` is the scenes file next to the script, which `options.scenes`
+/// exports to or imports from. It is left out for samples of the script.
+#[allow(clippy::too_many_arguments)]
 pub fn convert_video_av1an(
     vpy_input: &Path,
+    scenes_base: &Path,
+    shared_scenes: Option<&Path>,
     output: &Path,
-    encoder: VideoEncoder,
+    video: &VideoOutput,
     dimensions: VideoDimensions,
     force_keyframes: &Option<String>,
     colorimetry: &Colorimetry,
+    options: &Av1anOptions,
 ) -> Result<()> {
+    let encoder = video.encoder;
     if dimensions.width % 8 != 0 {
-        eprintln!(
-            "{} {} {} {}",
-            Yellow.bold().paint("[Warning]"),
-            Yellow.paint("Width"),
-            Yellow.paint(dimensions.width.to_string()),
-            Yellow.paint("is not divisble by 8")
-        );
+        warn!("Width {} is not divisble by 8", dimensions.width);
     }
     if dimensions.height % 8 != 0 {
-        eprintln!(
-            "{} {} {} {}",
-            Yellow.bold().paint("[Warning]"),
-            Yellow.paint("Height"),
-            Yellow.paint(dimensions.height.to_string()),
-            Yellow.paint("is not divisble by 8")
-        );
-    }
-
-    if output.exists() && get_video_frame_count(output).unwrap_or(0) == dimensions.frames {
-        eprintln!("Video output already exists, reusing");
-        return Ok(());
+        warn!("Height {} is not divisble by 8", dimensions.height);
     }
 
     let fps = (dimensions.fps.0 as f32 / dimensions.fps.1 as f32).round() as u32;
-    // We may not actually split tiles at this point,
-    // but we want to make sure we don't run out of memory
-    let tiles = NonZeroUsize::new(
-        if dimensions.height >= 2000 || (dimensions.height >= 1550 && dimensions.width >= 3600) {
-            2
-        } else {
-            1
-        } * if dimensions.width >= 2000 { 2 } else { 1 },
-    )
-    .expect("not 0");
-    let cores = available_parallelism().expect("Unable to get machine parallelism count");
-    let workers = NonZeroUsize::new(match encoder {
-        VideoEncoder::Aom { .. } | VideoEncoder::Rav1e { .. } | VideoEncoder::SvtAv1 { .. } => {
-            std::cmp::max(cores.get() / tiles.get(), 1)
+    let av1an_workers = Av1anWorkers::new(video, dimensions, options)?;
+    let Av1anWorkers { cores, workers, .. } = av1an_workers;
+    let profile = match encoder {
+        VideoEncoder::Aom { profile, .. }
+        | VideoEncoder::Rav1e { profile, .. }
+        | VideoEncoder::SvtAv1 { profile, .. }
+        | VideoEncoder::X264 { profile, .. }
+        | VideoEncoder::X265 { profile, .. } => profile,
+        VideoEncoder::Copy => unreachable!(),
+    };
+    let (extra_split, min_scene_len) = if profile.is_anime() {
+        (fps * 15, fps / 2)
+    } else {
+        (fps * 10, fps)
+    };
+    let sc_downscale = dimensions.height > 1080;
+    // Every output of the same source shares a scenes file as long as the
+    // scene detection settings match, so detection only runs once per source.
+    // av1an writes the file if it does not exist, and reads it if it does.
+    // Forced keyframes are cuts in the file, so outputs forcing different ones
+    // can't share it.
+    let scenes_file = scenes_base.with_extension(format!(
+        "scenes-x{}-m{}{}{}.json",
+        extra_split,
+        min_scene_len,
+        if sc_downscale { "-d1080" } else { "" },
+        force_keyframes
+            .as_deref()
+            .map(|frames| format!("-kf{:08x}", short_hash(frames)))
+            .unwrap_or_default()
+    ));
+    let shared_scenes = shared_scenes.and_then(|path| options.scenes.map(|mode| (mode, path)));
+    let scenes_file = match shared_scenes {
+        Some((ScenesMode::Import, path)) => {
+            check_scenes_file(path, dimensions.frames)?;
+            path.to_path_buf()
         }
-        _ => (std::cmp::max(cores.get() / tiles.get(), 1) / 4).max(1),
-    })
-    .unwrap();
-    assert!(
-        workers <= cores,
-        "Worker count exceeded core count, this is a bug"
-    );
+        _ => scenes_file,
+    };
 
-    let threads_per_worker = NonZeroUsize::new(std::cmp::min(
-        64,
-        (cores.get() as f32 / workers.get() as f32 * 1.5).ceil() as usize + 2,
-    ))
-    .unwrap();
-    let mut command = Command::new("av1an");
+    if output.exists() && get_video_frame_count(output).unwrap_or(0) == dimensions.frames {
+        info!("Video output already exists, reusing");
+        if let Some((ScenesMode::Export, path)) = shared_scenes {
+            export_scenes(&scenes_file, path)?;
+        }
+        return Ok(());
+    }
+    let encoder_args = av1an_encoder_args(
+        video,
+        dimensions,
+        colorimetry,
+        &av1an_workers,
+        force_keyframes,
+    )?;
+    let mut command = if let Some(ref nodes) = options.numa_nodes {
+        // Keep av1an and every worker on the CPUs of the chosen nodes,
+        // and allocate memory on whichever node each encoder thread is running on.
+        // Workers aren't pinned to a single node, the kernel schedules them
+        // anywhere within the chosen nodes.
+        let mut command = Command::new("numactl");
+        command
+            .arg(format!("--cpunodebind={}", nodes.iter().join(",")))
+            .arg("--localalloc")
+            .arg("av1an");
+        command
+    } else {
+        Command::new("av1an")
+    };
     command
         .arg("-i")
         .arg(absolute_path(vpy_input).expect("Unable to get absolute path"))
         .arg("-e")
         .arg(encoder.get_av1an_name())
         .arg("-v")
-        .arg(&encoder.get_args_string(
-            dimensions,
-            colorimetry,
-            threads_per_worker,
-            cores,
-            workers,
-            force_keyframes,
-        )?)
+        .arg(&encoder_args)
         .arg("--sc-method")
         .arg("standard")
         .arg("-x")
-        .arg(
-            match encoder {
-                VideoEncoder::Aom { profile, .. }
-                | VideoEncoder::Rav1e { profile, .. }
-                | VideoEncoder::SvtAv1 { profile, .. }
-                | VideoEncoder::X264 { profile, .. }
-                | VideoEncoder::X265 { profile, .. } => {
-                    if profile.is_anime() {
-                        fps * 15
-                    } else {
-                        fps * 10
-                    }
-                }
-                VideoEncoder::Copy => unreachable!(),
-            }
-            .to_string(),
-        )
+        .arg(extra_split.to_string())
         .arg("--min-scene-len")
-        .arg(
-            match encoder {
-                VideoEncoder::Aom { profile, .. }
-                | VideoEncoder::Rav1e { profile, .. }
-                | VideoEncoder::SvtAv1 { profile, .. }
-                | VideoEncoder::X264 { profile, .. }
-                | VideoEncoder::X265 { profile, .. } => {
-                    if profile.is_anime() {
-                        fps / 2
-                    } else {
-                        fps
-                    }
-                }
-                VideoEncoder::Copy => unreachable!(),
-            }
-            .to_string(),
-        )
+        .arg(min_scene_len.to_string())
+        .arg("--scenes")
+        .arg(absolute_path(&scenes_file).expect("Unable to get absolute path"))
         .arg("-w")
         .arg(workers.to_string())
         .arg("--pix-format")
-        .arg(match (dimensions.bit_depth, dimensions.pixel_format) {
-            (8, PixelFormat::Yuv420) => "yuv420p".to_string(),
-            (8, PixelFormat::Yuv422) => "yuv422p".to_string(),
-            (8, PixelFormat::Yuv444) => "yuv444p".to_string(),
-            (bd, PixelFormat::Yuv420) => format!("yuv420p{}le", bd),
-            (bd, PixelFormat::Yuv422) => format!("yuv422p{}le", bd),
-            (bd, PixelFormat::Yuv444) => format!("yuv444p{}le", bd),
-        })
-        .arg("-r")
+        .arg(ffmpeg_pix_fmt(dimensions))
         .arg("-o")
         .arg(absolute_path(output).expect("Unable to get absolute path"));
+    if let Some(ref chunk_method) = video.chunk_method {
+        command.arg("--chunk-method").arg(chunk_method);
+    }
+    let temp_dir = if options.keep_temp || options.resume_chunks {
+        // Use a temp dir that is stable for this output,
+        // so that it can be found again after an interrupted encode.
+        let temp_dir = absolute_path(work_path(vpy_input).with_extension("av1an"))
+            .expect("Unable to get absolute path");
+        command.arg("--temp").arg(&temp_dir);
+        Some(temp_dir)
+    } else {
+        None
+    };
+    if options.keep_temp {
+        command.arg("--keep");
+    }
+    if options.resume_chunks {
+        command.arg("--resume");
+    }
     if let Some(force_keyframes) = force_keyframes {
         command.arg("--force-keyframes").arg(force_keyframes);
     }
-    if dimensions.height > 1080 {
+    if sc_downscale {
         command.arg("--sc-downscale-height").arg("1080");
     }
-    if encoder.uses_av1an_thread_pinning() {
+    // av1an pins workers to CPUs counted from 0,
+    // which may be outside the nodes numactl bound us to
+    if encoder.uses_av1an_thread_pinning() && options.numa_nodes.is_none() {
         command
             .arg("--set-thread-affinity")
             .arg((cores.get() / workers).to_string());
@@ -376,7 +1511,8 @@ pub fn convert_video_av1an(
     | VideoEncoder::Rav1e { grain, .. }
     | VideoEncoder::SvtAv1 { grain, .. } = encoder
     {
-        if grain > 0 {
+        // A grain table replaces the synthesized noise
+        if grain > 0 && video.grain_table.is_none() {
             command
                 .arg("--photon-noise")
                 .arg(grain.to_string())
@@ -386,11 +1522,43 @@ pub fn convert_video_av1an(
     if let VideoEncoder::X265 { .. } = encoder {
         command.arg("--concat").arg("mkvmerge");
     }
-    let status = command
-        .status()
-        .map_err(|e| anyhow::anyhow!("Failed to execute av1an: {}", e))?;
+    if let Some(target_quality) = video.target_quality {
+        // av1an substitutes its own quantizer into the `-v` args for each probe,
+        // so the rest of our encoder settings are still respected.
+        command
+            .arg("--target-quality")
+            .arg(target_quality.to_string());
+        if let Some(probes) = video.probes {
+            command.arg("--probes").arg(probes.to_string());
+        }
+        if let Some(probing_rate) = video.probing_rate {
+            command.arg("--probing-rate").arg(probing_rate.to_string());
+        }
+    }
+    if let Some(ref av1an_args) = video.av1an_args {
+        for arg in av1an_args.split_ascii_whitespace() {
+            command.arg(arg);
+        }
+    }
+    let status = run_with_progress(
+        &mut command,
+        format!(
+            "av1an {}",
+            output.file_name().unwrap_or_default().to_string_lossy()
+        ),
+        dimensions.frames,
+        match temp_dir {
+            Some(ref temp_dir) => ProgressSource::Av1an(temp_dir),
+            // Without a known temp dir, there is no `done.json` to follow
+            None => ProgressSource::Stderr(|_| None),
+        },
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to execute av1an: {}", e))?;
 
     if status.success() {
+        if let Some((ScenesMode::Export, path)) = shared_scenes {
+            export_scenes(&scenes_file, path)?;
+        }
         Ok(())
     } else {
         Err(anyhow::anyhow!(
@@ -408,7 +1576,7 @@ pub enum VideoEncoder {
         speed: u8,
         profile: Profile,
         grain: u8,
-        compat: bool,
+        compat: Compat,
     },
     Rav1e {
         crf: i16,
@@ -421,16 +1589,17 @@ pub enum VideoEncoder {
         speed: u8,
         profile: Profile,
         grain: u8,
+        direct: bool,
     },
     X264 {
         crf: i16,
         profile: Profile,
-        compat: bool,
+        compat: Compat,
     },
     X265 {
         crf: i16,
         profile: Profile,
-        compat: bool,
+        compat: Compat,
     },
 }
 
@@ -439,6 +1608,17 @@ impl VideoEncoder {
         &["aom", "rav1e", "svt", "x264", "x265", "copy"]
     }
 
+    pub const fn crf(&self) -> Option<i16> {
+        match *self {
+            VideoEncoder::Copy => None,
+            VideoEncoder::Aom { crf, .. }
+            | VideoEncoder::Rav1e { crf, .. }
+            | VideoEncoder::SvtAv1 { crf, .. }
+            | VideoEncoder::X264 { crf, .. }
+            | VideoEncoder::X265 { crf, .. } => Some(crf),
+        }
+    }
+
     pub const fn get_av1an_name(&self) -> &str {
         match self {
             VideoEncoder::Copy => "copy",
@@ -450,6 +1630,7 @@ impl VideoEncoder {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn get_args_string(
         self,
         dimensions: VideoDimensions,
@@ -458,6 +1639,9 @@ impl VideoEncoder {
         cores: NonZeroUsize,
         workers: NonZeroUsize,
         force_keyframes: &Option<String>,
+        tiles: (u8, u8),
+        svt_tuning: &SvtTuning,
+        overrides: &ProfileOverrides,
     ) -> anyhow::Result<String> {
         Ok(match self {
             VideoEncoder::Aom {
@@ -472,9 +1656,10 @@ impl VideoEncoder {
                 profile,
                 colorimetry,
                 computed_threads,
+                tiles,
             ),
             VideoEncoder::Rav1e { crf, speed, .. } => {
-                build_rav1e_args_string(crf, speed, dimensions, colorimetry)
+                build_rav1e_args_string(crf, speed, colorimetry, tiles)
             }
             VideoEncoder::SvtAv1 { crf, speed, .. } => build_svtav1_args_string(
                 crf,
@@ -482,6 +1667,9 @@ impl VideoEncoder {
                 cores.get() / workers.get(),
                 dimensions,
                 colorimetry,
+                None,
+                tiles,
+                svt_tuning,
             ),
             VideoEncoder::X264 {
                 crf,
@@ -494,6 +1682,7 @@ impl VideoEncoder {
                 compat,
                 force_keyframes,
                 colorimetry,
+                overrides,
             )?,
             VideoEncoder::X265 {
                 crf,
@@ -507,11 +1696,29 @@ impl VideoEncoder {
                 compat,
                 colorimetry,
                 computed_threads,
+                overrides,
             ),
             VideoEncoder::Copy => unreachable!(),
         })
     }
 
+    /// A rough, deliberately pessimistic estimate of how much memory
+    /// a single av1an worker uses with this encoder, in MiB
+    pub fn estimated_worker_memory_mb(self, dimensions: VideoDimensions) -> u64 {
+        let megapixels = dimensions.width as f64 * dimensions.height as f64 / 1_000_000.0;
+        let per_megapixel = match self {
+            VideoEncoder::Aom { .. } => 600,
+            VideoEncoder::Rav1e { .. } => 800,
+            VideoEncoder::SvtAv1 { .. } => 500,
+            VideoEncoder::X264 { .. } => 150,
+            VideoEncoder::X265 { .. } => 300,
+            VideoEncoder::Copy => 0,
+        };
+        let high_bit_depth = if dimensions.bit_depth > 8 { 2.0 } else { 1.0 };
+        // Each worker also has its own vspipe and decoder running
+        200 + (megapixels * per_megapixel as f64 * high_bit_depth).ceil() as u64
+    }
+
     pub const fn uses_av1an_thread_pinning(self) -> bool {
         matches!(
             self,
@@ -520,15 +1727,253 @@ impl VideoEncoder {
     }
 }
 
+/// Measures the grain lost between `reference` and the AV1 `video` with grav1synth,
+/// and writes a copy of `video` to `output` with matching grain synthesis applied
+pub fn apply_matched_grain(reference: &Path, video: &Path, output: &Path) -> Result<()> {
+    let table = output.with_extension("tbl");
+    let status = run_logged(
+        Command::new("grav1synth")
+            .arg("diff")
+            .arg(reference)
+            .arg(video)
+            .arg("-o")
+            .arg(&table)
+            .arg("-y"),
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to execute grav1synth: {}", e))?;
+    if !status.success() {
+        anyhow::bail!(
+            "Failed to measure grain with grav1synth: Exited with code {:x}",
+            status.code().unwrap_or(-1)
+        );
+    }
+    let status = run_logged(
+        Command::new("grav1synth")
+            .arg("apply")
+            .arg(video)
+            .arg("-g")
+            .arg(&table)
+            .arg("-o")
+            .arg(output)
+            .arg("-y"),
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to execute grav1synth: {}", e))?;
+    let _ = fs::remove_file(&table);
+    if !status.success() {
+        anyhow::bail!(
+            "Failed to apply grain with grav1synth: Exited with code {:x}",
+            status.code().unwrap_or(-1)
+        );
+    }
+    Ok(())
+}
+
+/// The encoder arguments which apply a film grain table
+fn grain_table_args(encoder: VideoEncoder, grain_table: &Path) -> Result<String> {
+    let path = absolute_path(grain_table)?;
+    let path = path.to_string_lossy();
+    // av1an splits the encoder arguments on whitespace
+    if path.contains(char::is_whitespace) {
+        anyhow::bail!(
+            "Grain table paths used with av1an may not contain spaces: {}",
+            path
+        );
+    }
+    Ok(match encoder {
+        VideoEncoder::Aom { .. } => format!(" --film-grain-table={} ", path),
+        VideoEncoder::SvtAv1 { .. } => format!(" --fgs-table {} ", path),
+        _ => unreachable!("Grain tables are only supported by aom and svt"),
+    })
+}
+
+/// The memory limit used when `--max-memory` is not given, in MiB:
+/// three quarters of the currently available memory, leaving headroom
+/// for av1an itself and the rest of the system.
+fn default_memory_limit_mb() -> Option<u64> {
+    available_system_memory_mb().map(|mb| mb / 4 * 3)
+}
+
+/// Returns the memory currently available for new processes in MiB,
+/// if it can be detected
+fn available_system_memory_mb() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    // Formatted like `MemAvailable:   65795144 kB`
+    let kb = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse::<u64>()
+        .ok()?;
+    Some(kb / 1024)
+}
+
+/// Counts the CPUs belonging to the given NUMA nodes
+fn numa_node_cores(nodes: &[u32]) -> Result<NonZeroUsize> {
+    let mut count = 0;
+    for node in nodes {
+        let cpulist = fs::read_to_string(format!("/sys/devices/system/node/node{}/cpulist", node))
+            .map_err(|e| anyhow::anyhow!("Unable to read CPUs for NUMA node {}: {}", node, e))?;
+        // Formatted like `0-15,32-47`
+        for range in cpulist.trim().split(',').filter(|range| !range.is_empty()) {
+            count += match range.split_once('-') {
+                Some((start, end)) => end.parse::<usize>()? - start.parse::<usize>()? + 1,
+                None => 1,
+            };
+        }
+    }
+    NonZeroUsize::new(count).ok_or_else(|| anyhow::anyhow!("No CPUs found on the given NUMA nodes"))
+}
+
+/// Returns the log2 tile columns and rows to use for an encode,
+/// unless they have been overridden
+pub fn tile_config(dimensions: VideoDimensions, tiles: Option<(u8, u8)>) -> (u8, u8) {
+    tiles.unwrap_or_else(|| {
+        (
+            u8::from(dimensions.width >= 2000),
+            u8::from(
+                dimensions.height >= 2000
+                    || (dimensions.height >= 1550 && dimensions.width >= 3600),
+            ),
+        )
+    })
+}
+
+/// Fails unless `path` is a scenes file for a video of `frames` frames,
+/// since av1an would otherwise encode with scene changes for a different cut
+fn check_scenes_file(path: &Path, frames: u32) -> Result<()> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Unable to read scenes from {}: {}", path.display(), e))?;
+    let scenes: serde_json::Value = serde_json::from_str(&contents)
+        .map_err(|e| anyhow::anyhow!("Unable to parse scenes from {}: {}", path.display(), e))?;
+    match scenes["frames"].as_u64() {
+        Some(scene_frames) if scene_frames != u64::from(frames) => Err(anyhow::anyhow!(
+            "Scenes in {} are for {} frames, but the video has {}",
+            path.display(),
+            scene_frames,
+            frames
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The keyframes an encode without av1an is forced to place: `force_keyframes`,
+/// and the start of each scene in `shared_scenes` if `options.scenes` imports them
+pub fn direct_keyframes(
+    force_keyframes: &Option<String>,
+    shared_scenes: Option<&Path>,
+    options: &Av1anOptions,
+    frames: u32,
+) -> Result<Vec<u32>> {
+    let mut keyframes = match force_keyframes {
+        Some(force_keyframes) => force_keyframes
+            .split(',')
+            .map(|frame| {
+                frame
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|_| anyhow::anyhow!("Invalid keyframe: {}", frame))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    if let (Some(ScenesMode::Import), Some(path)) = (options.scenes, shared_scenes) {
+        check_scenes_file(path, frames)?;
+        let scenes: serde_json::Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+        keyframes.extend(
+            scenes["scenes"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|scene| scene["start_frame"].as_u64())
+                .map(|frame| frame as u32),
+        );
+    }
+    keyframes.retain(|&frame| frame > 0 && frame < frames);
+    keyframes.sort_unstable();
+    keyframes.dedup();
+    Ok(keyframes)
+}
+
+fn export_scenes(scenes_file: &Path, path: &Path) -> Result<()> {
+    if is_dry_run() || !scenes_file.exists() {
+        return Ok(());
+    }
+    fs::copy(scenes_file, path)?;
+    info!("Exported scene changes to {}", path.display());
+    Ok(())
+}
+
+/// Removes any scenes files that av1an created for `input`
+pub fn remove_scenes_files(input: &Path) -> Result<()> {
+    let prefix = format!(
+        "{}.scenes-",
+        input
+            .file_stem()
+            .expect("File should have a name")
+            .to_string_lossy()
+    );
+    for entry in input
+        .parent()
+        .expect("File should have a parent dir")
+        .read_dir()?
+    {
+        let path = entry?.path();
+        let filename = path
+            .file_name()
+            .expect("File should have a name")
+            .to_string_lossy();
+        if filename.starts_with(&prefix) && filename.ends_with(".json") {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
 pub fn copy_hdr_data(input: &Path, target: &Path) -> Result<()> {
-    let status = Command::new("hdrcopier")
-        .arg("copy")
-        .arg("--chapters")
-        .arg(input)
-        .arg(target)
-        .status()?;
+    let status = run_logged(
+        Command::new("hdrcopier")
+            .arg("copy")
+            .arg("--chapters")
+            .arg(input)
+            .arg(target),
+    )?;
     if !status.success() {
         anyhow::bail!("Error copying hdr data");
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_samples_of_short_clips() {
+        assert_eq!(checksum_sample_ranges(0), vec![]);
+        assert_eq!(checksum_sample_ranges(3), vec![(0, 2)]);
+        assert_eq!(checksum_sample_ranges(20), vec![(0, 19)]);
+        assert_eq!(
+            checksum_sample_ranges(80),
+            vec![
+                (0, 4),
+                (8, 12),
+                (16, 20),
+                (24, 28),
+                (32, 36),
+                (40, 44),
+                (48, 52),
+                (56, 60),
+                (64, 68),
+                (72, 76)
+            ]
+        );
+        for frames in 0..200 {
+            let ranges = checksum_sample_ranges(frames);
+            assert!(ranges
+                .iter()
+                .all(|&(start, end)| start <= end && end < frames));
+            assert!(ranges.windows(2).all(|pair| pair[0].1 + 1 < pair[1].0));
+        }
+    }
+}
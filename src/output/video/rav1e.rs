@@ -1,20 +1,17 @@
 use av_data::pixel::{ColorPrimaries, MatrixCoefficients, TransferCharacteristic, YUVRange};
 
-use crate::input::{Colorimetry, VideoDimensions};
+use crate::input::Colorimetry;
 
 pub fn build_rav1e_args_string(
     crf: i16,
     speed: u8,
-    dimensions: VideoDimensions,
     colorimetry: &Colorimetry,
+    tiles: (u8, u8),
 ) -> String {
     // TODO: Add proper HDR metadata
     // TODO: Remove rdo-lookahead-frames limitation if we can reduce rav1e memory
     // usage
-    let tile_cols = i32::from(dimensions.width >= 2000);
-    let tile_rows = i32::from(
-        dimensions.height >= 2000 || (dimensions.height >= 1550 && dimensions.width >= 3600),
-    );
+    let (tile_cols, tile_rows) = tiles;
     let prim = match colorimetry.primaries {
         ColorPrimaries::BT709 => "BT709",
         ColorPrimaries::BT470M => "BT470M",
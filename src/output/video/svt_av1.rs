@@ -1,19 +1,228 @@
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+    thread::available_parallelism,
+};
+
 use av_data::pixel::{ChromaLocation, ToPrimitive, YUVRange};
+use itertools::Itertools;
+use tracing::{debug, info, warn};
+
+use crate::{
+    absolute_path,
+    input::{get_video_frame_count, Colorimetry, VideoDimensions},
+    output::{tile_config, Profile},
+    progress::{parse_svtav1_progress, run_with_progress, ProgressSource},
+    tool_log::{run_logged, spawn_logged},
+};
 
-use crate::input::{Colorimetry, VideoDimensions};
+/// Tuning for the psy-focused forks of SVT-AV1,
+/// with each setting left to the encoder's default if unset
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SvtTuning {
+    pub ac_bias: Option<f32>,
+    pub variance_boost_strength: Option<u8>,
+    pub variance_octile: Option<u8>,
+    pub sharpness: Option<i8>,
+    pub qm_max: Option<u8>,
+}
+
+impl SvtTuning {
+    fn args(&self) -> String {
+        let mut args = String::new();
+        if let Some(ac_bias) = self.ac_bias {
+            args.push_str(&format!("--ac-bias {ac_bias} "));
+        }
+        if self.variance_boost_strength.is_some() || self.variance_octile.is_some() {
+            args.push_str("--enable-variance-boost 1 ");
+        }
+        if let Some(strength) = self.variance_boost_strength {
+            args.push_str(&format!("--variance-boost-strength {strength} "));
+        }
+        if let Some(octile) = self.variance_octile {
+            args.push_str(&format!("--variance-octile {octile} "));
+        }
+        if let Some(sharpness) = self.sharpness {
+            args.push_str(&format!("--sharpness {sharpness} "));
+        }
+        args
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn convert_video_svtav1(
+    vpy_input: &Path,
+    output: &Path,
+    crf: i16,
+    speed: u8,
+    profile: Profile,
+    grain: u8,
+    dimensions: VideoDimensions,
+    keyframes: &[u32],
+    colorimetry: &Colorimetry,
+    extra_args: Option<&str>,
+    tiles: Option<(u8, u8)>,
+    grain_table: Option<&Path>,
+    tuning: &SvtTuning,
+) -> anyhow::Result<()> {
+    if dimensions.width % 8 != 0 {
+        warn!("Width {} is not divisble by 8", dimensions.width);
+    }
+    if dimensions.height % 8 != 0 {
+        warn!("Height {} is not divisble by 8", dimensions.height);
+    }
+
+    if output.exists() && get_video_frame_count(output).unwrap_or(0) == dimensions.frames {
+        info!("Video output already exists, reusing");
+        return Ok(());
+    }
+
+    let mut pipe = spawn_logged(
+        Command::new("vspipe")
+            .arg("-c")
+            .arg("y4m")
+            .arg(absolute_path(vpy_input).expect("Unable to get absolute path"))
+            .arg("-")
+            .stdout(Stdio::piped()),
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to execute vspipe for SVT-AV1 encoding: {}", e))?;
+
+    let args = svtav1_direct_args(
+        crf,
+        speed,
+        profile,
+        grain,
+        dimensions,
+        colorimetry,
+        extra_args,
+        tiles,
+        grain_table.is_some(),
+        tuning,
+    );
+    debug!("SvtAv1EncApp args: {args}");
+
+    // SvtAv1EncApp can only write raw IVF, so encode to a temporary file and
+    // remux it into the container the rest of the pipeline expects.
+    let ivf_out = output.with_extension("ivf");
+    let mut command = Command::new("SvtAv1EncApp");
+    command
+        .arg("-i")
+        .arg("stdin")
+        .arg("-n")
+        .arg(dimensions.frames.to_string());
+    for arg in args.split_ascii_whitespace() {
+        command.arg(arg);
+    }
+    if !keyframes.is_empty() {
+        command.arg("--force-key-frames").arg(
+            keyframes
+                .iter()
+                .map(|frame| format!("{}f", frame))
+                .join(","),
+        );
+    }
+    if let Some(grain_table) = grain_table {
+        command
+            .arg("--fgs-table")
+            .arg(absolute_path(grain_table).expect("Unable to get absolute path"));
+    }
+    command
+        .arg("-b")
+        .arg(absolute_path(&ivf_out).expect("Unable to get absolute path"));
+    command.stdin(pipe.stdout());
+    let status = run_with_progress(
+        &mut command,
+        format!(
+            "SVT-AV1 {}",
+            output.file_name().unwrap_or_default().to_string_lossy()
+        ),
+        dimensions.frames,
+        ProgressSource::Stderr(parse_svtav1_progress),
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to execute SvtAv1EncApp: {}", e))?;
+    pipe.wait()?;
 
+    if !status.success() {
+        anyhow::bail!(
+            "Failed to execute SvtAv1EncApp: Exited with code {:x}",
+            status.code().unwrap_or(-1)
+        );
+    }
+
+    let status = run_logged(
+        Command::new("mkvmerge")
+            .arg("--quiet")
+            .arg("--output")
+            .arg(output)
+            .arg(&ivf_out),
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to execute mkvmerge: {}", e))?;
+    if !status.success() {
+        anyhow::bail!("Failed to remux SVT-AV1 output");
+    }
+    let _ = fs::remove_file(&ivf_out);
+
+    Ok(())
+}
+
+/// The arguments for encoding with SvtAv1EncApp itself, rather than through av1an,
+/// including any extra arguments of the output
+#[allow(clippy::too_many_arguments)]
+pub fn svtav1_direct_args(
+    crf: i16,
+    speed: u8,
+    profile: Profile,
+    grain: u8,
+    dimensions: VideoDimensions,
+    colorimetry: &Colorimetry,
+    extra_args: Option<&str>,
+    tiles: Option<(u8, u8)>,
+    has_grain_table: bool,
+    tuning: &SvtTuning,
+) -> String {
+    // Without av1an there is no scene detection to place keyframes for us,
+    // so let the encoder do it with the same keyint limits we use for x264.
+    let fps = (dimensions.fps.0 as f32 / dimensions.fps.1 as f32).round() as u32;
+    let keyint = if profile.is_anime() {
+        fps * 15
+    } else {
+        fps * 10
+    };
+    let threads = available_parallelism().expect("Unable to get machine parallelism count");
+    let mut args = build_svtav1_args_string(
+        crf,
+        speed,
+        threads.get(),
+        dimensions,
+        colorimetry,
+        Some(keyint),
+        tile_config(dimensions, tiles),
+        tuning,
+    );
+    // A grain table replaces the synthesized noise
+    if grain > 0 && !has_grain_table {
+        args.push_str(&format!("--film-grain {grain} "));
+    }
+    if let Some(extra_args) = extra_args {
+        args.push_str(extra_args);
+    }
+    args
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn build_svtav1_args_string(
     crf: i16,
     speed: u8,
     threads: usize,
     dimensions: VideoDimensions,
     colorimetry: &Colorimetry,
+    keyint: Option<u32>,
+    tiles: (u8, u8),
+    tuning: &SvtTuning,
 ) -> String {
     let depth = dimensions.bit_depth;
-    let tile_cols = i32::from(dimensions.width >= 2000);
-    let tile_rows = i32::from(
-        dimensions.height >= 2000 || (dimensions.height >= 1550 && dimensions.width >= 3600),
-    );
+    let (tile_cols, tile_rows) = tiles;
     let prim = colorimetry.primaries.to_u8().unwrap();
     let matrix = colorimetry.matrix.to_u8().unwrap();
     let transfer = colorimetry.transfer.to_u8().unwrap();
@@ -28,12 +237,20 @@ pub fn build_svtav1_args_string(
         ChromaLocation::Left => "left",
         _ => "unknown",
     };
+    // av1an handles scene detection and keyframe placement itself,
+    // so only enable it in the encoder when running standalone.
+    let (scd, keyint) = match keyint {
+        Some(keyint) => (1, keyint as i64),
+        None => (0, -1),
+    };
+    let qm_max = tuning.qm_max.unwrap_or(8);
+    let tuning = tuning.args();
     format!(
         " --input-depth {depth} --scm 0 --preset {speed} --crf {crf} --film-grain-denoise 0 \
          --tile-columns {tile_cols} --tile-rows {tile_rows} --rc 0 --enable-qm 1 \
-         --qm-min 0 --qm-max 8 --tune 3 --scd 0 --keyint -1 --lp {threads} \
+         --qm-min 0 --qm-max {qm_max} --tune 3 --scd {scd} --keyint {keyint} --lp {threads} \
          --pin 0 --color-primaries {prim} --matrix-coefficients {matrix} \
          --transfer-characteristics {transfer} --color-range {range} --chroma-sample-position \
-         {csp} "
+         {csp} {tuning}"
     )
 }
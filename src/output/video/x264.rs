@@ -3,19 +3,20 @@ use std::{
     fs::File,
     io::Write,
     path::Path,
-    process::{Command, Stdio},
+    process::Command,
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use ansi_term::Color::Yellow;
 use av_data::pixel::{
     ChromaLocation, ColorPrimaries, MatrixCoefficients, TransferCharacteristic, YUVRange,
 };
+use tracing::{debug, info, warn};
 
 use crate::{
     absolute_path,
     input::{get_video_frame_count, Colorimetry, PixelFormat, VideoDimensions},
-    output::Profile,
+    output::{video::y4m::ScriptPipe, Compat, Profile, ProfileOverrides},
+    progress::{parse_x264_progress, run_with_progress_from, ProgressSource},
 };
 
 #[allow(clippy::too_many_arguments)]
@@ -24,43 +25,31 @@ pub fn convert_video_x264(
     output: &Path,
     crf: i16,
     profile: Profile,
-    compat: bool,
+    compat: Compat,
     dimensions: VideoDimensions,
     force_keyframes: &Option<String>,
     colorimetry: &Colorimetry,
+    overrides: &ProfileOverrides,
+    sar: Option<(u32, u32)>,
+    zones: Option<&str>,
+    extra_args: Option<&str>,
 ) -> anyhow::Result<()> {
     if dimensions.width % 8 != 0 {
-        eprintln!(
-            "{} {} {} {}",
-            Yellow.bold().paint("[Warning]"),
-            Yellow.paint("Width"),
-            Yellow.paint(dimensions.width.to_string()),
-            Yellow.paint("is not divisble by 8")
-        );
+        warn!("Width {} is not divisble by 8", dimensions.width);
     }
     if dimensions.height % 8 != 0 {
-        eprintln!(
-            "{} {} {} {}",
-            Yellow.bold().paint("[Warning]"),
-            Yellow.paint("Height"),
-            Yellow.paint(dimensions.height.to_string()),
-            Yellow.paint("is not divisble by 8")
-        );
+        warn!("Height {} is not divisble by 8", dimensions.height);
     }
 
     if output.exists() && get_video_frame_count(output).unwrap_or(0) == dimensions.frames {
-        eprintln!("Video output already exists, reusing");
+        info!("Video output already exists, reusing");
         return Ok(());
     }
 
-    let mut pipe = Command::new("vspipe")
-        .arg("-c")
-        .arg("y4m")
-        .arg(absolute_path(vpy_input).expect("Unable to get absolute path"))
-        .arg("-")
-        .stdout(Stdio::piped())
-        .spawn()
-        .map_err(|e| anyhow::anyhow!("Failed to execute vspipe for x264 encoding: {}", e))?;
+    let pipe = ScriptPipe::new(
+        &absolute_path(vpy_input).expect("Unable to get absolute path"),
+        None,
+    );
 
     let mut command = Command::new("x264");
     command
@@ -68,15 +57,19 @@ pub fn convert_video_x264(
         .arg("y4m")
         .arg("--frames")
         .arg(dimensions.frames.to_string());
-    let args = build_x264_args_string(
+    let args = x264_args(
         crf,
-        dimensions,
         profile,
         compat,
+        dimensions,
         force_keyframes,
         colorimetry,
+        overrides,
+        sar,
+        zones,
+        extra_args,
     )?;
-    eprintln!("x264 args: {args}");
+    debug!("x264 args: {args}");
     for arg in args.split_ascii_whitespace() {
         command.arg(arg);
     }
@@ -84,13 +77,18 @@ pub fn convert_video_x264(
         .arg("-o")
         .arg(absolute_path(output).expect("Unable to get absolute path"))
         .arg("-");
-    command
-        .stdin(pipe.stdout.take().expect("stdout should be writeable"))
-        .stderr(Stdio::inherit());
-    let status = command
-        .status()
-        .map_err(|e| anyhow::anyhow!("Failed to execute av1an: {}", e))?;
-    pipe.wait()?;
+    let status = run_with_progress_from(
+        &mut command,
+        format!(
+            "x264 {}",
+            output.file_name().unwrap_or_default().to_string_lossy()
+        ),
+        dimensions.frames,
+        ProgressSource::Stderr(parse_x264_progress),
+        Some(pipe.writer()),
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to execute x264: {}", e))?;
+    pipe.finish()?;
 
     if status.success() {
         Ok(())
@@ -102,35 +100,79 @@ pub fn convert_video_x264(
     }
 }
 
+/// The profile's arguments, followed by any aspect ratio, zones and extra arguments of the output
+#[allow(clippy::too_many_arguments)]
+pub fn x264_args(
+    crf: i16,
+    profile: Profile,
+    compat: Compat,
+    dimensions: VideoDimensions,
+    force_keyframes: &Option<String>,
+    colorimetry: &Colorimetry,
+    overrides: &ProfileOverrides,
+    sar: Option<(u32, u32)>,
+    zones: Option<&str>,
+    extra_args: Option<&str>,
+) -> anyhow::Result<String> {
+    let mut args = build_x264_args_string(
+        crf,
+        dimensions,
+        profile,
+        compat,
+        force_keyframes,
+        colorimetry,
+        overrides,
+    )?;
+    if let Some((num, den)) = sar {
+        args.push_str(&format!(" --sar {}:{} ", num, den));
+    }
+    if let Some(zones) = zones {
+        args.push_str(&format!(
+            " --zones {} ",
+            zones.split_whitespace().collect::<String>()
+        ));
+    }
+    if let Some(extra_args) = extra_args {
+        args.push_str(extra_args);
+    }
+    Ok(args)
+}
+
 pub fn build_x264_args_string(
     crf: i16,
     dimensions: VideoDimensions,
     profile: Profile,
-    compat: bool,
+    compat: Compat,
     force_keyframes: &Option<String>,
     colorimetry: &Colorimetry,
+    overrides: &ProfileOverrides,
 ) -> anyhow::Result<String> {
     let fps = (dimensions.fps.0 as f32 / dimensions.fps.1 as f32).round() as u32;
-    let min_keyint = if profile.is_anime() { fps / 2 } else { fps };
-    let max_keyint = if profile.is_anime() {
-        fps * 15
+    let (min_keyint, max_keyint) = if compat == Compat::Bluray {
+        // Blu-ray players need a keyframe at least every second
+        (1, fps)
+    } else if profile.is_anime() {
+        (fps / 2, fps * 15)
     } else {
-        fps * 10
+        (fps, fps * 10)
     };
     let preset = if profile == Profile::Fast {
         "faster"
     } else {
         "veryslow"
     };
-    let bframes = match profile {
+    let mut bframes = overrides.bframes.unwrap_or(match profile {
         Profile::Film | Profile::Grain => 5,
         Profile::Anime | Profile::AnimeDetailed | Profile::AnimeGrain => 8,
         Profile::Fast => 3,
-    };
-    let psy_rd = if profile.is_anime() {
-        format!("{:.1}:{:.1}", 0.7, 0.0)
-    } else {
-        format!("{:.1}:{:.1}", 1.0, 0.0)
+    });
+    if compat == Compat::Bluray {
+        bframes = bframes.min(3);
+    }
+    let psy_rd = match overrides.psy_rd {
+        Some(psy_rd) => format!("{}:{:.1}", psy_rd, 0.0),
+        None if profile.is_anime() => format!("{:.1}:{:.1}", 0.7, 0.0),
+        None => format!("{:.1}:{:.1}", 1.0, 0.0),
     };
     let deblock = if profile.is_anime() {
         format!("{}:{}", -2, -1)
@@ -144,16 +186,16 @@ pub fn build_x264_args_string(
     } else {
         24
     };
-    let aq_str = match profile {
-        Profile::Grain => "0.9",
-        Profile::Film | Profile::AnimeGrain => "0.8",
-        Profile::Anime | Profile::AnimeDetailed | Profile::Fast => "0.7",
-    };
-    let qcomp = match profile {
+    let aq_str = overrides.aq_strength.unwrap_or(match profile {
+        Profile::Grain => 0.9,
+        Profile::Film | Profile::AnimeGrain => 0.8,
+        Profile::Anime | Profile::AnimeDetailed | Profile::Fast => 0.7,
+    });
+    let qcomp = overrides.qcomp.unwrap_or(match profile {
         Profile::Film | Profile::Grain | Profile::Fast => 0.75,
         Profile::AnimeGrain => 0.7,
         Profile::Anime | Profile::AnimeDetailed => 0.65,
-    };
+    });
     let prim = match colorimetry.primaries {
         ColorPrimaries::BT709 => "bt709",
         ColorPrimaries::BT470M => "bt470m",
@@ -219,10 +261,23 @@ pub fn build_x264_args_string(
         _ => "",
     };
     let depth = dimensions.bit_depth;
-    let vbv = if compat {
-        "--level 4.1 --vbv-maxrate 50000 --vbv-bufsize 78125"
-    } else {
-        ""
+    if let Some(limits) = compat.limits() {
+        limits.check_dimensions(compat.name(), dimensions);
+    }
+    let vbv = match compat.limits() {
+        None => String::new(),
+        Some(limits) if compat != Compat::Bluray => {
+            let (level, maxrate, bufsize) = limits.h264_level(dimensions);
+            format!(
+                "--level {} --vbv-maxrate {} --vbv-bufsize {}",
+                level, maxrate, bufsize
+            )
+        }
+        Some(_) => format!(
+            "--bluray-compat --level 4.1 --vbv-maxrate 40000 --vbv-bufsize 30000 --slices 4 \
+             --aud --nal-hrd vbr --b-pyramid strict {}",
+            bluray_structure(dimensions)?
+        ),
     };
     let level = match dimensions.pixel_format {
         PixelFormat::Yuv422 => "--profile high422 --output-csp i422",
@@ -255,3 +310,31 @@ pub fn build_x264_args_string(
          {level} {qpfile} "
     ))
 }
+
+/// Blu-ray only allows a few resolution and frame rate combinations,
+/// some of which have to be flagged as interlaced or pulled down
+/// even though the encode itself is progressive
+fn bluray_structure(dimensions: VideoDimensions) -> anyhow::Result<&'static str> {
+    if dimensions.bit_depth != 8 || dimensions.pixel_format != PixelFormat::Yuv420 {
+        anyhow::bail!("Blu-ray requires 8-bit 4:2:0 video");
+    }
+    let fps = dimensions.fps;
+    let film = fps == (24000, 1001) || fps == (24, 1);
+    let ntsc = fps == (30000, 1001);
+    let pal = fps == (25, 1);
+    Ok(match (dimensions.width, dimensions.height) {
+        (1920 | 1440, 1080) if film => "",
+        (1920 | 1440, 1080) if ntsc || pal => "--fake-interlaced --pic-struct",
+        (1280, 720) if film || fps == (50, 1) || fps == (60000, 1001) => "",
+        (720, 480) if fps == (24000, 1001) => "--pulldown 32 --fake-interlaced",
+        (720, 480) if ntsc => "--fake-interlaced --pic-struct",
+        (720, 576) if pal => "--fake-interlaced --pic-struct",
+        (width, height) => anyhow::bail!(
+            "{}x{} at {}/{} fps is not allowed on Blu-ray",
+            width,
+            height,
+            fps.0,
+            fps.1
+        ),
+    })
+}
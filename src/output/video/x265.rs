@@ -6,26 +6,27 @@ use av_data::pixel::{
 
 use crate::{
     input::{Colorimetry, VideoDimensions},
-    output::Profile,
+    output::{Compat, Profile, ProfileOverrides},
 };
 
 pub fn build_x265_args_string(
     crf: i16,
     dimensions: VideoDimensions,
     profile: Profile,
-    compat: bool,
+    compat: Compat,
     colorimetry: &Colorimetry,
     threads: NonZeroUsize,
+    overrides: &ProfileOverrides,
 ) -> String {
     // TODO: Add full HDR metadata
 
     let deblock = if profile.is_anime() { -1 } else { -2 };
     let chroma_offset = if profile.is_anime() { -2 } else { 0 };
-    let bframes = match profile {
+    let bframes = overrides.bframes.unwrap_or(match profile {
         Profile::Film | Profile::Grain => 5,
         Profile::Anime | Profile::AnimeDetailed | Profile::AnimeGrain => 8,
         Profile::Fast => 3,
-    };
+    });
     let refframes = match profile {
         Profile::Film | Profile::Grain | Profile::AnimeGrain => 4,
         Profile::Anime | Profile::AnimeDetailed => 6,
@@ -38,22 +39,23 @@ pub fn build_x265_args_string(
     } else {
         "--no-sao --no-strong-intra-smoothing"
     };
-    let psy_rd = match profile {
-        Profile::Anime | Profile::Fast => "1.0",
-        Profile::Film | Profile::AnimeDetailed => "1.5",
-        Profile::Grain | Profile::AnimeGrain => "2.0",
-    };
+    let psy_rd = overrides.psy_rd.unwrap_or(match profile {
+        Profile::Anime | Profile::Fast => 1.0,
+        Profile::Film | Profile::AnimeDetailed => 1.5,
+        Profile::Grain | Profile::AnimeGrain => 2.0,
+    });
     let psy_rdo = match profile {
         Profile::Anime | Profile::Fast => "1.0",
         Profile::AnimeDetailed => "1.5",
         Profile::Film | Profile::AnimeGrain => "2.0",
         Profile::Grain => "4.0",
     };
-    let aq_str = match profile {
-        Profile::Grain => "0.9",
-        Profile::Film | Profile::AnimeGrain => "0.8",
-        Profile::Anime | Profile::AnimeDetailed | Profile::Fast => "0.7",
-    };
+    let aq_str = overrides.aq_strength.unwrap_or(match profile {
+        Profile::Grain => 0.9,
+        Profile::Film | Profile::AnimeGrain => 0.8,
+        Profile::Anime | Profile::AnimeDetailed | Profile::Fast => 0.7,
+    });
+    let qcomp = overrides.qcomp.unwrap_or(0.65);
     let prim = match colorimetry.primaries {
         ColorPrimaries::BT709 => "bt709",
         ColorPrimaries::BT470M => "bt470m",
@@ -119,14 +121,24 @@ pub fn build_x265_args_string(
         _ => "",
     };
     let depth = dimensions.bit_depth;
-    let level = if compat {
-        if dimensions.bit_depth == 10 {
-            "--profile main10 --level-idc 5.1"
-        } else {
-            "--profile main --level-idc 5.1"
+    let level = match compat.limits() {
+        Some(limits) => {
+            limits.check_dimensions(compat.name(), dimensions);
+            let (level, vbv) = limits.hevc_level(dimensions);
+            let profile = if dimensions.bit_depth == 10 {
+                "main10"
+            } else {
+                "main"
+            };
+            match vbv {
+                Some((maxrate, bufsize)) => format!(
+                    "--profile {} --level-idc {} --vbv-maxrate {} --vbv-bufsize {}",
+                    profile, level, maxrate, bufsize
+                ),
+                None => format!("--profile {} --level-idc {}", profile, level),
+            }
         }
-    } else {
-        ""
+        None => String::new(),
     };
     let hdr: &str = if colorimetry.is_hdr() {
         "--hdr10-opt"
@@ -135,7 +147,7 @@ pub fn build_x265_args_string(
     };
     format!(
         " --crf {crf} --preset slow --bframes {bframes} --ref {refframes} --keyint -1 --min-keyint 1 \
-          --no-scenecut {sao} --deblock {deblock}:{deblock} --psy-rd {psy_rd} --psy-rdoq {psy_rdo} --qcomp 0.65 \
+          --no-scenecut {sao} --deblock {deblock}:{deblock} --psy-rd {psy_rd} --psy-rdoq {psy_rdo} --qcomp {qcomp} \
          --aq-mode 3 --aq-strength {aq_str} --cbqpoffs {chroma_offset} --crqpoffs {chroma_offset} \
          --no-open-gop --no-cutree --fades --colorprim {prim} --colormatrix {matrix} --transfer {transfer} \
          --range {range} {csp} --output-depth {depth} --frame-threads {threads} --lookahead-threads {threads} \
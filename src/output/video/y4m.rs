@@ -0,0 +1,202 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::{ChildStdin, Command, Stdio},
+    sync::{mpsc, Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Result};
+use vapoursynth::{
+    format::{ColorFamily, SampleType},
+    frame::FrameRef,
+    video_info::Property,
+};
+
+use crate::{
+    input::{get_video_dimensions, open_script, script_output},
+    progress::StdinWriter,
+    tool_log::{current_tool_log, is_dry_run, spawn_logged},
+};
+
+/// Renders a script in this process and writes it to a tool as y4m,
+/// in place of piping `vspipe -c y4m` into the tool.
+///
+/// A frame which fails to render is reported along with the script's error,
+/// rather than as the tool's input ending early.
+pub struct ScriptPipe {
+    script: PathBuf,
+    /// The inclusive range of frames to render, or every frame
+    range: Option<(u32, u32)>,
+    error: Arc<Mutex<Option<anyhow::Error>>>,
+}
+
+impl ScriptPipe {
+    pub fn new(script: &Path, range: Option<(u32, u32)>) -> Self {
+        if is_dry_run() {
+            // The tool is printed reading from the vspipe it replaces
+            let _ = spawn_logged(&mut vspipe_command(script, range));
+        } else if let Some(log) = current_tool_log() {
+            log.write_line(&format!("# Rendering {} as y4m", script.display()));
+        }
+        ScriptPipe {
+            script: script.to_path_buf(),
+            range,
+            error: Arc::default(),
+        }
+    }
+
+    /// Writes the frames to the tool's stdin
+    pub fn writer(&self) -> StdinWriter {
+        let script = self.script.clone();
+        let range = self.range;
+        let error = Arc::clone(&self.error);
+        Box::new(move |stdin| {
+            match write_y4m(&script, range, stdin) {
+                // The tool stopped reading, which it reports itself
+                Err(e) if e.is::<io::Error>() => (),
+                Err(e) => *error.lock().unwrap() = Some(e),
+                Ok(()) => (),
+            }
+        })
+    }
+
+    /// Fails if the script could not be rendered, which should be reported
+    /// ahead of however the tool failed
+    pub fn finish(self) -> Result<()> {
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+fn vspipe_command(script: &Path, range: Option<(u32, u32)>) -> Command {
+    let mut command = Command::new("vspipe");
+    command
+        .arg("-c")
+        .arg("y4m")
+        .arg("-o")
+        .arg(script_output(script).to_string());
+    if let Some((start, end)) = range {
+        command
+            .arg("-s")
+            .arg(start.to_string())
+            .arg("-e")
+            .arg(end.to_string());
+    }
+    command.arg(script).arg("-").stdout(Stdio::piped());
+    command
+}
+
+fn write_y4m(script: &Path, range: Option<(u32, u32)>, stdin: ChildStdin) -> Result<()> {
+    let env = open_script(script)?;
+    let (node, _) = env.get_output(script_output(script))?;
+    let info = node.info();
+    let variable = |property| anyhow!("{} has a variable {}", script.display(), property);
+    let format = match info.format {
+        Property::Constant(format) => format,
+        Property::Variable => return Err(variable("format")),
+    };
+    let resolution = match info.resolution {
+        Property::Constant(resolution) => resolution,
+        Property::Variable => return Err(variable("resolution")),
+    };
+    let framerate = match info.framerate {
+        Property::Constant(framerate) => (framerate.numerator, framerate.denominator),
+        // Written at the average rate, since the timecodes are muxed separately
+        Property::Variable => {
+            let (num, den) = get_video_dimensions(script)?.fps;
+            (num as u64, den as u64)
+        }
+    };
+    let subsampling = match (
+        format.color_family(),
+        format.sample_type(),
+        format.sub_sampling_w(),
+        format.sub_sampling_h(),
+    ) {
+        (ColorFamily::YUV, SampleType::Integer, 1, 1) => "420",
+        (ColorFamily::YUV, SampleType::Integer, 1, 0) => "422",
+        (ColorFamily::YUV, SampleType::Integer, 0, 0) => "444",
+        _ => bail!(
+            "{} outputs {}, which can't be written as y4m",
+            script.display(),
+            format.name()
+        ),
+    };
+    let bits = format.bits_per_sample();
+    let (start, end) = range.unwrap_or((0, info.num_frames.saturating_sub(1) as u32));
+
+    let mut out = BufWriter::with_capacity(1 << 20, stdin);
+    writeln!(
+        out,
+        "YUV4MPEG2 C{}{} W{} H{} F{}:{} Ip A0:0 XLENGTH={}",
+        subsampling,
+        if bits > 8 {
+            format!("p{}", bits)
+        } else {
+            String::new()
+        },
+        resolution.width,
+        resolution.height,
+        framerate.0,
+        framerate.1,
+        end - start + 1
+    )?;
+
+    // Frames are rendered in parallel, as many at a time as the core has threads
+    let parallel = env.get_core()?.info().num_threads.max(1) as u32;
+    let (sender, receiver) = mpsc::channel::<(u32, Result<FrameRef, String>)>();
+    let mut rendered = BTreeMap::new();
+    let mut requested = start;
+    let mut outstanding = 0;
+    let mut result = Ok(());
+    for n in start..=end {
+        while requested <= end && requested < n + parallel {
+            let sender = sender.clone();
+            node.get_frame_async(requested as usize, move |frame, n, _| {
+                let _ = sender.send((n as u32, frame.map_err(|e| e.to_string())));
+            });
+            requested += 1;
+            outstanding += 1;
+        }
+        let frame = loop {
+            if let Some(frame) = rendered.remove(&n) {
+                break frame;
+            }
+            let (i, frame) = receiver.recv()?;
+            outstanding -= 1;
+            rendered.insert(i, frame);
+        };
+        result = frame
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to render frame {} of {}: {}",
+                    n,
+                    script.display(),
+                    e
+                )
+            })
+            .and_then(|frame| {
+                out.write_all(b"FRAME\n")?;
+                for plane in 0..format.plane_count() {
+                    for row in 0..frame.height(plane) {
+                        out.write_all(frame.data_row(plane, row))?;
+                    }
+                }
+                Ok(())
+            });
+        if result.is_err() {
+            break;
+        }
+    }
+    // Frames still being rendered belong to the script, so they must finish before it is freed
+    for _ in 0..outstanding {
+        let _ = receiver.recv();
+    }
+    drop(rendered);
+    result?;
+    out.flush()?;
+    Ok(())
+}
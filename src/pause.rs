@@ -0,0 +1,44 @@
+use anyhow::Result;
+
+/// Pauses or resumes every tool we spawned, including their own children,
+/// returning how many tools were signalled.
+#[cfg(unix)]
+pub fn set_children_paused(paused: bool) -> Result<usize> {
+    crate::cancel::signal_tools(if paused { libc::SIGSTOP } else { libc::SIGCONT })
+}
+
+#[cfg(not(unix))]
+pub fn set_children_paused(_paused: bool) -> Result<usize> {
+    anyhow::bail!("Pausing encodes is not supported on this platform")
+}
+
+/// Pauses child processes on SIGUSR1 and resumes them on SIGUSR2
+#[cfg(unix)]
+pub fn handle_pause_signals() -> Result<()> {
+    use signal_hook::{
+        consts::{SIGUSR1, SIGUSR2},
+        iterator::Signals,
+    };
+    use tracing::{info, warn};
+
+    let mut signals = Signals::new([SIGUSR1, SIGUSR2])?;
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            let paused = signal == SIGUSR1;
+            match set_children_paused(paused) {
+                Ok(count) => info!(
+                    "{} {} encoding processes",
+                    if paused { "Paused" } else { "Resumed" },
+                    count
+                ),
+                Err(err) => warn!("{}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn handle_pause_signals() -> Result<()> {
+    Ok(())
+}
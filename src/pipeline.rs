@@ -0,0 +1,1684 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fmt::Write as _,
+    fs, panic,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use dotenvy_macro::dotenv;
+use itertools::Itertools;
+use serde_json::{json, Value};
+use size::Size;
+use tracing::{info, warn};
+
+use crate::{
+    build_sample_script, build_video_suffix, build_vpy_script,
+    cancel::{check_cancelled, current_cancel_token, set_cancel_token, CancelToken},
+    checksum::{checksum_path, write_checksum},
+    cli::{Track, TrackSource},
+    config::output_config,
+    error::{classify, Error},
+    events::{emit, path_value},
+    fonts::check_subtitle_fonts,
+    history::{encoder_version, record_encode, unix_time},
+    input::*,
+    logging::{current_warnings, set_current_warnings},
+    metrics::{frame_scores, scores_json, Metric, ScoreSummary},
+    naming::{output_height, sanitize_filename, NameTokens},
+    notify::format_duration,
+    output::*,
+    state::{StageMarkers, StageStatus},
+    tool_log::{
+        current_tool_log, is_dry_run, print_dry_run, print_dry_run_command, print_dry_run_script,
+        set_current_tool_log, set_tool_log,
+    },
+    upload::UploadDestination,
+    work_dir::{create_work_dir, work_path},
+};
+
+/// A subtitle file to mux, along with whether it is enabled and forced
+pub type SubtitleOutput = (PathBuf, bool, bool);
+
+/// Settings which apply to every input in a run
+#[derive(Debug, Clone, Default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct ProcessOptions {
+    pub output_dir: Option<String>,
+    pub keep_lossless: bool,
+    pub lossless_only: bool,
+    pub skip_lossless: bool,
+    pub lossless_codec: LosslessCodec,
+    pub verify_lossless: bool,
+    pub lossless_segments: Option<u32>,
+    /// Audio from the source to include in the lossless
+    pub lossless_audio: Option<LosslessAudio>,
+    pub force_keyframes: Option<String>,
+    pub verify_frame_count: bool,
+    pub ignore_delay: bool,
+    pub no_retry: bool,
+    /// Mux outputs without dates or random UIDs, so they can be compared byte for byte
+    pub reproducible: bool,
+    /// Template for the title of each output, see `--title`
+    pub title: Option<String>,
+    /// Template for the filename of each output, see `--name`
+    pub name_template: Option<String>,
+    /// Write a SHA-256 checksum next to each output
+    pub checksum: bool,
+    /// Fail inputs whose subtitles use fonts which aren't installed
+    pub strict_fonts: bool,
+    /// Extra directories each finished output is linked or copied into
+    pub copy_to: Vec<PathBuf>,
+    /// Copy each finished output here
+    pub upload: Option<UploadDestination>,
+    pub delete_after_upload: bool,
+    /// Score every output with this metric once it is muxed
+    pub quality_check: Option<Metric>,
+    /// Fail outputs whose mean score is below this
+    pub min_quality: Option<f64>,
+    pub av1an: Av1anOptions,
+}
+
+impl ProcessOptions {
+    /// The directory final outputs are written to
+    pub fn output_path(&self) -> &Path {
+        Path::new(self.output_dir.as_deref().unwrap_or(dotenv!("OUTPUT_PATH")))
+    }
+}
+
+/// State for a single input script, shared by every stage
+pub struct InputContext<'a> {
+    pub input_vpy: &'a Path,
+    pub outputs: &'a [Output],
+    pub options: &'a ProcessOptions,
+    pub source_video: PathBuf,
+    /// Where the audio, subtitles and chapters come from
+    pub sources: SourceMap,
+    pub colorimetry: Colorimetry,
+    pub skip_lossless: bool,
+    /// Which intermediates were finished, and with which settings
+    pub markers: Arc<StageMarkers>,
+    /// How many times the lossless encode failed and was tried again
+    pub lossless_retries: Cell<u32>,
+    /// The checks the lossless passed when it was made or found in this run
+    pub lossless_verified: Vec<&'static str>,
+}
+
+impl<'a> InputContext<'a> {
+    pub fn new(
+        input_vpy: &'a Path,
+        outputs: &'a [Output],
+        options: &'a ProcessOptions,
+    ) -> Result<Self> {
+        Ok(InputContext {
+            input_vpy,
+            outputs,
+            options,
+            source_video: find_source_file(input_vpy),
+            sources: find_source_map(input_vpy),
+            colorimetry: get_video_colorimetry(input_vpy)?,
+            skip_lossless: options.skip_lossless,
+            markers: Arc::new(StageMarkers::load(input_vpy)),
+            lossless_retries: Cell::new(0),
+            lossless_verified: Vec::new(),
+        })
+    }
+
+    /// The keyframes `output` forces, which are its own if it has any,
+    /// then `--force-keyframes`, then the sections of the script's Wobbly project
+    pub fn output_keyframes(&self, output: &Output) -> Result<Option<String>> {
+        if let Some(ref frames) = output
+            .video
+            .force_keyframes
+            .as_ref()
+            .or(self.options.force_keyframes.as_ref())
+        {
+            return Ok(Some(frames.to_string()));
+        }
+        Ok(wobbly_project(self.input_vpy)?
+            .filter(|project| !project.keyframes.is_empty())
+            .map(|project| project.keyframes.iter().join(",")))
+    }
+
+    /// The colorimetry `output` is encoded with: the script's, with the output's overrides,
+    /// and with anything still unspecified guessed from the script's resolution
+    pub fn output_colorimetry(&self, output: &Output) -> Result<Colorimetry> {
+        let dimensions = get_video_dimensions(self.input_vpy)?;
+        Ok(self
+            .colorimetry
+            .with_overrides(&output.video.colorimetry)
+            .with_fallbacks(self.input_vpy, dimensions.width, dimensions.height))
+    }
+}
+
+/// An output which was written, along with any quality scores measured for it
+pub struct FinishedOutput {
+    pub path: PathBuf,
+    pub scores: Vec<(Metric, ScoreSummary)>,
+    /// The settings the output resolved to, as `--print-config` shows them
+    pub settings: Option<Value>,
+    pub duration: Duration,
+    /// How many times the lossless it was encoded from had to be retried
+    pub lossless_retries: u32,
+    /// The checks the output and its lossless passed
+    pub verified: Vec<&'static str>,
+}
+
+/// State for a single output of an input, shared by every stage
+pub struct OutputContext<'a> {
+    pub output: &'a Output,
+    pub output_vpy: PathBuf,
+    pub video_out: PathBuf,
+    pub audio_outputs: Vec<(PathBuf, Track, AudioEncoder)>,
+    /// The script's own audio outputs which are used as tracks
+    pub vpy_audio: Vec<u8>,
+    pub subtitle_outputs: Vec<SubtitleOutput>,
+    pub output_path: PathBuf,
+    /// Written once every stream is ready to mux,
+    /// so a rerun after a failed mux can skip straight to it
+    pub mux_marker: PathBuf,
+    /// Whether a previous run already prepared every stream
+    pub streams_prepared: bool,
+    /// Stages may run work in the background,
+    /// which must finish before the streams are muxed
+    pub pending_audio: Option<JoinHandle<Result<()>>>,
+    pub pending_subtitles: Option<JoinHandle<Result<Vec<SubtitleOutput>>>>,
+    pub started: Instant,
+    /// Quality scores measured for the finished output
+    pub scores: Vec<(Metric, ScoreSummary)>,
+    /// Whether the output was uploaded, in which case it may no longer exist locally
+    pub uploaded: bool,
+    /// The encode settings, as they appear in the default output filename
+    pub settings: String,
+    /// The checks stages made of the output which passed
+    pub verified: Vec<&'static str>,
+}
+
+impl<'a> OutputContext<'a> {
+    pub fn new(input: &InputContext, output: &'a Output) -> Result<Self> {
+        let input_vpy = input.input_vpy;
+        let video_suffix = build_video_suffix(output)?;
+        let output_vpy = input_vpy.with_extension(format!("{}.vpy", video_suffix));
+        let video_out = work_path(&output_vpy).with_extension("mkv");
+
+        let mut audio_tracks = if output.audio_tracks.is_empty() {
+            vec![Track {
+                source: TrackSource::FromVideo(0),
+                enabled: true,
+                forced: false,
+            }]
+        } else {
+            output.audio_tracks.clone()
+        };
+        let chosen_script_audio = audio_tracks
+            .iter()
+            .any(|track| matches!(track.source, TrackSource::ScriptAudio(_)));
+        if !chosen_script_audio {
+            // A script's own audio replaces the source's
+            let script_audio = script_audio_outputs(input_vpy)?;
+            if !script_audio.is_empty() {
+                audio_tracks = script_audio
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, index)| Track {
+                        source: TrackSource::ScriptAudio(index),
+                        enabled: i == 0,
+                        forced: false,
+                    })
+                    .collect();
+            }
+        }
+        let vpy_audio = audio_tracks
+            .iter()
+            .filter_map(|track| match track.source {
+                TrackSource::ScriptAudio(index) => Some(index),
+                _ => None,
+            })
+            .unique()
+            .collect();
+        let mut audio_outputs = Vec::new();
+        let mut audio_suffixes = Vec::new();
+        for (i, audio_track) in audio_tracks.iter().enumerate() {
+            let audio_suffix = format!(
+                "{}-{}kbpc-at{}",
+                output.audio.encoder, output.audio.kbps_per_channel, i
+            );
+            let audio_out = work_path(input_vpy).with_extension(format!("{}.mka", audio_suffix));
+            audio_outputs.push((audio_out, audio_track.clone(), output.audio.encoder));
+            audio_suffixes.push(audio_suffix);
+        }
+        let audio_suffix = audio_suffixes.join("-");
+        let settings = format!("{}-{}", video_suffix, audio_suffix);
+        let mut output_path = input.options.output_path().to_path_buf();
+        match input.options.name_template {
+            Some(ref template) => {
+                let mut tokens = NameTokens::new(input_vpy, settings.clone())?;
+                if template.contains("{resolution}") {
+                    tokens.resolution = Some(output_height(input_vpy, output)?);
+                }
+                output_path.push(format!(
+                    "{}.{}",
+                    sanitize_filename(&tokens.fill(template)),
+                    output.video.output_ext
+                ));
+            }
+            None => output_path.push(
+                input_vpy
+                    .with_extension(format!("{}.{}", settings, output.video.output_ext))
+                    .file_name()
+                    .expect("File should have a name"),
+            ),
+        }
+
+        let mux_marker = work_path(input_vpy)
+            .with_extension(format!("{}-{}.mux-ready", video_suffix, audio_suffix));
+        let prepared_subtitles = read_mux_marker(&mux_marker, &video_out, &audio_outputs, output);
+
+        Ok(OutputContext {
+            output,
+            output_vpy,
+            video_out,
+            audio_outputs,
+            vpy_audio,
+            streams_prepared: prepared_subtitles.is_some(),
+            subtitle_outputs: prepared_subtitles.unwrap_or_default(),
+            output_path,
+            mux_marker,
+            pending_audio: None,
+            pending_subtitles: None,
+            started: Instant::now(),
+            scores: Vec::new(),
+            uploaded: false,
+            settings,
+            verified: input.lossless_verified.clone(),
+        })
+    }
+
+    /// Waits for any audio and subtitle work running in the background
+    pub fn wait_for_streams(&mut self) -> Result<()> {
+        let audio_result = self.pending_audio.take().map(join_stage_thread);
+        let subtitles_result = self.pending_subtitles.take().map(join_stage_thread);
+        if let Some(result) = audio_result {
+            result.map_err(|e| classify(e, Error::EncodeFailed))?;
+        }
+        if let Some(result) = subtitles_result {
+            self.subtitle_outputs = result.map_err(|e| classify(e, Error::EncodeFailed))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OutputContext<'_> {
+    fn drop(&mut self) {
+        // Don't leave background work running if a later stage failed
+        let _ = self.wait_for_streams();
+    }
+}
+
+fn join_stage_thread<T>(handle: JoinHandle<T>) -> T {
+    handle.join().unwrap_or_else(|e| panic::resume_unwind(e))
+}
+
+/// A step in processing an input.
+///
+/// Each stage is run in order for the input as a whole,
+/// then in order for each of its outputs,
+/// then in order once more after every output has finished.
+pub trait Stage: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The kind of error a failure of this stage is, unless it says otherwise
+    fn error_kind(&self) -> Option<fn(String) -> Error> {
+        None
+    }
+
+    /// Runs once for the input, before any of its outputs.
+    ///
+    /// Returning `false` stops processing the input without an error.
+    fn run_input(&self, _input: &mut InputContext) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Runs once for each output of the input
+    fn run_output(&self, _input: &InputContext, _output: &mut OutputContext) -> Result<()> {
+        Ok(())
+    }
+
+    /// Runs once for the input, after all of its outputs have finished
+    fn finish_input(&self, _input: &InputContext) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// An ordered list of stages to run on each input
+pub struct Pipeline {
+    pub stages: Vec<Box<dyn Stage>>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline {
+            stages: default_stages(),
+        }
+    }
+}
+
+impl Pipeline {
+    /// Processes an input, until it finishes or `cancel` is cancelled
+    pub fn run(
+        &self,
+        input_vpy: &Path,
+        outputs: &[Output],
+        options: &ProcessOptions,
+        cancel: &CancelToken,
+        on_stage: &dyn Fn(&str),
+    ) -> Result<Vec<FinishedOutput>> {
+        set_cancel_token(Some(cancel.clone()));
+        let result = self.run_stages(input_vpy, outputs, options, on_stage);
+        let _ = set_tool_log(None);
+        set_cancel_token(None);
+        result
+    }
+
+    /// Tools run for the input as a whole are logged next to the input,
+    /// and tools run for each output are logged next to its intermediates.
+    fn run_stages(
+        &self,
+        input_vpy: &Path,
+        outputs: &[Output],
+        options: &ProcessOptions,
+        on_stage: &dyn Fn(&str),
+    ) -> Result<Vec<FinishedOutput>> {
+        set_tool_log(Some(&input_vpy.with_extension("log")))?;
+        if !is_dry_run() {
+            create_work_dir(input_vpy)?;
+        }
+        let mut input = InputContext::new(input_vpy, outputs, options)
+            .map_err(|e| classify(e, Error::SourceProbeFailed))?;
+        for stage in &self.stages {
+            check_cancelled()?;
+            on_stage(stage.name());
+            emit_stage_started(stage.name(), input_vpy, None);
+            let result = stage.run_input(&mut input);
+            emit_stage_finished(stage.name(), input_vpy, None, result.as_ref().err());
+            if !result.map_err(|e| stage_error(stage.as_ref(), e))? {
+                return Ok(Vec::new());
+            }
+        }
+
+        let mut output_paths = Vec::new();
+        for output in outputs {
+            let mut context = OutputContext::new(&input, output)?;
+            let log = context.output_vpy.with_extension("log");
+            set_tool_log(Some(&log))?;
+            info!(
+                "Encoding {}",
+                context
+                    .output_vpy
+                    .file_name()
+                    .expect("File should have a name")
+                    .to_string_lossy()
+            );
+            for stage in &self.stages {
+                check_cancelled()?;
+                on_stage(stage.name());
+                emit_stage_started(stage.name(), input_vpy, Some(&context.output_path));
+                let result = stage.run_output(&input, &mut context);
+                emit_stage_finished(
+                    stage.name(),
+                    input_vpy,
+                    Some(&context.output_path),
+                    result.as_ref().err(),
+                );
+                result.map_err(|e| {
+                    let message = |e| {
+                        format!(
+                            "{} stage: {} (tool output is logged in {})",
+                            stage.name(),
+                            e,
+                            log.display()
+                        )
+                    };
+                    match stage.error_kind() {
+                        Some(kind) => anyhow::Error::new(classify(e, kind).map_message(message)),
+                        None => anyhow!(message(e.to_string())),
+                    }
+                })?;
+            }
+            if is_dry_run() {
+                print_dry_run(&format!("# Output: {}", context.output_path.display()));
+                continue;
+            }
+            // Not every pipeline writes the output
+            if context.output_path.exists() || context.uploaded {
+                emit(
+                    "output_written",
+                    json!({
+                        "input": path_value(input_vpy),
+                        "output": path_value(&context.output_path),
+                    }),
+                );
+                output_paths.push(FinishedOutput {
+                    path: context.output_path.clone(),
+                    scores: context.scores.clone(),
+                    settings: get_video_dimensions(input_vpy)
+                        .and_then(|dimensions| output_config(&input, output, dimensions))
+                        .ok(),
+                    duration: context.started.elapsed(),
+                    lossless_retries: input.lossless_retries.get(),
+                    verified: context.verified.clone(),
+                });
+            }
+        }
+
+        for stage in &self.stages {
+            stage
+                .finish_input(&input)
+                .map_err(|e| stage_error(stage.as_ref(), e))?;
+        }
+        Ok(output_paths)
+    }
+}
+
+/// Gives a stage's error the stage's kind, if it has one
+fn stage_error(stage: &dyn Stage, err: anyhow::Error) -> anyhow::Error {
+    match stage.error_kind() {
+        Some(kind) => classify(err, kind).into(),
+        None => err,
+    }
+}
+
+fn emit_stage_started(stage: &str, input: &Path, output: Option<&Path>) {
+    emit(
+        "stage_started",
+        json!({
+            "stage": stage,
+            "input": path_value(input),
+            "output": output.map(path_value),
+        }),
+    );
+}
+
+fn emit_stage_finished(
+    stage: &str,
+    input: &Path,
+    output: Option<&Path>,
+    error: Option<&anyhow::Error>,
+) {
+    emit(
+        "stage_finished",
+        json!({
+            "stage": stage,
+            "input": path_value(input),
+            "output": output.map(path_value),
+            "success": error.is_none(),
+            "error": error.map(ToString::to_string),
+        }),
+    );
+}
+
+/// The stages used for a normal run.
+///
+/// Audio and subtitles are prepared in the background while the video encodes,
+/// so they come before the video stage.
+pub fn default_stages() -> Vec<Box<dyn Stage>> {
+    vec![
+        Box::new(AnalyzeStage),
+        Box::new(LosslessStage),
+        Box::new(AudioStage),
+        Box::new(SubtitleStage),
+        Box::new(VideoStage),
+        Box::new(GrainSynthStage),
+        Box::new(MuxStage),
+        Box::new(MetadataStage),
+        Box::new(QualityStage),
+        Box::new(PostStage),
+        Box::new(CopyStage),
+        Box::new(UploadStage),
+    ]
+}
+
+/// The stages used for `--estimate`, which stop after the lossless
+/// to encode short probes of each output
+pub fn estimate_stages() -> Vec<Box<dyn Stage>> {
+    vec![
+        Box::new(AnalyzeStage),
+        Box::new(LosslessStage),
+        Box::new(EstimateStage),
+    ]
+}
+
+/// The stages used for `--matrix`, which stop after the lossless
+/// to encode and score a sample of each output
+pub fn matrix_stages() -> Vec<Box<dyn Stage>> {
+    vec![
+        Box::new(AnalyzeStage),
+        Box::new(LosslessStage),
+        Box::new(SampleStage::default()),
+    ]
+}
+
+/// Reports on the source and decides whether a lossless is needed
+pub struct AnalyzeStage;
+
+impl Stage for AnalyzeStage {
+    fn name(&self) -> &'static str {
+        "analyze"
+    }
+
+    fn error_kind(&self) -> Option<fn(String) -> Error> {
+        Some(Error::SourceProbeFailed)
+    }
+
+    fn run_input(&self, input: &mut InputContext) -> Result<bool> {
+        let source_video = &input.source_video;
+        let info = get_video_info(source_video)?;
+        info!(
+            "{} ({}{})",
+            source_video
+                .file_name()
+                .expect("File should have a name")
+                .to_string_lossy(),
+            Size::from_bytes(
+                source_video
+                    .metadata()
+                    .expect("Unable to get source file metadata")
+                    .len()
+            )
+            .format(),
+            info.video.and_then(|video| video.stream_size).map_or_else(
+                String::new,
+                |stream_size| format!(
+                    " - Video stream: {}",
+                    Size::from_bytes(stream_size).format()
+                )
+            ),
+        );
+        // Checked before the lossless, since that is where the subtitles are rendered
+        check_subtitle_fonts(input.input_vpy, input.options.strict_fonts)?;
+        if input
+            .outputs
+            .iter()
+            .all(|output| matches!(output.video.encoder, VideoEncoder::Copy))
+        {
+            input.skip_lossless = true;
+        }
+        Ok(true)
+    }
+}
+
+/// Creates the lossless intermediate which every output is encoded from
+pub struct LosslessStage;
+
+impl Stage for LosslessStage {
+    fn name(&self) -> &'static str {
+        "lossless"
+    }
+
+    fn error_kind(&self) -> Option<fn(String) -> Error> {
+        Some(Error::EncodeFailed)
+    }
+
+    fn run_input(&self, input: &mut InputContext) -> Result<bool> {
+        let input_vpy = input.input_vpy;
+        let options = input.options;
+        if !input.skip_lossless {
+            info!(
+                "Encoding {} lossless",
+                input_vpy
+                    .file_name()
+                    .expect("File should have a name")
+                    .to_string_lossy()
+            );
+            let lossless = work_path(input_vpy).with_extension("lossless.mkv");
+            let settings = format!(
+                "{}\n{}\n{:?}\n{:?}",
+                fs::read_to_string(input_vpy)?,
+                options.lossless_codec,
+                options.lossless_segments,
+                options.lossless_audio
+            );
+            if input.markers.status("lossless", &settings) == StageStatus::Stale && !is_dry_run() {
+                // Scene changes are detected from the lossless
+                let _ = remove_scenes_files(&work_path(input_vpy));
+            } else if lossless.exists() && is_lossless_stale(input_vpy, &lossless) && !is_dry_run()
+            {
+                // The settings only cover the script's text,
+                // so changes to its sources are caught by their modification times
+                warn!("Sources have changed since the lossless was created, recreating it");
+                let _ = fs::remove_file(&lossless);
+                let _ = remove_scenes_files(&work_path(input_vpy));
+            }
+            let mut verified = Vec::new();
+            input
+                .markers
+                .run("lossless", &settings, &lossless, &[], || {
+                    let mut retry_count = 0;
+                    loop {
+                        // I hate this lazy workaround,
+                        // but this is due to a heisenbug in Vapoursynth
+                        // due to some sort of race condition,
+                        // which causes crashes often enough to be annoying.
+                        //
+                        // Essentially, we retry the encode until it works.
+                        let dimensions = get_video_dimensions(input_vpy)?;
+                        let result = create_lossless(
+                            input_vpy,
+                            dimensions,
+                            options.verify_frame_count,
+                            options.lossless_codec,
+                            options.verify_lossless,
+                            options.lossless_segments,
+                            options.lossless_audio.as_ref(),
+                        );
+                        match result {
+                            Ok(checks) => {
+                                verified = checks;
+                                return Ok(());
+                            }
+                            Err(e) => {
+                                // A cancelled encode would only be cancelled again
+                                check_cancelled()?;
+                                if options.no_retry || retry_count >= 3 {
+                                    bail!("While encoding lossless: {}", e);
+                                } else {
+                                    retry_count += 1;
+                                    input.lossless_retries.set(retry_count);
+                                    warn!(
+                                        "Lossless encode failed, retrying ({}/3): {}",
+                                        retry_count, e
+                                    );
+                                }
+                            }
+                        }
+                    }
+                })?;
+            input.lossless_verified = verified;
+        }
+
+        if options.lossless_only {
+            if input.skip_lossless {
+                warn!(
+                    "Received both --lossless-only and --skip-lossless. Doing nothing. This is \
+                     probably a mistake."
+                );
+            }
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn finish_input(&self, input: &InputContext) -> Result<()> {
+        if !input.options.keep_lossless && !is_dry_run() {
+            let _ = fs::remove_file(work_path(input.input_vpy).with_extension("lossless.mkv"));
+            // Scene changes are detected from the lossless,
+            // so they are only worth keeping alongside it.
+            let _ = remove_scenes_files(&work_path(input.input_vpy));
+        }
+        Ok(())
+    }
+}
+
+/// Encodes the audio tracks in the background
+pub struct AudioStage;
+
+impl Stage for AudioStage {
+    fn name(&self) -> &'static str {
+        "audio"
+    }
+
+    fn error_kind(&self) -> Option<fn(String) -> Error> {
+        Some(Error::EncodeFailed)
+    }
+
+    fn run_output(&self, input: &InputContext, output: &mut OutputContext) -> Result<()> {
+        if output.streams_prepared {
+            return Ok(());
+        }
+        let input_vpy = input.input_vpy.to_path_buf();
+        let audio = output.output.audio;
+        let audio_outputs = output.audio_outputs.clone();
+        let vpy_audio = output.vpy_audio.clone();
+        let audio_source = input.sources.audio.clone();
+        let markers = Arc::clone(&input.markers);
+        let log = current_tool_log();
+        let warnings = current_warnings();
+        let cancel = current_cancel_token();
+        output.pending_audio = Some(thread::spawn(move || {
+            set_current_tool_log(log);
+            set_current_warnings(warnings);
+            set_cancel_token(cancel);
+            for index in vpy_audio {
+                save_vpy_audio(&input_vpy, index)?;
+            }
+            for (audio_out, audio_track, _) in &audio_outputs {
+                let stage = format!(
+                    "audio:{}",
+                    audio_out
+                        .file_name()
+                        .expect("File should have a name")
+                        .to_string_lossy()
+                );
+                let settings = format!(
+                    "{:?}\n{:?}\n{}",
+                    audio,
+                    audio_track.source,
+                    audio_source.display()
+                );
+                markers.run(&stage, &settings, audio_out, &[], || {
+                    convert_audio(
+                        &input_vpy,
+                        audio_out,
+                        audio.encoder,
+                        audio_track,
+                        audio.kbps_per_channel,
+                        audio.normalize,
+                    )
+                })?;
+            }
+            Ok(())
+        }));
+        if is_dry_run() {
+            // Keeps the printed commands in stage order
+            output.wait_for_streams()?;
+        }
+        Ok(())
+    }
+}
+
+/// Extracts the subtitle tracks in the background
+pub struct SubtitleStage;
+
+impl Stage for SubtitleStage {
+    fn name(&self) -> &'static str {
+        "subtitles"
+    }
+
+    fn error_kind(&self) -> Option<fn(String) -> Error> {
+        Some(Error::EncodeFailed)
+    }
+
+    fn run_output(&self, input: &InputContext, output: &mut OutputContext) -> Result<()> {
+        if output.streams_prepared {
+            return Ok(());
+        }
+        let base = work_path(input.input_vpy);
+        let subtitle_source = input.sources.subtitles.clone();
+        let sub_tracks = output.output.sub_tracks.clone();
+        let log = current_tool_log();
+        let warnings = current_warnings();
+        let cancel = current_cancel_token();
+        output.pending_subtitles = Some(thread::spawn(move || {
+            set_current_tool_log(log);
+            set_current_warnings(warnings);
+            set_cancel_token(cancel);
+            let source_tracks = if sub_tracks
+                .iter()
+                .any(|track| matches!(track.source, TrackSource::FromVideo(_)))
+            {
+                get_subtitle_tracks(&subtitle_source).unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            let mut subtitle_outputs = Vec::new();
+            for (i, subtitle) in sub_tracks.iter().enumerate() {
+                let mut subtitle_out;
+                match &subtitle.source {
+                    TrackSource::External(path) => {
+                        let ext = path
+                            .extension()
+                            .expect("Output file should have an extension")
+                            .to_string_lossy();
+                        subtitle_out = base.with_extension(format!("{}.{}", i, ext));
+                        if is_dry_run() {
+                            print_dry_run_command(Command::new("cp").arg(path).arg(&subtitle_out));
+                        } else {
+                            fs::copy(path, &subtitle_out)?;
+                        }
+                    }
+                    TrackSource::ScriptAudio(_) => {
+                        unreachable!("Subtitle tracks can't be script audio")
+                    }
+                    TrackSource::FromVideo(j) => {
+                        let format = source_tracks
+                            .get(*j as usize)
+                            .and_then(|track| SubtitleFormat::from_codec(&track.codec));
+                        if let Some(format) = format {
+                            subtitle_out =
+                                base.with_extension(format!("{}.{}", i, format.extension()));
+                            extract_subtitles(&subtitle_source, *j, format, &subtitle_out)?;
+                        } else {
+                            // Try the formats which can be converted to from most others
+                            subtitle_out = base.with_extension(format!("{}.ass", i));
+                            if extract_subtitles(
+                                &subtitle_source,
+                                *j,
+                                SubtitleFormat::Ass,
+                                &subtitle_out,
+                            )
+                            .is_err()
+                            {
+                                subtitle_out = base.with_extension(format!("{}.srt", i));
+                                extract_subtitles(
+                                    &subtitle_source,
+                                    *j,
+                                    SubtitleFormat::Srt,
+                                    &subtitle_out,
+                                )?;
+                            }
+                        }
+                    }
+                }
+                subtitle_outputs.push((subtitle_out, subtitle.enabled, subtitle.forced));
+            }
+            Ok(subtitle_outputs)
+        }));
+        if is_dry_run() {
+            output.wait_for_streams()?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes the video with the output's encoder
+pub struct VideoStage;
+
+impl Stage for VideoStage {
+    fn name(&self) -> &'static str {
+        "video"
+    }
+
+    fn error_kind(&self) -> Option<fn(String) -> Error> {
+        Some(Error::EncodeFailed)
+    }
+
+    fn run_output(&self, input: &InputContext, output: &mut OutputContext) -> Result<()> {
+        if output.streams_prepared {
+            return Ok(());
+        }
+        let video_out = &output.video_out;
+        if let VideoEncoder::Copy = output.output.video.encoder {
+            extract_video(&input.source_video, video_out)?;
+            return Ok(());
+        }
+        build_vpy_script(
+            &output.output_vpy,
+            input.input_vpy,
+            output.output,
+            input.skip_lossless,
+        );
+        if is_dry_run() {
+            print_dry_run_script(&output.output_vpy)?;
+        }
+        let stage = format!(
+            "video:{}",
+            video_out
+                .file_name()
+                .expect("File should have a name")
+                .to_string_lossy()
+        );
+        // The script covers the filters, and whether it reads the lossless
+        let force_keyframes = input.output_keyframes(output.output)?;
+        let settings = format!(
+            "{:?}\n{}\n{:?}",
+            output.output.video,
+            fs::read_to_string(&output.output_vpy).unwrap_or_default(),
+            force_keyframes
+        );
+        let av1an_temp = work_path(&output.output_vpy).with_extension("av1an");
+        input
+            .markers
+            .run(&stage, &settings, video_out, &[av1an_temp], || {
+                encode_video(
+                    input,
+                    output.output,
+                    &output.output_vpy,
+                    &work_path(input.input_vpy),
+                    Some(&input.input_vpy.with_extension("scenes.json")),
+                    video_out,
+                    &force_keyframes,
+                )
+            })
+    }
+}
+
+/// Encodes `vpy` with the output's encoder, which must not be `Copy`.
+///
+/// Scene changes detected by av1an are shared with other outputs based on `scenes_base`,
+/// and exported to or imported from `shared_scenes` if asked to.
+#[allow(clippy::too_many_arguments)]
+fn encode_video(
+    input: &InputContext,
+    output: &Output,
+    vpy: &Path,
+    scenes_base: &Path,
+    shared_scenes: Option<&Path>,
+    video_out: &Path,
+    force_keyframes: &Option<String>,
+) -> Result<()> {
+    let video = &output.video;
+    let dimensions = if is_dry_run() {
+        // The script reads the lossless, which a dry run doesn't create
+        video.filtered_dimensions(get_video_dimensions(input.input_vpy)?)
+    } else {
+        get_video_dimensions(vpy)?
+    };
+    let colorimetry = input.output_colorimetry(output)?;
+    match video.encoder {
+        VideoEncoder::Copy => unreachable!("Copied video is not encoded"),
+        VideoEncoder::X264 {
+            crf,
+            profile,
+            compat,
+        } => convert_video_x264(
+            vpy,
+            video_out,
+            crf,
+            profile,
+            compat,
+            dimensions,
+            force_keyframes,
+            &colorimetry,
+            &video.overrides,
+            video.sar,
+            video.x264_zones.as_deref(),
+            video.extra_args.as_deref(),
+        ),
+        VideoEncoder::SvtAv1 {
+            crf,
+            speed,
+            profile,
+            grain,
+            direct: true,
+        } => convert_video_svtav1(
+            vpy,
+            video_out,
+            crf,
+            speed,
+            profile,
+            grain,
+            dimensions,
+            &direct_keyframes(
+                force_keyframes,
+                shared_scenes,
+                &input.options.av1an,
+                dimensions.frames,
+            )?,
+            &colorimetry,
+            video.extra_args.as_deref(),
+            video.tiles,
+            video.grain_table.as_deref(),
+            &video.svt_tuning,
+        ),
+        _ => convert_video_av1an(
+            vpy,
+            scenes_base,
+            shared_scenes,
+            video_out,
+            video,
+            dimensions,
+            force_keyframes,
+            &colorimetry,
+            &input.options.av1an,
+        ),
+    }
+}
+
+/// Replaces the grain synthesis of the encoded video with grain matched to the source,
+/// if the output asks for it
+pub struct GrainSynthStage;
+
+impl Stage for GrainSynthStage {
+    fn name(&self) -> &'static str {
+        "grainsynth"
+    }
+
+    fn error_kind(&self) -> Option<fn(String) -> Error> {
+        Some(Error::EncodeFailed)
+    }
+
+    fn run_output(&self, input: &InputContext, output: &mut OutputContext) -> Result<()> {
+        if !output.output.video.post_grain_synth {
+            return Ok(());
+        }
+        // The encoded video is kept as it was, so a rerun never applies grain twice
+        let grained = output.video_out.with_extension("grain.mkv");
+        if !(output.streams_prepared && grained.exists()) {
+            info!("Matching grain with grav1synth");
+            let lossless = work_path(input.input_vpy).with_extension("lossless.mkv");
+            let reference = if !input.skip_lossless && lossless.exists() {
+                lossless
+            } else {
+                input.source_video.clone()
+            };
+            apply_matched_grain(&reference, &output.video_out, &grained)?;
+        }
+        output.video_out = grained;
+        Ok(())
+    }
+}
+
+/// Muxes the prepared streams into the final output file
+pub struct MuxStage;
+
+impl Stage for MuxStage {
+    fn name(&self) -> &'static str {
+        "mux"
+    }
+
+    fn error_kind(&self) -> Option<fn(String) -> Error> {
+        Some(Error::MuxFailed)
+    }
+
+    fn run_output(&self, input: &InputContext, output: &mut OutputContext) -> Result<()> {
+        if output.streams_prepared {
+            info!("Encoded streams already exist, skipping to muxing");
+        } else {
+            output.wait_for_streams()?;
+            if !is_dry_run() {
+                write_mux_marker(&output.mux_marker, &output.subtitle_outputs)?;
+            }
+        }
+
+        // A copied video keeps the timestamps of its source
+        let timecodes = match output.output.video.encoder {
+            VideoEncoder::Copy => None,
+            _ => script_timecodes(input.input_vpy)?,
+        };
+        if let Some(ref timecodes) = timecodes {
+            if !is_dry_run() {
+                let frames = get_video_frame_count(&output.video_out)?;
+                if frames as usize != timecodes.len() {
+                    bail!(Error::VerificationFailed(format!(
+                        "The encode has {} frames, but the script's timecodes have {}",
+                        frames,
+                        timecodes.len()
+                    )));
+                }
+                output.verified.push("timecodes");
+            }
+        }
+        let timecodes_file = timecodes.map(|_| timecodes_path(input.input_vpy));
+        let display_dimensions = output.output.video.display_dimensions(
+            output
+                .output
+                .video
+                .filtered_dimensions(get_video_dimensions(input.input_vpy)?),
+        );
+
+        mux_video(
+            &input.sources,
+            &output.video_out,
+            timecodes_file.as_deref(),
+            display_dimensions,
+            &output.audio_outputs,
+            &output.subtitle_outputs,
+            output
+                .output
+                .sub_tracks
+                .iter()
+                .any(|track| matches!(track.source, TrackSource::FromVideo(_))),
+            input.options.ignore_delay,
+            input.options.reproducible,
+            &output.output_path,
+        )
+    }
+}
+
+/// Sets the title of the muxed output, if asked to
+pub struct MetadataStage;
+
+impl Stage for MetadataStage {
+    fn name(&self) -> &'static str {
+        "metadata"
+    }
+
+    fn error_kind(&self) -> Option<fn(String) -> Error> {
+        Some(Error::MuxFailed)
+    }
+
+    fn run_output(&self, input: &InputContext, output: &mut OutputContext) -> Result<()> {
+        let template = match input.options.title {
+            Some(ref template) => template,
+            None => return Ok(()),
+        };
+        if output.output.video.output_ext != "mkv" {
+            warn!("Titles can only be set on mkv outputs, skipping");
+            return Ok(());
+        }
+        let mut tokens = NameTokens::new(input.input_vpy, output.settings.clone())?;
+        if template.contains("{resolution}") {
+            tokens.resolution = Some(if is_dry_run() {
+                output_height(input.input_vpy, output.output)?
+            } else {
+                get_video_dimensions(&output.output_vpy)?.height
+            });
+        }
+        set_title(&output.output_path, &tokens.fill(template))
+    }
+}
+
+/// Scores the output against the lossless, if asked to
+pub struct QualityStage;
+
+impl Stage for QualityStage {
+    fn name(&self) -> &'static str {
+        "quality"
+    }
+
+    fn error_kind(&self) -> Option<fn(String) -> Error> {
+        Some(Error::VerificationFailed)
+    }
+
+    fn run_output(&self, input: &InputContext, output: &mut OutputContext) -> Result<()> {
+        let options = input.options;
+        let metric = match options.quality_check {
+            Some(metric) => metric,
+            None => return Ok(()),
+        };
+        if let VideoEncoder::Copy = output.output.video.encoder {
+            return Ok(());
+        }
+        let lossless = work_path(input.input_vpy).with_extension("lossless.mkv");
+        let reference = if !input.skip_lossless && lossless.exists() {
+            lossless
+        } else {
+            input.source_video.clone()
+        };
+        let scores = frame_scores(&reference, None, &output.output_path, metric)?;
+        if is_dry_run() {
+            return Ok(());
+        }
+        let summary = ScoreSummary::from_scores(&scores).expect("Scores are not empty");
+        info!(
+            "{} {:.2} mean, {:.2} 1% low, {:.2} min",
+            metric.to_string().to_uppercase(),
+            summary.mean,
+            summary.low_1pct,
+            summary.min
+        );
+        output.scores.push((metric, summary));
+        if let Some(min_quality) = options.min_quality {
+            if summary.mean < min_quality {
+                bail!(
+                    "Mean {} of {:.2} is below the minimum of {}, keeping {} for inspection",
+                    metric.to_string().to_uppercase(),
+                    summary.mean,
+                    min_quality,
+                    output.output_path.display()
+                );
+            }
+            output.verified.push("min_quality");
+        }
+        Ok(())
+    }
+}
+
+/// Copies over metadata which the encoders don't preserve, and cleans up
+pub struct PostStage;
+
+impl Stage for PostStage {
+    fn name(&self) -> &'static str {
+        "post"
+    }
+
+    fn run_output(&self, input: &InputContext, output: &mut OutputContext) -> Result<()> {
+        if input
+            .colorimetry
+            .with_overrides(&output.output.video.colorimetry)
+            .is_hdr()
+        {
+            copy_hdr_data(&input.source_video, &output.output_path)?;
+        }
+        if is_dry_run() {
+            return Ok(());
+        }
+        if input.options.checksum {
+            write_checksum(&output.output_path)?;
+        }
+        let _ = fs::remove_file(&output.mux_marker);
+        if let Err(e) = record_history(input, output) {
+            warn!("Failed to record encode history: {}", e);
+        }
+
+        info!(
+            success = true,
+            "Finished encoding {}",
+            output
+                .output_vpy
+                .file_name()
+                .expect("File should have a name")
+                .to_string_lossy()
+        );
+        Ok(())
+    }
+}
+
+/// Places the finished output in each extra destination directory
+pub struct CopyStage;
+
+impl Stage for CopyStage {
+    fn name(&self) -> &'static str {
+        "copy"
+    }
+
+    fn run_output(&self, input: &InputContext, output: &mut OutputContext) -> Result<()> {
+        let checksum = checksum_path(&output.output_path);
+        let mut files = vec![output.output_path.as_path()];
+        if input.options.checksum && checksum.exists() {
+            files.push(&checksum);
+        }
+        for dir in &input.options.copy_to {
+            if is_dry_run() {
+                print_dry_run(&format!(
+                    "# Link or copy {} to {}",
+                    output.output_path.display(),
+                    dir.display()
+                ));
+                continue;
+            }
+            // One unreachable destination shouldn't lose the output everywhere else
+            if let Err(e) = files.iter().try_for_each(|file| link_or_copy(file, dir)) {
+                warn!("Failed to copy output to {}: {}", dir.display(), e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hard links `file` into `dir`, or copies it if they are on different filesystems
+fn link_or_copy(file: &Path, dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let target = dir.join(file.file_name().expect("File should have a name"));
+    if target.exists() && fs::canonicalize(&target)? == fs::canonicalize(file)? {
+        // The destination is where the output was written
+        return Ok(());
+    }
+    if target.exists() {
+        fs::remove_file(&target)?;
+    }
+    if fs::hard_link(file, &target).is_err() {
+        // Copy to a temporary name, so a failed copy never looks finished
+        let partial = target.with_extension("partial");
+        fs::copy(file, &partial)?;
+        fs::rename(&partial, &target)?;
+    }
+    Ok(())
+}
+
+/// Copies the finished output to the upload destination, if there is one
+pub struct UploadStage;
+
+impl Stage for UploadStage {
+    fn name(&self) -> &'static str {
+        "upload"
+    }
+
+    fn run_output(&self, input: &InputContext, output: &mut OutputContext) -> Result<()> {
+        let options = input.options;
+        let destination = match options.upload {
+            Some(ref destination) => destination,
+            None => return Ok(()),
+        };
+        let checksum = checksum_path(&output.output_path);
+        let mut files = vec![output.output_path.clone()];
+        if options.checksum && checksum.exists() {
+            files.push(checksum);
+        }
+        for file in &files {
+            info!("Uploading {} to {}", file.display(), destination);
+            destination.upload(file)?;
+        }
+        output.uploaded = true;
+        if options.delete_after_upload && !is_dry_run() {
+            for file in &files {
+                fs::remove_file(file)?;
+            }
+        }
+        info!(
+            success = true,
+            "Uploaded {}",
+            output
+                .output_path
+                .file_name()
+                .expect("File should have a name")
+                .to_string_lossy()
+        );
+        Ok(())
+    }
+}
+
+fn record_history(input: &InputContext, output: &OutputContext) -> Result<()> {
+    let duration = output.started.elapsed().as_secs_f64();
+    let frames = get_video_frame_count(&output.output_path).ok();
+    let encoder = output.output.video.encoder;
+    record_encode(&json!({
+        "time": unix_time(),
+        "input": input.input_vpy.to_string_lossy(),
+        "source": input.source_video.to_string_lossy(),
+        "output": output.output_path.to_string_lossy(),
+        "settings": build_video_suffix(output.output)?,
+        "encoder": encoder.get_av1an_name(),
+        "encoder_version": encoder_version(encoder),
+        "crf": encoder.crf(),
+        "duration_secs": duration,
+        "frames": frames,
+        "fps": frames.map(|frames| frames as f64 / duration),
+        "output_size": output.output_path.metadata()?.len(),
+        "source_size": input.source_video.metadata().ok().map(|meta| meta.len()),
+        "scores": scores_json(&output.scores),
+    }))
+}
+
+/// How many probes are encoded to estimate an output
+const PROBE_COUNT: u32 = 5;
+/// How long each probe is
+const PROBE_SECONDS: u32 = 5;
+
+/// Encodes a few short probes spread across the video with the output's settings,
+/// and extrapolates the size and encode time of the whole video from them
+pub struct EstimateStage;
+
+impl Stage for EstimateStage {
+    fn name(&self) -> &'static str {
+        "estimate"
+    }
+
+    fn error_kind(&self) -> Option<fn(String) -> Error> {
+        Some(Error::EncodeFailed)
+    }
+
+    fn run_output(&self, input: &InputContext, output: &mut OutputContext) -> Result<()> {
+        let name = output
+            .output_vpy
+            .file_name()
+            .expect("File should have a name")
+            .to_string_lossy()
+            .into_owned();
+        if let VideoEncoder::Copy = output.output.video.encoder {
+            info!("{} copies the video, nothing to estimate", name);
+            return Ok(());
+        }
+        build_vpy_script(
+            &output.output_vpy,
+            input.input_vpy,
+            output.output,
+            input.skip_lossless,
+        );
+        let dimensions = get_video_dimensions(&output.output_vpy)?;
+        if dimensions.frames == 0 {
+            info!("{} has no frames, nothing to estimate", name);
+            return Ok(());
+        }
+        let fps = dimensions.fps.0 as f64 / dimensions.fps.1 as f64;
+
+        let mut probe_frames = 0;
+        let mut probe_size = 0;
+        let mut probe_time = 0.0;
+        for (i, (first, last)) in probe_ranges(dimensions.frames, fps).into_iter().enumerate() {
+            let probe_vpy = output.output_vpy.with_extension(format!("probe{}.vpy", i));
+            let probe_base = work_path(&probe_vpy);
+            let probe_out = probe_base.with_extension("mkv");
+            build_sample_script(&output.output_vpy, &probe_vpy, &[(first, last)]);
+            let started = Instant::now();
+            let result = encode_video(
+                input,
+                output.output,
+                &probe_vpy,
+                &probe_base,
+                None,
+                &probe_out,
+                &None,
+            );
+            probe_time += started.elapsed().as_secs_f64();
+            let size = probe_out.metadata().map(|meta| meta.len());
+            let _ = fs::remove_file(&probe_vpy);
+            let _ = fs::remove_file(&probe_out);
+            let _ = remove_scenes_files(&probe_base);
+            result?;
+            probe_size += size?;
+            probe_frames += last - first + 1;
+        }
+
+        let scale = dimensions.frames as f64 / probe_frames as f64;
+        let size = (probe_size as f64 * scale) as u64;
+        let seconds = dimensions.frames as f64 / fps;
+        let kbps = size as f64 * 8.0 / 1000.0 / seconds;
+        let time = Duration::from_secs_f64(probe_time * scale);
+        info!(
+            "{}: about {} at {:.0} kbps, taking about {} to encode",
+            name,
+            Size::from_bytes(size).format(),
+            kbps,
+            format_duration(time)
+        );
+        emit(
+            "estimate",
+            json!({
+                "input": path_value(input.input_vpy),
+                "output": path_value(&output.output_path),
+                "size": size,
+                "bitrate_kbps": kbps,
+                "encode_secs": time.as_secs_f64(),
+            }),
+        );
+        Ok(())
+    }
+}
+
+/// Picks evenly spaced frame ranges to probe, or the whole video if it is too short,
+/// or none if it is empty
+fn probe_ranges(frames: u32, fps: f64) -> Vec<(u32, u32)> {
+    let length = std::cmp::max((fps * PROBE_SECONDS as f64).round() as u32, 1);
+    if frames == 0 {
+        return Vec::new();
+    }
+    if frames <= length * PROBE_COUNT {
+        return vec![(0, frames - 1)];
+    }
+    (0..PROBE_COUNT)
+        .map(|i| {
+            let center = (frames as u64 * (2 * i as u64 + 1) / (2 * PROBE_COUNT as u64)) as u32;
+            let first = center - length / 2;
+            (first, first + length - 1)
+        })
+        .collect()
+}
+
+/// Encodes a sample of each output, made of the same frames as the probes for an estimate,
+/// and compares the size, speed and quality of every output of the input once they are done
+#[derive(Default)]
+pub struct SampleStage {
+    /// The samples of every input being processed, by input script
+    samples: Mutex<HashMap<PathBuf, Vec<Sample>>>,
+}
+
+struct Sample {
+    settings: String,
+    size: u64,
+    bitrate_kbps: f64,
+    fps: f64,
+    score: Option<ScoreSummary>,
+}
+
+impl Stage for SampleStage {
+    fn name(&self) -> &'static str {
+        "sample"
+    }
+
+    fn error_kind(&self) -> Option<fn(String) -> Error> {
+        Some(Error::EncodeFailed)
+    }
+
+    fn run_output(&self, input: &InputContext, output: &mut OutputContext) -> Result<()> {
+        let settings = build_video_suffix(output.output)?;
+        if let VideoEncoder::Copy = output.output.video.encoder {
+            info!("{} copies the video, nothing to sample", settings);
+            return Ok(());
+        }
+        build_vpy_script(
+            &output.output_vpy,
+            input.input_vpy,
+            output.output,
+            input.skip_lossless,
+        );
+        let dimensions = get_video_dimensions(&output.output_vpy)?;
+        if dimensions.frames == 0 {
+            info!("{} has no frames, nothing to sample", settings);
+            return Ok(());
+        }
+        let fps = dimensions.fps.0 as f64 / dimensions.fps.1 as f64;
+        let ranges = probe_ranges(dimensions.frames, fps);
+        let frames = ranges
+            .iter()
+            .map(|(first, last)| last - first + 1)
+            .sum::<u32>();
+
+        let sample_vpy = output.output_vpy.with_extension("sample.vpy");
+        let sample_base = work_path(&sample_vpy);
+        let sample_out = sample_base.with_extension("mkv");
+        build_sample_script(&output.output_vpy, &sample_vpy, &ranges);
+        // An existing sample would be reused, which would spoil the timing
+        let _ = fs::remove_file(&sample_out);
+        let started = Instant::now();
+        let result = encode_video(
+            input,
+            output.output,
+            &sample_vpy,
+            &sample_base,
+            None,
+            &sample_out,
+            &None,
+        );
+        let encode_secs = started.elapsed().as_secs_f64();
+        let _ = fs::remove_file(&sample_vpy);
+        let _ = remove_scenes_files(&sample_base);
+        result?;
+
+        let size = sample_out.metadata()?.len();
+        let bitrate_kbps = size as f64 * 8.0 / 1000.0 / (frames as f64 / fps);
+        let metric = input.options.quality_check.unwrap_or(Metric::Vmaf);
+        let lossless = work_path(input.input_vpy).with_extension("lossless.mkv");
+        let reference = if !input.skip_lossless && lossless.exists() {
+            lossless
+        } else {
+            input.source_video.clone()
+        };
+        let score = match frame_scores(&reference, Some(&ranges), &sample_out, metric) {
+            Ok(scores) => ScoreSummary::from_scores(&scores),
+            Err(e) => {
+                warn!("Unable to score the sample for {}: {}", settings, e);
+                None
+            }
+        };
+        info!(
+            "Sampled {} in {}",
+            settings,
+            sample_out
+                .file_name()
+                .expect("File should have a name")
+                .to_string_lossy()
+        );
+        emit(
+            "sample",
+            json!({
+                "input": path_value(input.input_vpy),
+                "settings": settings,
+                "path": path_value(&sample_out),
+                "size": size,
+                "bitrate_kbps": bitrate_kbps,
+                "encode_secs": encode_secs,
+                "scores": score.map(|score| scores_json(&[(metric, score)])),
+            }),
+        );
+        self.samples
+            .lock()
+            .unwrap()
+            .entry(input.input_vpy.to_path_buf())
+            .or_default()
+            .push(Sample {
+                settings,
+                size,
+                bitrate_kbps,
+                fps: frames as f64 / encode_secs,
+                score,
+            });
+        Ok(())
+    }
+
+    fn finish_input(&self, input: &InputContext) -> Result<()> {
+        let samples = match self.samples.lock().unwrap().remove(input.input_vpy) {
+            Some(samples) => samples,
+            None => return Ok(()),
+        };
+        let metric = input
+            .options
+            .quality_check
+            .unwrap_or(Metric::Vmaf)
+            .to_string()
+            .to_uppercase();
+        let mut table = format!(
+            "{:<40}  {:>10}  {:>12}  {:>8}  {:>9}  {:>9}\n",
+            "SETTINGS", "SIZE", "BITRATE", "FPS", metric, "1% LOW"
+        );
+        for sample in samples {
+            let _ = writeln!(
+                table,
+                "{:<40}  {:>10}  {:>7.0} kbps  {:>8.2}  {:>9}  {:>9}",
+                sample.settings,
+                Size::from_bytes(sample.size).format().to_string(),
+                sample.bitrate_kbps,
+                sample.fps,
+                sample
+                    .score
+                    .map_or_else(|| "-".to_string(), |score| format!("{:.2}", score.mean)),
+                sample
+                    .score
+                    .map_or_else(|| "-".to_string(), |score| format!("{:.2}", score.low_1pct)),
+            );
+        }
+        eprint!("\n{}\n", table);
+        Ok(())
+    }
+}
+
+/// Records that all streams for an output were prepared, along with the
+/// subtitle files, whose extension is only known after extraction
+fn write_mux_marker(marker: &Path, subtitle_outputs: &[SubtitleOutput]) -> Result<()> {
+    let contents = subtitle_outputs
+        .iter()
+        .map(|(path, ..)| format!("{}\n", path.to_string_lossy()))
+        .collect::<String>();
+    fs::write(marker, contents)?;
+    Ok(())
+}
+
+/// Returns the subtitle outputs from a previous run if it prepared
+/// every stream for this output but did not finish muxing
+fn read_mux_marker(
+    marker: &Path,
+    video_out: &Path,
+    audio_outputs: &[(PathBuf, Track, AudioEncoder)],
+    output: &Output,
+) -> Option<Vec<SubtitleOutput>> {
+    let contents = fs::read_to_string(marker).ok()?;
+    let subtitle_outputs = contents
+        .lines()
+        .map(PathBuf::from)
+        .zip(output.sub_tracks.iter())
+        .map(|(path, track)| (path, track.enabled, track.forced))
+        .collect::<Vec<_>>();
+    if subtitle_outputs.len() != output.sub_tracks.len()
+        || !video_out.exists()
+        || !audio_outputs.iter().all(|(path, ..)| path.exists())
+        || !subtitle_outputs.iter().all(|(path, ..)| path.exists())
+    {
+        return None;
+    }
+    Some(subtitle_outputs)
+}
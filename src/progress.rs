@@ -0,0 +1,257 @@
+use std::{
+    fs,
+    io::{self, BufReader, Read},
+    path::Path,
+    process::{ChildStdin, Command, ExitStatus, Stdio},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use once_cell::sync::OnceCell;
+use serde_json::json;
+
+use crate::{
+    cancel::spawn_tracked,
+    events::{emit, has_listeners, tool_stdout},
+    tool_log::{
+        current_tool_log, dry_run_status, is_dry_run, log_command, print_dry_run_command,
+        tee_stdout, ToolLog,
+    },
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where the number of encoded frames is read from
+#[derive(Debug, Clone, Copy)]
+pub enum ProgressSource<'a> {
+    /// Lines written by the tool to stderr, parsed with the given function
+    Stderr(fn(&str) -> Option<u64>),
+    /// The `done.json` which av1an keeps in its temp directory
+    Av1an(&'a Path),
+}
+
+/// Writes what a tool reads from stdin on its own thread,
+/// such as frames rendered in this process
+pub type StdinWriter = Box<dyn FnOnce(ChildStdin) + Send>;
+
+/// Every bar is drawn through one `MultiProgress`,
+/// so that parallel jobs each get their own line.
+fn progress() -> &'static MultiProgress {
+    static PROGRESS: OnceCell<MultiProgress> = OnceCell::new();
+    PROGRESS.get_or_init(MultiProgress::new)
+}
+
+/// Hides the progress bars while `f` writes to the terminal
+pub fn suspend_progress<R>(f: impl FnOnce() -> R) -> R {
+    progress().suspend(f)
+}
+
+/// Runs `command`, showing its progress through `total_frames` as a progress bar
+/// instead of the tool's own output.
+///
+/// Any output which is not progress is printed above the bars.
+pub fn run_with_progress(
+    command: &mut Command,
+    label: String,
+    total_frames: u32,
+    source: ProgressSource,
+) -> io::Result<ExitStatus> {
+    run_with_progress_from(command, label, total_frames, source, None)
+}
+
+/// Runs `command` like [`run_with_progress`], with `stdin` writing what it reads
+pub fn run_with_progress_from(
+    command: &mut Command,
+    label: String,
+    total_frames: u32,
+    source: ProgressSource,
+    stdin: Option<StdinWriter>,
+) -> io::Result<ExitStatus> {
+    if is_dry_run() {
+        print_dry_run_command(command);
+        return Ok(dry_run_status());
+    }
+    let bar = progress().add(ProgressBar::new(total_frames as u64));
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{prefix:.bold} [{bar:40.cyan/blue}] {pos}/{len} frames, {per_sec}, ETA {eta}",
+        )
+        .expect("Template is valid")
+        .progress_chars("=> "),
+    );
+    bar.set_prefix(label.clone());
+
+    let mut last_event: Option<Instant> = None;
+    let mut set_frames = |frames: u64| {
+        bar.set_position(frames);
+        if has_listeners() && last_event.map_or(true, |last| last.elapsed() >= EVENT_INTERVAL) {
+            emit(
+                "progress",
+                json!({ "label": label, "frames": frames, "total_frames": total_frames }),
+            );
+            last_event = Some(Instant::now());
+        }
+    };
+
+    let log = current_tool_log();
+    log_command(command);
+    let stdout = if log.is_some() {
+        Stdio::piped()
+    } else {
+        tool_stdout()
+    };
+    if stdin.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    let (mut child, _tracked) = spawn_tracked(command.stdout(stdout).stderr(Stdio::piped()))?;
+    // The writer stops once the tool exits, since its writes then fail
+    let stdin_writer = stdin.map(|write| {
+        let pipe = child.stdin.take().expect("stdin should be writeable");
+        thread::spawn(move || write(pipe))
+    });
+    let stdout_reader = match (child.stdout.take(), &log) {
+        (Some(stdout), Some(log)) => Some(tee_stdout(stdout, Arc::clone(log))),
+        _ => None,
+    };
+    let stderr = child.stderr.take().expect("stderr should be readable");
+    let status = match source {
+        ProgressSource::Stderr(parse) => {
+            forward_output(stderr, &bar, log.as_deref(), parse, set_frames);
+            child.wait()
+        }
+        ProgressSource::Av1an(temp_dir) => {
+            let reader = {
+                let bar = bar.clone();
+                thread::spawn(move || {
+                    forward_output(stderr, &bar, log.as_deref(), |_| None, |_| ())
+                })
+            };
+            let done_file = temp_dir.join("done.json");
+            let status = loop {
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+                if let Some(frames) = read_av1an_done(&done_file) {
+                    set_frames(frames);
+                }
+                thread::sleep(POLL_INTERVAL);
+            };
+            let _ = reader.join();
+            Ok(status)
+        }
+    };
+    if let Some(reader) = stdout_reader {
+        let _ = reader.join();
+    }
+    if let Some(writer) = stdin_writer {
+        let _ = writer.join();
+    }
+    bar.finish_and_clear();
+    emit("progress_finished", json!({ "label": bar.prefix() }));
+    status
+}
+
+/// Splits the tool's output on carriage returns as well as newlines,
+/// since progress is usually redrawn over the same line.
+///
+/// Progress lines are left out of the log, except for the last one.
+fn forward_output(
+    output: impl Read,
+    bar: &ProgressBar,
+    log: Option<&ToolLog>,
+    parse: fn(&str) -> Option<u64>,
+    mut set_frames: impl FnMut(u64),
+) {
+    let mut reader = BufReader::new(output);
+    let mut line = Vec::new();
+    let mut last_progress = None;
+    let mut byte = [0u8];
+    loop {
+        let end = match reader.read(&mut byte) {
+            Ok(0) | Err(_) => true,
+            Ok(_) => false,
+        };
+        if end || byte[0] == b'\r' || byte[0] == b'\n' {
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim();
+            if !text.is_empty() {
+                match parse(text) {
+                    Some(frames) => {
+                        set_frames(frames);
+                        last_progress = Some(text.to_string());
+                    }
+                    None => {
+                        // A hidden bar prints nothing, such as when stderr isn't a terminal
+                        if bar.is_hidden() {
+                            eprintln!("{}", text);
+                        } else {
+                            bar.println(text);
+                        }
+                        if let Some(log) = log {
+                            log.write_line(text);
+                        }
+                    }
+                }
+            }
+            line.clear();
+        } else {
+            line.push(byte[0]);
+        }
+        if end {
+            break;
+        }
+    }
+    if let (Some(log), Some(last_progress)) = (log, last_progress) {
+        log.write_line(&last_progress);
+    }
+}
+
+/// Sums the frames of the chunks av1an has finished
+fn read_av1an_done(path: &Path) -> Option<u64> {
+    let data = fs::read_to_string(path).ok()?;
+    let done: serde_json::Value = serde_json::from_str(&data).ok()?;
+    Some(
+        done.get("done")?
+            .as_object()?
+            .values()
+            .filter_map(|frames| frames.as_u64())
+            .sum(),
+    )
+}
+
+/// Parses `frame=  123 fps=...`
+pub fn parse_ffmpeg_progress(line: &str) -> Option<u64> {
+    line.strip_prefix("frame=")?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Parses `[12.3%] 123/1000 frames, ...`
+pub fn parse_x264_progress(line: &str) -> Option<u64> {
+    let (before, _) = line.split_once(" frames")?;
+    before
+        .split_whitespace()
+        .last()?
+        .split('/')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Parses `Encoding frame  123 ...` from older versions,
+/// and `Encoding:  123/1000 Frames ...` from newer ones
+pub fn parse_svtav1_progress(line: &str) -> Option<u64> {
+    let rest = line
+        .strip_prefix("Encoding frame")
+        .or_else(|| line.strip_prefix("Encoding:"))?
+        .trim_start();
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
@@ -0,0 +1,274 @@
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+/// Matches a single or double quoted Python string literal, optionally raw,
+/// including any escaped quotes inside it
+pub const STRING_LITERAL: &str = r#"[rR]?(?:"(?:[^"\\\n]|\\.)*"|'(?:[^'\\\n]|\\.)*')"#;
+
+/// Matches a path expression written by `python_path`, or any string literal
+pub const PATH_EXPRESSION: &str = r#"(?:__import__\(\s*['"]os['"]\s*\)\.fsdecode\(\s*[bB](?:"(?:[^"\\\n]|\\.)*"|'(?:[^'\\\n]|\\.)*')\s*\)|[rR]?(?:"(?:[^"\\\n]|\\.)*"|'(?:[^'\\\n]|\\.)*'))"#;
+
+/// A Python expression evaluating to `path`, for writing into generated scripts.
+///
+/// Paths which aren't valid UTF-8 are written as bytes and decoded the same way
+/// Python decodes paths from the filesystem.
+pub fn python_path(path: &Path) -> String {
+    match path.to_str() {
+        Some(path) => python_string(path),
+        None => non_utf8_path(path),
+    }
+}
+
+#[cfg(unix)]
+fn non_utf8_path(path: &Path) -> String {
+    let mut literal = String::from("__import__('os').fsdecode(b\"");
+    for &byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'\\' => literal.push_str(r"\\"),
+            b'"' => literal.push_str("\\\""),
+            0x20..=0x7e => literal.push(byte as char),
+            _ => literal.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    literal.push_str("\")");
+    literal
+}
+
+#[cfg(not(unix))]
+fn non_utf8_path(path: &Path) -> String {
+    python_string(&path.to_string_lossy())
+}
+
+/// A double quoted Python string literal containing `value`
+pub fn python_string(value: &str) -> String {
+    let mut literal = String::with_capacity(value.len() + 2);
+    literal.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => literal.push_str(r"\\"),
+            '"' => literal.push_str("\\\""),
+            '\n' => literal.push_str(r"\n"),
+            '\r' => literal.push_str(r"\r"),
+            '\t' => literal.push_str(r"\t"),
+            c if c.is_control() => literal.push_str(&format!("\\u{:04x}", c as u32)),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// The path given by an expression matching `PATH_EXPRESSION`
+pub fn parse_path_expression(expression: &str) -> Option<PathBuf> {
+    let expression = expression.trim();
+    if let Some(bytes) = expression
+        .strip_prefix("__import__")
+        .and_then(|rest| rest.find(".fsdecode(").map(|pos| &rest[pos + 10..]))
+        .and_then(|rest| rest.trim_end().strip_suffix(')'))
+    {
+        return parse_bytes_literal(bytes.trim()).and_then(bytes_to_path);
+    }
+    parse_string_literal(expression).map(PathBuf::from)
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    Some(PathBuf::from(OsStr::from_bytes(&bytes)))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// The value of a single or double quoted Python string literal, optionally raw
+pub fn parse_string_literal(literal: &str) -> Option<String> {
+    let (raw, body) = split_literal(literal.trim(), &['r', 'R'])?;
+    if raw {
+        return Some(body.to_string());
+    }
+    let mut value = String::with_capacity(body.len());
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\n' => (),
+            'n' => value.push('\n'),
+            'r' => value.push('\r'),
+            't' => value.push('\t'),
+            'x' => value.push(hex_char(&mut chars, 2)?),
+            'u' => value.push(hex_char(&mut chars, 4)?),
+            'U' => value.push(hex_char(&mut chars, 8)?),
+            c @ ('\\' | '\'' | '"') => value.push(c),
+            // Python keeps the backslash of unrecognized escapes
+            c => {
+                value.push('\\');
+                value.push(c);
+            }
+        }
+    }
+    Some(value)
+}
+
+fn parse_bytes_literal(literal: &str) -> Option<Vec<u8>> {
+    let (_, body) = split_literal(literal, &['b', 'B'])?;
+    let mut value = Vec::with_capacity(body.len());
+    let mut bytes = body.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            value.push(byte);
+            continue;
+        }
+        match bytes.next()? {
+            b'n' => value.push(b'\n'),
+            b'r' => value.push(b'\r'),
+            b't' => value.push(b'\t'),
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                value.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            byte @ (b'\\' | b'\'' | b'"') => value.push(byte),
+            byte => {
+                value.push(b'\\');
+                value.push(byte);
+            }
+        }
+    }
+    Some(value)
+}
+
+/// Whether the literal has one of the prefixes, and the text between its quotes
+fn split_literal<'a>(literal: &'a str, prefixes: &[char]) -> Option<(bool, &'a str)> {
+    let (prefixed, quoted) = match literal.strip_prefix(prefixes) {
+        Some(quoted) => (true, quoted),
+        None => (false, literal),
+    };
+    let quote = quoted.chars().next().filter(|&c| c == '"' || c == '\'')?;
+    let body = quoted.strip_prefix(quote)?.strip_suffix(quote)?;
+    Some((prefixed, body))
+}
+
+fn hex_char(chars: &mut std::str::Chars, digits: usize) -> Option<char> {
+    let hex: String = chars.take(digits).collect();
+    if hex.len() != digits {
+        return None;
+    }
+    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)
+}
+
+/// Splits the arguments of a call on their top level commas,
+/// ignoring commas inside strings and nested brackets
+pub fn split_arguments(args: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in args.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                parts.push(args[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    let last = args[start..].trim();
+    if !last.is_empty() {
+        parts.push(last);
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+
+    const HOSTILE_NAMES: &[&str] = &[
+        "plain.mkv",
+        "it's here.mkv",
+        r#"the "best" episode.mkv"#,
+        r"C:\videos\back\slash.mkv",
+        "[Group] Show (2020) - 01 [1080p].mkv",
+        "commas, and (parens).mkv",
+        "tab\tand\nnewline.mkv",
+        "ユニコード 🎬.mkv",
+        r#"'"mixed\'"\\quotes".mkv"#,
+    ];
+
+    #[test]
+    fn string_literals_round_trip() {
+        for name in HOSTILE_NAMES {
+            let literal = python_string(name);
+            assert_eq!(parse_string_literal(&literal).as_deref(), Some(*name));
+        }
+    }
+
+    #[test]
+    fn path_expressions_match_and_round_trip() {
+        let pattern = Regex::new(&format!("^{}$", PATH_EXPRESSION)).unwrap();
+        for name in HOSTILE_NAMES {
+            let path = Path::new("/media").join(name);
+            let expression = python_path(&path);
+            assert!(pattern.is_match(&expression), "{}", expression);
+            assert_eq!(parse_path_expression(&expression), Some(path));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_round_trip() {
+        let path = PathBuf::from(OsStr::from_bytes(b"/media/caf\xe9 \"\\ \xff.mkv"));
+        let expression = python_path(&path);
+        assert!(expression.starts_with("__import__('os').fsdecode(b\""));
+        assert!(expression.is_ascii());
+        let pattern = Regex::new(&format!("^{}$", PATH_EXPRESSION)).unwrap();
+        assert!(pattern.is_match(&expression), "{}", expression);
+        assert_eq!(parse_path_expression(&expression), Some(path));
+    }
+
+    #[test]
+    fn raw_literals_keep_backslashes() {
+        assert_eq!(
+            parse_string_literal(r#"r"C:\videos\new.mkv""#).as_deref(),
+            Some(r"C:\videos\new.mkv")
+        );
+        assert_eq!(
+            parse_string_literal(r"'C:\videos\new.mkv'").as_deref(),
+            Some("C:\\videos\new.mkv")
+        );
+    }
+
+    #[test]
+    fn arguments_split_outside_strings_and_brackets() {
+        assert_eq!(
+            split_arguments(r#"source="a, (b).mkv", cache=0, fpsnum=f(1, 2)"#),
+            vec![r#"source="a, (b).mkv""#, "cache=0", "fpsnum=f(1, 2)"]
+        );
+        assert_eq!(
+            split_arguments(r#"'it\'s, here.mkv'"#),
+            vec![r#"'it\'s, here.mkv'"#]
+        );
+        assert!(split_arguments("").is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+};
+
+use serde_json::{json, Value};
+
+use crate::{cancel::CancelToken, output::Output};
+
+/// An input waiting to be processed, along with its outputs
+pub type Job = (PathBuf, Vec<Output>);
+
+/// Inputs waiting to be processed by the workers,
+/// and the status of the ones which already started
+#[derive(Default)]
+pub struct JobQueue {
+    state: Mutex<QueueState>,
+    job_added: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    pending: VecDeque<Job>,
+    /// Inputs currently being processed, with the stage they are in
+    /// and the token which stops them
+    running: Vec<(PathBuf, String, CancelToken)>,
+    /// Inputs which finished, with the error if they failed
+    finished: Vec<(PathBuf, Option<String>)>,
+    /// Whether more jobs may be added once the queue is empty
+    keep_open: bool,
+}
+
+impl JobQueue {
+    pub fn new(jobs: VecDeque<Job>, keep_open: bool) -> Self {
+        JobQueue {
+            state: Mutex::new(QueueState {
+                pending: jobs,
+                keep_open,
+                ..QueueState::default()
+            }),
+            job_added: Condvar::new(),
+        }
+    }
+
+    pub fn push(&self, job: Job) {
+        self.state.lock().unwrap().pending.push_back(job);
+        self.job_added.notify_one();
+    }
+
+    /// Takes the next job to process, marking it as running,
+    /// along with the token which cancels it.
+    ///
+    /// If the queue is kept open, waits for a job to be added
+    /// instead of returning `None` when it is empty.
+    pub fn next(&self) -> Option<(Job, CancelToken)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.pending.pop_front() {
+                let token = CancelToken::new();
+                state
+                    .running
+                    .push((job.0.clone(), String::new(), token.clone()));
+                return Some((job, token));
+            }
+            if !state.keep_open {
+                return None;
+            }
+            state = self.job_added.wait(state).unwrap();
+        }
+    }
+
+    /// Removes a job which has not started yet, returning whether it was found
+    pub fn cancel(&self, input: &Path) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let len = state.pending.len();
+        state.pending.retain(|(pending, _)| pending != input);
+        state.pending.len() != len
+    }
+
+    /// The token which cancels a job which has already started
+    pub fn running_token(&self, input: &Path) -> Option<CancelToken> {
+        let state = self.state.lock().unwrap();
+        state
+            .running
+            .iter()
+            .find(|(path, ..)| path == input)
+            .map(|(.., token)| token.clone())
+    }
+
+    pub fn set_stage(&self, input: &Path, stage: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(running) = state.running.iter_mut().find(|(path, ..)| path == input) {
+            running.1 = stage.to_string();
+        }
+    }
+
+    pub fn finish(&self, input: &Path, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.running.retain(|(path, ..)| path != input);
+        state.finished.push((input.to_path_buf(), error));
+    }
+
+    pub fn status_json(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        json!({
+            "pending": state
+                .pending
+                .iter()
+                .map(|(path, _)| path.to_string_lossy())
+                .collect::<Vec<_>>(),
+            "running": state
+                .running
+                .iter()
+                .map(|(path, stage, token)| json!({
+                    "file": path.to_string_lossy(),
+                    "stage": stage,
+                    "cancelled": token.is_cancelled(),
+                }))
+                .collect::<Vec<_>>(),
+            "finished": state
+                .finished
+                .iter()
+                .map(|(path, error)| json!({
+                    "file": path.to_string_lossy(),
+                    "status": if error.is_some() { "failed" } else { "completed" },
+                    "error": error,
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    collections::BTreeMap,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    events::subscribe, is_input_script, pause::set_children_paused, queue::JobQueue,
+    try_parse_outputs, validate::validate_job,
+};
+
+/// The latest frame progress of each running tool, by its label
+type Progress = Arc<Mutex<BTreeMap<String, Value>>>;
+
+/// What requests are handled with
+struct ServerState {
+    queue: Arc<JobQueue>,
+    progress: Progress,
+    default_formats: Option<String>,
+    /// The token requests must give as `Authorization: Bearer <token>`, if any
+    token: Option<String>,
+    /// Enqueued scripts must be inside this directory
+    root: PathBuf,
+}
+
+/// Serves the status of the queue over HTTP, and lets jobs be added or removed.
+///
+/// - `GET /status`: pending, running, and finished inputs,
+///   and the frames each running tool has done so far
+/// - `POST /enqueue`: `{"path": "...", "formats": "..."}`, where `formats`
+///   defaults to the formats given on the command line
+/// - `POST /cancel`: `{"path": "..."}`, which removes an input which has not started yet,
+///   or stops the tools of one which is running
+/// - `POST /pause`, `POST /resume`: suspend or continue the running encodes
+///
+/// Enqueued scripts are run as Python, so only scripts inside `root` are accepted.
+/// A bare port listens only on this machine, and listening anywhere else
+/// requires a `token`, which every request must then give.
+pub fn start_server(
+    addr: &str,
+    token: Option<String>,
+    root: &Path,
+    queue: Arc<JobQueue>,
+    default_formats: Option<String>,
+) -> Result<()> {
+    let addr = listen_addr(addr);
+    if token.is_none() && !is_loopback(&addr)? {
+        bail!(
+            "Serving on {} requires a token, since it is reachable from other machines",
+            addr
+        );
+    }
+    let server =
+        Server::http(&addr).map_err(|e| anyhow!("Failed to start server on {}: {}", addr, e))?;
+    let state = ServerState {
+        queue,
+        progress: Progress::default(),
+        default_formats,
+        token,
+        root: root
+            .canonicalize()
+            .map_err(|e| anyhow!("Failed to resolve {}: {}", root.display(), e))?,
+    };
+    track_progress(Arc::clone(&state.progress));
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            handle_request(request, &state);
+        }
+    });
+    Ok(())
+}
+
+/// The address to listen on, where a bare port listens only on this machine
+fn listen_addr(addr: &str) -> String {
+    if addr.parse::<u16>().is_ok() {
+        format!("127.0.0.1:{}", addr)
+    } else {
+        addr.to_string()
+    }
+}
+
+fn is_loopback(addr: &str) -> Result<bool> {
+    let mut addrs = addr
+        .to_socket_addrs()
+        .map_err(|e| anyhow!("Invalid address {}: {}", addr, e))?
+        .peekable();
+    Ok(addrs.peek().is_some() && addrs.all(|addr| addr.ip().is_loopback()))
+}
+
+fn is_authorized(request: &Request, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+    request.headers().iter().any(|header| {
+        header.field.equiv("Authorization")
+            && header.value.as_str().strip_prefix("Bearer ") == Some(token)
+    })
+}
+
+/// Keeps the latest progress event of each tool, until the tool exits
+fn track_progress(progress: Progress) {
+    let events = subscribe();
+    thread::spawn(move || {
+        for event in events {
+            let label = match event.fields["label"].as_str() {
+                Some(label) => label.to_string(),
+                None => continue,
+            };
+            let mut progress = progress.lock().expect("Lock should not be poisoned");
+            match event.kind {
+                "progress" => {
+                    progress.insert(label, event.fields);
+                }
+                "progress_finished" => {
+                    progress.remove(&label);
+                }
+                _ => (),
+            }
+        }
+    });
+}
+
+fn handle_request(mut request: Request, state: &ServerState) {
+    let queue = &state.queue;
+    let (status, body) = match (request.method(), request.url()) {
+        _ if !is_authorized(&request, state.token.as_deref()) => {
+            (401, json!({ "error": "Unauthorized" }))
+        }
+        (Method::Get, "/status") => {
+            let mut status = queue.status_json();
+            status["progress"] = state
+                .progress
+                .lock()
+                .expect("Lock should not be poisoned")
+                .values()
+                .cloned()
+                .collect();
+            (200, status)
+        }
+        (Method::Post, "/enqueue") => match read_json(&mut request) {
+            Ok(body) => enqueue(&body, state),
+            Err(e) => (400, json!({ "error": e.to_string() })),
+        },
+        (Method::Post, "/cancel") => match read_json(&mut request) {
+            Ok(body) => match body.get("path").and_then(Value::as_str) {
+                Some(path) => cancel(&PathBuf::from(path), queue),
+                None => (400, json!({ "error": "Missing path" })),
+            },
+            Err(e) => (400, json!({ "error": e.to_string() })),
+        },
+        (Method::Post, url @ ("/pause" | "/resume")) => {
+            let paused = url == "/pause";
+            match set_children_paused(paused) {
+                Ok(count) => (200, json!({ "paused": paused, "processes": count })),
+                Err(e) => (500, json!({ "error": e.to_string() })),
+            }
+        }
+        _ => (404, json!({ "error": "Not found" })),
+    };
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(
+            "Content-Type: application/json"
+                .parse::<Header>()
+                .expect("Header is valid"),
+        );
+    let _ = request.respond(response);
+}
+
+fn read_json(request: &mut Request) -> Result<Value> {
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body)?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+fn cancel(path: &Path, queue: &JobQueue) -> (u16, Value) {
+    if queue.cancel(path) {
+        return (200, json!({ "cancelled": path.to_string_lossy() }));
+    }
+    match queue.running_token(path).map(|token| token.cancel()) {
+        Some(Ok(count)) => (
+            200,
+            json!({ "cancelled": path.to_string_lossy(), "processes": count }),
+        ),
+        Some(Err(e)) => (500, json!({ "error": e.to_string() })),
+        None => (
+            404,
+            json!({ "error": "No pending or running job for this path" }),
+        ),
+    }
+}
+
+fn enqueue(body: &Value, state: &ServerState) -> (u16, Value) {
+    let path = match body.get("path").and_then(Value::as_str) {
+        Some(path) => PathBuf::from(path),
+        None => return (400, json!({ "error": "Missing path" })),
+    };
+    if !path.is_file() || !is_input_script(&path) {
+        return (400, json!({ "error": "Path is not a Vapoursynth script" }));
+    }
+    // Resolved first, so neither `..` nor symlinks can leave the input directory
+    match path.canonicalize() {
+        Ok(resolved) if resolved.starts_with(&state.root) => (),
+        _ => {
+            return (
+                403,
+                json!({ "error": "Path is not inside the input directory" }),
+            )
+        }
+    }
+    let queue = &state.queue;
+    let formats = body
+        .get("formats")
+        .and_then(Value::as_str)
+        .or(state.default_formats.as_deref());
+    match try_parse_outputs(formats, &path) {
+        Ok(outputs) => {
+            if let Err(err) = validate_job(&path, &outputs) {
+                return (400, json!({ "error": err.to_string() }));
+            }
+            let response = json!({ "enqueued": path.to_string_lossy() });
+            queue.push((path, outputs));
+            (200, response)
+        }
+        Err(err) => (400, json!({ "error": err.to_string() })),
+    }
+}
@@ -0,0 +1,46 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+/// Defaults read from the settings file, for options which rarely change
+/// between runs
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Directories each finished output is also placed in,
+    /// used when `--copy-to` isn't given
+    pub destinations: Vec<PathBuf>,
+}
+
+/// Where the settings are read from, a JSON file like
+/// `{ "destinations": ["/mnt/archive", "/mnt/nas/videos"] }`.
+///
+/// Can be moved with the `MP4BATCH_CONFIG` environment variable.
+pub fn settings_path() -> Result<PathBuf> {
+    if let Some(path) = env::var_os("MP4BATCH_CONFIG") {
+        return Ok(PathBuf::from(path));
+    }
+    let config_dir =
+        dirs::config_dir().ok_or_else(|| anyhow!("Unable to find a config directory"))?;
+    Ok(config_dir.join("mp4batch").join("config.json"))
+}
+
+/// Reads the settings file, using the defaults if there isn't one
+pub fn load_settings() -> Result<Settings> {
+    let path = settings_path()?;
+    if !path.exists() {
+        return Ok(Settings::default());
+    }
+    read_settings(&path)
+}
+
+fn read_settings(path: &Path) -> Result<Settings> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Unable to read settings {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents)
+        .map_err(|e| anyhow!("Invalid settings in {}: {}", path.display(), e))
+}
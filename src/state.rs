@@ -0,0 +1,391 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::{Display, Write as _},
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    iter,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::{absolute_path, tool_log::is_dry_run, work_dir::work_path};
+
+const STATE_FILENAME: &str = ".mp4batch-state";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputStatus {
+    Pending,
+    Completed,
+    Failed,
+}
+
+impl Display for InputStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                InputStatus::Pending => "pending",
+                InputStatus::Completed => "completed",
+                InputStatus::Failed => "failed",
+            }
+        )
+    }
+}
+
+impl FromStr for InputStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "pending" => InputStatus::Pending,
+            "completed" => InputStatus::Completed,
+            "failed" => InputStatus::Failed,
+            _ => return Err(anyhow!("Unrecognized input status: {}", s)),
+        })
+    }
+}
+
+/// Tracks the status of each input in a directory batch,
+/// persisted to disk so that an interrupted batch can be resumed.
+pub struct BatchState {
+    dir: PathBuf,
+    entries: Vec<(PathBuf, InputStatus)>,
+}
+
+impl BatchState {
+    /// Starts a new batch, with every input pending
+    pub fn new(dir: &Path, inputs: &[PathBuf]) -> Result<Self> {
+        let state = BatchState {
+            dir: dir.to_path_buf(),
+            entries: inputs
+                .iter()
+                .map(|input| (relative_to(dir, input), InputStatus::Pending))
+                .collect(),
+        };
+        state.save()?;
+        Ok(state)
+    }
+
+    /// Continues the batch from an existing state file.
+    /// Inputs which were not part of the previous run are added as pending.
+    ///
+    /// Returns `None` if there is no previous state in this directory.
+    pub fn resume(dir: &Path, inputs: &[PathBuf]) -> Result<Option<Self>> {
+        let contents = match fs::read_to_string(dir.join(STATE_FILENAME)) {
+            Ok(contents) => contents,
+            Err(_) => return Ok(None),
+        };
+        let mut entries = Vec::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let (status, path) = line
+                .split_once('\t')
+                .ok_or_else(|| anyhow!("Malformed batch state line: {}", line))?;
+            entries.push((PathBuf::from(path), status.parse()?));
+        }
+        let mut state = BatchState {
+            dir: dir.to_path_buf(),
+            entries,
+        };
+        for input in inputs {
+            let path = relative_to(dir, input);
+            if !state.entries.iter().any(|(entry, _)| entry == &path) {
+                state.entries.push((path, InputStatus::Pending));
+            }
+        }
+        state.save()?;
+        Ok(Some(state))
+    }
+
+    pub fn status(&self, input: &Path) -> InputStatus {
+        let path = relative_to(&self.dir, input);
+        self.entries
+            .iter()
+            .find(|(entry, _)| entry == &path)
+            .map_or(InputStatus::Pending, |(_, status)| *status)
+    }
+
+    pub fn set_status(&mut self, input: &Path, status: InputStatus) -> Result<()> {
+        let path = relative_to(&self.dir, input);
+        match self.entries.iter_mut().find(|(entry, _)| entry == &path) {
+            Some(entry) => entry.1 = status,
+            None => self.entries.push((path, status)),
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let contents = self
+            .entries
+            .iter()
+            .map(|(path, status)| format!("{}\t{}\n", status, path.to_string_lossy()))
+            .collect::<String>();
+        // Write then rename, so a crash can't leave a truncated state file
+        let path = self.dir.join(STATE_FILENAME);
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+}
+
+fn relative_to(dir: &Path, input: &Path) -> PathBuf {
+    input.strip_prefix(dir).unwrap_or(input).to_path_buf()
+}
+
+const DONE_FILENAME: &str = ".mp4batch-done";
+
+/// Every input which has been fully processed, kept in the input directory
+/// so later runs skip them, even when the batch isn't being resumed.
+///
+/// Inputs are recorded by a hash of their path within the directory, their script,
+/// and the formats they were processed with, so changing any of them processes
+/// the input again, and identical scripts of different episodes are kept apart.
+///
+/// The outputs written for them are recorded too, on lines starting with `output`,
+/// so outputs in the directory aren't mistaken for raw video inputs.
+pub struct DoneList {
+    dir: PathBuf,
+    formats: String,
+    hashes: Mutex<HashSet<String>>,
+    /// Absolute paths of every output written
+    outputs: Mutex<HashSet<PathBuf>>,
+}
+
+const OUTPUT_PREFIX: &str = "output";
+
+impl DoneList {
+    pub fn load(dir: &Path, formats: Option<&str>) -> Result<Self> {
+        let contents = match fs::read_to_string(dir.join(DONE_FILENAME)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(anyhow!("Unable to read {}: {}", DONE_FILENAME, e)),
+        };
+        let mut hashes = HashSet::new();
+        let mut outputs = HashSet::new();
+        for line in contents.lines() {
+            match line.split_once('\t') {
+                Some((OUTPUT_PREFIX, output)) => {
+                    outputs.insert(absolute_path(dir.join(output))?);
+                }
+                Some((hash, _)) if !hash.trim().is_empty() => {
+                    hashes.insert(hash.to_string());
+                }
+                _ => (),
+            }
+        }
+        Ok(DoneList {
+            dir: dir.to_path_buf(),
+            formats: formats.unwrap_or_default().to_string(),
+            hashes: Mutex::new(hashes),
+            outputs: Mutex::new(outputs),
+        })
+    }
+
+    /// Whether `path` is an output written for an input in the list
+    pub fn is_output(&self, path: &Path) -> bool {
+        absolute_path(path).map_or(false, |path| {
+            self.outputs
+                .lock()
+                .expect("Lock should not be poisoned")
+                .contains(&path)
+        })
+    }
+
+    fn hash(&self, input: &Path) -> Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(relative_to(&self.dir, input).to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(fs::read(input)?);
+        hasher.update([0]);
+        hasher.update(self.formats.as_bytes());
+        Ok(to_hex(&hasher.finalize()))
+    }
+
+    pub fn contains(&self, input: &Path) -> bool {
+        match self.hash(input) {
+            Ok(hash) => self
+                .hashes
+                .lock()
+                .expect("Lock should not be poisoned")
+                .contains(&hash),
+            Err(_) => false,
+        }
+    }
+
+    /// Records `input` as done, along with the `outputs` written for it
+    pub fn add(&self, input: &Path, outputs: &[PathBuf]) -> Result<()> {
+        let hash = self.hash(input)?;
+        let mut hashes = self.hashes.lock().expect("Lock should not be poisoned");
+        let mut known_outputs = self.outputs.lock().expect("Lock should not be poisoned");
+        let mut lines = String::new();
+        if !hashes.contains(&hash) {
+            writeln!(
+                lines,
+                "{}\t{}",
+                hash,
+                relative_to(&self.dir, input).to_string_lossy()
+            )?;
+        }
+        for output in outputs {
+            let output = absolute_path(output)?;
+            if !known_outputs.contains(&output) {
+                writeln!(
+                    lines,
+                    "{}\t{}",
+                    OUTPUT_PREFIX,
+                    relative_to(&absolute_path(&self.dir)?, &output).to_string_lossy()
+                )?;
+                known_outputs.insert(output);
+            }
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(DONE_FILENAME))?
+            .write_all(lines.as_bytes())?;
+        hashes.insert(hash);
+        Ok(())
+    }
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    let mut hex = String::with_capacity(digest.len() * 2);
+    for byte in digest {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageStatus {
+    /// Finished with the same settings
+    Done,
+    /// Finished with different settings
+    Stale,
+    /// Not recorded, either because it never finished,
+    /// or because it was made before markers were kept
+    Unknown,
+}
+
+/// Which intermediates of an input have been finished, and a hash of the
+/// settings each was made with, kept in a `.stages` file beside them.
+///
+/// Each line is a stage name and its settings hash, separated by a tab.
+pub struct StageMarkers {
+    path: PathBuf,
+    stages: Mutex<BTreeMap<String, String>>,
+}
+
+impl StageMarkers {
+    pub fn load(input_vpy: &Path) -> Self {
+        let path = work_path(input_vpy).with_extension("stages");
+        // A missing or damaged file only means intermediates are checked the old way
+        let stages = fs::read_to_string(&path)
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| line.split_once('\t'))
+                    .map(|(stage, hash)| (stage.to_string(), hash.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        StageMarkers {
+            path,
+            stages: Mutex::new(stages),
+        }
+    }
+
+    pub fn status(&self, stage: &str, settings: &str) -> StageStatus {
+        match self
+            .stages
+            .lock()
+            .expect("Lock should not be poisoned")
+            .get(stage)
+        {
+            Some(hash) if *hash == settings_hash(settings) => StageStatus::Done,
+            Some(_) => StageStatus::Stale,
+            None => StageStatus::Unknown,
+        }
+    }
+
+    /// Runs a stage which makes `output`, unless it was already made with the same
+    /// settings. Anything in `artifacts` is removed along with `output` if it was
+    /// made with different settings.
+    ///
+    /// The stage is forgotten while it runs, so an interrupted run is never
+    /// mistaken for a finished one.
+    pub fn run(
+        &self,
+        stage: &str,
+        settings: &str,
+        output: &Path,
+        artifacts: &[PathBuf],
+        run: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        match self.status(stage, settings) {
+            StageStatus::Done if output.exists() => {
+                info!(
+                    "{} was already made with these settings, reusing it",
+                    output
+                        .file_name()
+                        .expect("File should have a name")
+                        .to_string_lossy()
+                );
+                return Ok(());
+            }
+            StageStatus::Stale if !is_dry_run() => {
+                info!(
+                    "{} was made with different settings, making it again",
+                    output
+                        .file_name()
+                        .expect("File should have a name")
+                        .to_string_lossy()
+                );
+                for path in iter::once(output).chain(artifacts.iter().map(PathBuf::as_path)) {
+                    if path.is_dir() {
+                        let _ = fs::remove_dir_all(path);
+                    } else {
+                        let _ = fs::remove_file(path);
+                    }
+                }
+            }
+            _ => (),
+        }
+        self.update(stage, None)?;
+        run()?;
+        self.update(stage, Some(settings_hash(settings)))
+    }
+
+    fn update(&self, stage: &str, hash: Option<String>) -> Result<()> {
+        if is_dry_run() {
+            return Ok(());
+        }
+        let mut stages = self.stages.lock().expect("Lock should not be poisoned");
+        match hash {
+            Some(hash) => stages.insert(stage.to_string(), hash),
+            None => stages.remove(stage),
+        };
+        let contents = stages
+            .iter()
+            .map(|(stage, hash)| format!("{}\t{}\n", stage, hash))
+            .collect::<String>();
+        // Write then rename, so a crash can't leave a truncated file
+        let temp_path = self.path.with_extension("stages.tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+fn settings_hash(settings: &str) -> String {
+    to_hex(&Sha256::digest(settings.as_bytes()))
+}
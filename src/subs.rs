@@ -0,0 +1,94 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use tracing::{info, warn};
+
+use crate::{
+    input::{find_source_map, get_subtitle_tracks, SubtitleTrack},
+    naming::sanitize_filename,
+    output::{extract_subtitles, SubtitleFormat},
+};
+
+/// Extracts every subtitle track of a video, or of the source of a script,
+/// into `output_dir`, or next to the video if it isn't given
+pub fn extract_all_subtitles(input: &Path, output_dir: Option<&Path>) -> Result<()> {
+    let source = if input.extension().map_or(false, |ext| ext == "vpy") {
+        find_source_map(input).subtitles
+    } else {
+        input.to_path_buf()
+    };
+    let tracks = get_subtitle_tracks(&source)?;
+    if tracks.is_empty() {
+        info!("{} has no subtitle tracks", source.display());
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for (i, track) in tracks.iter().enumerate() {
+        let format = match SubtitleFormat::from_codec(&track.codec) {
+            Some(format) => format,
+            None => {
+                warn!(
+                    "Skipping subtitle track {}, {} subtitles can't be extracted",
+                    i, track.codec
+                );
+                failed += 1;
+                continue;
+            }
+        };
+        let output = subtitle_filename(&source, output_dir, i, track, format);
+        match extract_subtitles(&source, i as u8, format, &output) {
+            Ok(()) => info!(
+                success = true,
+                "Extracted {} subtitle track {}{} to {}",
+                format,
+                i,
+                track
+                    .title
+                    .as_ref()
+                    .map_or_else(String::new, |title| format!(" ({})", title)),
+                output.display()
+            ),
+            Err(e) => {
+                warn!("Failed to extract subtitle track {}: {}", i, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "Failed to extract {} of {} subtitle tracks",
+            failed,
+            tracks.len()
+        );
+    }
+    Ok(())
+}
+
+/// Names the file after the video, the track number, its language,
+/// and whether it is forced, such as `Show 01.2.eng.forced.ass`
+fn subtitle_filename(
+    source: &Path,
+    output_dir: Option<&Path>,
+    index: usize,
+    track: &SubtitleTrack,
+    format: SubtitleFormat,
+) -> PathBuf {
+    let mut name = source
+        .file_stem()
+        .expect("File should have a name")
+        .to_string_lossy()
+        .into_owned();
+    name.push_str(&format!(".{}", index));
+    if let Some(ref language) = track.language {
+        name.push_str(&format!(".{}", sanitize_filename(language)));
+    }
+    if track.forced {
+        name.push_str(".forced");
+    }
+    name.push_str(&format!(".{}", format.extension()));
+    match output_dir {
+        Some(dir) => dir.join(name),
+        None => source.with_file_name(name),
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use size::Size;
+
+use crate::{
+    error::{exit_code, Error},
+    history::unix_time,
+    input::{find_source_file, get_media_duration},
+    metrics::{scores_json, Metric, ScoreSummary},
+    notify::format_duration,
+    pipeline::FinishedOutput,
+    tools::tool_versions,
+};
+
+/// The file format of the report written at the end of a batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Markdown,
+    Json,
+}
+
+/// What happened to every input in a batch, for reporting once it finishes
+#[derive(Default)]
+pub struct BatchSummary {
+    inputs: Mutex<Vec<InputSummary>>,
+}
+
+struct InputSummary {
+    input: PathBuf,
+    source_size: Option<u64>,
+    outputs: Vec<OutputSummary>,
+    duration: Duration,
+    error: Option<String>,
+    /// The code the error would exit with, see `Error::exit_code`
+    exit_code: i32,
+    warnings: Vec<String>,
+}
+
+struct OutputSummary {
+    path: PathBuf,
+    size: Option<u64>,
+    bitrate_kbps: Option<f64>,
+    scores: Vec<(Metric, ScoreSummary)>,
+    settings: Option<Value>,
+    duration: Duration,
+    lossless_retries: u32,
+    verified: Vec<&'static str>,
+}
+
+impl BatchSummary {
+    pub fn add(
+        &self,
+        input: &Path,
+        outputs: &[FinishedOutput],
+        duration: Duration,
+        error: Option<&anyhow::Error>,
+        warnings: Vec<String>,
+    ) {
+        let outputs = outputs
+            .iter()
+            .map(|output| {
+                let path = &output.path;
+                let size = path.metadata().ok().map(|meta| meta.len());
+                let bitrate_kbps =
+                    size.zip(get_media_duration(path).ok())
+                        .and_then(|(size, duration)| {
+                            (duration.as_secs_f64() > 0.0)
+                                .then(|| size as f64 * 8.0 / 1000.0 / duration.as_secs_f64())
+                        });
+                OutputSummary {
+                    path: path.clone(),
+                    size,
+                    bitrate_kbps,
+                    scores: output.scores.clone(),
+                    settings: output.settings.clone(),
+                    duration: output.duration,
+                    lossless_retries: output.lossless_retries,
+                    verified: output.verified.clone(),
+                }
+            })
+            .collect();
+        self.inputs.lock().unwrap().push(InputSummary {
+            input: input.to_path_buf(),
+            source_size: find_source_file(input)
+                .metadata()
+                .ok()
+                .map(|meta| meta.len()),
+            outputs,
+            duration,
+            error: error.map(ToString::to_string),
+            exit_code: error.map_or(0, exit_code),
+            warnings,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.lock().unwrap().is_empty()
+    }
+
+    pub fn completed(&self) -> usize {
+        let inputs = self.inputs.lock().unwrap();
+        inputs.iter().filter(|input| input.error.is_none()).count()
+    }
+
+    /// The outputs written for `input`, or `None` if it failed or wasn't processed
+    pub fn outputs(&self, input: &Path) -> Option<Vec<PathBuf>> {
+        let inputs = self.inputs.lock().unwrap();
+        inputs
+            .iter()
+            .find(|summary| summary.input == input && summary.error.is_none())
+            .map(|summary| {
+                summary
+                    .outputs
+                    .iter()
+                    .map(|output| output.path.clone())
+                    .collect()
+            })
+    }
+
+    pub fn failed(&self) -> usize {
+        let inputs = self.inputs.lock().unwrap();
+        inputs.iter().filter(|input| input.error.is_some()).count()
+    }
+
+    /// The code to exit with, which is 0 if every input completed.
+    ///
+    /// Failures of different kinds exit with 1, unless the batch was interrupted.
+    pub fn exit_code(&self) -> i32 {
+        let inputs = self.inputs.lock().unwrap();
+        let mut codes = inputs
+            .iter()
+            .map(|input| input.exit_code)
+            .filter(|&code| code != 0);
+        let first = match codes.next() {
+            Some(code) => code,
+            None => return 0,
+        };
+        let interrupted = Error::Interrupted(String::new()).exit_code();
+        let mut code = first;
+        for other in codes {
+            if other == interrupted || code == interrupted {
+                code = interrupted;
+            } else if other != code {
+                code = 1;
+            }
+        }
+        code
+    }
+
+    /// Formats the summary as a plain text table
+    pub fn to_table(&self) -> String {
+        let inputs = self.inputs.lock().unwrap();
+        let mut table = format!(
+            "{:<32}  {:<6}  {:>9}  {:<48}  {:>10}  {:>6}  {:>10}  {}\n",
+            "INPUT", "STATUS", "TIME", "OUTPUT", "SIZE", "% SRC", "BITRATE", "QUALITY"
+        );
+        for input in inputs.iter() {
+            let mut outputs = input.outputs.iter();
+            let first = outputs.next();
+            let _ = writeln!(
+                table,
+                "{:<32}  {:<6}  {:>9}  {}",
+                file_name(&input.input),
+                input.status(),
+                format_duration(input.duration),
+                first.map_or_else(String::new, |output| input.output_columns(output))
+            );
+            for output in outputs {
+                let _ = writeln!(
+                    table,
+                    "{:<32}  {:<6}  {:>9}  {}",
+                    "",
+                    "",
+                    "",
+                    input.output_columns(output)
+                );
+            }
+        }
+        // Warnings scroll away during the encodes, so they are gathered by file at the end
+        let problems = inputs
+            .iter()
+            .filter(|input| input.error.is_some() || !input.warnings.is_empty());
+        let mut first = true;
+        for input in problems {
+            if first {
+                table.push_str("\nPROBLEMS\n");
+                first = false;
+            }
+            let _ = writeln!(table, "{}", file_name(&input.input));
+            if let Some(ref error) = input.error {
+                let _ = writeln!(table, "  error: {}", error);
+            }
+            for warning in input.grouped_warnings() {
+                let _ = writeln!(table, "  warning: {}", warning);
+            }
+        }
+        table
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let inputs = self.inputs.lock().unwrap();
+        let mut report = String::from(
+            "# mp4batch report\n\n| Input | Status | Time | Output | Size | % of source | Bitrate \
+             | Quality |\n| --- | --- | --- | --- | ---: | ---: | ---: | --- |\n",
+        );
+        for input in inputs.iter() {
+            let row_start = format!(
+                "| {} | {} | {} |",
+                file_name(&input.input),
+                input.status(),
+                format_duration(input.duration)
+            );
+            if input.outputs.is_empty() {
+                let _ = writeln!(report, "{} | | | | |", row_start);
+            }
+            for output in &input.outputs {
+                let _ = writeln!(
+                    report,
+                    "{} {} | {} | {} | {} | {} |",
+                    row_start,
+                    file_name(&output.path),
+                    output.size_string(),
+                    input.source_percent(output),
+                    output.bitrate_string(),
+                    output.scores_string()
+                );
+            }
+        }
+        let problems = inputs
+            .iter()
+            .filter(|input| input.error.is_some() || !input.warnings.is_empty());
+        let mut first = true;
+        for input in problems {
+            if first {
+                report.push_str("\n## Problems\n");
+                first = false;
+            }
+            let _ = writeln!(report, "\n### {}\n", file_name(&input.input));
+            if let Some(ref error) = input.error {
+                let _ = writeln!(report, "- Error: {}", error);
+            }
+            for warning in input.grouped_warnings() {
+                let _ = writeln!(report, "- Warning: {}", warning);
+            }
+        }
+        report
+    }
+
+    pub fn to_json(&self) -> Value {
+        let inputs = self.inputs.lock().unwrap();
+        Value::Array(
+            inputs
+                .iter()
+                .map(|input| {
+                    json!({
+                        "input": input.input.to_string_lossy(),
+                        "status": input.status(),
+                        "duration_secs": input.duration.as_secs_f64(),
+                        "source_size": input.source_size,
+                        "error": input.error,
+                        "warnings": input.warnings,
+                        "outputs": input.outputs.iter().map(|output| json!({
+                            "path": output.path.to_string_lossy(),
+                            "size": output.size,
+                            "bitrate_kbps": output.bitrate_kbps,
+                            "scores": scores_json(&output.scores),
+                            "duration_secs": output.duration.as_secs_f64(),
+                            "lossless_retries": output.lossless_retries,
+                            "verified": output.verified,
+                            "settings": output.settings,
+                        })).collect::<Vec<_>>(),
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// Writes the report to `dir`, returning the path it was written to
+    pub fn write_report(&self, dir: &Path, format: ReportFormat) -> Result<PathBuf> {
+        let (extension, contents) = match format {
+            ReportFormat::Markdown => ("md", self.to_markdown()),
+            ReportFormat::Json => ("json", format!("{:#}\n", self.to_json())),
+        };
+        let path = dir.join(format!("mp4batch-report-{}.{}", unix_time(), extension));
+        fs::write(&path, contents)?;
+        Ok(path)
+    }
+
+    /// Writes `run-report.json` to `dir`, describing the whole run for automation
+    /// which checks the outputs, returning the path it was written to
+    pub fn write_run_report(&self, dir: &Path, started: u64, exit_code: i32) -> Result<PathBuf> {
+        let finished = unix_time();
+        let report = json!({
+            "mp4batch_version": env!("CARGO_PKG_VERSION"),
+            "command_line": env::args().collect::<Vec<_>>(),
+            "started": started,
+            "finished": finished,
+            "duration_secs": finished.saturating_sub(started),
+            "exit_code": exit_code,
+            "completed": self.completed(),
+            "failed": self.failed(),
+            "tools": tool_versions(),
+            "inputs": self.to_json(),
+        });
+        let path = dir.join("run-report.json");
+        fs::write(&path, format!("{:#}\n", report))?;
+        Ok(path)
+    }
+}
+
+impl InputSummary {
+    fn status(&self) -> &'static str {
+        if self.error.is_some() {
+            "failed"
+        } else {
+            "done"
+        }
+    }
+
+    /// The warnings in the order they were first logged,
+    /// with how many times each was repeated, such as once per output
+    fn grouped_warnings(&self) -> Vec<String> {
+        let mut grouped: Vec<(&str, usize)> = Vec::new();
+        for warning in &self.warnings {
+            match grouped.iter_mut().find(|(seen, _)| seen == warning) {
+                Some((_, count)) => *count += 1,
+                None => grouped.push((warning, 1)),
+            }
+        }
+        grouped
+            .into_iter()
+            .map(|(warning, count)| {
+                if count > 1 {
+                    format!("{} (x{})", warning, count)
+                } else {
+                    warning.to_string()
+                }
+            })
+            .collect()
+    }
+
+    fn source_percent(&self, output: &OutputSummary) -> String {
+        match (output.size, self.source_size) {
+            (Some(size), Some(source_size)) if source_size > 0 => {
+                format!("{:.1}%", size as f64 * 100.0 / source_size as f64)
+            }
+            _ => "-".to_string(),
+        }
+    }
+
+    fn output_columns(&self, output: &OutputSummary) -> String {
+        format!(
+            "{:<48}  {:>10}  {:>6}  {:>10}  {}",
+            file_name(&output.path),
+            output.size_string(),
+            self.source_percent(output),
+            output.bitrate_string(),
+            output.scores_string()
+        )
+    }
+}
+
+impl OutputSummary {
+    fn size_string(&self) -> String {
+        self.size.map_or_else(
+            || "-".to_string(),
+            |size| Size::from_bytes(size).format().to_string(),
+        )
+    }
+
+    /// Formats each score as its mean and 1% low
+    fn scores_string(&self) -> String {
+        self.scores
+            .iter()
+            .map(|(metric, summary)| {
+                format!(
+                    "{} {:.2}/{:.2}",
+                    metric.to_string().to_uppercase(),
+                    summary.mean,
+                    summary.low_1pct
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn bitrate_string(&self) -> String {
+        self.bitrate_kbps
+            .map_or_else(|| "-".to_string(), |kbps| format!("{:.0} kbps", kbps))
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .expect("File should have a name")
+        .to_string_lossy()
+        .into_owned()
+}
@@ -0,0 +1,275 @@
+use std::{
+    cell::RefCell,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    iter,
+    path::Path,
+    process::{Child, ChildStderr, ChildStdout, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::{
+    cancel::{spawn_tracked, TrackedChild},
+    events::{events_enabled, tool_stdout},
+};
+
+/// A file which the output of every tool spawned for an input or output is copied into,
+/// so a failure can be looked into after the terminal is gone
+pub struct ToolLog {
+    file: Mutex<File>,
+}
+
+impl ToolLog {
+    fn open(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(
+            file,
+            "=== mp4batch {} started at {} ===",
+            env!("CARGO_PKG_VERSION"),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs())
+        )?;
+        Ok(ToolLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn write(&self, data: &[u8]) {
+        let _ = self.file.lock().unwrap().write_all(data);
+    }
+
+    pub fn write_line(&self, line: &str) {
+        let mut file = self.file.lock().unwrap();
+        let _ = writeln!(file, "{}", line);
+    }
+
+    fn write_command(&self, command: &Command) {
+        let args = command
+            .get_args()
+            .map(|arg| format!(" {:?}", arg))
+            .collect::<String>();
+        self.write_line(&format!("$ {:?}{}", command.get_program(), args));
+    }
+}
+
+thread_local! {
+    static CURRENT_LOG: RefCell<Option<Arc<ToolLog>>> = const { RefCell::new(None) };
+    /// The command line of a tool spawned during a dry run, waiting for the tool reading its stdout
+    static PENDING_PIPE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Makes every tool run through this module print its command line instead of running
+pub fn set_dry_run(enabled: bool) {
+    DRY_RUN.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+/// Prints a line of a dry run, which goes to stderr while stdout is used for events
+pub fn print_dry_run(line: &str) {
+    if events_enabled() {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// Prints the command line a dry run skips, quoted so it can be pasted into a shell.
+///
+/// A tool reading the stdout of one spawned before it is printed as a pipeline.
+pub fn print_dry_run_command(command: &Command) {
+    let line = shell_command(command);
+    match PENDING_PIPE.with(|pipe| pipe.borrow_mut().take()) {
+        Some(producer) => print_dry_run(&format!("$ {} \\\n  | {}", producer, line)),
+        None => print_dry_run(&format!("$ {}", line)),
+    }
+}
+
+/// Prints the contents of a generated script as a heredoc writing it
+pub fn print_dry_run_script(path: &Path) -> Result<()> {
+    let contents = fs::read_to_string(path)?;
+    print_dry_run(&format!(
+        "$ cat > {} <<'EOF'\n{}\nEOF",
+        shell_quote(&path.to_string_lossy()),
+        contents.trim_end()
+    ));
+    Ok(())
+}
+
+fn shell_command(command: &Command) -> String {
+    iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=+,@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// The status of a tool which a dry run pretends ran successfully
+pub fn dry_run_status() -> ExitStatus {
+    #[cfg(unix)]
+    {
+        std::os::unix::process::ExitStatusExt::from_raw(0)
+    }
+    #[cfg(windows)]
+    {
+        std::os::windows::process::ExitStatusExt::from_raw(0)
+    }
+}
+
+/// Sends the output of tools spawned by this thread to the log at `path`,
+/// or stops logging it if `path` is `None`
+pub fn set_tool_log(path: Option<&Path>) -> Result<()> {
+    let log = match path {
+        // Nothing runs during a dry run, so there is nothing to log
+        Some(path) if !is_dry_run() => Some(Arc::new(ToolLog::open(path)?)),
+        _ => None,
+    };
+    set_current_tool_log(log);
+    Ok(())
+}
+
+/// Shares a log with another thread
+pub fn set_current_tool_log(log: Option<Arc<ToolLog>>) {
+    CURRENT_LOG.with(|current| *current.borrow_mut() = log);
+}
+
+pub fn current_tool_log() -> Option<Arc<ToolLog>> {
+    CURRENT_LOG.with(|current| current.borrow().clone())
+}
+
+/// Records the command line of a tool about to be run
+pub fn log_command(command: &Command) {
+    if let Some(log) = current_tool_log() {
+        log.write_command(command);
+    }
+}
+
+/// A tool whose stdout is used by another tool
+pub struct ToolPipe {
+    /// Absent during a dry run, where nothing is spawned
+    child: Option<(Child, TrackedChild)>,
+}
+
+impl ToolPipe {
+    /// The tool's stdout, for use as the stdin of the next tool
+    pub fn stdout(&mut self) -> Stdio {
+        match self.child {
+            Some((ref mut child, _)) => child
+                .stdout
+                .take()
+                .expect("stdout should be writeable")
+                .into(),
+            None => Stdio::null(),
+        }
+    }
+
+    pub fn wait(&mut self) -> io::Result<()> {
+        if let Some((ref mut child, _)) = self.child {
+            child.wait()?;
+        }
+        Ok(())
+    }
+}
+
+/// Spawns a tool whose stdout is used by another tool,
+/// copying its stderr to the log as well as our own stderr
+pub fn spawn_logged(command: &mut Command) -> io::Result<ToolPipe> {
+    if is_dry_run() {
+        let line = shell_command(command);
+        PENDING_PIPE.with(|pipe| *pipe.borrow_mut() = Some(line));
+        return Ok(ToolPipe { child: None });
+    }
+    let log = match current_tool_log() {
+        Some(log) => log,
+        None => {
+            let (child, tracked) = spawn_tracked(command)?;
+            return Ok(ToolPipe {
+                child: Some((child, tracked)),
+            });
+        }
+    };
+    log.write_command(command);
+    let (mut child, tracked) = spawn_tracked(command.stderr(Stdio::piped()))?;
+    let stderr = child.stderr.take().expect("stderr should be readable");
+    tee_stderr(stderr, log);
+    Ok(ToolPipe {
+        child: Some((child, tracked)),
+    })
+}
+
+/// Runs a tool to completion, copying its stdout and stderr
+/// to the log as well as our own
+pub fn run_logged(command: &mut Command) -> io::Result<ExitStatus> {
+    if is_dry_run() {
+        print_dry_run_command(command);
+        return Ok(dry_run_status());
+    }
+    let log = match current_tool_log() {
+        Some(log) => log,
+        None => {
+            let (mut child, _tracked) = spawn_tracked(command.stdout(tool_stdout()))?;
+            return child.wait();
+        }
+    };
+    log.write_command(command);
+    let (mut child, _tracked) =
+        spawn_tracked(command.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+    let stdout = tee_stdout(
+        child.stdout.take().expect("stdout should be readable"),
+        Arc::clone(&log),
+    );
+    let stderr = tee_stderr(child.stderr.take().expect("stderr should be readable"), log);
+    let status = child.wait();
+    let _ = stdout.join();
+    let _ = stderr.join();
+    status
+}
+
+/// Copies a tool's stdout, which goes to our stderr while stdout is used for events
+pub fn tee_stdout(stdout: ChildStdout, log: Arc<ToolLog>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        if events_enabled() {
+            tee(stdout, &log, io::stderr())
+        } else {
+            tee(stdout, &log, io::stdout())
+        }
+    })
+}
+
+fn tee_stderr(stderr: ChildStderr, log: Arc<ToolLog>) -> JoinHandle<()> {
+    thread::spawn(move || tee(stderr, &log, io::stderr()))
+}
+
+fn tee(mut reader: impl Read, log: &ToolLog, mut terminal: impl Write) {
+    let mut buffer = [0u8; 8192];
+    loop {
+        let len = match reader.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => len,
+        };
+        log.write(&buffer[..len]);
+        let _ = terminal.write_all(&buffer[..len]);
+        let _ = terminal.flush();
+    }
+}
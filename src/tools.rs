@@ -0,0 +1,302 @@
+use std::process::Command;
+
+use anyhow::{bail, Result};
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use tracing::{debug, warn};
+use which::which;
+
+/// An external tool with a minimum version known to support every flag we pass it
+pub struct Tool {
+    pub binary: &'static str,
+    /// The argument which makes the tool print its version
+    pub version_arg: &'static str,
+    pub minimum: Option<&'static str>,
+    /// Whether every run needs this tool, rather than only some outputs
+    pub required: bool,
+}
+
+pub const TOOLS: &[Tool] = &[
+    Tool {
+        binary: "vspipe",
+        version_arg: "--version",
+        minimum: None,
+        required: true,
+    },
+    Tool {
+        binary: "ffmpeg",
+        version_arg: "-version",
+        minimum: Some("6.0"),
+        required: true,
+    },
+    Tool {
+        binary: "ffprobe",
+        version_arg: "-version",
+        minimum: None,
+        required: false,
+    },
+    Tool {
+        binary: "mediainfo",
+        version_arg: "--version",
+        minimum: None,
+        required: false,
+    },
+    Tool {
+        binary: "mkvmerge",
+        version_arg: "--version",
+        minimum: Some("60.0"),
+        required: true,
+    },
+    Tool {
+        binary: "mkvpropedit",
+        version_arg: "--version",
+        minimum: Some("60.0"),
+        required: false,
+    },
+    Tool {
+        binary: "av1an",
+        version_arg: "--version",
+        minimum: Some("0.4.1"),
+        required: false,
+    },
+    Tool {
+        binary: "x264",
+        version_arg: "--version",
+        minimum: None,
+        required: false,
+    },
+    Tool {
+        binary: "x265",
+        version_arg: "--version",
+        minimum: Some("3.5"),
+        required: false,
+    },
+    Tool {
+        binary: "SvtAv1EncApp",
+        version_arg: "--version",
+        minimum: Some("2.0.0"),
+        required: false,
+    },
+    Tool {
+        binary: "aomenc",
+        version_arg: "--help",
+        minimum: None,
+        required: false,
+    },
+    Tool {
+        binary: "rav1e",
+        version_arg: "--version",
+        minimum: None,
+        required: false,
+    },
+    Tool {
+        binary: "hdrcopier",
+        version_arg: "--version",
+        minimum: None,
+        required: false,
+    },
+    Tool {
+        binary: "grav1synth",
+        version_arg: "--version",
+        minimum: None,
+        required: false,
+    },
+];
+
+/// Runs `binary` with `arg`, returning everything it printed
+pub fn tool_output(binary: &str, arg: &str) -> Option<String> {
+    let output = Command::new(binary).arg(arg).output().ok()?;
+    Some(format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    ))
+}
+
+/// Finds the first dotted version number, such as `6.1.1` in `ffmpeg version n6.1.1`
+pub fn parse_version(text: &str) -> Option<Vec<u32>> {
+    static PATTERN: OnceCell<Regex> = OnceCell::new();
+    let version = PATTERN
+        .get_or_init(|| Regex::new(r"\d+(\.\d+)+").expect("Valid regex"))
+        .find(text)?;
+    version
+        .as_str()
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+impl Tool {
+    /// The line of the tool's output which names its version
+    pub fn version_line(&self) -> Option<String> {
+        let output = tool_output(self.binary, self.version_arg)?;
+        let mut lines = output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty());
+        let line = match self.binary {
+            // aomenc has no version flag, but lists the encoder version in its help
+            "aomenc" => lines.find(|line| line.contains("AV1 Encoder"))?,
+            // x265 prints its build options before the version
+            "x265" => lines.find(|line| line.contains("version"))?,
+            _ => lines.next()?,
+        };
+        Some(line.to_string())
+    }
+
+    /// Whether the installed version is at least the known-good minimum.
+    ///
+    /// Versions which can't be parsed, such as git builds, are assumed to be new enough.
+    fn meets_minimum(&self, line: &str) -> bool {
+        let minimum = match self.minimum.and_then(parse_version) {
+            Some(minimum) => minimum,
+            None => return true,
+        };
+        match parse_version(line) {
+            Some(version) => version >= minimum,
+            None => {
+                debug!("Unable to parse the version of {}: {}", self.binary, line);
+                true
+            }
+        }
+    }
+}
+
+/// The version of every installed tool, by its binary
+pub fn tool_versions() -> Value {
+    TOOLS
+        .iter()
+        .filter(|tool| which(tool.binary).is_ok())
+        .map(|tool| (tool.binary.to_string(), json!(tool.version_line())))
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+/// Checks the versions of every installed tool, failing if a required one is too old
+/// and warning about any others
+pub fn check_tool_versions() -> Result<()> {
+    for tool in TOOLS {
+        if which(tool.binary).is_err() {
+            continue;
+        }
+        let line = match tool.version_line() {
+            Some(line) => line,
+            None => continue,
+        };
+        if tool.meets_minimum(&line) {
+            continue;
+        }
+        let minimum = tool.minimum.unwrap_or_default();
+        if tool.required {
+            bail!(
+                "{} is too old, version {} or newer is required: {}",
+                tool.binary,
+                minimum,
+                line
+            );
+        }
+        warn!(
+            "{} is older than version {}, some options may not work: {}",
+            tool.binary, minimum, line
+        );
+    }
+    if which("SvtAv1EncApp").is_ok() && !svt_is_psy_build() {
+        warn!(
+            "SvtAv1EncApp does not support the psy options mp4batch uses (--tune 3, --ac-bias), \
+             which need a build of SVT-AV1-PSY"
+        );
+    }
+    Ok(())
+}
+
+/// Whether SvtAv1EncApp is one of the psy forks, which have options mainline builds lack
+pub fn svt_is_psy_build() -> bool {
+    tool_output("SvtAv1EncApp", "--help").map_or(false, |help| help.contains("--ac-bias"))
+}
+
+/// VapourSynth plugins, by namespace, and whether every run needs them
+const PLUGINS: &[(&str, bool)] = &[
+    ("lsmas", true),
+    ("ffms2", true),
+    ("bs", false),
+    ("sub", false),
+    ("assrender", false),
+    ("vszip", false),
+    ("vship", false),
+];
+
+/// Prints the core version and which plugins and modules load, one per line
+const VAPOURSYNTH_CHECK: &str = r#"
+import sys
+try:
+    import vapoursynth as vs
+except Exception as e:
+    print("vapoursynth: failed to load (%s)" % e)
+    sys.exit(0)
+core = vs.core
+try:
+    print("core: R%d" % core.core_version.release_major)
+except AttributeError:
+    print("core: %s" % core.version().splitlines()[-1])
+print("python: %s" % sys.version.split()[0])
+for namespace in sys.argv[1:]:
+    print("plugin %s: %s" % (namespace, "ok" if hasattr(core, namespace) else "missing"))
+try:
+    import vsutil
+    print("module vsutil: ok")
+except Exception:
+    print("module vsutil: missing")
+"#;
+
+/// Prints every external tool and its version, and the state of VapourSynth,
+/// as a block which can be pasted into a bug report
+pub fn print_doctor() {
+    println!("```");
+    println!(
+        "mp4batch {} ({} {})",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+    println!();
+    println!("Tools:");
+    for tool in TOOLS {
+        let status = match which(tool.binary) {
+            Err(_) if tool.required => "NOT FOUND (required)".to_string(),
+            Err(_) => "not found".to_string(),
+            Ok(path) => {
+                let version = tool
+                    .version_line()
+                    .unwrap_or_else(|| "unknown version".to_string());
+                let too_old = if tool.meets_minimum(&version) {
+                    String::new()
+                } else {
+                    format!(" (older than {})", tool.minimum.unwrap_or_default())
+                };
+                format!("{}{} [{}]", version, too_old, path.display())
+            }
+        };
+        println!("  {:<14} {}", tool.binary, status);
+    }
+    println!();
+    println!("VapourSynth:");
+    let python = if cfg!(windows) { "python" } else { "python3" };
+    let output = Command::new(python)
+        .arg("-c")
+        .arg(VAPOURSYNTH_CHECK)
+        .args(PLUGINS.iter().map(|(namespace, _)| namespace))
+        .output();
+    match output {
+        Ok(output) => {
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                let required = PLUGINS.iter().any(|&(namespace, required)| {
+                    required && line == format!("plugin {}: missing", namespace)
+                });
+                println!("  {}{}", line, if required { " (required)" } else { "" });
+            }
+        }
+        Err(e) => println!("  unable to run {}: {}", python, e),
+    }
+    println!("```");
+}
@@ -0,0 +1,130 @@
+use std::{
+    env::temp_dir,
+    fmt::{self, Display},
+    fs,
+    path::Path,
+    process::Command,
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+use which::which;
+
+use crate::{error::Error, tool_log::run_logged};
+
+/// A remote location finished outputs are copied to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadDestination {
+    /// `rsync://host/module/path`, or `[user@]host:path` over SSH
+    Rsync(String),
+    /// `sftp://[user@]host[:port]/path`
+    Sftp(String),
+    /// `s3://bucket/prefix`, using the AWS CLI and its configured credentials
+    S3(String),
+}
+
+impl FromStr for UploadDestination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("s3://") {
+            Ok(UploadDestination::S3(s.to_string()))
+        } else if s.starts_with("sftp://") {
+            Ok(UploadDestination::Sftp(s.to_string()))
+        } else if s.starts_with("rsync://") || is_ssh_path(s) {
+            Ok(UploadDestination::Rsync(s.to_string()))
+        } else {
+            Err(format!(
+                "Unrecognized upload destination '{}', expected rsync://, sftp://, s3:// or \
+                 host:path",
+                s
+            ))
+        }
+    }
+}
+
+impl Display for UploadDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadDestination::Rsync(dest)
+            | UploadDestination::Sftp(dest)
+            | UploadDestination::S3(dest) => write!(f, "{}", dest),
+        }
+    }
+}
+
+/// Whether this looks like rsync's `[user@]host:path`,
+/// and not a Windows path like `C:\videos`
+fn is_ssh_path(s: &str) -> bool {
+    match s.split_once(':') {
+        Some((host, _)) => host.len() > 1 && !host.contains(['/', '\\']),
+        None => false,
+    }
+}
+
+impl UploadDestination {
+    const fn tool(&self) -> &'static str {
+        match self {
+            UploadDestination::Rsync(_) => "rsync",
+            UploadDestination::Sftp(_) => "sftp",
+            UploadDestination::S3(_) => "aws",
+        }
+    }
+
+    /// Fails early if the tool for this destination is missing
+    pub fn check_tool(&self) -> Result<()> {
+        which(self.tool()).map_err(|_| Error::ToolMissing(self.tool().to_string()))?;
+        Ok(())
+    }
+
+    /// Copies `file` into the destination directory
+    pub fn upload(&self, file: &Path) -> Result<()> {
+        let status = match self {
+            UploadDestination::Rsync(dest) => run_logged(
+                Command::new("rsync")
+                    .arg("--partial")
+                    .arg("--times")
+                    .arg(file)
+                    .arg(with_trailing_slash(dest)),
+            )?,
+            UploadDestination::Sftp(dest) => {
+                // sftp reads its commands from a batch file, and changes
+                // into the directory at the end of the URI before running them
+                let batch = temp_dir().join(format!(
+                    "mp4batch-{}-{}.sftp",
+                    std::process::id(),
+                    file.file_name()
+                        .expect("File should have a name")
+                        .to_string_lossy()
+                ));
+                fs::write(
+                    &batch,
+                    format!("put \"{}\"\n", file.to_string_lossy().replace('"', "\\\"")),
+                )?;
+                let status = run_logged(Command::new("sftp").arg("-b").arg(&batch).arg(dest));
+                let _ = fs::remove_file(&batch);
+                status?
+            }
+            UploadDestination::S3(dest) => run_logged(
+                Command::new("aws")
+                    .arg("s3")
+                    .arg("cp")
+                    .arg("--only-show-errors")
+                    .arg(file)
+                    .arg(with_trailing_slash(dest)),
+            )?,
+        };
+        if !status.success() {
+            bail!("Failed to upload {} to {}", file.display(), self);
+        }
+        Ok(())
+    }
+}
+
+fn with_trailing_slash(dest: &str) -> String {
+    if dest.ends_with('/') {
+        dest.to_string()
+    } else {
+        format!("{}/", dest)
+    }
+}
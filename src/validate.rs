@@ -0,0 +1,219 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
+use tracing::error;
+use which::which;
+
+use crate::{
+    cli::TrackSource,
+    error::Error,
+    input::{
+        find_source_file, find_source_map, find_timecodes_file, get_video_dimensions,
+        get_video_info, read_timecodes_file, script_audio_outputs, wobbly_project,
+    },
+    output::{Output, VideoEncoder},
+    pipeline::ProcessOptions,
+    tool_log::is_dry_run,
+};
+
+/// Checks everything about a batch which can be checked before any work starts,
+/// so that a mistake in a later input or output isn't found hours in.
+///
+/// Every problem is logged, rather than only the first.
+pub fn validate_batch(jobs: &[(PathBuf, Vec<Output>)], options: &ProcessOptions) -> Result<()> {
+    let mut problems = Vec::new();
+    let tools = jobs
+        .iter()
+        .flat_map(|(_, outputs)| outputs.iter().flat_map(required_tools))
+        .unique();
+    problems.extend(missing_tools(tools));
+    problems.extend(check_output_dir(options.output_path()));
+    for dir in &options.copy_to {
+        if !dir.is_dir() {
+            problems.push(anyhow!(
+                "--copy-to directory does not exist: {}",
+                dir.display()
+            ));
+        }
+    }
+    for (input, outputs) in jobs {
+        problems.extend(check_input(input, outputs));
+    }
+    report(problems)
+}
+
+/// Checks a single job, such as one added while a batch is already running
+pub fn validate_job(input: &Path, outputs: &[Output]) -> Result<()> {
+    let mut problems = missing_tools(outputs.iter().flat_map(required_tools).unique());
+    problems.extend(check_input(input, outputs));
+    report(problems)
+}
+
+fn report(problems: Vec<anyhow::Error>) -> Result<()> {
+    let count = problems.len();
+    if count > 1 {
+        for problem in &problems {
+            error!("{}", problem);
+        }
+    }
+    match problems.into_iter().next() {
+        None => Ok(()),
+        Some(first) if count == 1 => Err(first),
+        // The first problem decides the exit code
+        Some(first) => Err(first.context(format!("Found {} problems, nothing was started", count))),
+    }
+}
+
+/// The tools an output needs, besides the ones every output needs
+fn required_tools(output: &Output) -> Vec<&'static str> {
+    let video = &output.video;
+    let mut tools = match video.encoder {
+        VideoEncoder::Copy => vec![],
+        VideoEncoder::X264 { .. } => vec!["x264"],
+        VideoEncoder::SvtAv1 { direct: true, .. } => vec!["SvtAv1EncApp"],
+        VideoEncoder::SvtAv1 { .. } => vec!["av1an", "SvtAv1EncApp"],
+        VideoEncoder::X265 { .. } => vec!["av1an", "x265"],
+        VideoEncoder::Aom { .. } => vec!["av1an", "aomenc"],
+        VideoEncoder::Rav1e { .. } => vec!["av1an", "rav1e"],
+    };
+    if video.post_grain_synth {
+        tools.push("grav1synth");
+    }
+    tools
+}
+
+fn missing_tools<'a>(tools: impl Iterator<Item = &'a str>) -> Vec<anyhow::Error> {
+    tools
+        .filter(|tool| which(tool).is_err())
+        .map(|tool| Error::ToolMissing(tool.to_string()).into())
+        .collect()
+}
+
+/// The output directory must exist, and be writable unless this is a dry run
+fn check_output_dir(dir: &Path) -> Option<anyhow::Error> {
+    if !dir.is_dir() {
+        return Some(anyhow!(
+            "Output directory does not exist: {}",
+            dir.display()
+        ));
+    }
+    if is_dry_run() {
+        return None;
+    }
+    let probe = dir.join(".mp4batch-write-test");
+    match fs::write(&probe, []) {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            None
+        }
+        Err(e) => Some(anyhow!(
+            "Output directory is not writable: {}: {}",
+            dir.display(),
+            e
+        )),
+    }
+}
+
+/// The source must be readable, and any external tracks must exist
+fn check_input(input: &Path, outputs: &[Output]) -> Vec<anyhow::Error> {
+    let mut problems = Vec::new();
+    let source = find_source_file(input);
+    if !source.is_file() {
+        problems.push(
+            Error::SourceProbeFailed(format!(
+                "Source of {} does not exist: {}",
+                input.display(),
+                source.display()
+            ))
+            .into(),
+        );
+    } else if get_video_info(&source).map_or(true, |info| info.video.is_none()) {
+        problems.push(
+            Error::SourceProbeFailed(format!("Unable to read the video of {}", source.display()))
+                .into(),
+        );
+    }
+    let sources = find_source_map(input);
+    for mapped in [
+        Some(&sources.audio),
+        Some(&sources.subtitles),
+        sources.chapters.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .unique()
+    {
+        if mapped != &source && !mapped.is_file() {
+            problems.push(
+                Error::SourceProbeFailed(format!(
+                    "Source named in {} does not exist: {}",
+                    input.display(),
+                    mapped.display()
+                ))
+                .into(),
+            );
+        }
+    }
+    if let Err(e) = wobbly_project(input) {
+        problems.push(Error::SourceProbeFailed(e.to_string()).into());
+    }
+    // Checked up front, rather than finding the timecodes don't fit once the encode is done
+    if let Some(timecodes) = find_timecodes_file(input) {
+        if let Err(e) = get_video_dimensions(input)
+            .and_then(|dimensions| read_timecodes_file(&timecodes, dimensions.frames))
+        {
+            problems.push(Error::SourceProbeFailed(e.to_string()).into());
+        }
+    }
+    let tracks = outputs
+        .iter()
+        .flat_map(|output| output.audio_tracks.iter().chain(&output.sub_tracks))
+        .filter_map(|track| match track.source {
+            TrackSource::External(ref path) => Some(path),
+            TrackSource::FromVideo(_) | TrackSource::ScriptAudio(_) => None,
+        })
+        .unique();
+    for track in tracks {
+        if !track.is_file() {
+            problems.push(
+                Error::SourceProbeFailed(format!(
+                    "Track file for {} does not exist: {}",
+                    input.display(),
+                    track.display()
+                ))
+                .into(),
+            );
+        }
+    }
+    let script_audio = outputs
+        .iter()
+        .flat_map(|output| &output.audio_tracks)
+        .filter_map(|track| match track.source {
+            TrackSource::ScriptAudio(index) => Some(index),
+            _ => None,
+        })
+        .unique()
+        .collect::<Vec<_>>();
+    if !script_audio.is_empty() {
+        match script_audio_outputs(input) {
+            Ok(available) => {
+                for index in script_audio.into_iter().filter(|i| !available.contains(i)) {
+                    problems.push(
+                        Error::SourceProbeFailed(format!(
+                            "{} has no audio output {}",
+                            input.display(),
+                            index
+                        ))
+                        .into(),
+                    );
+                }
+            }
+            Err(e) => problems.push(e),
+        }
+    }
+    problems
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use notify::{recommended_watcher, Event, EventKind, RecursiveMode, Watcher};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watches `dir` for new or changed files accepted by `filter`,
+/// calling `on_ready` once each one has stopped growing for `settle`.
+///
+/// A file is called again if it is modified after it was processed.
+///
+/// Runs until the watcher fails.
+pub fn watch_directory(
+    dir: &Path,
+    settle: Duration,
+    filter: impl Fn(&Path) -> bool,
+    mut on_ready: impl FnMut(PathBuf),
+) -> Result<()> {
+    let (tx, rx) = channel::<notify::Result<Event>>();
+    let mut watcher =
+        recommended_watcher(tx).map_err(|e| anyhow!("Failed to start watching: {}", e))?;
+    watcher
+        .watch(dir, RecursiveMode::Recursive)
+        .map_err(|e| anyhow!("Failed to watch {}: {}", dir.display(), e))?;
+
+    // Files which have appeared, with their last seen size and when it last changed
+    let mut pending: HashMap<PathBuf, (u64, Instant)> = HashMap::new();
+    // Files which have been processed, with their modification time when they were
+    let mut processed: HashMap<PathBuf, SystemTime> = HashMap::new();
+    loop {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => {
+                let event = event.map_err(|e| anyhow!("Error while watching: {}", e))?;
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        if path.is_file()
+                            && filter(&path)
+                            && processed.get(&path) != modified_time(&path).as_ref()
+                        {
+                            pending.entry(path).or_insert((0, Instant::now()));
+                        }
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow!("Directory watcher stopped unexpectedly"));
+            }
+        }
+
+        let mut ready = Vec::new();
+        pending.retain(|path, (last_size, last_change)| {
+            let size = match path.metadata() {
+                Ok(meta) => meta.len(),
+                // Deleted or renamed before it finished
+                Err(_) => return false,
+            };
+            if size != *last_size {
+                *last_size = size;
+                *last_change = Instant::now();
+                return true;
+            }
+            if last_change.elapsed() < settle {
+                return true;
+            }
+            ready.push(path.clone());
+            false
+        });
+        ready.sort();
+        for path in ready {
+            if let Some(modified) = modified_time(&path) {
+                processed.insert(path.clone(), modified);
+            }
+            on_ready(path);
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|meta| meta.modified()).ok()
+}
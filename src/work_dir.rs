@@ -0,0 +1,55 @@
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+
+use crate::absolute_path;
+
+static WORK_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+/// Puts intermediates in `dir` instead of next to the scripts they are made from
+pub fn set_work_dir(dir: &Path) -> io::Result<()> {
+    let dir = absolute_path(dir)?;
+    WORK_DIR
+        .set(dir)
+        .expect("Work directory should only be set once");
+    Ok(())
+}
+
+/// The path intermediates of `path` are named after, by replacing its extension.
+///
+/// This is `path` itself, unless there is a work directory. Scripts from
+/// different directories get their own directory inside it, so scripts with
+/// the same name don't share intermediates.
+pub fn work_path(path: &Path) -> PathBuf {
+    let work_dir = match WORK_DIR.get() {
+        Some(work_dir) => work_dir,
+        None => return path.to_path_buf(),
+    };
+    let parent = absolute_path(path)
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+    let digest = Sha256::digest(parent.to_string_lossy().as_bytes());
+    let mut dir = parent
+        .file_name()
+        .map_or_else(String::new, |name| format!("{}-", name.to_string_lossy()));
+    for byte in &digest[..4] {
+        let _ = write!(dir, "{:02x}", byte);
+    }
+    work_dir
+        .join(dir)
+        .join(path.file_name().expect("File should have a name"))
+}
+
+/// Creates the directory the intermediates of `path` go in
+pub fn create_work_dir(path: &Path) -> io::Result<()> {
+    match work_path(path).parent() {
+        Some(dir) => fs::create_dir_all(dir),
+        None => Ok(()),
+    }
+}